@@ -0,0 +1,70 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use latex_snippet::{render, RenderOptions, Visibility};
+
+/// Snippets like the problems we render in bulk.
+const CORPUS: &[(&str, &str)] = &[
+    ("problem", include_str!("corpus/problem.tex")),
+    ("handout", include_str!("corpus/handout.tex")),
+    ("prose", include_str!("corpus/prose.tex")),
+];
+
+fn html_string(c: &mut Criterion) {
+    let mut group = c.benchmark_group("html_string");
+    for (name, latex) in CORPUS {
+        group.throughput(Throughput::Bytes(latex.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), latex, |b, latex| {
+            b.iter(|| latex_snippet::html_string(latex))
+        });
+    }
+    group.finish();
+}
+
+fn render_with_options(c: &mut Criterion) {
+    let options = RenderOptions::new()
+        .physics_macros(true)
+        .solutions(Visibility::Hide)
+        .image_directory("figs/");
+    let mut group = c.benchmark_group("render");
+    for (name, latex) in CORPUS {
+        group.throughput(Throughput::Bytes(latex.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), latex, |b, latex| {
+            b.iter(|| render(latex, &options).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn check_latex(c: &mut Criterion) {
+    let mut group = c.benchmark_group("check_latex");
+    for (name, latex) in CORPUS {
+        group.throughput(Throughput::Bytes(latex.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), latex, |b, latex| {
+            b.iter(|| latex_snippet::check_latex(latex))
+        });
+    }
+    group.finish();
+}
+
+/// Many small snippets one after another, as when re-exporting a whole
+/// database of problems.
+fn bulk(c: &mut Criterion) {
+    let snippets: Vec<&str> = CORPUS
+        .iter()
+        .map(|(_, latex)| *latex)
+        .cycle()
+        .take(300)
+        .collect();
+    let mut group = c.benchmark_group("bulk");
+    group.throughput(Throughput::Elements(snippets.len() as u64));
+    group.bench_function("html_string", |b| {
+        b.iter(|| {
+            for latex in &snippets {
+                latex_snippet::html_string(latex);
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, html_string, render_with_options, check_latex, bulk);
+criterion_main!(benches);
@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|latex: &str| {
+    latex_snippet::check_latex(latex);
+    latex_snippet::check_math(latex);
+});
@@ -0,0 +1,23 @@
+#![no_main]
+use latex_snippet::{render, RenderOptions, Visibility};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|latex: &str| {
+    latex_snippet::strip_comments(latex);
+    latex_snippet::pull_sections_out(latex);
+    latex_snippet::include_solutions(latex);
+    latex_snippet::omit_solutions(latex);
+    latex_snippet::omit_guide(latex);
+    latex_snippet::omit_handout(latex);
+    latex_snippet::only_handout(latex);
+    latex_snippet::with_image_directory(latex, "figs/");
+    for visibility in [Visibility::Show, Visibility::Hide, Visibility::Only] {
+        let options = RenderOptions::new()
+            .physics_macros(true)
+            .solutions(visibility)
+            .guides(visibility)
+            .handouts(visibility)
+            .image_directory("figs/");
+        render(latex, &options).unwrap();
+    }
+});
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|latex: &str| {
+    latex_snippet::html_string(latex);
+    latex_snippet::html_with_diagnostics(latex);
+    latex_snippet::html_string_with_source_positions(latex);
+});
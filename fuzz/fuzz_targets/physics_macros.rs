@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|latex: &str| {
+    let expanded = latex_snippet::physics_macros(latex);
+    latex_snippet::html_string(&expanded);
+});
@@ -1,3 +1,5 @@
+from collections import namedtuple
+
 from .latex_snippet import lib, ffi
 
 def html(s):
@@ -22,3 +24,64 @@ def omit_handout(s):
 def only_handout(s):
     return ffi.string(lib.c_only_handout(s.encode())).decode()
 
+
+_VISIBILITY = {'show': 0, 'hide': 1, 'only': 2}
+_EXPONENT = {'times': 0, 'cdot': 1, 'letter': 2}
+_PER_MODE = {'power': 0, 'symbol': 1}
+_MATH = {'tex': 0, 'mathml': 1, 'lite': 2}
+_SPEECH = {'off': 0, 'brief': 1, 'verbose': 2}
+
+Rendered = namedtuple('Rendered', ['html', 'diagnostics', 'table_of_contents'])
+Diagnostic = namedtuple('Diagnostic', ['code', 'severity', 'message', 'start', 'end'])
+
+def render_full(s, physics_macros=False, solutions='show', guides='show',
+                handouts='show', image_directory=None, heading_level=2,
+                fail_on_errors=False, source_positions=False, max_depth=0,
+                max_output_bytes=0, max_expansions=0, macros=None,
+                decimal_marker='.', group_digits=True, exponent='times',
+                per_mode='power', math='tex', number_equations=False,
+                speech='off', number_sections=False, heading_ids=False):
+    options = ffi.new("CRenderOptions *")
+    options.physics_macros = physics_macros
+    options.solutions = _VISIBILITY[solutions]
+    options.guides = _VISIBILITY[guides]
+    options.handouts = _VISIBILITY[handouts]
+    directory = ffi.NULL
+    if image_directory is not None:
+        directory = ffi.new("char[]", image_directory.encode())
+    options.image_directory = directory
+    options.heading_level = heading_level
+    options.fail_on_errors = fail_on_errors
+    options.source_positions = source_positions
+    options.max_depth = max_depth
+    options.max_output_bytes = max_output_bytes
+    options.max_expansions = max_expansions
+    definitions = ffi.NULL
+    if macros is not None:
+        definitions = ffi.new("char[]", macros.encode())
+    options.macros = definitions
+    options.decimal_marker = ord(decimal_marker)
+    options.ungrouped_digits = not group_digits
+    options.exponent = _EXPONENT[exponent]
+    options.per_mode = _PER_MODE[per_mode]
+    options.math = _MATH[math]
+    options.number_equations = number_equations
+    options.speech = _SPEECH[speech]
+    options.number_sections = number_sections
+    options.heading_ids = heading_ids
+    out = ffi.gc(lib.latex_render_full(s.encode(), options), lib.latex_rendered_free)
+    if out == ffi.NULL:
+        raise ValueError('invalid LaTeX')
+    diagnostics = [Diagnostic(ffi.string(d.code).decode(),
+                              'error' if d.severity else 'warning',
+                              ffi.string(d.message).decode(), d.start, d.end)
+                   for d in out.diagnostics[0:out.diagnostic_count]]
+    if out.html == ffi.NULL:
+        errors = ''.join('\n{}..{}: {}'.format(d.start, d.end, d.message)
+                         for d in diagnostics if d.severity == 'error')
+        raise ValueError('invalid LaTeX' + errors, diagnostics)
+    return Rendered(ffi.string(out.html).decode(), diagnostics,
+                    ffi.string(out.table_of_contents).decode())
+
+def render(s, *args, **kwargs):
+    return render_full(s, *args, **kwargs).html
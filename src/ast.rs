@@ -0,0 +1,200 @@
+//! The typed tree produced by [`parse`](crate::parse).
+
+/// A parsed LaTeX snippet.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Document {
+    /// The top-level nodes, in source order.
+    pub content: Vec<Node>,
+}
+
+/// A piece of a parsed LaTeX snippet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// What sort of node this is.
+    pub kind: NodeKind,
+    /// The byte range of this node in the LaTeX that was parsed.
+    pub span: std::ops::Range<usize>,
+}
+
+impl Node {
+    /// Create a node of `kind` that came from `span`.
+    pub fn new(kind: NodeKind, span: std::ops::Range<usize>) -> Self {
+        Node { kind, span }
+    }
+
+    /// Visit this node and everything within it, parents first.
+    pub(crate) fn walk(&self, f: &mut impl FnMut(&Node)) {
+        f(self);
+        for nodes in self.children() {
+            for node in nodes {
+                node.walk(f);
+            }
+        }
+    }
+
+    /// The lists of nodes directly within this node.
+    pub(crate) fn children(&self) -> Vec<&Vec<Node>> {
+        match &self.kind {
+            NodeKind::Text(_)
+            | NodeKind::NonBreakingSpace
+            | NodeKind::Math { .. }
+            | NodeKind::Error(_) => Vec::new(),
+            NodeKind::Paragraph(content)
+            | NodeKind::Group(content)
+            | NodeKind::Declaration { content, .. } => vec![content],
+            NodeKind::Section {
+                short_title,
+                title,
+                content,
+                ..
+            } => short_title
+                .iter()
+                .chain(Some(title))
+                .chain(Some(content))
+                .collect(),
+            NodeKind::Macro { args, .. } => args
+                .iter()
+                .filter_map(|a| match a {
+                    Argument::Latex(content) => Some(content),
+                    _ => None,
+                })
+                .collect(),
+            NodeKind::Environment { content, .. } => vec![content],
+            NodeKind::Item { label, content } => label.iter().chain(Some(content)).collect(),
+            NodeKind::Row(cells) => cells.iter().collect(),
+        }
+    }
+
+    /// Visit this node and everything within it, parents first, so as
+    /// to change them.
+    pub(crate) fn walk_mut(&mut self, f: &mut impl FnMut(&mut Node)) {
+        f(self);
+        let children: Vec<&mut Vec<Node>> = match &mut self.kind {
+            NodeKind::Text(_)
+            | NodeKind::NonBreakingSpace
+            | NodeKind::Math { .. }
+            | NodeKind::Error(_) => Vec::new(),
+            NodeKind::Paragraph(content)
+            | NodeKind::Group(content)
+            | NodeKind::Declaration { content, .. } => vec![content],
+            NodeKind::Section {
+                short_title,
+                title,
+                content,
+                ..
+            } => short_title
+                .iter_mut()
+                .chain(Some(title))
+                .chain(Some(content))
+                .collect(),
+            NodeKind::Macro { args, .. } => args
+                .iter_mut()
+                .filter_map(|a| match a {
+                    Argument::Latex(content) => Some(content),
+                    _ => None,
+                })
+                .collect(),
+            NodeKind::Environment { content, .. } => vec![content],
+            NodeKind::Item { label, content } => label.iter_mut().chain(Some(content)).collect(),
+            NodeKind::Row(cells) => cells.iter_mut().collect(),
+        };
+        for nodes in children {
+            for node in nodes.iter_mut() {
+                node.walk_mut(f);
+            }
+        }
+    }
+}
+
+/// The different kinds of [`Node`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeKind {
+    /// Plain text, not yet escaped for any output format.
+    Text(String),
+    /// A non-breaking space, written `~`.
+    NonBreakingSpace,
+    /// A paragraph that was separated from its neighbors by blank lines.
+    ///
+    /// Content that stands alone is not wrapped in a paragraph.
+    Paragraph(Vec<Node>),
+    /// A heading together with everything up to the next heading of
+    /// the same level.
+    Section {
+        /// 1 for `\section`, 2 for `\subsection` and 3 for `\subsubsection`.
+        level: u8,
+        /// Whether the heading was starred.
+        starred: bool,
+        /// The `[...]` short title, for the table of contents.
+        short_title: Option<Vec<Node>>,
+        /// The title of the heading.
+        title: Vec<Node>,
+        /// The content of the section.
+        content: Vec<Node>,
+    },
+    /// A `{...}` group.
+    Group(Vec<Node>),
+    /// A macro such as `\emph{...}` along with its arguments.
+    Macro {
+        /// The name of the macro, without the backslash.
+        name: String,
+        /// The arguments, in source order.
+        args: Vec<Argument>,
+    },
+    /// A declaration such as `\it` or `\centering`, which applies to
+    /// the rest of the enclosing group.
+    Declaration {
+        /// The name of the declaration, without the backslash.
+        name: String,
+        /// The content the declaration applies to.
+        content: Vec<Node>,
+    },
+    /// A `\begin{name}...\end{name}` environment.
+    Environment {
+        /// The name of the environment.
+        name: String,
+        /// Any arguments following `\begin{name}`.
+        args: Vec<Argument>,
+        /// The content of the environment.
+        content: Vec<Node>,
+    },
+    /// An `\item` of a list environment.
+    Item {
+        /// The `[...]` label of a `description` item.
+        label: Option<Vec<Node>>,
+        /// The content of the item.
+        content: Vec<Node>,
+    },
+    /// A row of a `tabular`, holding the content of each cell.
+    Row(Vec<Vec<Node>>),
+    /// Math, which is kept as LaTeX.
+    Math {
+        /// How the math was delimited.
+        style: MathStyle,
+        /// The LaTeX inside the delimiters.
+        latex: String,
+    },
+    /// LaTeX that could not be understood, kept as written.
+    Error(String),
+}
+
+/// An argument of a [`NodeKind::Macro`] or [`NodeKind::Environment`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Argument {
+    /// A `[...]` optional argument, taken verbatim.
+    Optional(String),
+    /// A required argument holding LaTeX.
+    Latex(Vec<Node>),
+    /// A required argument taken verbatim, such as a url or a file name.
+    Verbatim(String),
+}
+
+/// How a piece of math was delimited.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MathStyle {
+    /// `$...$` or `\(...\)`
+    Inline,
+    /// `$$...$$` or `\[...\]`
+    Display,
+    /// A math environment such as `align`, holding its name.
+    Environment(String),
+}
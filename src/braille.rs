@@ -0,0 +1,1243 @@
+//! Transcribing LaTeX into braille, with its text in Unified English
+//! Braille (UEB) and its math in Nemeth code, switching between them
+//! with the Nemeth indicators as is done in UEB contexts.
+
+use crate::equations::Equations;
+use crate::headings::Headings;
+use crate::math::{self, Math, MathError};
+use crate::references::{self, Kind, References, Target};
+use crate::{mhchem, siunitx, text, UnitOptions};
+use crate::{
+    parse_with_diagnostics, Argument, Diagnostic, MathStyle, Node, NodeKind, RenderLimits, Severity,
+};
+use wasm_bindgen::prelude::*;
+
+/// Which grade of UEB to use for text.  Math is always in Nemeth code.
+///
+/// ```
+/// use latex_snippet::{braille, BrailleGrade};
+/// let contracted = braille("Go and see", BrailleGrade::Contracted);
+/// assert_eq!("⠠⠛⠀⠯⠀⠎⠑⠑", contracted.braille);
+/// let uncontracted = braille("Go and see", BrailleGrade::Uncontracted);
+/// assert_eq!("⠠⠛⠕⠀⠁⠝⠙⠀⠎⠑⠑", uncontracted.braille);
+/// ```
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BrailleGrade {
+    /// Grade 1, with every letter spelled out.
+    Uncontracted,
+    /// Grade 2 with its wordsigns, such as `⠯` for "and" standing
+    /// alone, and its common groupsigns within words, such as `⠹` for
+    /// "th", but without its shortforms, its contractions of two cells
+    /// or the prefixes "be", "con" and "dis".
+    Contracted,
+}
+
+/// LaTeX transcribed into braille by [`braille`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Braille {
+    /// The braille, as Unicode braille patterns, with a line for each
+    /// line of braille.
+    pub braille: String,
+    /// Any problems found in the LaTeX, including anything we could not
+    /// transcribe, which is left as its LaTeX.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Braille {
+    /// The braille as Braille ASCII, as in a BRF file.
+    pub fn brf(&self) -> String {
+        self.braille
+            .chars()
+            .map(|c| match c as u32 {
+                cell @ 0x2800..=0x283f => BRAILLE_ASCII[cell as usize - 0x2800] as char,
+                _ => c,
+            })
+            .collect()
+    }
+}
+
+/// The Braille ASCII for each of the 64 six-dot cells, in the order of
+/// Unicode braille.
+const BRAILLE_ASCII: &[u8; 64] =
+    b" A1B'K2L@CIF/MSP\"E3H9O6R^DJG>NTQ,*5<-U8V.%[$+X!&;:4\\0Z7(_?W]#Y)=";
+
+/// Transcribe `latex` into braille.
+pub fn braille(latex: &str, grade: BrailleGrade) -> Braille {
+    let (document, mut diagnostics) = parse_with_diagnostics(latex);
+    let mut equations = Equations::new(&document.content, latex);
+    diagnostics.append(&mut equations.diagnostics);
+    let headings = Headings::new(&document.content);
+    let mut references = References::new(&document.content, &headings, Some(&mut equations));
+    diagnostics.append(&mut references.diagnostics);
+    let mut transcriber = Transcriber {
+        grade,
+        out: String::new(),
+        diagnostics: Vec::new(),
+        equations,
+        references,
+    };
+    transcriber.nodes(&document.content);
+    diagnostics.append(&mut transcriber.diagnostics);
+    diagnostics.sort_by_key(|d| d.span.start);
+    Braille {
+        braille: layout(&transcriber.out),
+        diagnostics,
+    }
+}
+
+/// Transcribe `latex` into braille, as Braille ASCII if `brf`, leaving
+/// as LaTeX anything that cannot be transcribed.
+#[wasm_bindgen]
+pub fn braille_string(latex: &str, grade: BrailleGrade, brf: bool) -> String {
+    let braille = braille(latex, grade);
+    if brf {
+        braille.brf()
+    } else {
+        braille.braille
+    }
+}
+
+/// Tidy the lines of `braille`, without blank cells at their ends or
+/// more than one blank line in a row.
+fn layout(braille: &str) -> String {
+    let mut out = String::with_capacity(braille.len());
+    let mut blank = true;
+    for line in braille.lines().map(|line| line.trim_end_matches(BLANK)) {
+        if line.is_empty() {
+            if !blank {
+                out.push('\n');
+            }
+            blank = true;
+        } else {
+            out.push_str(line);
+            out.push('\n');
+            blank = false;
+        }
+    }
+    out.truncate(out.trim_end().len());
+    out
+}
+
+const BLANK: char = '\u{2800}';
+
+/// The letters a to z.
+const LETTERS: [&str; 26] = [
+    "⠁", "⠃", "⠉", "⠙", "⠑", "⠋", "⠛", "⠓", "⠊", "⠚", "⠅", "⠇", "⠍", "⠝", "⠕", "⠏", "⠟", "⠗", "⠎",
+    "⠞", "⠥", "⠧", "⠺", "⠭", "⠽", "⠵",
+];
+
+fn letter(c: char) -> &'static str {
+    LETTERS[(c.to_ascii_lowercase() as u8 - b'a') as usize]
+}
+
+/// UEB indicators.
+const CAPITAL: &str = "⠠";
+const NUMERIC: &str = "⠼";
+const GRADE_1: &str = "⠰";
+const NEMETH_OPEN: &str = "⠸⠩";
+const NEMETH_CLOSE: &str = "⠸⠱";
+const NOTE_OPEN: &str = "⠈⠨⠣";
+const NOTE_CLOSE: &str = "⠈⠨⠜";
+
+/// UEB punctuation and signs.
+const PUNCTUATION: &[(char, &str)] = &[
+    (',', "⠂"),
+    (';', "⠆"),
+    (':', "⠒"),
+    ('.', "⠲"),
+    ('!', "⠖"),
+    ('?', "⠦"),
+    ('\'', "⠄"),
+    ('`', "⠠⠦"),
+    ('-', "⠤"),
+    ('(', "⠐⠣"),
+    (')', "⠐⠜"),
+    ('[', "⠨⠣"),
+    (']', "⠨⠜"),
+    ('"', "⠠⠶"),
+    ('/', "⠸⠌"),
+    ('&', "⠈⠯"),
+    ('%', "⠨⠴"),
+    ('#', "⠸⠹"),
+    ('$', "⠈⠎"),
+    ('*', "⠐⠔"),
+    ('+', "⠐⠖"),
+    ('=', "⠐⠶"),
+    ('<', "⠈⠣"),
+    ('>', "⠈⠜"),
+    ('@', "⠈⠁"),
+    ('_', "⠨⠤"),
+    ('\\', "⠸⠡"),
+    ('{', "⠸⠣"),
+    ('}', "⠸⠜"),
+    ('^', "⠈⠢"),
+    ('~', "⠈⠔"),
+    ('|', "⠸⠳"),
+];
+
+/// The UEB for LaTeX's ligatures for quotes and dashes.
+const LIGATURES: &[(&str, &str)] = &[("``", "⠘⠦"), ("''", "⠘⠴"), ("---", "⠠⠤"), ("--", "⠠⠤")];
+
+/// The wordsigns of contracted UEB, which stand for whole words.
+const WORDSIGNS: &[(&str, &str)] = &[
+    ("but", "⠃"),
+    ("can", "⠉"),
+    ("do", "⠙"),
+    ("every", "⠑"),
+    ("from", "⠋"),
+    ("go", "⠛"),
+    ("have", "⠓"),
+    ("just", "⠚"),
+    ("knowledge", "⠅"),
+    ("like", "⠇"),
+    ("more", "⠍"),
+    ("not", "⠝"),
+    ("people", "⠏"),
+    ("quite", "⠟"),
+    ("rather", "⠗"),
+    ("so", "⠎"),
+    ("that", "⠞"),
+    ("us", "⠥"),
+    ("very", "⠧"),
+    ("will", "⠺"),
+    ("it", "⠭"),
+    ("you", "⠽"),
+    ("as", "⠵"),
+    ("and", "⠯"),
+    ("for", "⠿"),
+    ("of", "⠷"),
+    ("the", "⠮"),
+    ("with", "⠾"),
+    ("child", "⠡"),
+    ("shall", "⠩"),
+    ("this", "⠹"),
+    ("which", "⠱"),
+    ("out", "⠳"),
+    ("still", "⠌"),
+];
+
+/// Where in a word a groupsign may go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Place {
+    Anywhere,
+    /// Anywhere but at the start.
+    NotFirst,
+    /// Neither at the start nor at the end.
+    Middle,
+}
+
+/// The groupsigns of contracted UEB, which stand for letters within a
+/// word, longest first, so that "things" has "ing" rather than "in".
+const GROUPSIGNS: &[(&str, &str, Place)] = &[
+    ("with", "⠾", Place::Anywhere),
+    ("and", "⠯", Place::Anywhere),
+    ("for", "⠿", Place::Anywhere),
+    ("the", "⠮", Place::Anywhere),
+    ("ing", "⠬", Place::NotFirst),
+    ("of", "⠷", Place::Anywhere),
+    ("ch", "⠡", Place::Anywhere),
+    ("gh", "⠣", Place::Anywhere),
+    ("sh", "⠩", Place::Anywhere),
+    ("th", "⠹", Place::Anywhere),
+    ("wh", "⠱", Place::Anywhere),
+    ("ed", "⠫", Place::Anywhere),
+    ("er", "⠻", Place::Anywhere),
+    ("ou", "⠳", Place::Anywhere),
+    ("ow", "⠪", Place::Anywhere),
+    ("st", "⠌", Place::Anywhere),
+    ("ar", "⠜", Place::Anywhere),
+    ("en", "⠢", Place::Anywhere),
+    ("in", "⠔", Place::Anywhere),
+    ("ea", "⠂", Place::Middle),
+    ("bb", "⠆", Place::Middle),
+    ("cc", "⠒", Place::Middle),
+    ("ff", "⠖", Place::Middle),
+    ("gg", "⠶", Place::Middle),
+];
+
+/// The groupsign for the letters at `i` in `word`, if one may go there,
+/// with how many letters it stands for.  With `capitals`, only the
+/// first of them may be a capital, since the capital indicator goes
+/// before the groupsign.
+fn groupsign(word: &str, i: usize, capitals: bool) -> Option<(usize, &'static str)> {
+    let rest = &word[i..];
+    GROUPSIGNS.iter().find_map(|&(letters, sign, place)| {
+        let end = i + letters.len();
+        let found = rest.get(..letters.len())?;
+        let fits = found.eq_ignore_ascii_case(letters)
+            && !(capitals && found[1..].contains(|c: char| c.is_ascii_uppercase()))
+            // Standing alone, it would be read as a wordsign.
+            && letters.len() < word.len()
+            && match place {
+                Place::Anywhere => true,
+                Place::NotFirst => i > 0,
+                Place::Middle => i > 0 && end < word.len(),
+            };
+        fits.then_some((letters.len(), sign))
+    })
+}
+
+/// The UEB modifiers for the accents of [`text::accented`], which go
+/// before the letter.
+const ACCENTS: &[(&str, &str)] = &[
+    ("'", "⠘⠌"),
+    ("`", "⠘⠡"),
+    ("^", "⠘⠩"),
+    ("\"", "⠘⠒"),
+    ("~", "⠘⠻"),
+    ("c", "⠘⠯"),
+];
+
+/// The UEB indicators for a word, a passage and the end of a passage in
+/// a typeform.
+const ITALIC: [&str; 3] = ["⠨⠂", "⠨⠶", "⠨⠄"];
+const BOLD: [&str; 3] = ["⠘⠂", "⠘⠶", "⠘⠄"];
+const UNDERLINE: [&str; 3] = ["⠸⠂", "⠸⠶", "⠸⠄"];
+
+struct Transcriber {
+    grade: BrailleGrade,
+    out: String,
+    diagnostics: Vec<Diagnostic>,
+    equations: Equations,
+    references: References,
+}
+
+impl Transcriber {
+    fn unsupported(&mut self, node: &Node, message: String) {
+        self.diagnostics.push(Diagnostic {
+            code: "unsupported-braille",
+            severity: Severity::Warning,
+            message,
+            span: node.span.clone(),
+        });
+    }
+
+    fn new_line(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with('\n') {
+            self.out.push('\n');
+        }
+    }
+
+    fn space(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with([BLANK, '\n']) {
+            self.out.push(BLANK);
+        }
+    }
+
+    fn nodes(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            self.node(node);
+        }
+    }
+
+    /// Transcribe `nodes` by themselves, rather than after what we have
+    /// written.
+    fn apart(&mut self, nodes: &[Node]) -> String {
+        let out = std::mem::take(&mut self.out);
+        self.nodes(nodes);
+        std::mem::replace(&mut self.out, out)
+    }
+
+    /// Transcribe `nodes` in a typeform, with `indicators` for a word,
+    /// a passage and the end of a passage.
+    fn typeform(&mut self, indicators: [&str; 3], nodes: &[Node]) {
+        let inner = self.apart(nodes);
+        let words: Vec<&str> = inner.split(BLANK).filter(|w| !w.is_empty()).collect();
+        if words.len() < 3 {
+            for (i, word) in words.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(BLANK);
+                }
+                self.out.push_str(indicators[0]);
+                self.out.push_str(word);
+            }
+        } else {
+            self.out.push_str(indicators[1]);
+            self.out.push_str(&inner);
+            self.out.push_str(indicators[2]);
+        }
+    }
+
+    fn node(&mut self, node: &Node) {
+        match &node.kind {
+            NodeKind::Text(text) => self.text(text, self.grade),
+            NodeKind::NonBreakingSpace => self.out.push(BLANK),
+            NodeKind::Paragraph(content) => {
+                self.new_line();
+                self.out.push_str("⠀⠀");
+                self.nodes(content);
+                self.new_line();
+            }
+            NodeKind::Section { title, content, .. } => {
+                self.new_line();
+                self.out.push('\n');
+                self.nodes(title);
+                self.out.push('\n');
+                self.nodes(content);
+            }
+            NodeKind::Group(content) => self.nodes(content),
+            NodeKind::Declaration { name, content } => match name.as_str() {
+                "it" | "em" | "sl" => self.typeform(ITALIC, content),
+                "bf" => self.typeform(BOLD, content),
+                _ => self.nodes(content),
+            },
+            NodeKind::Macro { name, args } => self.command(node, name, args),
+            NodeKind::Environment {
+                name,
+                args,
+                content,
+            } => self.environment(node, name, args, content),
+            NodeKind::Item { label, content } => {
+                self.new_line();
+                if let Some(label) = label {
+                    self.typeform(BOLD, label);
+                    self.space();
+                }
+                self.nodes(content);
+            }
+            NodeKind::Row(cells) => {
+                self.new_line();
+                for (i, cell) in cells.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str("⠀⠀");
+                    }
+                    self.nodes(cell);
+                }
+            }
+            NodeKind::Math { style, latex } => self.math(node, style, latex),
+            // We have already reported these.
+            NodeKind::Error(latex) => self.text(latex, BrailleGrade::Uncontracted),
+        }
+    }
+
+    fn command(&mut self, node: &Node, name: &str, args: &[Argument]) {
+        match (name, args) {
+            ("\\" | "newpage" | "vspace" | "vfill", _) => self.new_line(),
+            ("label" | "centering" | "noindent" | "hline" | "tableofcontents", _) => (),
+            ("textbackslash", []) => self.text("\\", BrailleGrade::Uncontracted),
+            ("ldots", []) => self.out.push_str("⠲⠲⠲"),
+            (" ", []) => self.out.push(BLANK),
+            ("%" | "#" | "$" | "&" | "_" | "{" | "}", []) => {
+                self.text(name, BrailleGrade::Uncontracted)
+            }
+            (accent, [Argument::Verbatim(base)]) if text::is_accent(accent) => {
+                let modifier = ACCENTS.iter().find(|(a, _)| *a == accent);
+                match (modifier, base.trim().chars().next()) {
+                    // Written on nothing, these are the characters themselves.
+                    (_, None) if accent == "^" || accent == "~" => {
+                        self.text(accent, BrailleGrade::Uncontracted)
+                    }
+                    (Some((_, modifier)), Some(c)) if c.is_ascii_alphabetic() => {
+                        self.out.push_str(modifier);
+                        if c.is_ascii_uppercase() {
+                            self.out.push_str(CAPITAL);
+                        }
+                        self.out.push_str(letter(c));
+                    }
+                    _ => {
+                        let latex = format!(r"\{}{{{}}}", accent, base);
+                        self.unsupported(node, format!("no braille for {}", latex));
+                        self.text(&latex, BrailleGrade::Uncontracted);
+                    }
+                }
+            }
+            (name, [Argument::Verbatim(label)]) if references::is_reference(name) => {
+                // We have reported any undefined labels.
+                let target = self.references.target(label).cloned().or_else(|| {
+                    let number = self.equations.number(label)?;
+                    Some(Target {
+                        kind: Kind::Equation,
+                        number: number.to_string(),
+                    })
+                });
+                match target {
+                    Some(target) => {
+                        self.text(&references::reference_text(name, &target), self.grade)
+                    }
+                    None => self.text("??", BrailleGrade::Uncontracted),
+                }
+            }
+            ("verb", [Argument::Verbatim(content)]) => {
+                self.text(content, BrailleGrade::Uncontracted)
+            }
+            ("url", [Argument::Verbatim(url)]) => self.text(url, BrailleGrade::Uncontracted),
+            ("emph" | "textit", [Argument::Latex(content)]) => self.typeform(ITALIC, content),
+            ("textbf", [Argument::Latex(content)]) => self.typeform(BOLD, content),
+            ("underline", [Argument::Latex(content)]) => self.typeform(UNDERLINE, content),
+            ("footnote", [Argument::Latex(content)]) => {
+                self.out.push_str("⠐⠔");
+                self.out.push_str(NOTE_OPEN);
+                self.nodes(content);
+                self.out.push_str(NOTE_CLOSE);
+            }
+            ("caption" | "paragraph" | "paragraph*", [Argument::Latex(content)]) => {
+                self.new_line();
+                self.nodes(content);
+                self.new_line();
+            }
+            (
+                "texttt" | "warning" | "error" | "href" | "hyperref" | "textcolor",
+                [.., Argument::Latex(content)],
+            ) => self.nodes(content),
+            ("includegraphics", _) => {
+                self.unsupported(node, "no braille for images".to_string());
+            }
+            _ => {
+                let latex = format!(r"\{}", name);
+                self.unsupported(node, format!("no braille for {}", latex));
+                self.text(&latex, BrailleGrade::Uncontracted);
+                for arg in args {
+                    match arg {
+                        Argument::Latex(content) => self.nodes(content),
+                        Argument::Optional(text) | Argument::Verbatim(text) => {
+                            self.text(text, BrailleGrade::Uncontracted)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn environment(&mut self, node: &Node, name: &str, args: &[Argument], content: &[Node]) {
+        match name {
+            "itemize" | "enumerate" => {
+                let mut count = 0;
+                for item in content {
+                    if let NodeKind::Item { content, .. } = &item.kind {
+                        count += 1;
+                        self.new_line();
+                        if name == "itemize" {
+                            self.out.push_str("⠸⠲");
+                        } else {
+                            self.text(&format!("{}.", count), BrailleGrade::Uncontracted);
+                        }
+                        self.out.push(BLANK);
+                        self.nodes(content);
+                    } else {
+                        self.node(item);
+                    }
+                }
+                self.new_line();
+            }
+            "verbatim" => {
+                self.new_line();
+                if let [Argument::Verbatim(text)] = args {
+                    for line in text.lines() {
+                        self.text(line, BrailleGrade::Uncontracted);
+                        self.out.push('\n');
+                    }
+                }
+            }
+            "figure" | "wrapfigure" | "table" | "solution" | "guide" | "handout" | "tabular"
+            | "center" | "quote" | "quotation" | "description" => {
+                self.new_line();
+                self.nodes(content);
+                self.new_line();
+            }
+            _ if references::theorem_name(name).is_some() => {
+                self.new_line();
+                let mut title = references::theorem_name(name)
+                    .unwrap_or_default()
+                    .to_string();
+                if let Some(number) = self.references.number(&node.span) {
+                    title = format!("{} {}", title, number);
+                }
+                if let [Argument::Optional(note)] = args {
+                    title = format!("{} ({})", title, note);
+                }
+                self.text(&format!("{}.", title), self.grade);
+                self.out.push(BLANK);
+                self.nodes(content);
+                self.new_line();
+            }
+            _ => {
+                self.unsupported(node, format!("no braille for the environment {}", name));
+                self.nodes(content);
+            }
+        }
+    }
+
+    /// Transcribe `text` into UEB of `grade`.
+    fn text(&mut self, text: &str, grade: BrailleGrade) {
+        let mut words = text.split(|c: char| c.is_whitespace()).peekable();
+        while let Some(word) = words.next() {
+            self.word(word, grade);
+            if words.peek().is_some() {
+                self.space();
+            }
+        }
+    }
+
+    fn word(&mut self, word: &str, grade: BrailleGrade) {
+        // The word itself, without any punctuation around it.
+        let start = word.find(|c: char| c.is_ascii_alphanumeric());
+        let end = word.rfind(|c: char| c.is_ascii_alphanumeric());
+        let (before, core, after) = match (start, end) {
+            (Some(start), Some(end)) => (&word[..start], &word[start..=end], &word[end + 1..]),
+            _ => (word, "", ""),
+        };
+        let letters = core.chars().filter(char::is_ascii_alphabetic).count();
+        let shouting = letters > 1 && !core.chars().any(|c| c.is_ascii_lowercase());
+        let wordsign = match grade {
+            BrailleGrade::Contracted => WORDSIGNS
+                .iter()
+                .find(|(w, _)| w.eq_ignore_ascii_case(core))
+                .map(|(_, sign)| *sign),
+            BrailleGrade::Uncontracted => None,
+        };
+        self.characters(before);
+        if let Some(sign) = wordsign {
+            if shouting {
+                self.out.push_str(CAPITAL);
+                self.out.push_str(CAPITAL);
+            } else if core.starts_with(|c: char| c.is_ascii_uppercase()) {
+                self.out.push_str(CAPITAL);
+            }
+            self.out.push_str(sign);
+        } else {
+            // A letter by itself would otherwise be read as a wordsign.
+            let lone = letters == 1 && core.len() == 1;
+            if grade == BrailleGrade::Contracted && lone && !"aAiIoO".contains(core) {
+                self.out.push_str(GRADE_1);
+            }
+            if shouting {
+                self.out.push_str(CAPITAL);
+                self.out.push_str(CAPITAL);
+            }
+            let contracted = grade == BrailleGrade::Contracted;
+            self.characters_in(core, !shouting, contracted);
+        }
+        self.characters(after);
+    }
+
+    fn characters(&mut self, text: &str) {
+        self.characters_in(text, true, false);
+    }
+
+    /// Transcribe `text` a character at a time, marking each capital if
+    /// `capitals`, and using groupsigns if `text` is a word to contract.
+    fn characters_in(&mut self, text: &str, capitals: bool, contracted: bool) {
+        let mut numeric = false;
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            if let Some((ligature, sign)) = LIGATURES.iter().find(|(l, _)| rest.starts_with(l)) {
+                self.out.push_str(sign);
+                rest = &rest[ligature.len()..];
+                numeric = false;
+                continue;
+            }
+            let next = rest[c.len_utf8()..].chars().next();
+            match c {
+                '0'..='9' => {
+                    if !numeric {
+                        self.out.push_str(NUMERIC);
+                        numeric = true;
+                    }
+                    // The digits are the letters a to j.
+                    let digit = (c as u8 - b'0') as usize;
+                    self.out.push_str(LETTERS[(digit + 9) % 10]);
+                }
+                ',' | '.' if numeric && next.is_some_and(|c| c.is_ascii_digit()) => {
+                    self.out.push_str(if c == ',' { "⠂" } else { "⠲" });
+                }
+                c if c.is_ascii_alphabetic() && contracted && !numeric => {
+                    let i = text.len() - rest.len();
+                    if capitals && c.is_ascii_uppercase() {
+                        self.out.push_str(CAPITAL);
+                    }
+                    match groupsign(text, i, capitals) {
+                        Some((len, sign)) => {
+                            self.out.push_str(sign);
+                            rest = &rest[len..];
+                            continue;
+                        }
+                        None => self.out.push_str(letter(c)),
+                    }
+                }
+                c if c.is_ascii_alphabetic() => {
+                    // After a number, a to j would be read as digits.
+                    if numeric && ('a'..='j').contains(&c) {
+                        self.out.push_str(GRADE_1);
+                    }
+                    numeric = false;
+                    if capitals && c.is_ascii_uppercase() {
+                        self.out.push_str(CAPITAL);
+                    }
+                    self.out.push_str(letter(c));
+                }
+                c => {
+                    numeric = false;
+                    match PUNCTUATION.iter().find(|(p, _)| *p == c) {
+                        Some((_, sign)) => self.out.push_str(sign),
+                        // Anything else is left as it is, to be seen.
+                        None => self.out.push(c),
+                    }
+                }
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    fn math(&mut self, node: &Node, style: &MathStyle, source: &str) {
+        let mut style = std::borrow::Cow::Borrowed(style);
+        let mut latex = std::borrow::Cow::Borrowed(source);
+        if let Some(numbered) = self.equations.take(&node.span) {
+            style = std::borrow::Cow::Owned(MathStyle::Environment(numbered.environment));
+            latex = numbered.latex.into();
+        }
+        if let Some(resolved) = self.equations.resolve_in_math(&latex) {
+            latex = resolved.into();
+        }
+        let units = UnitOptions::new();
+        if let Some(expanded) = mhchem::expand_in_math(&latex, &units) {
+            latex = expanded.into();
+        }
+        if let Some(expanded) = siunitx::expand_in_math(&latex, &units) {
+            latex = expanded.into();
+        }
+        let display = *style != MathStyle::Inline;
+        if display {
+            self.new_line();
+        } else {
+            self.space();
+        }
+        match nemeth(&latex, &style) {
+            Ok(braille) => {
+                self.out.push_str(NEMETH_OPEN);
+                self.out.push(BLANK);
+                self.out.push_str(&braille);
+                self.out.push(BLANK);
+                self.out.push_str(NEMETH_CLOSE);
+            }
+            Err(e) => {
+                if e.code == "max-depth" {
+                    self.diagnostics.push(Diagnostic {
+                        code: e.code,
+                        severity: Severity::Error,
+                        message: format!("left as LaTeX: math {}", e.message),
+                        span: node.span.clone(),
+                    });
+                } else {
+                    self.unsupported(node, format!("left as LaTeX: {}", e.message));
+                }
+                self.text(source, BrailleGrade::Uncontracted);
+            }
+        }
+        if display {
+            self.new_line();
+        }
+    }
+}
+
+/// The Nemeth code for Greek letters, by their names.
+const GREEK: &[(&str, &str)] = &[
+    ("alpha", "⠁"),
+    ("beta", "⠃"),
+    ("gamma", "⠛"),
+    ("delta", "⠙"),
+    ("epsilon", "⠑"),
+    ("zeta", "⠵"),
+    ("eta", "⠱"),
+    ("theta", "⠹"),
+    ("iota", "⠊"),
+    ("kappa", "⠅"),
+    ("lambda", "⠇"),
+    ("mu", "⠍"),
+    ("nu", "⠝"),
+    ("xi", "⠭"),
+    ("omicron", "⠕"),
+    ("pi", "⠏"),
+    ("rho", "⠗"),
+    ("sigma", "⠎"),
+    ("tau", "⠞"),
+    ("upsilon", "⠥"),
+    ("phi", "⠋"),
+    ("chi", "⠯"),
+    ("psi", "⠽"),
+    ("omega", "⠺"),
+];
+
+/// The Nemeth digits 0 to 9, which are in the lower part of the cell.
+const DIGITS: [&str; 10] = ["⠴", "⠂", "⠆", "⠒", "⠲", "⠢", "⠖", "⠶", "⠦", "⠔"];
+
+/// Nemeth signs of comparison, which have a space on each side.
+const COMPARISONS: &[(&str, &str)] = &[
+    ("=", "⠨⠅"),
+    ("<", "⠐⠅"),
+    (">", "⠨⠂"),
+    ("\u{2264}", "⠐⠅⠱"),
+    ("\u{2265}", "⠨⠂⠱"),
+    ("\u{2260}", "⠌⠨⠅"),
+    ("\u{2248}", "⠈⠱⠈⠱"),
+    ("\u{223c}", "⠈⠱"),
+    ("\u{2208}", "⠈⠑"),
+    ("\u{2282}", "⠸⠐⠅"),
+    ("\u{2286}", "⠸⠐⠅⠱"),
+    ("\u{2192}", "⠫⠒⠒⠕"),
+];
+
+/// Other Nemeth signs and symbols.
+const SIGNS: &[(&str, &str)] = &[
+    ("+", "⠬"),
+    ("\u{2212}", "⠤"),
+    ("\u{b1}", "⠬⠤"),
+    ("\u{2213}", "⠤⠬"),
+    ("\u{d7}", "⠈⠡"),
+    ("\u{22c5}", "⠡"),
+    ("\u{f7}", "⠨⠌"),
+    ("/", "⠸⠌"),
+    ("\u{2217}", "⠈⠼"),
+    ("!", "⠯"),
+    ("\u{2218}", "⠨⠡"),
+    ("\u{222a}", "⠨⠬"),
+    ("\u{2229}", "⠨⠩"),
+    ("(", "⠷"),
+    (")", "⠾"),
+    ("[", "⠈⠷"),
+    ("]", "⠈⠾"),
+    ("{", "⠨⠷"),
+    ("}", "⠨⠾"),
+    ("|", "⠳"),
+    ("\u{2032}", "⠄"),
+    ("\u{2032}\u{2032}", "⠄⠄"),
+    ("\u{2026}", "⠄⠄⠄"),
+    ("\u{22ef}", "⠄⠄⠄"),
+    (";", "⠸⠆"),
+    (":", "⠸⠒"),
+    (".", "⠸⠲"),
+    ("\u{221e}", "⠠⠿"),
+    ("\u{2202}", "⠈⠙"),
+    ("\u{2207}", "⠨⠫"),
+    ("\u{2211}", "⠨⠠⠎"),
+    ("\u{220f}", "⠨⠠⠏"),
+    ("\u{222b}", "⠮"),
+    ("\u{222c}", "⠮⠮"),
+    ("\u{222d}", "⠮⠮⠮"),
+];
+
+/// The Nemeth modifiers for accents.
+const MODIFIERS: &[(&str, &str)] = &[
+    ("\u{af}", "⠱"),
+    ("_", "⠱"),
+    ("\u{2192}", "⠫⠒⠒⠕"),
+    ("\u{2dc}", "⠈⠱"),
+    ("\u{2d9}", "⠡"),
+];
+
+/// Nemeth indicators.
+const NEMETH_NUMERIC: &str = "⠼";
+const ENGLISH_LETTER: &str = "⠰";
+const SUPERSCRIPT: &str = "⠘";
+const SUBSCRIPT: &str = "⠰";
+const BASELINE: &str = "⠐";
+const MULTIPURPOSE: &str = "⠐";
+const DIRECTLY_OVER: &str = "⠣";
+const DIRECTLY_UNDER: &str = "⠩";
+const TERMINATION: &str = "⠻";
+
+/// The Nemeth code for the math `latex`, or what we could not
+/// transcribe.
+fn nemeth(latex: &str, style: &MathStyle) -> Result<String, MathError> {
+    let latex = latex.replace(crate::LATEX_DBAR, r"{\mathit{\unicode{273}}}");
+    let environment = match style {
+        MathStyle::Environment(name) => Some(name.as_str()),
+        _ => None,
+    };
+    let math = math::parse(&latex, environment, RenderLimits::new().depth())?;
+    let mut nemeth = Nemeth::default();
+    nemeth.math(&math).map_err(|message| MathError {
+        code: "unsupported-braille",
+        message,
+        span: 0..latex.len(),
+    })?;
+    Ok(nemeth.out.trim_end_matches(BLANK).to_string())
+}
+
+#[derive(Default)]
+struct Nemeth {
+    out: String,
+    /// The level indicator for the scripts we are in, empty at the
+    /// baseline.
+    level: String,
+    /// The level indicator to write before the next sign, having come
+    /// back from a script.
+    pending: Option<String>,
+    /// How many radicals we are inside.
+    radicals: usize,
+}
+
+/// How deeply fractions are nested in `math`.
+fn fraction_depth(math: &Math) -> usize {
+    match math {
+        Math::Fraction {
+            numerator,
+            denominator,
+            ..
+        } => 1 + fraction_depth(numerator).max(fraction_depth(denominator)),
+        Math::Row(items) => items.iter().map(fraction_depth).max().unwrap_or(0),
+        Math::Fenced { content, .. } | Math::Variant { content, .. } => fraction_depth(content),
+        Math::Scripts { base, .. } => fraction_depth(base),
+        Math::Root { radicand, .. } => fraction_depth(radicand),
+        _ => 0,
+    }
+}
+
+fn is_letter(math: &Math) -> bool {
+    matches!(math, Math::Identifier(name) if name.len() == 1 && name.starts_with(|c: char| c.is_ascii_alphabetic()))
+}
+
+impl Nemeth {
+    /// Write the whole of `math`, as it was parsed.
+    fn math(&mut self, math: &Math) -> Result<(), String> {
+        match math {
+            // An alignment has a line for each row.
+            Math::Table { rows, .. } => {
+                for (i, row) in rows.iter().enumerate() {
+                    if i > 0 {
+                        self.new_line();
+                    }
+                    for (j, cell) in row.iter().enumerate() {
+                        // The number of an equation is set apart from it.
+                        if let (Math::Text(_), true) = (cell, j > 0 && j + 1 == row.len()) {
+                            self.space();
+                        }
+                        self.write(cell)?;
+                    }
+                }
+            }
+            // A letter by itself would be read as a word.
+            Math::Row(items) if items.len() == 1 && is_letter(&items[0]) => {
+                self.sign(ENGLISH_LETTER);
+                self.write(math)?;
+            }
+            math => self.write(math)?,
+        }
+        Ok(())
+    }
+
+    /// Write `sign`, after any level indicator we need first.
+    fn sign(&mut self, sign: &str) {
+        if let Some(level) = self.pending.take() {
+            self.out.push_str(&level);
+        }
+        self.out.push_str(sign);
+    }
+
+    /// Whether a number here needs the numeric indicator.
+    fn after_space(&self) -> bool {
+        self.pending.is_none() && (self.out.is_empty() || self.out.ends_with([BLANK, '\n']))
+    }
+
+    fn space(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with([BLANK, '\n']) {
+            self.out.push(BLANK);
+        }
+        // A space takes us back to the baseline, so in a script we must
+        // say again where we are.
+        self.pending = (!self.level.is_empty()).then(|| self.level.clone());
+    }
+
+    fn new_line(&mut self) {
+        self.out.truncate(self.out.trim_end_matches(BLANK).len());
+        self.out.push('\n');
+        self.pending = None;
+    }
+
+    fn number(&mut self, digits: &str) {
+        if self.after_space() {
+            self.out.push_str(NEMETH_NUMERIC);
+        }
+        for c in digits.chars() {
+            match c {
+                '0'..='9' => self.sign(DIGITS[(c as u8 - b'0') as usize]),
+                '.' => self.sign("⠨"),
+                _ => self.sign("⠠"),
+            }
+        }
+    }
+
+    fn symbol(&mut self, symbol: &str) -> Result<(), String> {
+        if let Some((_, sign)) = COMPARISONS.iter().find(|(s, _)| *s == symbol) {
+            self.space();
+            self.sign(sign);
+            self.space();
+            return Ok(());
+        }
+        if symbol == "," {
+            self.sign("⠠");
+            self.space();
+            return Ok(());
+        }
+        if let Some((_, sign)) = SIGNS.iter().find(|(s, _)| *s == symbol) {
+            self.sign(sign);
+            return Ok(());
+        }
+        let mut chars = symbol.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphabetic() => {
+                if c.is_ascii_uppercase() {
+                    self.sign(CAPITAL);
+                }
+                self.sign(letter(c));
+                Ok(())
+            }
+            (Some(c), None) if c.is_ascii_digit() => {
+                self.number(symbol);
+                Ok(())
+            }
+            (Some(' '), None) => {
+                self.space();
+                Ok(())
+            }
+            _ => match math::greek_name(symbol) {
+                Some(name) => {
+                    let lower = name.trim_start_matches("var").to_ascii_lowercase();
+                    let Some((_, sign)) = GREEK.iter().find(|(n, _)| *n == lower) else {
+                        return Err(format!("no Nemeth braille for {}", symbol));
+                    };
+                    self.sign("⠨");
+                    if name.starts_with(|c: char| c.is_ascii_uppercase()) {
+                        self.out.push_str(CAPITAL);
+                    }
+                    self.out.push_str(sign);
+                    Ok(())
+                }
+                None => Err(format!("no Nemeth braille for {}", symbol)),
+            },
+        }
+    }
+
+    /// Write `math` as a script, with the level indicator `level`.
+    fn script(&mut self, level: &str, math: &Math) -> Result<(), String> {
+        let inner = format!("{}{}", self.level, level);
+        let outer = std::mem::replace(&mut self.level, inner);
+        self.pending = None;
+        self.out.push_str(&self.level);
+        self.write(math)?;
+        self.level = outer;
+        self.pending = Some(if self.level.is_empty() {
+            BASELINE.to_string()
+        } else {
+            self.level.clone()
+        });
+        Ok(())
+    }
+
+    fn write(&mut self, math: &Math) -> Result<(), String> {
+        match math {
+            Math::Identifier(name) | Math::Operator(name) | Math::LargeOperator(name) => {
+                self.symbol(name)?
+            }
+            Math::Number(digits) => self.number(digits),
+            Math::Function(name) => {
+                self.text(name)?;
+                self.space();
+            }
+            Math::Text(text) => self.text(text)?,
+            Math::Space(_) => (),
+            Math::Row(items) => {
+                for item in items {
+                    self.write(item)?;
+                }
+            }
+            Math::Fraction {
+                numerator,
+                denominator,
+                line: true,
+            } => {
+                // A fraction within a fraction is complex, and so on.
+                let order = "⠠".repeat(fraction_depth(math) - 1);
+                self.sign(&format!("{}⠹", order));
+                self.write(numerator)?;
+                self.sign(&format!("{}⠌", order));
+                self.write(denominator)?;
+                self.sign(&format!("{}⠼", order));
+            }
+            Math::Fraction { line: false, .. } => {
+                return Err("no Nemeth braille for binomial coefficients".to_string())
+            }
+            Math::Root { radicand, index } => {
+                let order = "⠨".repeat(self.radicals);
+                self.radicals += 1;
+                if let Some(index) = index {
+                    self.sign("⠣");
+                    self.write(index)?;
+                }
+                self.sign(&format!("{}⠜", order));
+                self.write(radicand)?;
+                self.sign(&format!("{}⠻", order));
+                self.radicals -= 1;
+            }
+            Math::Scripts {
+                base,
+                sub,
+                sup,
+                limits,
+            } => {
+                if *limits {
+                    // Limits go directly under and over, as modifiers.
+                    self.sign(MULTIPURPOSE);
+                    self.write(base)?;
+                    if let Some(sub) = sub {
+                        self.sign(DIRECTLY_UNDER);
+                        self.write(sub)?;
+                    }
+                    if let Some(sup) = sup {
+                        self.sign(DIRECTLY_OVER);
+                        self.write(sup)?;
+                    }
+                    self.sign(TERMINATION);
+                    if let Math::Function(_) = &**base {
+                        self.space();
+                    }
+                    return Ok(());
+                }
+                match &**base {
+                    // The space after a function goes after its scripts.
+                    Math::Function(name) => self.text(name)?,
+                    base => self.write(base)?,
+                }
+                // Primes are not superscripts.
+                let sup = match sup.as_deref() {
+                    Some(Math::Operator(primes)) if primes.starts_with('\u{2032}') => {
+                        self.symbol(primes)?;
+                        None
+                    }
+                    Some(Math::Row(items)) => match items.split_first() {
+                        Some((Math::Operator(primes), rest)) if primes.starts_with('\u{2032}') => {
+                            self.symbol(primes)?;
+                            Some(Math::Row(rest.to_vec()))
+                        }
+                        _ => sup.as_deref().cloned(),
+                    },
+                    sup => sup.cloned(),
+                };
+                if let Some(sub) = sub {
+                    match &**sub {
+                        // A number directly below a letter needs no
+                        // subscript indicator.
+                        Math::Number(digits)
+                            if self.level.is_empty()
+                                && is_letter(base)
+                                && digits.chars().all(|c| c.is_ascii_digit()) =>
+                        {
+                            for c in digits.chars() {
+                                self.sign(DIGITS[(c as u8 - b'0') as usize]);
+                            }
+                        }
+                        sub => self.script(SUBSCRIPT, sub)?,
+                    }
+                }
+                if let Some(sup) = &sup {
+                    self.script(SUPERSCRIPT, sup)?;
+                }
+                if let Math::Function(_) = &**base {
+                    self.space();
+                }
+            }
+            Math::Accent { base, mark, over } => {
+                let Some((_, modifier)) = MODIFIERS.iter().find(|(m, _)| m == mark) else {
+                    return Err(format!("no Nemeth braille for the accent {}", mark));
+                };
+                self.sign(MULTIPURPOSE);
+                self.write(base)?;
+                self.sign(if *over { DIRECTLY_OVER } else { DIRECTLY_UNDER });
+                self.sign(modifier);
+                self.sign(TERMINATION);
+            }
+            Math::Fenced { content, .. } if matches!(**content, Math::Table { .. }) => {
+                return Err("no Nemeth braille for matrices".to_string())
+            }
+            Math::Fenced {
+                open,
+                close,
+                content,
+            } => {
+                if !open.is_empty() {
+                    self.symbol(open)?;
+                }
+                self.write(content)?;
+                if !close.is_empty() {
+                    self.symbol(close)?;
+                }
+            }
+            Math::Variant {
+                variant: "normal" | "italic",
+                content,
+            } => self.write(content)?,
+            Math::Variant {
+                variant: "bold",
+                content,
+            } if is_letter(content) => {
+                self.sign("⠸");
+                self.write(content)?;
+            }
+            Math::Variant { variant, .. } => {
+                return Err(format!("no Nemeth braille for {} letters", variant))
+            }
+            Math::Table { .. } => return Err("no Nemeth braille for arrays".to_string()),
+        }
+        Ok(())
+    }
+
+    /// Write letters and the like, as in the name of a function.
+    fn text(&mut self, text: &str) -> Result<(), String> {
+        for c in text.chars() {
+            self.symbol(c.encode_utf8(&mut [0; 4]))?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_nemeth() {
+    let inline = |latex| nemeth(latex, &MathStyle::Inline);
+    assert_eq!(Ok("⠰⠭".to_string()), inline("x"));
+    assert_eq!(Ok("⠭⠘⠆⠐⠬⠂".to_string()), inline("x^2+1"));
+    assert_eq!(Ok("⠭⠀⠨⠅⠀⠹⠂⠌⠆⠼".to_string()), inline(r"x = \frac12"));
+    assert_eq!(Ok("⠼⠂⠨⠢".to_string()), inline("1.5"));
+    assert_eq!(Ok("⠭⠂⠘⠆".to_string()), inline("x_1^2"));
+    assert_eq!(Ok("⠭⠰⠊⠘⠝".to_string()), inline("x_i^n"));
+    assert_eq!(Ok("⠭⠘⠝⠘⠘⠊".to_string()), inline("x^{n^i}"));
+    assert_eq!(Ok("⠜⠭⠘⠆⠐⠻".to_string()), inline(r"\sqrt{x^2}"));
+    assert_eq!(Ok("⠣⠒⠜⠭⠻".to_string()), inline(r"\sqrt[3]{x}"));
+    assert_eq!(Ok("⠠⠹⠹⠂⠌⠆⠼⠠⠌⠭⠠⠼".to_string()), inline(r"\frac{\frac12}{x}"));
+    assert_eq!(Ok("⠨⠏⠗⠘⠆".to_string()), inline(r"\pi r^2"));
+    assert_eq!(Ok("⠨⠠⠙".to_string()), inline(r"\Delta"));
+    assert_eq!(Ok("⠋⠄⠷⠭⠾".to_string()), inline("f'(x)"));
+    assert_eq!(Ok("⠎⠊⠝⠀⠭".to_string()), inline(r"\sin x"));
+    assert_eq!(
+        Ok("⠐⠨⠠⠎⠩⠊⠀⠨⠅⠀⠼⠂⠣⠝⠻".to_string()),
+        nemeth(r"\sum_{i=1}^n", &MathStyle::Display)
+    );
+    assert_eq!(
+        Ok("⠭⠘⠆⠀⠨⠅⠀⠼⠲\n⠭⠀⠨⠅⠀⠤⠂".to_string()),
+        nemeth(
+            r"x^2 &= 4 \\ x &= -1",
+            &MathStyle::Environment("align*".to_string())
+        )
+    );
+    assert!(inline(r"\binom{n}{k}").is_err());
+    assert!(inline(r"\begin{pmatrix}1\end{pmatrix}").is_err());
+    let deep = format!("{}x{}", "{".repeat(5_000), "}".repeat(5_000));
+    assert_eq!("max-depth", inline(&deep).unwrap_err().code);
+}
+
+#[test]
+fn test_ueb() {
+    let ueb = |latex| braille(latex, BrailleGrade::Uncontracted).braille;
+    let contracted = |latex| braille(latex, BrailleGrade::Contracted).braille;
+    assert_eq!("⠠⠠⠃⠗⠋", ueb("BRF"));
+    assert_eq!("⠼⠁⠃⠉⠰⠁", ueb("123a"));
+    assert_eq!("⠼⠁⠂⠚⠚⠚", ueb("1,000"));
+    assert_eq!("⠘⠦⠠⠓⠊⠲⠘⠴", ueb("``Hi.''"));
+    assert_eq!("⠨⠂⠰⠑⠀⠮⠀⠰⠃", contracted(r"\emph{e} the b"));
+    assert_eq!("⠠⠮⠀⠠⠠⠭⠂", contracted("The IT,"));
+    assert_eq!("⠨⠶⠁⠀⠃⠀⠉⠨⠄", ueb(r"\textit{a b c}"));
+    assert_eq!("⠹⠬⠎", contracted("things"));
+    assert_eq!("⠠⠮⠀⠠⠠⠹⠬⠎", contracted("The THINGS"));
+    assert_eq!("⠎⠓⠀⠔⠓⠫⠀⠗⠂⠙⠀⠎⠑⠁", contracted("sh inhed read sea"));
+    assert_eq!("⠿⠑⠌⠀⠔⠛⠗⠁⠍⠀⠼⠙⠞⠓", contracted("forest ingram 4th"));
+    assert_eq!("⠠⠌⠠⠜⠀⠎⠠⠞", contracted("StAr sT"));
+}
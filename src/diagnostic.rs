@@ -0,0 +1,27 @@
+//! Problems found while parsing LaTeX.
+
+use wasm_bindgen::prelude::*;
+
+/// How serious a [`Diagnostic`] is.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// Something that renders, but possibly not as intended.
+    Warning,
+    /// Something that is rendered as an error.
+    Error,
+}
+
+/// A problem found in some LaTeX.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// A short identifier for the kind of problem, such as
+    /// `"unknown-macro"` or `"unclosed-environment"`.
+    pub code: &'static str,
+    /// How serious the problem is.
+    pub severity: Severity,
+    /// A human-readable description of the problem.
+    pub message: String,
+    /// The byte range of the offending LaTeX in the input.
+    pub span: std::ops::Range<usize>,
+}
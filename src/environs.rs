@@ -0,0 +1,261 @@
+//! The math environments we understand.  The parser, the math renderers,
+//! the MathJax checks and [`check_latex`](crate::check_latex) all look
+//! them up here, so that they agree about which are fine.
+
+use crate::parse::{macro_name, Braces};
+use std::ops::Range;
+
+/// Where a math environment may go, and how we write it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Placement {
+    /// Inline math, like `$...$`.
+    Inline,
+    /// Display math, like `\[...\]`.
+    Display,
+    /// A display environment of its own, such as `align`.
+    Environment,
+    /// An environment that belongs inside math, such as `pmatrix`, which
+    /// we put in display math when it is found outside.
+    Inner,
+}
+
+/// How the equations in an environment are numbered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Numbering {
+    None,
+    /// One number for the whole environment.
+    Single,
+    /// A number for each row.
+    PerRow,
+}
+
+/// What the argument of an environment gives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Arg {
+    /// The number of pairs of right and left aligned columns, as for
+    /// `alignat`.
+    Pairs,
+    /// A column specification, as for `array`.
+    Columns,
+}
+
+/// A math environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MathEnviron {
+    pub(crate) name: &'static str,
+    pub(crate) placement: Placement,
+    /// Whether there is also a starred form, without numbers.
+    pub(crate) starred: bool,
+    pub(crate) numbering: Numbering,
+    /// Whether its columns are separated by `&`.
+    pub(crate) aligned: bool,
+    /// The alignment of the columns of the table we make of it, or
+    /// `None` if it is not a table, or one we cannot write.
+    pub(crate) columns: Option<&'static str>,
+    /// The delimiters around it.
+    pub(crate) delimiters: (&'static str, &'static str),
+    pub(crate) argument: Option<Arg>,
+}
+
+/// An environment of its own with numbered rows, aligned like `align`.
+const fn alignment(name: &'static str, starred: bool, argument: Option<Arg>) -> MathEnviron {
+    MathEnviron {
+        name,
+        placement: Placement::Environment,
+        starred,
+        numbering: Numbering::PerRow,
+        aligned: true,
+        columns: Some("right left"),
+        delimiters: ("", ""),
+        argument,
+    }
+}
+
+/// An environment that goes inside math, with its columns separated by
+/// `&`.
+const fn inner(
+    name: &'static str,
+    columns: &'static str,
+    delimiters: (&'static str, &'static str),
+    argument: Option<Arg>,
+) -> MathEnviron {
+    MathEnviron {
+        name,
+        placement: Placement::Inner,
+        starred: false,
+        numbering: Numbering::None,
+        aligned: true,
+        columns: Some(columns),
+        delimiters,
+        argument,
+    }
+}
+
+/// A matrix, which has centered columns.
+const fn matrix(name: &'static str, open: &'static str, close: &'static str) -> MathEnviron {
+    inner(name, "center", (open, close), None)
+}
+
+/// Every math environment we understand.
+pub(crate) const MATH_ENVIRONS: &[MathEnviron] = &[
+    MathEnviron {
+        name: "equation",
+        placement: Placement::Environment,
+        starred: true,
+        numbering: Numbering::Single,
+        aligned: false,
+        columns: None,
+        delimiters: ("", ""),
+        argument: None,
+    },
+    MathEnviron {
+        numbering: Numbering::Single,
+        aligned: false,
+        columns: Some("center"),
+        ..alignment("multline", true, None)
+    },
+    MathEnviron {
+        aligned: false,
+        columns: Some("center"),
+        ..alignment("gather", true, None)
+    },
+    alignment("align", true, None),
+    alignment("alignat", true, Some(Arg::Pairs)),
+    alignment("flalign", true, None),
+    alignment("xalignat", false, Some(Arg::Pairs)),
+    MathEnviron {
+        numbering: Numbering::None,
+        ..alignment("xxalignat", false, Some(Arg::Pairs))
+    },
+    MathEnviron {
+        columns: Some("right center left"),
+        ..alignment("eqnarray", true, None)
+    },
+    MathEnviron {
+        name: "math",
+        placement: Placement::Inline,
+        starred: false,
+        numbering: Numbering::None,
+        aligned: false,
+        columns: None,
+        delimiters: ("", ""),
+        argument: None,
+    },
+    MathEnviron {
+        name: "displaymath",
+        placement: Placement::Display,
+        starred: false,
+        numbering: Numbering::None,
+        aligned: false,
+        columns: None,
+        delimiters: ("", ""),
+        argument: None,
+    },
+    inner("aligned", "right left", ("", ""), None),
+    inner("alignedat", "right left", ("", ""), Some(Arg::Pairs)),
+    inner("split", "right left", ("", ""), None),
+    MathEnviron {
+        aligned: false,
+        ..inner("gathered", "center", ("", ""), None)
+    },
+    inner("cases", "left", ("{", ""), None),
+    inner("dcases", "left", ("{", ""), None),
+    inner("rcases", "left", ("", "}"), None),
+    matrix("matrix", "", ""),
+    matrix("smallmatrix", "", ""),
+    matrix("pmatrix", "(", ")"),
+    matrix("bmatrix", "[", "]"),
+    matrix("Bmatrix", "{", "}"),
+    matrix("vmatrix", "|", "|"),
+    matrix("Vmatrix", "\u{2016}", "\u{2016}"),
+    inner("array", "center", ("", ""), Some(Arg::Columns)),
+    inner("darray", "center", ("", ""), Some(Arg::Columns)),
+    inner("subarray", "center", ("", ""), Some(Arg::Columns)),
+    // Commutative diagrams, which only MathJax can draw.
+    MathEnviron {
+        columns: None,
+        ..inner("CD", "", ("", ""), None)
+    },
+];
+
+/// The math environment called `name`, which may be the starred form.
+pub(crate) fn find(name: &str) -> Option<&'static MathEnviron> {
+    let (unstarred, starred) = match name.strip_suffix('*') {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+    MATH_ENVIRONS
+        .iter()
+        .find(|e| e.name == unstarred && (e.starred || !starred))
+}
+
+/// The byte ranges of the rows of `latex`, which are separated by `\\`
+/// outside of any braces or inner environment.
+pub(crate) fn rows(latex: &str) -> Vec<Range<usize>> {
+    let mut rows = Vec::new();
+    let mut row_start = 0;
+    let braces = Braces::new(latex);
+    scan(latex, |at, rest| match rest.strip_prefix(r"\\") {
+        Some(after) => {
+            rows.push(row_start..at);
+            let len = 2 + braces.optional_argument(after).len();
+            row_start = at + len;
+            len
+        }
+        None => 0,
+    });
+    rows.push(row_start..latex.len());
+    rows
+}
+
+/// How many `&` separate the columns of the row `latex`, outside of any
+/// braces or inner environment.
+pub(crate) fn separators(latex: &str) -> usize {
+    let mut count = 0;
+    scan(latex, |_, rest| {
+        if rest.starts_with('&') {
+            count += 1;
+        }
+        0
+    });
+    count
+}
+
+/// Call `f` with the offset and the rest of `latex` at each `\\` or `&`
+/// that is outside of any braces or inner environment.  It gives how
+/// much of the rest it has consumed.
+fn scan(latex: &str, mut f: impl FnMut(usize, &str) -> usize) {
+    let mut depth = 0usize;
+    let mut rest = latex;
+    while let Some(c) = rest.chars().next() {
+        let at = latex.len() - rest.len();
+        let mut len = c.len_utf8();
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            '&' if depth == 0 => len = len.max(f(at, rest)),
+            '\\' => {
+                let name = macro_name(rest);
+                len = name.len();
+                match name {
+                    r"\begin" => depth += 1,
+                    r"\end" => depth = depth.saturating_sub(1),
+                    r"\\" if depth == 0 => len = len.max(f(at, rest)),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+        rest = &rest[len..];
+    }
+}
+
+#[test]
+fn test_environs() {
+    assert_eq!(Some("align"), find("align*").map(|e| e.name));
+    assert_eq!(None, find("pmatrix*"));
+    assert_eq!(None, find("tabular"));
+    assert_eq!(vec![0..3, 5..8, 15..17], rows(r"a&b\\c&d\\[1em]ef"));
+    assert_eq!(vec![0..31], rows(r"\begin{matrix}1\\2\end{matrix}x"));
+    assert_eq!(3, separators(r"a&b&{c&d}\&\begin{cases}1&2\end{cases}&e"));
+}
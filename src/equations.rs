@@ -0,0 +1,302 @@
+//! Numbering equations ourselves, so that `\ref` and `\eqref` give the
+//! right number wherever the equation is, rather than leaving it to
+//! MathJax.
+
+use crate::environs::{self, rows, Numbering};
+use crate::parse::{inside, macro_name, rewrite_macros, Braces};
+use crate::{math_start, Diagnostic, MathStyle, Node, NodeKind, Severity};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// An environment of numbered equations, rewritten so that its numbers
+/// are given by `\tag`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Numbered {
+    /// The environment, which is now starred.
+    pub(crate) environment: String,
+    pub(crate) latex: String,
+    /// The labels in the environment, which need anchors.
+    pub(crate) labels: Vec<String>,
+}
+
+/// The numbers of all the equations in a document.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Equations {
+    /// The number of each label.
+    numbers: HashMap<String, String>,
+    /// Each numbered environment, by where it came from.
+    numbered: Vec<(Range<usize>, Numbered)>,
+    /// Where each label is first defined.
+    spans: HashMap<String, Range<usize>>,
+    /// Undefined and duplicate labels, and those on unnumbered
+    /// equations.
+    pub(crate) diagnostics: Vec<Diagnostic>,
+}
+
+/// A `\label` in some math, with where it is in the math.
+struct Label<'a> {
+    name: &'a str,
+    span: Range<usize>,
+}
+
+impl Equations {
+    /// Number the equations in `content`, which was parsed from
+    /// `source`.
+    pub(crate) fn new(content: &[Node], source: &str) -> Self {
+        let mut equations = Equations::default();
+        let mut count = 0;
+        let mut labels = Vec::new();
+        for node in content {
+            node.walk(&mut |node| {
+                let (environment, latex) = match &node.kind {
+                    NodeKind::Math {
+                        style: MathStyle::Environment(environment),
+                        latex,
+                    } if environs::find(environment)
+                        .is_some_and(|e| e.numbering != Numbering::None) =>
+                    {
+                        (environment, latex)
+                    }
+                    _ => return,
+                };
+                let start = math_start(source, node, latex);
+                let (numbered, found) = number(latex, environment, &mut count);
+                for (label, number) in found {
+                    let span = match start {
+                        Some(start) => start + label.span.start..start + label.span.end,
+                        None => node.span.clone(),
+                    };
+                    let i = equations.numbered.len();
+                    labels.push((label.name.to_string(), number, span, i));
+                }
+                equations.numbered.push((node.span.clone(), numbered));
+            });
+        }
+        for (name, number, span, i) in labels {
+            if equations.spans.contains_key(&name) {
+                // Only the first gets an anchor.
+                let anchors = &mut equations.numbered[i].1.labels;
+                if let Some(j) = anchors.iter().rposition(|l| *l == name) {
+                    anchors.remove(j);
+                }
+                equations.diagnostics.push(Diagnostic {
+                    code: "duplicate-label",
+                    severity: Severity::Warning,
+                    message: format!("the label {} is already defined", name),
+                    span,
+                });
+            } else if let Some(number) = number {
+                equations.spans.insert(name.clone(), span);
+                equations.numbers.insert(name, number);
+            } else {
+                equations.spans.insert(name.clone(), span.clone());
+                equations.diagnostics.push(Diagnostic {
+                    code: "stray-label",
+                    severity: Severity::Warning,
+                    message: format!("the label {} is on an unnumbered equation", name),
+                    span,
+                });
+            }
+        }
+        for node in content {
+            node.walk(&mut |node| equations.check_references(source, node));
+        }
+        equations
+    }
+
+    /// Complain about any reference in the math `node` to a label we do
+    /// not know.
+    fn check_references(&mut self, source: &str, node: &Node) {
+        let mut undefined = Vec::new();
+        // References in the text are checked along with those to
+        // sections and figures.
+        if let NodeKind::Math { latex, .. } = &node.kind {
+            let start = math_start(source, node, latex);
+            rewrite_macros(latex, references, |name, args| {
+                let label = args.first()?;
+                if self.number(label).is_none() {
+                    let reference = format!(r"\{}{{{}}}", name, label);
+                    let span = match start.zip(latex.find(&reference)) {
+                        Some((start, i)) => start + i..start + i + reference.len(),
+                        None => node.span.clone(),
+                    };
+                    undefined.push((label.to_string(), span));
+                }
+                None
+            });
+        }
+        for (label, span) in undefined {
+            self.diagnostics.push(Diagnostic {
+                code: "undefined-label",
+                severity: Severity::Warning,
+                message: format!("the label {} is not defined", label),
+                span,
+            });
+        }
+    }
+
+    /// The number of the equation labelled `label`.
+    pub(crate) fn number(&self, label: &str) -> Option<&str> {
+        self.numbers.get(label).map(String::as_str)
+    }
+
+    /// Where the label `label` is first defined in an equation.
+    pub(crate) fn label_span(&self, label: &str) -> Option<&Range<usize>> {
+        self.spans.get(label)
+    }
+
+    /// Forget the label `label`, which is defined in the text first, so
+    /// that it neither gets an anchor nor refers to an equation.
+    pub(crate) fn remove_label(&mut self, label: &str) {
+        self.spans.remove(label);
+        self.numbers.remove(label);
+        for (_, numbered) in &mut self.numbered {
+            numbered.labels.retain(|l| l != label);
+        }
+    }
+
+    /// The numbered environment that came from `span`, which we will not
+    /// give out again.
+    pub(crate) fn take(&mut self, span: &Range<usize>) -> Option<Numbered> {
+        let i = self.numbered.iter().position(|(s, _)| s == span)?;
+        Some(self.numbered.remove(i).1)
+    }
+
+    /// Replace each `\ref` and `\eqref` in `math` with the number it
+    /// refers to, or `None` if there are none.
+    pub(crate) fn resolve_in_math(&self, math: &str) -> Option<String> {
+        rewrite_macros(math, references, |name, args| {
+            let number = args.first().and_then(|label| self.number(label));
+            Some(match (name, number) {
+                ("eqref", Some(number)) => format!(r"\text{{({})}}", number),
+                (_, Some(number)) => format!(r"\text{{{}}}", number),
+                (_, None) => r"\text{??}".to_string(),
+            })
+        })
+    }
+}
+
+/// How many arguments the referencing macro `name` takes.
+fn references(name: &str) -> Option<usize> {
+    matches!(name, "ref" | "eqref").then_some(1)
+}
+
+/// Number the equations in `latex`, the content of `environment`,
+/// counting from `count`, and return the rewritten environment along
+/// with each label and the number it refers to.
+fn number<'a>(
+    latex: &'a str,
+    environment: &str,
+    count: &mut usize,
+) -> (Numbered, Vec<(Label<'a>, Option<String>)>) {
+    let starred = environment.ends_with('*');
+    let single = environs::find(environment).map(|e| e.numbering) == Some(Numbering::Single);
+    let rows = if single {
+        std::iter::once(0..latex.len()).collect()
+    } else {
+        rows(latex)
+    };
+    let mut out = String::with_capacity(latex.len() + 16);
+    let mut found = Vec::new();
+    let mut copied = 0;
+    let braces = Braces::new(latex);
+    for row in rows {
+        out.push_str(&latex[copied..row.start]);
+        copied = row.end;
+        let mut tag = None;
+        let mut numbered = !starred;
+        let mut labels = Vec::new();
+        let mut rest = &latex[row.clone()];
+        let content = rest;
+        while let Some(i) = rest.find('\\') {
+            out.push_str(&rest[..i]);
+            rest = &rest[i..];
+            let name = macro_name(rest);
+            let start = row.start + content.len() - rest.len();
+            let arg = braces.argument(&rest[name.len()..]);
+            let braced = arg.len() > 1 && arg.starts_with('{');
+            match name {
+                r"\label" if braced => {
+                    let span = start..start + name.len() + arg.len();
+                    labels.push(Label {
+                        name: inside(arg),
+                        span,
+                    });
+                    rest = &rest[name.len() + arg.len()..];
+                }
+                r"\tag" | r"\tag*" if braced => {
+                    tag = Some((name, inside(arg)));
+                    rest = &rest[name.len() + arg.len()..];
+                }
+                r"\nonumber" | r"\notag" => {
+                    numbered = false;
+                    rest = &rest[name.len()..];
+                }
+                _ => {
+                    out.push_str(name);
+                    rest = &rest[name.len()..];
+                }
+            }
+        }
+        out.push_str(rest);
+        let number = match tag {
+            Some((name, tag)) => {
+                out.push_str(&format!("{}{{{}}}", name, tag));
+                Some(tag.to_string())
+            }
+            None if numbered && !latex[row].trim().is_empty() => {
+                *count += 1;
+                out.push_str(&format!(r"\tag{{{}}}", count));
+                Some(count.to_string())
+            }
+            None => None,
+        };
+        found.extend(labels.into_iter().map(|label| (label, number.clone())));
+    }
+    out.push_str(&latex[copied..]);
+    let numbered = Numbered {
+        environment: format!("{}*", environment.trim_end_matches('*')),
+        latex: out,
+        labels: found
+            .iter()
+            .map(|(label, _)| label.name.to_string())
+            .collect(),
+    };
+    (numbered, found)
+}
+
+#[test]
+fn test_number() {
+    let mut count = 0;
+    let (numbered, found) = number(
+        r"a \label{a} \\ b \nonumber \\ c \tag{x}\label{c}",
+        "align",
+        &mut count,
+    );
+    assert_eq!("align*", numbered.environment);
+    assert_eq!(r"a  \tag{1}\\ b  \\ c \tag{x}", numbered.latex);
+    assert_eq!(vec!["a", "c"], numbered.labels);
+    let numbers: Vec<_> = found
+        .iter()
+        .map(|(l, n)| (l.name, n.as_deref(), l.span.clone()))
+        .collect();
+    assert_eq!(
+        vec![("a", Some("1"), 2..11), ("c", Some("x"), 39..48)],
+        numbers
+    );
+
+    let (numbered, _) = number(r"x \\ y \label{b}", "multline", &mut count);
+    assert_eq!(r"x \\ y \tag{2}", numbered.latex);
+    let (numbered, _) = number(r"x \\ y", "gather*", &mut count);
+    assert_eq!(r"x \\ y", numbered.latex);
+    let (numbered, _) = number(
+        r"\begin{matrix} 1 \\ 2 \end{matrix} \\",
+        "gather",
+        &mut count,
+    );
+    assert_eq!(
+        r"\begin{matrix} 1 \\ 2 \end{matrix} \tag{3}\\",
+        numbered.latex
+    );
+    assert_eq!(3, count);
+}
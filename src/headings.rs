@@ -0,0 +1,150 @@
+//! Numbering headings and giving them ids, so that a table of contents
+//! can link to them.
+
+use crate::{Node, NodeKind};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+/// A heading, as it goes in the table of contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Heading {
+    /// 1 for `\section`, 2 for `\subsection` and so on.
+    pub(crate) level: u8,
+    /// The number, such as `1.2`, unless the heading is starred.
+    pub(crate) number: Option<String>,
+    /// The id, made from the title, which is unique in the document.
+    pub(crate) id: String,
+    /// The short title if there is one, and otherwise the title.
+    pub(crate) title: Vec<Node>,
+}
+
+/// All the headings in a document.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Headings {
+    headings: Vec<Heading>,
+    /// Which of the headings came from each span.
+    spans: HashMap<Range<usize>, usize>,
+    /// Whether the document asks for a table of contents.
+    pub(crate) wants_contents: bool,
+}
+
+impl Headings {
+    /// Number the headings in `content`, as LaTeX would.
+    pub(crate) fn new(content: &[Node]) -> Self {
+        let mut headings = Headings::default();
+        let mut counts = [0usize; 3];
+        let mut ids = HashSet::new();
+        // The suffix to try next for each slug, so that many sections
+        // with the same title need not each try all those before.
+        let mut suffixes = HashMap::new();
+        for node in content {
+            node.walk(&mut |node| match &node.kind {
+                NodeKind::Section {
+                    level,
+                    starred,
+                    short_title,
+                    title,
+                    ..
+                } => {
+                    let level = *level;
+                    let depth = (level as usize).clamp(1, counts.len());
+                    let number = if *starred {
+                        None
+                    } else {
+                        counts[depth - 1] += 1;
+                        counts[depth..].iter_mut().for_each(|count| *count = 0);
+                        let numbers: Vec<_> =
+                            counts[..depth].iter().map(usize::to_string).collect();
+                        Some(numbers.join("."))
+                    };
+                    let base = slug(title);
+                    let mut id = base.clone();
+                    let n = suffixes.entry(base.clone()).or_insert(1);
+                    while !ids.insert(id.clone()) {
+                        *n += 1;
+                        id = format!("{}-{}", base, n);
+                    }
+                    let i = headings.headings.len();
+                    headings.spans.insert(node.span.clone(), i);
+                    headings.headings.push(Heading {
+                        level,
+                        number,
+                        id,
+                        title: short_title.as_ref().unwrap_or(title).clone(),
+                    });
+                }
+                NodeKind::Macro { name, .. } if name == "tableofcontents" => {
+                    headings.wants_contents = true;
+                }
+                _ => (),
+            });
+        }
+        headings
+    }
+
+    /// The heading of the section that came from `span`.
+    pub(crate) fn get(&self, span: &Range<usize>) -> Option<&Heading> {
+        self.spans.get(span).map(|&i| &self.headings[i])
+    }
+
+    /// The headings that go in the table of contents, which leaves out
+    /// starred ones as LaTeX does.
+    pub(crate) fn contents(&self) -> Vec<&Heading> {
+        self.headings
+            .iter()
+            .filter(|h| h.number.is_some())
+            .collect()
+    }
+}
+
+/// An id for a heading titled `title`, made of the lowercase letters
+/// and digits of its text, with hyphens between words.
+fn slug(title: &[Node]) -> String {
+    let mut text = String::new();
+    for node in title {
+        node.walk(&mut |node| match &node.kind {
+            NodeKind::Text(t) => text.push_str(t),
+            NodeKind::NonBreakingSpace => text.push(' '),
+            _ => (),
+        });
+    }
+    let mut slug = String::with_capacity(text.len());
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(slug.trim_end_matches('-').len());
+    if slug.is_empty() {
+        slug.push_str("section");
+    }
+    slug
+}
+
+#[test]
+fn test_headings() {
+    let document = crate::parse(
+        r"\section{Intro} \subsection*{Aside} \subsection[Short]{A \emph{long} title}
+\section{Intro} \subsubsection{$x$} \tableofcontents",
+    );
+    let headings = Headings::new(&document.content);
+    let summary: Vec<_> = headings
+        .headings
+        .iter()
+        .map(|h| (h.level, h.number.as_deref(), h.id.as_str()))
+        .collect();
+    assert_eq!(
+        vec![
+            (1, Some("1"), "intro"),
+            (2, None, "aside"),
+            (2, Some("1.1"), "a-long-title"),
+            (1, Some("2"), "intro-2"),
+            (3, Some("2.0.1"), "section"),
+        ],
+        summary
+    );
+    assert!(headings.wants_contents);
+    assert_eq!(4, headings.contents().len());
+}
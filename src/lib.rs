@@ -2,50 +2,116 @@
 
 //! This crate turns (a subset of) latex into html, with syntax errors
 //! reported using span elements.
-
+//!
+//! No function in this crate panics, however broken the LaTeX it is
+//! given: errors end up in the HTML (or in the diagnostics) instead.  The
+//! fuzzing targets in `fuzz/` and the property tests hold us to this.
+
+use regex::Regex;
+use std::collections::HashSet;
+use std::io::Write;
+use std::sync::LazyLock;
 use wasm_bindgen::prelude::*;
 
+mod ast;
+mod braille;
+mod diagnostic;
+mod environs;
+mod equations;
+mod headings;
+mod macros;
+mod math;
+mod mathjax;
+mod mathlite;
+mod mathml;
+mod mhchem;
+mod parse;
+mod references;
+mod render;
+mod siunitx;
+mod source_map;
+mod speech;
+mod text;
+
+pub use ast::{Argument, Document, MathStyle, Node, NodeKind};
+pub use braille::{braille, braille_string, Braille, BrailleGrade};
+pub use diagnostic::{Diagnostic, Severity};
+use equations::Equations;
+use headings::Headings;
+pub use macros::MacroTable;
+pub use parse::{parse, parse_with_diagnostics};
+use references::References;
+pub use render::{
+    render, Error, ErrorPolicy, MathOutput, RenderLimits, RenderOptions, Rendered, Speech,
+    Visibility,
+};
+pub use siunitx::{ExponentStyle, PerMode, UnitOptions};
+use source_map::Rewritten;
+
 #[cfg(test)]
 mod tests;
 
-/// A version of html_string suitable for export to C and python.
-#[no_mangle]
-pub extern "C" fn convert_html(s: *const std::os::raw::c_char) -> *const std::os::raw::c_char {
-    if s.is_null() {
-        return std::ptr::null();
-    }
-    let c_str = unsafe { std::ffi::CStr::from_ptr(s) };
-    if let Ok(my_str) = c_str.to_str() {
-        let output = html_string(my_str);
-        std::ffi::CString::new(output).unwrap().into_raw()
-    } else {
-        std::ptr::null()
-    }
-}
-
-static LATEX_DBAR: &'static str = r"{\mkern3mu\mathchar'26\mkern-12mu d}";
+static LATEX_DBAR: &str = r"{\mkern3mu\mathchar'26\mkern-12mu d}";
+
+static SUBSCRIPT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^_(\d+)$").unwrap());
+static SUBSCRIPT_OTHER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^_\{(\d+)\}$").unwrap());
+static SUPERSCRIPT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\^(\d+)$").unwrap());
+static SUPERSCRIPT_OTHER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\^\{(\d+)\}$").unwrap());
+static WIDTH_EM: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[\[\{]width=([0-9\.]+)(.+)[\}\]]").unwrap());
+static WIDTH_OTHER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[\[\{]([0-9\.]+)(.+)[\}\]]").unwrap());
+static SECTION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\\[sub]*section\{[^\}]+\}").unwrap());
+static BEGIN_ENVIRONMENT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\\begin\{([^\}]+)\}").unwrap());
+static MACRO: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\\([^0-9_/|><\\$\-+\s\(\)\[\]{}]+)").unwrap());
 
 /// Cut out comments
 ///
 /// This is only useful with html_section and friends, since html and
 /// html_string do this automatically.
 pub fn strip_comments(latex: &str) -> String {
-    let temp = latex.replace(r"\%", r"\percent_holder");
-    let mut out = String::with_capacity(temp.len() + 1);
-    for x in temp.split('\n') {
-        if x.chars().next() == Some('%') {
+    strip_comments_mapped(latex).text
+}
+
+/// Cut out comments, remembering where the remaining LaTeX came from.
+pub(crate) fn strip_comments_mapped(latex: &str) -> Rewritten {
+    let mut out = Rewritten::with_capacity(latex.len() + 1);
+    let mut start = 0;
+    for x in latex.split('\n') {
+        let line_start = start;
+        start += x.len() + 1;
+        if x.starts_with('%') {
             continue; // skip this line entirely
         }
-        if let Some(i) = x.find('%') {
-            out.push_str(&x[..i]);
-            out.push(' '); // comment "blocks" line ending.
+        let comment = x
+            .match_indices('%')
+            .map(|(i, _)| i)
+            .find(|&i| !x[..i].ends_with('\\'));
+        if let Some(i) = comment {
+            copy_percent_holders(&mut out, &x[..i], line_start);
+            let comment_end = (line_start + x.len() + 1).min(latex.len());
+            out.insert(" ", line_start + i..comment_end); // comment "blocks" line ending.
         } else {
-            out.push_str(x);
-            out.push('\n')
+            copy_percent_holders(&mut out, x, line_start);
+            out.copy("\n", line_start + x.len());
         }
     }
-    out.pop();
-    out.replace(r"\percent_holder", r"\%")
+    out.text.pop();
+    out
+}
+
+/// Copy text, turning any `\percent_holder` into `\%` as we always have.
+fn copy_percent_holders(out: &mut Rewritten, mut text: &str, mut start: usize) {
+    while let Some(i) = text.find(r"\percent_holder") {
+        out.copy(&text[..i], start);
+        out.insert(r"\%", start + i..start + i + r"\percent_holder".len());
+        text = &text[i + r"\percent_holder".len()..];
+        start += i + r"\percent_holder".len();
+    }
+    out.copy(text, start);
 }
 
 /// Convert some LaTeX into an HTML `String`.
@@ -55,6 +121,24 @@ pub fn html_string(latex: &str) -> String {
     String::from_utf8(s).expect("should be no problem with utf8 conversion")
 }
 
+/// Convert some LaTeX into an HTML `String`, along with any problems
+/// found, whose spans are byte ranges in `latex`.
+pub fn html_with_diagnostics(latex: &str) -> (String, Vec<Diagnostic>) {
+    let (document, diagnostics) = parse_with_diagnostics(latex);
+    let mut s: Vec<u8> = Vec::with_capacity(latex.len());
+    document.write_html(&mut s).unwrap();
+    let html = String::from_utf8(s).expect("should be no problem with utf8 conversion");
+    (html, diagnostics)
+}
+
+/// Convert some LaTeX into an HTML `String`, marking each element with
+/// `data-src-start` and `data-src-end` attributes that hold the byte
+/// range of `latex` it came from.
+pub fn html_string_with_source_positions(latex: &str) -> String {
+    let options = RenderOptions::new().source_positions(true);
+    render(latex, &options).expect("errors are rendered").html
+}
+
 macro_rules! ffi_str {
     ($mkstr:expr) => {
         |s: *const std::os::raw::c_char| -> *const std::os::raw::c_char {
@@ -64,7 +148,10 @@ macro_rules! ffi_str {
             let c_str = unsafe { std::ffi::CStr::from_ptr(s) };
             if let Ok(my_str) = c_str.to_str() {
                 let output = $mkstr(my_str);
-                std::ffi::CString::new(output).unwrap().into_raw()
+                match std::ffi::CString::new(output) {
+                    Ok(output) => output.into_raw(),
+                    Err(_) => std::ptr::null(),
+                }
             } else {
                 std::ptr::null()
             }
@@ -72,12 +159,355 @@ macro_rules! ffi_str {
     };
 }
 
+/// A version of html_string suitable for export to C and python.
+#[no_mangle]
+pub extern "C" fn convert_html(s: *const std::os::raw::c_char) -> *const std::os::raw::c_char {
+    ffi_str!(html_string)(s)
+}
+
+/// Options for [`latex_render`], for use from C and python.
+///
+/// Each visibility is 0 to show that environment, 1 to hide it, or 2 to
+/// show nothing else.  Each limit of 0 means the default from
+/// [`RenderLimits::new`].  Every other number picks a variant of the
+/// matching enum by its position, so that a zeroed struct holds the
+/// defaults of [`RenderOptions::new`].
+#[repr(C)]
+pub struct CRenderOptions {
+    /// Whether to expand the physics macros.
+    pub physics_macros: bool,
+    /// Whether to show solutions.
+    pub solutions: u8,
+    /// Whether to show guides.
+    pub guides: u8,
+    /// Whether to show handouts.
+    pub handouts: u8,
+    /// A directory for images, or null.
+    pub image_directory: *const std::os::raw::c_char,
+    /// The HTML heading level of a `\section`, or 0 for the default.
+    pub heading_level: u8,
+    /// Whether to fail rather than render errors.
+    pub fail_on_errors: bool,
+    /// Whether to add `data-src-start` and `data-src-end` attributes.
+    pub source_positions: bool,
+    /// How deeply groups and environments may nest.
+    pub max_depth: usize,
+    /// How much HTML to write.
+    pub max_output_bytes: usize,
+    /// How many macros to expand.
+    pub max_expansions: usize,
+    /// LaTeX defining macros to expand with `\newcommand` and friends,
+    /// or null.
+    pub macros: *const std::os::raw::c_char,
+    /// The decimal marker for numbers with units, or 0 for `.`.
+    pub decimal_marker: u32,
+    /// Whether to leave the digits of numbers with units ungrouped.
+    pub ungrouped_digits: bool,
+    /// The [`ExponentStyle`] of numbers with units.
+    pub exponent: u8,
+    /// The [`PerMode`] of units.
+    pub per_mode: u8,
+    /// The [`MathOutput`].
+    pub math: u8,
+    /// Whether to number equations.
+    pub number_equations: bool,
+    /// The [`Speech`] for math.
+    pub speech: u8,
+    /// Whether to number sections.
+    pub number_sections: bool,
+    /// Whether to give headings ids, and make a table of contents.
+    pub heading_ids: bool,
+}
+
+impl CRenderOptions {
+    fn to_options(&self) -> RenderOptions {
+        let visibility = |v| match v {
+            1 => Visibility::Hide,
+            2 => Visibility::Only,
+            _ => Visibility::Show,
+        };
+        let mut options = RenderOptions::new()
+            .physics_macros(self.physics_macros)
+            .solutions(visibility(self.solutions))
+            .guides(visibility(self.guides))
+            .handouts(visibility(self.handouts))
+            .source_positions(self.source_positions)
+            .number_equations(self.number_equations)
+            .number_sections(self.number_sections)
+            .heading_ids(self.heading_ids)
+            .math(match self.math {
+                1 => MathOutput::MathMl,
+                2 => MathOutput::Lite,
+                _ => MathOutput::Tex,
+            })
+            .speech(match self.speech {
+                1 => Speech::Brief,
+                2 => Speech::Verbose,
+                _ => Speech::Off,
+            });
+        let mut units = UnitOptions::new()
+            .group_digits(!self.ungrouped_digits)
+            .exponent(match self.exponent {
+                1 => ExponentStyle::Cdot,
+                2 => ExponentStyle::Letter,
+                _ => ExponentStyle::Times,
+            })
+            .per_mode(match self.per_mode {
+                1 => PerMode::Symbol,
+                _ => PerMode::Power,
+            });
+        if let Some(marker) = std::char::from_u32(self.decimal_marker).filter(|&c| c != '\0') {
+            units = units.decimal_marker(marker);
+        }
+        options = options.units(units);
+        if self.heading_level > 0 {
+            options = options.heading_level(self.heading_level);
+        }
+        if self.fail_on_errors {
+            options = options.errors(ErrorPolicy::Fail);
+        }
+        let mut limits = RenderLimits::new();
+        if self.max_depth > 0 {
+            limits = limits.max_depth(self.max_depth);
+        }
+        if self.max_output_bytes > 0 {
+            limits = limits.max_output_bytes(self.max_output_bytes);
+        }
+        if self.max_expansions > 0 {
+            limits = limits.max_expansions(self.max_expansions);
+        }
+        options = options.limits(limits);
+        if !self.image_directory.is_null() {
+            let directory = unsafe { std::ffi::CStr::from_ptr(self.image_directory) };
+            options = options.image_directory(&directory.to_string_lossy());
+        }
+        if !self.macros.is_null() {
+            let macros = unsafe { std::ffi::CStr::from_ptr(self.macros) };
+            options = options.macros(MacroTable::new().define_latex(&macros.to_string_lossy()));
+        }
+        options
+    }
+}
+
+/// A problem found by [`latex_render_full`], for use from C and python.
+#[repr(C)]
+pub struct CDiagnostic {
+    /// A short identifier for the kind of problem.
+    pub code: *mut std::os::raw::c_char,
+    /// 0 for a warning, or 1 for an error.
+    pub severity: u8,
+    /// A human-readable description of the problem.
+    pub message: *mut std::os::raw::c_char,
+    /// Where the offending LaTeX starts, in bytes.
+    pub start: usize,
+    /// Where the offending LaTeX ends, in bytes.
+    pub end: usize,
+}
+
+/// LaTeX rendered by [`latex_render_full`], for use from C and python,
+/// which [`latex_rendered_free`] frees.
+#[repr(C)]
+pub struct CRendered {
+    /// The HTML, or null if the LaTeX has errors and `fail_on_errors` is
+    /// set.
+    pub html: *mut std::os::raw::c_char,
+    /// The table of contents, which is empty unless `heading_ids` is set.
+    pub table_of_contents: *mut std::os::raw::c_char,
+    /// Any problems found in the LaTeX.
+    pub diagnostics: *mut CDiagnostic,
+    /// How many diagnostics there are.
+    pub diagnostic_count: usize,
+}
+
+fn c_string(s: String) -> *mut std::os::raw::c_char {
+    std::ffi::CString::new(s).map_or(std::ptr::null_mut(), std::ffi::CString::into_raw)
+}
+
+/// A version of render suitable for export to C and python.
+///
+/// This returns null if the LaTeX has errors and `fail_on_errors` is set.
+///
+/// # Safety
+///
+/// `s` must be null or a nul-terminated string, and `options` must be
+/// null or point to valid options.
+#[no_mangle]
+pub unsafe extern "C" fn latex_render(
+    s: *const std::os::raw::c_char,
+    options: *const CRenderOptions,
+) -> *const std::os::raw::c_char {
+    let options = options
+        .as_ref()
+        .map_or_else(RenderOptions::new, |o| o.to_options());
+    if s.is_null() {
+        return std::ptr::null();
+    }
+    let c_str = std::ffi::CStr::from_ptr(s);
+    match c_str.to_str().map(|latex| render(latex, &options)) {
+        Ok(Ok(rendered)) => match std::ffi::CString::new(rendered.html) {
+            Ok(html) => html.into_raw(),
+            Err(_) => std::ptr::null(),
+        },
+        _ => std::ptr::null(),
+    }
+}
+
+/// A version of render suitable for export to C and python, which
+/// returns the diagnostics and table of contents along with the HTML.
+///
+/// This returns null if `s` is null or not UTF-8.  Otherwise the result
+/// holds the diagnostics even if the LaTeX has errors and
+/// `fail_on_errors` is set, when it holds no HTML.
+///
+/// # Safety
+///
+/// `s` must be null or a valid C string, and `options` null or a valid
+/// pointer to a [`CRenderOptions`] whose strings are null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn latex_render_full(
+    s: *const std::os::raw::c_char,
+    options: *const CRenderOptions,
+) -> *mut CRendered {
+    let options = options
+        .as_ref()
+        .map_or_else(RenderOptions::new, |o| o.to_options());
+    if s.is_null() {
+        return std::ptr::null_mut();
+    }
+    let latex = match std::ffi::CStr::from_ptr(s).to_str() {
+        Ok(latex) => latex,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let (html, diagnostics, table_of_contents) = match render(latex, &options) {
+        Ok(rendered) => (
+            c_string(rendered.html),
+            rendered.diagnostics,
+            rendered.table_of_contents,
+        ),
+        Err(Error::Invalid(diagnostics)) => (std::ptr::null_mut(), diagnostics, String::new()),
+    };
+    let diagnostics: Box<[CDiagnostic]> = diagnostics
+        .into_iter()
+        .map(|d| CDiagnostic {
+            code: c_string(d.code.to_string()),
+            severity: d.severity as u8,
+            message: c_string(d.message),
+            start: d.span.start,
+            end: d.span.end,
+        })
+        .collect();
+    let diagnostic_count = diagnostics.len();
+    Box::into_raw(Box::new(CRendered {
+        html,
+        table_of_contents: c_string(table_of_contents),
+        diagnostics: Box::into_raw(diagnostics) as *mut CDiagnostic,
+        diagnostic_count,
+    }))
+}
+
+/// Free what [`latex_render_full`] returned.
+///
+/// # Safety
+///
+/// `rendered` must be null or have come from [`latex_render_full`], and
+/// not have been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn latex_rendered_free(rendered: *mut CRendered) {
+    if rendered.is_null() {
+        return;
+    }
+    let rendered = Box::from_raw(rendered);
+    let free = |s: *mut std::os::raw::c_char| {
+        if !s.is_null() {
+            drop(std::ffi::CString::from_raw(s));
+        }
+    };
+    let diagnostics = Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+        rendered.diagnostics,
+        rendered.diagnostic_count,
+    ));
+    for d in diagnostics.iter() {
+        free(d.code);
+        free(d.message);
+    }
+    free(rendered.html);
+    free(rendered.table_of_contents);
+}
+
+/// Render some LaTeX as HTML, throwing an error if we were asked to fail
+/// on errors and there were some.
+#[wasm_bindgen]
+#[cfg(target_arch = "wasm32")]
+pub fn render_html(latex: &str, options: &RenderOptions) -> Result<String, JsValue> {
+    set_panic_hook();
+    render(latex, options)
+        .map(|rendered| rendered.html)
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+/// LaTeX rendered as HTML by [`render_full`], for JavaScript.
+#[wasm_bindgen(js_name = Rendered, getter_with_clone)]
+#[cfg(target_arch = "wasm32")]
+pub struct JsRendered {
+    /// The HTML.
+    pub html: String,
+    /// Any problems found in the LaTeX.
+    pub diagnostics: Vec<JsDiagnostic>,
+    /// The table of contents, which is empty unless the headings have
+    /// ids.
+    pub table_of_contents: String,
+}
+
+/// A [`Diagnostic`], for JavaScript.
+#[wasm_bindgen(js_name = Diagnostic, getter_with_clone)]
+#[cfg(target_arch = "wasm32")]
+#[derive(Clone)]
+pub struct JsDiagnostic {
+    /// A short identifier for the kind of problem.
+    pub code: String,
+    /// How serious the problem is.
+    pub severity: Severity,
+    /// A human-readable description of the problem.
+    pub message: String,
+    /// Where the offending LaTeX starts, in bytes.
+    pub start: usize,
+    /// Where the offending LaTeX ends, in bytes.
+    pub end: usize,
+}
+
+/// Render some LaTeX as HTML, along with its diagnostics and table of
+/// contents, throwing an error if we were asked to fail on errors and
+/// there were some.
+#[wasm_bindgen]
+#[cfg(target_arch = "wasm32")]
+pub fn render_full(latex: &str, options: &RenderOptions) -> Result<JsRendered, JsValue> {
+    set_panic_hook();
+    let rendered = render(latex, options).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let diagnostics = rendered
+        .diagnostics
+        .into_iter()
+        .map(|d| JsDiagnostic {
+            code: d.code.to_string(),
+            severity: d.severity,
+            message: d.message,
+            start: d.span.start,
+            end: d.span.end,
+        })
+        .collect();
+    Ok(JsRendered {
+        html: rendered.html,
+        diagnostics,
+        table_of_contents: rendered.table_of_contents,
+    })
+}
+
 /// Convert some LaTeX into an HTML `String`.
 #[wasm_bindgen]
 #[cfg(target_arch = "wasm32")]
 pub fn html_with_solution(latex: &str) -> String {
     set_panic_hook();
-    html_string(&physics_macros(latex))
+    let options = RenderOptions::new().physics_macros(true);
+    render(latex, &options).expect("errors are rendered").html
 }
 
 /// A version of html_with_solution suitable for export to C and python.
@@ -93,7 +523,10 @@ pub extern "C" fn latex_to_html_with_solution(
 #[cfg(target_arch = "wasm32")]
 pub fn html_omit_solution(latex: &str) -> String {
     set_panic_hook();
-    html_string(&omit_solutions(&physics_macros(latex)))
+    let options = RenderOptions::new()
+        .physics_macros(true)
+        .solutions(Visibility::Hide);
+    render(latex, &options).expect("errors are rendered").html
 }
 
 /// A version of html_with_solution suitable for export to C and python.
@@ -135,10 +568,10 @@ pub extern "C" fn c_physics_macros(s: *const std::os::raw::c_char) -> *const std
 #[cfg(target_arch = "wasm32")]
 pub fn html_with_figures_and_solution(latex: &str, figure_directory: &str) -> String {
     set_panic_hook();
-    html_string(&with_image_directory(
-        &physics_macros(latex),
-        figure_directory,
-    ))
+    let options = RenderOptions::new()
+        .physics_macros(true)
+        .image_directory(figure_directory);
+    render(latex, &options).expect("errors are rendered").html
 }
 
 /// Convert some LaTeX into an HTML `String`, including figures.
@@ -146,10 +579,11 @@ pub fn html_with_figures_and_solution(latex: &str, figure_directory: &str) -> St
 #[cfg(target_arch = "wasm32")]
 pub fn html_with_figures_omit_solution(latex: &str, figure_directory: &str) -> String {
     set_panic_hook();
-    html_string(&omit_solutions(&with_image_directory(
-        &physics_macros(latex),
-        figure_directory,
-    )))
+    let options = RenderOptions::new()
+        .physics_macros(true)
+        .solutions(Visibility::Hide)
+        .image_directory(figure_directory);
+    render(latex, &options).expect("errors are rendered").html
 }
 
 fn needs_quoting_at_start(x: &str) -> Option<usize> {
@@ -162,7 +596,7 @@ fn needs_quoting_at_start(x: &str) -> Option<usize> {
         } else if badstuff.contains(c) {
             Some(1)
         } else if !c.is_ascii() {
-            x.find(|c: char| c.is_ascii()).or_else(|| Some(x.len()))
+            x.find(|c: char| c.is_ascii()).or(Some(x.len()))
         } else {
             None
         }
@@ -172,14 +606,13 @@ fn needs_quoting_at_start(x: &str) -> Option<usize> {
 }
 
 fn find_next_quoting(x: &str) -> Option<(usize, usize)> {
-    for i in 0..x.len() {
-        if let Some(substr) = x.get(i..) {
-            if let Some(len) = needs_quoting_at_start(substr) {
-                return Some((i, i + len));
-            }
-        }
-    }
-    None
+    // Only these bytes can start something that needs quoting, and the
+    // first non-ascii byte we come to always starts a character.
+    let mut candidates = x
+        .bytes()
+        .enumerate()
+        .filter(|&(_, b)| b"<>&\"'/`{".contains(&b) || !b.is_ascii());
+    candidates.find_map(|(i, _)| needs_quoting_at_start(&x[i..]).map(|len| (i, i + len)))
 }
 
 #[test]
@@ -191,15 +624,8 @@ fn test_find_next_quoting() {
 
 /// This just does simple textual formatting
 fn fmt_as_html(fmt: &mut impl std::io::Write, mut latex: &str) -> Result<(), std::io::Error> {
-    if latex.contains("amp#x7b;") || latex.contains("amp#x7d;") {
-        return fmt_as_html(
-            fmt,
-            &latex.replace("amp#x7b;", r"\{").replace("amp#x7d;", r"\}"),
-        );
-    }
-
     while let Some((start, end)) = find_next_quoting(latex) {
-        fmt.write_all(latex[..start].as_bytes())?;
+        fmt.write_all(&latex.as_bytes()[..start])?;
         let needs_quote = &latex[start..end];
         latex = &latex[end..];
         // eprintln!("needs quote is {} from {}-{}", needs_quote, start, end);
@@ -229,15 +655,8 @@ fn fmt_as_html(fmt: &mut impl std::io::Write, mut latex: &str) -> Result<(), std
 
 /// This just does simple textual formatting
 fn fmt_math_as_html(fmt: &mut impl std::io::Write, mut latex: &str) -> Result<(), std::io::Error> {
-    if latex.contains("amp#x7b;") || latex.contains("amp#x7d;") {
-        return fmt_math_as_html(
-            fmt,
-            &latex.replace("amp#x7b;", r"\{").replace("amp#x7d;", r"\}"),
-        );
-    }
-
     while let Some((start, end)) = find_next_quoting(latex) {
-        fmt.write_all(latex[..start].as_bytes())?;
+        fmt.write_all(&latex.as_bytes()[..start])?;
         let needs_quote = &latex[start..end];
         latex = &latex[end..];
         match needs_quote {
@@ -281,1278 +700,810 @@ fn fmt_error(fmt: &mut impl std::io::Write, latex: &str) -> Result<(), std::io::
     fmt.write_all(br#"</span>"#)
 }
 
-/// This just does error formatting with html escaping
-fn fmt_errors(fmt: &mut impl std::io::Write, latex: &[&str]) -> Result<(), std::io::Error> {
-    fmt.write_all(br#"<span class="error">"#)?;
-    for x in latex {
-        fmt_as_html(fmt, x)?;
-    }
-    fmt.write_all(br#"</span>"#)
-}
-
 /// Convert some LaTeX into HTML, and send the results to a `std::io::Write`.
 pub fn html(fmt: &mut impl std::io::Write, latex: &str) -> Result<(), std::io::Error> {
-    let latex = pull_sections_out(&strip_comments(latex));
-    let mut latex: &str = &latex;
-    if let Some(i) = latex.find(r"\section") {
-        html_section(fmt, &latex[..i])?;
-        latex = &latex[i + r"\section".len()..];
-        if latex.chars().next() == Some('*') {
-            latex = &latex[1..];
-        }
-        let title = parse_title(latex);
-        latex = &latex[title.len()..];
-        if title == "{" {
-            fmt.write_all(br#"<span class="error">\section{</span>"#)?;
-        } else {
-            fmt.write_all(b"<section><h2>")?;
-            html_paragraph(fmt, title)?;
-            fmt.write_all(b"</h2>")?;
-        }
-    } else {
-        html_section(fmt, latex)?;
-        latex = "";
-    }
-    while latex.len() > 0 {
-        if let Some(i) = latex.find(r"\section") {
-            html_section(fmt, &latex[..i])?;
-            fmt.write_all(b"</section>")?; // We finished a section.
-            latex = &latex[i + r"\section".len()..];
-            if latex.chars().next() == Some('*') {
-                latex = &latex[1..];
-            }
-            let title = parse_title(latex);
-            latex = &latex[title.len()..];
-            if title == "{" {
-                fmt.write_all(br#"<span class="error">\section{</span>"#)?;
-            } else {
-                fmt.write_all(b"<section><h2>")?;
-                html_paragraph(fmt, title)?;
-                fmt.write_all(b"</h2>")?;
-            }
-        } else {
-            html_section(fmt, latex)?;
-            fmt.write_all(b"</section>")?; // We finished a section.
-            latex = "";
-        }
-    }
-    Ok(())
+    parse(latex).write_html(fmt)
 }
 
 /// Convert some LaTeX into HTML, and send the results to a `std::io::Write`.
-pub fn html_section(fmt: &mut impl std::io::Write, mut latex: &str) -> Result<(), std::io::Error> {
-    if let Some(i) = latex.find(r"\subsection") {
-        html_subsection(fmt, &latex[..i])?;
-        latex = &latex[i + r"\subsection".len()..];
-        if latex.chars().next() == Some('*') {
-            latex = &latex[1..];
-        }
-        let title = parse_title(latex);
-        latex = &latex[title.len()..];
-        if title == "{" {
-            fmt.write_all(br#"<span class="error">\subsection{</span>"#)?;
-        } else {
-            fmt.write_all(b"<section><h3>")?;
-            html_paragraph(fmt, title)?;
-            fmt.write_all(b"</h3>")?;
-        }
-    } else {
-        html_subsection(fmt, latex)?;
-        latex = "";
-    }
-    while latex.len() > 0 {
-        if let Some(i) = latex.find(r"\subsection") {
-            html_subsection(fmt, &latex[..i])?;
-            fmt.write_all(b"</section>")?; // We finished a section.
-            latex = &latex[i + r"\subsection".len()..];
-            if latex.chars().next() == Some('*') {
-                latex = &latex[1..];
-            }
-            let title = parse_title(latex);
-            latex = &latex[title.len()..];
-            if title == "{" {
-                fmt.write_all(br#"<span class="error">\subsection{</span>"#)?;
-            } else {
-                fmt.write_all(b"<section><h3>")?;
-                html_paragraph(fmt, title)?;
-                fmt.write_all(b"</h3>")?;
-            }
-        } else {
-            html_subsection(fmt, latex)?;
-            fmt.write_all(b"</section>")?; // We finished a section.
-            latex = "";
-        }
-    }
-    Ok(())
-}
-
-fn process_url_argument(url: &str) -> String {
-    let mut url = url.to_string();
-    url = url.replace('{', "");
-    url = url.replace('}', "");
-    url = url.replace(r"\%", "%");
-    url = url.replace(r" ", "%20");
-    url
+pub fn html_section(fmt: &mut impl std::io::Write, latex: &str) -> Result<(), std::io::Error> {
+    HtmlWriter::new(fmt).write_document(&parse::Parser::new(latex).sections(latex, 2))
 }
 
 /// Convert some LaTeX into HTML, and send the results to a `std::io::Write`.
-pub fn html_subsection(
-    fmt: &mut impl std::io::Write,
-    mut latex: &str,
-) -> Result<(), std::io::Error> {
-    if let Some(i) = latex.find(r"\subsubsection") {
-        html_subsubsection(fmt, &latex[..i])?;
-        latex = &latex[i + r"\subsubsection".len()..];
-        if latex.chars().next() == Some('*') {
-            latex = &latex[1..];
-        }
-        let title = parse_title(latex);
-        latex = &latex[title.len()..];
-        if title == "{" {
-            fmt.write_all(br#"<span class="error">\subsubsection{</span>"#)?;
-        } else {
-            fmt.write_all(b"<section><h4>")?;
-            html_paragraph(fmt, title)?;
-            fmt.write_all(b"</h4>")?;
-        }
-    } else {
-        html_subsubsection(fmt, latex)?;
-        latex = "";
-    }
-    while latex.len() > 0 {
-        if let Some(i) = latex.find(r"\subsubsection") {
-            html_subsubsection(fmt, &latex[..i])?;
-            fmt.write_all(b"</section>")?; // We finished a section.
-            latex = &latex[i + r"\subsubsection".len()..];
-            if latex.chars().next() == Some('*') {
-                latex = &latex[1..];
-            }
-            let title = parse_title(latex);
-            latex = &latex[title.len()..];
-            if title == "{" {
-                fmt.write_all(br#"<span class="error">\subsubsection{</span>"#)?;
-            } else {
-                fmt.write_all(b"<section><h4>")?;
-                html_paragraph(fmt, title)?;
-                fmt.write_all(b"</h4>")?;
-            }
-        } else {
-            html_subsubsection(fmt, latex)?;
-            fmt.write_all(b"</section>")?; // We finished a section.
-            latex = "";
-        }
-    }
-    Ok(())
+pub fn html_subsection(fmt: &mut impl std::io::Write, latex: &str) -> Result<(), std::io::Error> {
+    HtmlWriter::new(fmt).write_document(&parse::Parser::new(latex).sections(latex, 3))
 }
 
 /// Convert some LaTeX into HTML, and send the results to a `std::io::Write`.
 pub fn html_subsubsection(
     fmt: &mut impl std::io::Write,
-    mut latex: &str,
+    latex: &str,
 ) -> Result<(), std::io::Error> {
-    let am_alone = finish_paragraph(latex).len() == latex.len();
-    loop {
-        let p = finish_paragraph(latex);
-        latex = &latex[p.len()..];
-        if p.len() == 0 {
-            return Ok(());
-        }
-        if p.trim().len() == 0 {
-            continue;
-        }
-        if !am_alone {
-            fmt.write_all(b"<p>")?;
-        }
-        html_paragraph(fmt, p)?;
-        if !am_alone {
-            fmt.write_all(b"</p>")?;
-        }
-    }
+    HtmlWriter::new(fmt).write_document(&parse::Parser::new(latex).paragraphs(latex))
 }
 
 /// Convert some LaTeX into HTML, and send the results to a `std::io::Write`.
 pub fn html_paragraph(fmt: &mut impl std::io::Write, latex: &str) -> Result<(), std::io::Error> {
-    let subscript = regex::Regex::new(r"^_(\d+)$").unwrap();
-    let subscript_other = regex::Regex::new(r"^_\{(\d+)\}$").unwrap();
-    let superscript = regex::Regex::new(r"^\^(\d+)$").unwrap();
-    let superscript_other = regex::Regex::new(r"^\^\{(\d+)\}$").unwrap();
-    let mut latex = latex;
-    let latex_sans_stuff: String;
-    if latex.contains(r"\{") || latex.contains(r"\}") {
-        latex_sans_stuff = latex.replace(r"\{", "amp#x7b;").replace(r"\}", "amp#x7d;");
-        latex = &latex_sans_stuff;
-    }
-    let math_environs = &[
-        "{equation}",
-        "{equation*}",
-        "{align}",
-        "{align*}",
-        "{eqnarray}",
-        "{eqnarray*}",
-        "{multline}",
-        "{multline*}",
-    ];
-    loop {
-        if latex.len() == 0 {
-            return Ok(());
-        }
-        if let Some(i) = latex.find(|c| c == '~' || c == '\\' || c == '{' || c == '$') {
-            fmt_as_html(fmt, &latex[..i])?;
-            latex = &latex[i..];
-            let c = latex.chars().next().unwrap();
-            if c == '~' {
-                latex = &latex[1..];
-                fmt.write_all(b"&nbsp;")?;
-            } else if c == '\\' {
-                let name = macro_name(latex);
-                latex = &latex[name.len()..];
-                match name {
-                    r"\\" => {
-                        fmt.write_all(b"<br/>")?;
-                    }
-                    r"\newpage" => {
-                        fmt.write_all(b"<br/>")?;
-                    }
-                    r"\vspace" | r"\vfill" => {
-                        let arg = argument(latex);
-                        latex = &latex[arg.len()..];
-                        if arg == "{" {
-                            fmt.write_all(br#"<span class="error">\vspace{</span>"#)?;
-                        } else {
-                            fmt.write_all(b"<br/>")?; // just treat a \vspace as a line break
-                        }
-                    }
-                    r"\textbackslash" => {
-                        fmt.write_all(b"\\")?;
-                    }
-                    r"\'" => {
-                        if latex.len() == 0 {
-                            fmt.write_all(br#"<span class="error">\'</span>"#)?;
-                        }
-                        let letter = &latex[..1];
-                        latex = &latex[1..];
-                        write!(fmt, "&{}acute;", letter)?;
-                    }
-                    r"\^" => {
-                        if latex.len() == 0 {
-                            fmt.write_all(br#"<span class="error">\^</span>"#)?;
-                        }
-                        let letter = &latex[..1];
-                        latex = &latex[1..];
-                        write!(fmt, "&{}circ;", letter)?;
-                    }
-                    r"\`" => {
-                        let letter = &latex[..1];
-                        latex = &latex[1..];
-                        write!(fmt, "&{}grave;", letter)?;
-                    }
-                    r#"\""# => {
-                        let letter = &latex[..1];
-                        latex = &latex[1..];
-                        write!(fmt, "&{}uml;", letter)?;
-                    }
-                    r#"\AA"# => {
-                        fmt.write_all(b"&#8491;")?;
-                    }
-                    r"\ldots" => {
-                        fmt.write_all(b"...")?;
-                    }
-                    r"\label" => {
-                        let arg = argument(latex);
-                        latex = &latex[arg.len()..];
-                        if arg == "{" {
-                            fmt.write_all(br#"<span class="error">\label{</span>"#)?;
-                        } else {
-                            write!(fmt, r#"<span class="error">\label{}</span>"#, arg)?;
-                        }
-                    }
-                    // r"\ref" => {
-                    //     let arg = argument(latex);
-                    //     latex = &latex[arg.len()..];
-                    //     if arg == "{" {
-                    //         fmt.write_all(br#"<span class="error">\ref{</span>"#)?;
-                    //     } else {
-                    //         write!(fmt, r##"<a class="ref" href="#{}">{}</a>"##, arg, arg)?;
-                    //     }
-                    // }
-                    r"\eqref" => {
-                        let arg = argument(latex);
-                        latex = &latex[arg.len()..];
-                        if arg == "{" {
-                            fmt.write_all(br#"<span class="error">\eqref{</span>"#)?;
-                        } else {
-                            fmt.write_all(br"\eqref")?;
-                            fmt.write_all(arg.as_bytes())?;
-                        }
-                    }
-                    r"\ref" => {
-                        let arg = argument(latex);
-                        latex = &latex[arg.len()..];
-                        if arg == "{" {
-                            fmt.write_all(br#"<span class="error">\ref{</span>"#)?;
-                        } else {
-                            fmt.write_all(br"\ref")?;
-                            fmt.write_all(arg.as_bytes())?;
-                        }
-                    }
-                    r"\verb" => {
-                        let sep = &latex[0..1];
-                        latex = &latex[1..];
-                        if let Some(end) = latex.find(&sep) {
-                            let content = &latex[..end];
-                            latex = &latex[end + 1..];
-                            fmt.write_all(b"<code>")?;
-                            fmt_as_html(fmt, content)?;
-                            fmt.write_all(b"</code>")?;
-                        } else {
-                            write!(fmt, r#"<span class="error">\verb{}</span>"#, sep)?;
-                        }
-                    }
-                    r"\emph" => {
-                        let arg = argument(latex);
-                        latex = &latex[arg.len()..];
-                        if arg == "{" {
-                            fmt.write_all(br#"<span class="error">\emph{</span>"#)?;
-                        } else {
-                            fmt.write_all(b"<em>")?;
-                            html_subsubsection(fmt, arg)?;
-                            fmt.write_all(b"</em>")?;
-                        }
-                    }
-                    r"\underline" => {
-                        let arg = argument(latex);
-                        latex = &latex[arg.len()..];
-                        if arg == "{" {
-                            fmt.write_all(br#"<span class="error">\underline{</span>"#)?;
-                        } else {
-                            fmt.write_all(b"<u>")?;
-                            html_subsubsection(fmt, arg)?;
-                            fmt.write_all(b"</u>")?;
-                        }
-                    }
-                    r"\textit" => {
-                        let arg = argument(latex);
-                        latex = &latex[arg.len()..];
-                        if arg == "{" {
-                            fmt.write_all(br#"<span class="error">\textit{</span>"#)?;
-                        } else {
-                            fmt.write_all(b"<i>")?;
-                            html_subsubsection(fmt, arg)?;
-                            fmt.write_all(b"</i>")?;
-                        }
-                    }
-                    r"\textcolor" => {
-                        let color = argument(latex);
-                        latex = &latex[color.len()..];
-                        if color == "{" {
-                            fmt.write_all(br#"<span class="error">\textcolor{</span>"#)?;
-                        } else {
-                            let color = color.replace("{", "").replace("}", "");
-                            let arg = argument(latex);
-                            latex = &latex[arg.len()..];
-                            if arg == "{" {
-                                fmt.write_all(br#"<span class="error">\textcolor{"#)?;
-                                fmt.write_all(color.as_bytes())?;
-                                fmt.write_all(br#"}{</span>"#)?;
-                            } else {
-                                if [
-                                    "red",
-                                    "blue",
-                                    "forestgreen",
-                                    "purple",
-                                    "brown",
-                                    "gray",
-                                    "orange",
-                                ]
-                                .contains(&color.as_ref())
-                                {
-                                    fmt.write_all(br#"<span style="color:"#)?;
-                                    fmt.write_all(color.as_bytes())?;
-                                    fmt.write_all(br#";">"#)?;
-                                    html_subsubsection(fmt, arg)?;
-                                    fmt.write_all(b"</span>")?;
-                                } else {
-                                    fmt.write_all(
-                                        br#"<span class="error">\textcolor{Invalid color "#,
-                                    )?;
-                                    fmt.write_all(color.as_bytes())?;
-                                    fmt.write_all(br#" Allowed colors: red, blue, forestgreen, purple, gray, brown}</span>"#)?;
-                                    html_subsubsection(fmt, arg)?;
-                                }
-                            }
-                        }
-                    }
-                    r"\footnote" => {
-                        let arg = argument(latex);
-                        latex = &latex[arg.len()..];
-                        if arg == "{" {
-                            fmt.write_all(br#"<span class="error">\footnote{</span>"#)?;
-                        } else {
-                            fmt.write_all(b"<sup>*</sup><aside><sup>*</sup>")?;
-                            html_subsubsection(fmt, arg)?;
-                            fmt.write_all(b"</aside>")?;
-                        }
-                    }
-                    r"\textbf" => {
-                        let arg = argument(latex);
-                        latex = &latex[arg.len()..];
-                        if arg == "{" {
-                            fmt.write_all(br#"<span class="error">\textbf{</span>"#)?;
-                        } else {
-                            fmt.write_all(b"<b>")?;
-                            html_subsubsection(fmt, arg)?;
-                            fmt.write_all(b"</b>")?;
-                        }
-                    }
-                    r"\texttt" => {
-                        let arg = argument(latex);
-                        latex = &latex[arg.len()..];
-                        if arg == "{" {
-                            fmt.write_all(br#"<span class="error">\texttt{</span>"#)?;
-                        } else {
-                            fmt.write_all(b"<code>")?;
-                            html_subsubsection(fmt, arg)?;
-                            fmt.write_all(b"</code>")?;
-                        }
-                    }
-                    r"\url" => {
-                        let arg = argument(latex); // strip of {} create function to validate url
-                        let url = process_url_argument(arg);
-                        latex = &latex[arg.len()..];
-                        if arg == "{" {
-                            fmt.write_all(
-                                br#"<span class="error" data-error="bad-argument">\url{</span>"#,
-                            )?;
-                        } else {
-                            if url.starts_with("https://") {
-                                fmt.write_all(b"<a target=\"_parent\" href=\"")?;
-                            } else {
-                                fmt.write_all(b"<a href=\"")?;
-                            }
-                            fmt.write_all(url.as_bytes())?;
-                            fmt.write_all(b"\">")?;
-                            fmt.write_all(url.as_bytes())?;
-                            fmt.write_all(b"</a>")?;
-                        }
-                    }
-                    r"\href" => {
-                        let url_arg = argument(latex); // strip of {}
-                        latex = &latex[url_arg.len()..];
-                        let url = process_url_argument(url_arg);
-                        if url == "{" {
-                            fmt.write_all(br#"<span class="error">\href{</span>"#)?;
-                        } else {
-                            let arg = argument(latex); // strip of {}
-                            latex = &latex[arg.len()..];
-                            if arg == "{" {
-                                fmt.write_all(br#"<span class="error">\href{"#)?;
-                                fmt.write_all(url.as_bytes())?;
-                                fmt.write_all(br#"}{</span>"#)?;
-                            } else {
-                                if url.starts_with("https://") {
-                                    fmt.write_all(b"<a target=\"_parent\" href=\"")?;
-                                } else {
-                                    fmt.write_all(b"<a href=\"")?;
-                                }
-                                fmt.write_all(url.as_bytes())?;
-                                fmt.write_all(b"\">")?;
-                                html_subsubsection(fmt, arg)?;
-                                fmt.write_all(b"</a>")?;
-                            }
-                        }
-                    }
-                    r"\includegraphics" => {
-                        let opt = optional_argument(latex);
-                        let width = parse_width(opt);
-                        latex = &latex[opt.len()..];
-                        let arg = argument(latex);
-                        latex = &latex[arg.len()..];
-                        if arg == "{" {
-                            fmt.write_all(br#"<span class="error">\includegraphics{</span>"#)?;
-                        } else {
-                            fmt.write_all(br#"<img"#)?;
-                            fmt.write_all(width.as_bytes())?;
-                            fmt.write_all(br#" src=""#)?;
-                            fmt_as_html(fmt, &arg[1..arg.len() - 1])?;
-                            fmt.write_all(br#""/>"#)?;
-                        }
-                    }
-                    r"\caption" => {
-                        let arg = argument(latex);
-                        latex = &latex[arg.len()..];
-                        if arg == "{" {
-                            fmt.write_all(br#"<span class="error">\caption{</span>"#)?;
-                        } else {
-                            fmt.write_all(b"<figcaption>")?;
-                            html_subsubsection(fmt, arg)?;
-                            fmt.write_all(b"</figcaption>")?;
-                        }
-                    }
-                    r"\warning" => {
-                        let arg = argument(latex);
-                        latex = &latex[arg.len()..];
-                        if arg == "{" {
-                            fmt.write_all(br#"<span class="error">\warning{</span>"#)?;
-                        } else {
-                            fmt.write_all(br#"<span class="warning">"#)?;
-                            html(fmt, arg)?;
-                            fmt.write_all(b"</span>")?;
-                        }
-                    }
-                    r"\error" => {
-                        let arg = argument(latex);
-                        latex = &latex[arg.len()..];
-                        if arg == "{" {
-                            fmt.write_all(br#"<span class="error">\error{</span>"#)?;
-                        } else {
-                            fmt.write_all(br#"<span> class="error">"#)?;
-                            html(fmt, arg)?;
-                            fmt.write_all(b"</span>")?;
-                        }
-                    }
-                    r"\paragraph" | r"\paragraph*" => {
-                        let arg = parse_title(latex);
-                        latex = latex[arg.len()..].trim_start();
-                        if arg == "{" {
-                            fmt.write_all(br#"<span class="error">\paragraph{</span>"#)?;
-                        } else {
-                            fmt.write_all(b"<h5>")?;
-                            html(fmt, arg)?;
-                            fmt.write_all(b"</h5>")?;
-                        }
-                    }
-                    r"\it" => {
-                        latex = finish_standalone_macro(latex);
-                        fmt.write_all(b"<i>")?;
-                        html_subsubsection(fmt, latex)?;
-                        return fmt.write_all(b"</i>");
-                    }
-                    r"\centering" => {
-                        latex = finish_standalone_macro(latex);
-                        fmt.write_all(br#"<div class="center">"#)?;
-                        html_subsubsection(fmt, latex)?;
-                        return fmt.write_all(b"</div>");
-                    }
-                    r"\ " => {
-                        fmt.write_all(b" ")?;
-                    }
-                    r"\noindent" => {
-                        // Nothing to do?
-                    }
-                    r"\%" => {
-                        fmt.write_all(b"%")?;
-                    }
-                    r"\#" => {
-                        fmt.write_all(b"#")?;
-                    }
-                    r"\$" => {
-                        fmt.write_all(br"<span>$</span>")?;
-                    }
-                    r"\&" => {
-                        fmt.write_all(b"&amp;")?;
-                    }
-                    r"\_" => {
-                        fmt.write_all(b"_")?;
-                    }
-                    r"\(" => {
-                        if let Some(i) = latex.find(r"\)") {
-                            fmt.write_all(br"\(")?;
-                            fmt_math_as_html(fmt, &latex[..i + 2])?;
-                            latex = &latex[i + 2..];
-                        } else {
-                            fmt_error(fmt, r"\(")?;
-                        }
-                    }
-                    r"\[" => {
-                        if let Some(i) = latex.find(r"\]") {
-                            fmt.write_all(br"\[")?;
-                            fmt_math_as_html(fmt, &latex[..i + 2])?;
-                            latex = &latex[i + 2..];
-                        } else {
-                            fmt_error(fmt, r"\(")?;
-                        }
-                    }
-                    r"\bf" => {
-                        latex = finish_standalone_macro(latex);
-                        fmt.write_all(b"<b>")?;
-                        html(fmt, latex)?;
-                        return fmt.write_all(b"</b>");
-                    }
-                    r"\sc" => {
-                        latex = finish_standalone_macro(latex);
-                        fmt.write_all(br#"<font style="font-variant: small-caps">"#)?;
-                        html(fmt, latex)?;
-                        return fmt.write_all(b"</font>");
-                    }
-                    r"\begin" => {
-                        // We are looking at an environment...
-                        let name = env_name(latex);
-                        latex = &latex[name.len()..];
-                        if name.chars().last() != Some('}') {
-                            fmt_errors(fmt, &[r"\begin", name])?;
-                        } else if name == "{figure}" {
-                            // Just skip any figure placement parameters
-                            if latex.chars().next().unwrap() == '[' {
-                                if let Some(i) = latex.find(']') {
-                                    latex = &latex[i + 1..];
-                                }
-                            }
-                            if let Some(i) = latex.find(r"\end{figure}") {
-                                if latex.starts_with(r"\centering ")
-                                    || latex.starts_with(r"\centering\n")
-                                {
-                                    fmt.write_all(br#"<figure class="center">"#)?;
-                                    html_paragraph(fmt, &latex[r"\centering ".len()..i])?;
-                                } else {
-                                    fmt.write_all(b"<figure>")?;
-                                    html_paragraph(fmt, &latex[..i])?;
-                                }
-                                fmt.write_all(b"</figure>")?;
-                                latex = &latex[i + br"\end{figure}".len()..];
-                            } else {
-                                fmt.write_all(br#"<span class="error">\begin{figure}</span>"#)?;
-                            }
-                        } else if name == "{wrapfigure}" {
-                            let align = argument(latex);
-                            latex = &latex[align.len()..];
-                            let width = argument(latex);
-                            latex = &latex[width.len()..];
-                            let width = parse_width(width);
-                            if let Some(i) = latex.find(r"\end{wrapfigure}") {
-                                if latex.starts_with(r"\centering ")
-                                    || latex.starts_with(r"\centering\n")
-                                {
-                                    fmt.write_all(br#"<figure class="wrapfigure center""#)?;
-                                    fmt.write_all(width.as_bytes())?;
-                                    fmt.write_all(b">")?;
-                                    html_paragraph(fmt, &latex[r"\centering ".len()..i])?;
-                                } else {
-                                    fmt.write_all(br#"<figure class="wrapfigure""#)?;
-                                    fmt.write_all(width.as_bytes())?;
-                                    fmt.write_all(b">")?;
-                                    html_paragraph(fmt, &latex[..i])?;
-                                }
-                                fmt.write_all(b"</figure>")?;
-                                latex = &latex[i + br"\end{wrapfigure}".len()..];
-                            } else {
-                                fmt.write_all(br#"<span class="error">\begin{wrapfigure}</span>"#)?;
-                            }
-                        } else if ["{solution}", "{guide}", "{handout}"].contains(&name) {
-                            let end = format!(r"\end{}", name);
-                            if let Some(i) = latex.find(&end) {
-                                if latex[..i].chars().all(|c| c.is_whitespace()) {
-                                    // Nothing to do here, this solution is empty
-                                } else {
-                                    let kind = &name[1..name.len() - 1];
-                                    fmt.write_all(br#"<blockquote class=""#)?;
-                                    fmt.write_all(kind.as_bytes())?;
-                                    fmt.write_all(br#"">"#)?;
-                                    html_subsubsection(fmt, &latex[..i])?;
-                                    fmt.write_all(b"</blockquote>")?;
-                                }
-                                latex = &latex[i + br"\end".len() + name.len()..];
-                            } else {
-                                fmt.write_all(br#"<span class="error">\begin"#)?;
-                                fmt.write_all(name.as_bytes())?;
-                                fmt.write_all(br#"</span>"#)?;
-                            }
-                        } else if name == "{tabular}" {
-                            if let Some(i) = latex.find(r"\end{tabular}") {
-                                let rest = &latex[i + br"\end{tabular}".len()..];
-                                let arg = argument(latex);
-                                latex = &latex[arg.len()..i];
-                                // We just ignore the alignment marks.
-                                fmt.write_all(br#"<table>"#)?;
-                                let table_contents = latex.replace(r"\hline", r"\\ \hline");
-                                for mut row in table_contents.split(r"\\") {
-                                    if row.starts_with(r" \hline") {
-                                        fmt.write_all(br#"<tr style="border-bottom:1px solid black"><td colspan="100%"></td></tr>
-"#)?;
-                                        row = &row[7..];
-                                    }
-                                    let row = row.replace(r"\&", "myampersand");
-                                    fmt.write_all(br#"<tr>"#)?;
-                                    for column in row.split("&") {
-                                        fmt.write_all(br#"<td>"#)?;
-                                        let column = column.replace("myampersand", r"\&");
-                                        html_paragraph(fmt, &column)?;
-                                        fmt.write_all(br#"</td>"#)?;
-                                    }
-                                    fmt.write_all(br#"</tr>"#)?;
-                                }
-                                fmt.write_all(b"</table>")?;
-                                latex = rest;
-                            } else {
-                                fmt.write_all(br#"<span class="error">\begin{tabular}</span>"#)?;
-                            }
-                        } else if name == "{center}" {
-                            if let Some(i) = latex.find(r"\end{center}") {
-                                fmt.write_all(br#"<div class="center">"#)?;
-                                html_paragraph(fmt, &latex[..i])?;
-                                fmt.write_all(b"</div>")?;
-                                latex = &latex[i + br"\end{center}".len()..];
-                            } else {
-                                fmt.write_all(br#"<span class="error">\begin{center}</span>"#)?;
-                            }
-                        } else if name == "{verbatim}" {
-                            if let Some(i) = latex.find(r"\end{verbatim}") {
-                                fmt.write_all(b"<pre>")?;
-                                fmt_as_html(fmt, &latex[..i])?;
-                                fmt.write_all(b"</pre>")?;
-                                latex = &latex[i + br"\end{verbatim}".len()..];
-                            } else {
-                                fmt.write_all(br#"<span class="error">\begin{verbatim}</span>"#)?;
-                            }
-                        } else if name == "{quote}" {
-                            if let Some(i) = latex.find(r"\end{quote}") {
-                                fmt.write_all(b"<blockquote>")?;
-                                html_paragraph(fmt, &latex[..i])?;
-                                fmt.write_all(b"</blockquote>")?;
-                                latex = &latex[i + br"\end{quote}".len()..];
-                            } else {
-                                fmt.write_all(br#"<span class="error">\begin{quote}</span>"#)?;
-                            }
-                        } else if name == "{quotation}" {
-                            if let Some(i) = latex.find(r"\end{quotation}") {
-                                fmt.write_all(b"<blockquote>")?;
-                                html_paragraph(fmt, &latex[..i])?;
-                                fmt.write_all(b"</blockquote>")?;
-                                latex = &latex[i + br"\end{quotation}".len()..];
-                            } else {
-                                fmt.write_all(br#"<span class="error">\begin{quotation}</span>"#)?;
-                            }
-                        } else if math_environs.contains(&name) {
-                            let env = end_env(name, latex);
-                            latex = &latex[env.len()..];
-                            if env == "" {
-                                fmt_errors(fmt, &[r"\begin", name])?;
-                            } else {
-                                fmt.write_all(br#"\begin"#)?;
-                                fmt_as_html(fmt, name)?;
-                                fmt_math_as_html(fmt, env)?;
-                            }
-                        } else if name == "{itemize}" {
-                            fmt.write_all(b"<ul>")?;
-                            let li = finish_item(latex);
-                            latex = &latex[li.len()..];
-                            if li.trim().len() > 0 {
-                                // Nothing should precede the first
-                                // \item except whitespace.
-                                fmt_error(fmt, li)?;
-                            }
-                            loop {
-                                let li = finish_item(latex);
-                                latex = &latex[li.len()..];
-                                if li.len() == 0 {
-                                    if latex.starts_with(r"\end{itemize}") {
-                                        latex = &latex[r"\end{itemize}".len()..];
-                                        fmt.write_all(b"</ul>")?;
-                                        break;
-                                    } else if latex.starts_with(r"\end{description}") {
-                                        latex = &latex[r"\end{description}".len()..];
-                                        fmt.write_all(
-                                            br#"</ol><span class="error">\end{enumerate}</span>"#,
-                                        )?;
-                                        break;
-                                    } else if latex.starts_with(r"\end{enumerate}") {
-                                        latex = &latex[r"\end{enumerate}".len()..];
-                                        fmt.write_all(
-                                            br#"</ul><span class="error">\end{enumerate}</span>"#,
-                                        )?;
-                                        break;
-                                    } else if latex.starts_with(r"\item") {
-                                        // It must start with \item
-                                        latex = &latex[r"\item".len()..];
-                                        latex = finish_standalone_macro(latex);
-                                    } else {
-                                        fmt.write_all(
-                                            br#"</ul><span class="error">MISSING \end{itemize}</span>"#,
-                                        )?;
-                                        break;
-                                    }
-                                } else {
-                                    fmt.write_all(b"<li>")?;
-                                    html(fmt, li)?;
-                                    fmt.write_all(b"</li>")?;
-                                }
-                            }
-                        } else if name == "{enumerate}" {
-                            fmt.write_all(b"<ol>")?;
-                            let li = finish_item(latex);
-                            latex = &latex[li.len()..];
-                            if li.trim().len() > 0 {
-                                // Nothing should precede the first
-                                // \item except whitespace.
-                                fmt_error(fmt, li)?;
-                            }
-                            loop {
-                                let li = finish_item(latex);
-                                latex = &latex[li.len()..];
-                                if li.len() == 0 {
-                                    if latex.starts_with(r"\end{itemize}") {
-                                        latex = &latex[r"\end{itemize}".len()..];
-                                        fmt.write_all(
-                                            br#"</ol><span class="error">\end{enumerate}</span>"#,
-                                        )?;
-                                        break;
-                                    } else if latex.starts_with(r"\end{description}") {
-                                        latex = &latex[r"\end{description}".len()..];
-                                        fmt.write_all(
-                                            br#"</ol><span class="error">\end{enumerate}</span>"#,
-                                        )?;
-                                        break;
-                                    } else if latex.starts_with(r"\end{enumerate}") {
-                                        latex = &latex[r"\end{enumerate}".len()..];
-                                        fmt.write_all(b"</ol>")?;
-                                        break;
-                                    } else if latex.starts_with(r"\item") {
-                                        // It must start with \item
-                                        latex = &latex[r"\item".len()..];
-                                        latex = finish_standalone_macro(latex);
-                                    } else {
-                                        fmt.write_all(
-                                            br#"</ol><span class="error">MISSING \end{enumerate}</span>"#,
-                                        )?;
-                                        break;
-                                    }
-                                } else {
-                                    fmt.write_all(b"<li>")?;
-                                    html(fmt, li)?;
-                                    fmt.write_all(b"</li>")?;
-                                }
-                            }
-                        } else if name == "{description}" {
-                            fmt.write_all(b"<dl>")?;
-                            let li = finish_item(latex);
-                            latex = &latex[li.len()..];
-                            if li.trim().len() > 0 {
-                                // Nothing should precede the first
-                                // \item except whitespace.
-                                fmt_error(fmt, li)?;
-                            }
-                            loop {
-                                let li = finish_item(latex);
-                                latex = &latex[li.len()..];
-                                if li.len() == 0 {
-                                    if latex.starts_with(r"\end{itemize}") {
-                                        latex = &latex[r"\end{itemize}".len()..];
-                                        fmt.write_all(
-                                            br#"</ol><span class="error">\end{description}</span>"#,
-                                        )?;
-                                        break;
-                                    } else if latex.starts_with(r"\end{enumerate}") {
-                                        latex = &latex[r"\end{enumerate}".len()..];
-                                        fmt.write_all(
-                                            br#"</dl><span class="error">\end{description}</span>"#,
-                                        )?;
-                                        break;
-                                    } else if latex.starts_with(r"\end{description}") {
-                                        latex = &latex[r"\end{description}".len()..];
-                                        fmt.write_all(b"</dl>")?;
-                                        break;
-                                    } else if latex.starts_with(r"\item") {
-                                        // It must start with \item
-                                        latex = &latex[r"\item".len()..];
-                                        latex = finish_standalone_macro(latex);
-                                    } else {
-                                        fmt.write_all(
-                                            br#"</dl><span class="error">MISSING \end{description}</span>"#,
-                                        )?;
-                                        break;
-                                    }
-                                } else {
-                                    let o = optional_argument(li);
-                                    let li = &li[o.len()..];
-                                    if o.len() > 2 {
-                                        fmt.write_all(b"<dt>")?;
-                                        html(fmt, &o[1..o.len() - 1])?;
-                                        fmt.write_all(b"</dt>")?;
-                                    }
-
-                                    fmt.write_all(b"<dd>")?;
-                                    html(fmt, li)?;
-                                    fmt.write_all(b"</dd>")?;
-                                }
-                            }
-                        } else {
-                            let env = end_env(name, latex);
-                            latex = &latex[env.len()..];
-                            fmt_errors(fmt, &[r"\begin", name, env])?;
-                        }
-                    }
-                    r"\end" => {
-                        let name = env_name(latex);
-                        latex = &latex[name.len()..];
-                        fmt_errors(fmt, &[r"\end", name])?;
-                    }
-                    _ => {
-                        fmt_error(fmt, name)?;
-                    }
-                }
-            } else if c == '$' {
-                if let Some(i) = latex[1..].find('$') {
-                    if i == 0 {
-                        // It is a $$ actually
-                        if let Some(i) = latex[2..].find("$$") {
-                            fmt.write_all(br"\[")?;
-                            fmt_math_as_html(fmt, &latex[2..i + 2])?;
-                            fmt.write_all(br"\]")?;
-                            latex = &latex[i + 4..];
-                        } else {
-                            fmt.write_all(br#"<span class="error">$$</span>"#)?;
-                            latex = &latex[2..];
-                        }
-                    } else {
-                        if let Some(sub) = subscript
-                            .captures_iter(&latex[1..i + 1])
-                            .next()
-                            .or(subscript_other.captures_iter(&latex[1..i + 1]).next())
-                        {
-                            fmt.write_all(b"<sub>")?;
-                            fmt.write_all(sub[1].as_bytes())?;
-                            fmt.write_all(b"</sub>")?;
-                        } else if let Some(sub) = superscript
-                            .captures_iter(&latex[1..i + 1])
-                            .next()
-                            .or(superscript_other.captures_iter(&latex[1..i + 1]).next())
-                        {
-                            fmt.write_all(b"<sup>")?;
-                            fmt.write_all(sub[1].as_bytes())?;
-                            fmt.write_all(b"</sup>")?;
-                        } else if &latex[1..i + 1] == r"^\circ" {
-                            fmt.write_all(b"&deg;")?;
-                        } else {
-                            fmt.write_all(br"\(")?;
-                            fmt_math_as_html(fmt, &latex[1..i + 1])?;
-                            fmt.write_all(br"\)")?;
-                        }
-                        latex = &latex[i + 2..];
-                    }
-                } else {
-                    fmt.write_all(br#"<span class="error">$</span>"#)?;
-                    latex = &latex[1..];
-                }
-            } else {
-                let arg = argument(latex);
-                latex = &latex[arg.len()..];
-                if arg == "{" {
-                    fmt.write_all(br#"<span class="error">{</span>"#)?;
-                } else {
-                    html(fmt, &arg[1..arg.len() - 1])?;
-                }
-            }
-        } else {
-            return fmt_as_html(fmt, latex);
-        }
-    }
+    HtmlWriter::new(fmt).write_document(&parse::Parser::new(latex).inline(latex))
 }
 
-fn finish_standalone_macro(latex: &str) -> &str {
-    if latex.len() == 0 {
-        ""
-    } else if latex.chars().next().unwrap() == ' ' {
-        &latex[1..]
-    } else {
-        latex
+impl Document {
+    /// Write this document as HTML to a `std::io::Write`.
+    pub fn write_html(&self, fmt: &mut impl std::io::Write) -> Result<(), std::io::Error> {
+        HtmlWriter::new(fmt).write_document(&self.content)
     }
-}
 
-fn macro_name(latex: &str) -> &str {
-    if let Some(i) = latex[1..].find(|c: char| !c.is_alphabetic() && c != '*') {
-        if i == 0 {
-            &latex[..2]
-        } else {
-            &latex[..i + 1]
-        }
-    } else {
-        latex
+    /// Write this document as HTML to a `std::io::Write`, marking each
+    /// element with `data-src-start` and `data-src-end` attributes that
+    /// hold the byte range of the LaTeX it came from.
+    pub fn write_html_with_source_positions(
+        &self,
+        fmt: &mut impl std::io::Write,
+    ) -> Result<(), std::io::Error> {
+        let mut w = HtmlWriter::new(Vec::with_capacity(1024));
+        w.sources = Some(Vec::new());
+        w.write_document(&self.content)?;
+        fmt.write_all(&w.with_source_positions())
     }
 }
 
-#[test]
-fn test_macro_name() {
-    assert_eq!(macro_name(r"\emph{foo"), r"\emph");
-    assert_eq!(macro_name(r"\\ extra"), r"\\");
-    assert_eq!(macro_name(r"\% extra"), r"\%");
+/// Writes [`Node`]s as HTML.
+struct HtmlWriter<W> {
+    fmt: W,
+    options: RenderOptions,
+    /// How many bytes we have written so far.
+    written: usize,
+    /// Where each element starts in the output, along with where it
+    /// came from in the LaTeX, if we are keeping track.
+    sources: Option<Vec<(usize, std::ops::Range<usize>)>>,
+    /// Where the first node we left out for want of room came from.
+    truncated: Option<std::ops::Range<usize>>,
+    /// Problems found while writing, such as math we could not convert.
+    diagnostics: Vec<Diagnostic>,
+    /// The numbers of the equations, if we number them ourselves.
+    equations: Option<Equations>,
+    /// The numbers and ids of the headings.
+    headings: Headings,
+    /// The numbers of everything labelled in the text.
+    references: References,
+    /// The labels we have written anchors for.
+    anchored: HashSet<String>,
+    /// What goes before the next caption, such as `Figure 2: `.
+    caption: Option<String>,
+    /// Whether we are writing a heading title, whether in its section
+    /// or in the table of contents, which must not hold the table of
+    /// contents again.
+    in_heading: bool,
 }
 
-fn env_name(latex: &str) -> &str {
-    if let Some(i) = latex.find(|c: char| c == '}') {
-        &latex[..i + 1]
-    } else if let Some(i) = latex.find(|c: char| c != '{' && !c.is_alphabetic()) {
-        &latex[..i + 1]
-    } else {
-        latex
+impl<W: std::io::Write> std::io::Write for HtmlWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.fmt.write(buf)?;
+        self.written += n;
+        Ok(n)
     }
-}
 
-fn end_env<'a>(name: &str, latex: &'a str) -> &'a str {
-    let end = format!(r"\end{}", name);
-    if let Some(i) = latex.find(&end) {
-        &latex[..i + end.len()]
-    } else {
-        ""
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.fmt.flush()
     }
 }
 
-fn earlier(a: Option<usize>, b: Option<usize>) -> bool {
-    if let Some(b) = b {
-        if let Some(a) = a {
-            a < b
-        } else {
-            false
+impl HtmlWriter<Vec<u8>> {
+    /// Our output, with the source range of each element added as
+    /// attributes of its opening tag.
+    fn with_source_positions(&self) -> Vec<u8> {
+        let html = &self.fmt;
+        let mut out = Vec::with_capacity(html.len() * 2);
+        let mut copied = 0;
+        let sources = self.sources.as_deref().unwrap_or_default();
+        for (i, (start, span)) in sources.iter().enumerate() {
+            let start = *start;
+            // Something that wrote nothing shares its start with whatever
+            // comes next, which is what the tag actually belongs to.
+            let shadowed = sources.get(i + 1).is_some_and(|(next, _)| *next == start);
+            if shadowed || !html[start..].starts_with(b"<") || html[start..].starts_with(b"</") {
+                continue;
+            }
+            let name_end = html[start..]
+                .iter()
+                .position(|&b| b == b' ' || b == b'>' || b == b'/')
+                .map_or(html.len(), |i| start + i);
+            out.extend_from_slice(&html[copied..name_end]);
+            let attributes = format!(
+                r#" data-src-start="{}" data-src-end="{}""#,
+                span.start, span.end
+            );
+            out.extend_from_slice(attributes.as_bytes());
+            copied = name_end;
         }
-    } else {
-        true
+        out.extend_from_slice(&html[copied..]);
+        out
     }
 }
 
-fn find_paragraph(latex: &str) -> Option<usize> {
-    let paragraph = regex::Regex::new("\n\\s*\n").unwrap();
-    paragraph.find(latex).map(|m| m.end())
-}
-
-#[test]
-fn test_find_paragraph() {
-    assert_eq!(Some(3), find_paragraph("\n\n\nHello world"));
-    assert_eq!(Some(5), find_paragraph("\n\n\n\r\nHello world"));
-}
-#[test]
-fn test_finish_paragraph() {
-    assert_eq!(
-        r"\begin{center}
-contents
-\end{center}
-
-",
-        finish_paragraph(
-            r"\begin{center}
-contents
-\end{center}
-
-"
-        )
-    );
-    assert_eq!(
-        r"\begin{center}
-contents
-
-with paragraph
-\end{center}
-
-",
-        finish_paragraph(
-            r"\begin{center}
-contents
-
-with paragraph
-\end{center}
-
-"
-        )
-    );
-    assert_eq!(
-        r"center
-contents
-
-",
-        finish_paragraph(
-            r"center
-contents
-
-with paragraph
-end center
-
-"
-        )
-    );
-    assert_eq!("\n\n\n", finish_paragraph("\n\n\nHello world"));
-    assert_eq!("\n\n\n\r\n", finish_paragraph("\n\n\n\r\nHello world"));
-    assert_eq!(
-        "\nFirst me\n\n\n\r\n",
-        finish_paragraph("\nFirst me\n\n\n\r\nHello world")
-    );
-
-    use expect_test::expect;
-    expect![[r#"
-
-        First paragraph.
-
-        \begin{itemize}
-        \item First
-        \item Second
-        \end{itemize}
-
-    "#]]
-    .assert_eq(finish_paragraph(
-        r#"
-First paragraph.
-
-\begin{itemize}
-\item First
-\item Second
-\end{itemize}
-
-Second paragraph.
-"#,
-    ));
+impl<W: std::io::Write> HtmlWriter<W> {
+    fn new(fmt: W) -> Self {
+        HtmlWriter::with_options(fmt, RenderOptions::new())
+    }
 
-    expect![[r#"
+    fn with_options(fmt: W, options: RenderOptions) -> Self {
+        HtmlWriter {
+            fmt,
+            options,
+            written: 0,
+            sources: None,
+            truncated: None,
+            diagnostics: Vec::new(),
+            equations: None,
+            headings: Headings::default(),
+            references: References::default(),
+            anchored: HashSet::new(),
+            caption: None,
+            in_heading: false,
+        }
+    }
 
-    First paragraph.
+    /// Write `content`, which is a whole document, so that a table of
+    /// contents can be made from its headings.
+    fn write_document(&mut self, content: &[Node]) -> Result<(), std::io::Error> {
+        self.headings = Headings::new(content);
+        self.references = References::new(content, &self.headings, self.equations.as_mut());
+        self.diagnostics.append(&mut self.references.diagnostics);
+        self.write_nodes(content)
+    }
 
-    \begin{itemize}
-    \item First
-    
-    \item Second
-    \end{itemize}
+    /// Whether headings get ids, which they need for a table of
+    /// contents to link to them.
+    fn heading_ids(&self) -> bool {
+        self.options.heading_anchors() || self.headings.wants_contents
+    }
 
-"#]]
-    .assert_eq(finish_paragraph(
-        r#"
-First paragraph.
+    /// Write the table of contents, as nested lists of links.
+    fn write_table_of_contents(&mut self) -> Result<(), std::io::Error> {
+        let headings = self.headings.clone();
+        let contents = headings.contents();
+        if contents.is_empty() {
+            return Ok(());
+        }
+        self.write_all(br#"<nav class="toc">"#)?;
+        self.write_contents(&contents)?;
+        self.write_all(b"</nav>")
+    }
 
-\begin{itemize}
-\item First
+    fn write_contents(&mut self, contents: &[&headings::Heading]) -> Result<(), std::io::Error> {
+        self.write_all(b"<ul>")?;
+        let mut i = 0;
+        while i < contents.len() {
+            let heading = contents[i];
+            // Everything deeper that follows goes in a list of its own.
+            let end = contents[i + 1..]
+                .iter()
+                .position(|h| h.level <= heading.level)
+                .map_or(contents.len(), |j| i + 1 + j);
+            self.write_all(br##"<li><a href="#"##)?;
+            fmt_as_html(self, &heading.id)?;
+            self.write_all(br#"">"#)?;
+            self.write_heading_number(heading)?;
+            let in_heading = std::mem::replace(&mut self.in_heading, true);
+            self.write_nodes(&heading.title)?;
+            self.in_heading = in_heading;
+            self.write_all(b"</a>")?;
+            if end > i + 1 {
+                self.write_contents(&contents[i + 1..end])?;
+            }
+            self.write_all(b"</li>")?;
+            i = end;
+        }
+        self.write_all(b"</ul>")
+    }
 
-\item Second
-\end{itemize}
+    fn write_heading_number(&mut self, heading: &headings::Heading) -> Result<(), std::io::Error> {
+        match &heading.number {
+            Some(number) if self.options.section_numbering() => {
+                write!(self, r#"<span class="section-number">{}</span> "#, number)
+            }
+            _ => Ok(()),
+        }
+    }
 
-Second paragraph.
-"#,
-    ));
-}
+    /// Write the reference `name`, such as `\autoref`, to `label`.
+    fn write_reference(&mut self, name: &str, label: &str) -> Result<(), std::io::Error> {
+        let target = match (self.references.target(label), &self.equations) {
+            (Some(target), _) => Some(target.clone()),
+            (None, Some(equations)) => equations.number(label).map(|number| references::Target {
+                kind: references::Kind::Equation,
+                number: number.to_string(),
+            }),
+            (None, None) if self.references.in_math(label) => {
+                // MathJax numbers the equations, so it resolves references
+                // to labels in math for us.
+                let (prefix, name) = match name {
+                    "autoref" => ("Equation ", "ref"),
+                    "cref" => ("eq. ", "eqref"),
+                    "Cref" => ("Equation ", "eqref"),
+                    "pageref" => ("", "ref"),
+                    _ => ("", name),
+                };
+                write!(self, r"{}\{}{{", prefix, name)?;
+                fmt_as_html(self, label)?;
+                return self.write_all(b"}");
+            }
+            (None, None) => None,
+        };
+        match target {
+            Some(target) => {
+                self.write_all(br##"<a href="#"##)?;
+                fmt_as_html(self, label)?;
+                self.write_all(br#"">"#)?;
+                fmt_as_html(self, &references::reference_text(name, &target))?;
+                self.write_all(b"</a>")
+            }
+            None => self.write_all(br#"<span class="error">??</span>"#),
+        }
+    }
 
-fn finish_paragraph(latex: &str) -> &str {
-    if latex.len() == 0 {
-        return "";
+    fn write_nodes(&mut self, nodes: &[Node]) -> Result<(), std::io::Error> {
+        for node in nodes {
+            self.write_node(node)?;
+        }
+        Ok(())
     }
-    let mut so_far = 0;
-    let mut nestedness = 0;
-    loop {
-        let next_paragraph = find_paragraph(&latex[so_far..]);
-        let next_end = latex[so_far..].find(r"\end{");
-        let next_begin = latex[so_far..].find(r"\begin{");
-        if earlier(next_paragraph, next_begin) && earlier(next_paragraph, next_end) && nestedness == 0 {
-                if let Some(i) = next_paragraph {
-                    return &latex[..so_far + i];
+
+    fn write_node(&mut self, node: &Node) -> Result<(), std::io::Error> {
+        if self.truncated.is_some() {
+            return Ok(());
+        }
+        if self.written >= self.options.render_limits().output_bytes() {
+            self.truncated = Some(node.span.clone());
+            return self.write_all(br#"<span class="error">output too large</span>"#);
+        }
+        if let Some(sources) = &mut self.sources {
+            match node.kind {
+                NodeKind::Text(_) | NodeKind::NonBreakingSpace | NodeKind::Group(_) => (),
+                _ => sources.push((self.written, node.span.clone())),
+            }
+        }
+        match &node.kind {
+            NodeKind::Text(text) => fmt_as_html(self, text),
+            NodeKind::NonBreakingSpace => self.write_all(b"&nbsp;"),
+            NodeKind::Paragraph(content) => {
+                self.write_all(b"<p>")?;
+                self.write_nodes(content)?;
+                self.write_all(b"</p>")
+            }
+            NodeKind::Section {
+                level,
+                title,
+                content,
+                ..
+            } => {
+                let h = self.options.html_heading(*level);
+                let heading = self.headings.get(&node.span).cloned();
+                write!(self, "<section><h{}", h)?;
+                if let Some(heading) = heading.as_ref().filter(|_| self.heading_ids()) {
+                    self.write_all(br#" id=""#)?;
+                    fmt_as_html(self, &heading.id)?;
+                    self.write_all(b"\"")?;
+                }
+                self.write_all(b">")?;
+                if let Some(heading) = &heading {
+                    self.write_heading_number(heading)?;
+                }
+                let in_heading = std::mem::replace(&mut self.in_heading, true);
+                self.write_nodes(title)?;
+                self.in_heading = in_heading;
+                write!(self, "</h{}>", h)?;
+                self.write_nodes(content)?;
+                self.write_all(b"</section>") // We finished a section.
+            }
+            NodeKind::Group(content) => self.write_nodes(content),
+            NodeKind::Macro { name, args } => self.write_macro(name, args),
+            NodeKind::Declaration { name, content } => {
+                let (open, close): (&[u8], &[u8]) = match name.as_str() {
+                    "it" => (b"<i>", b"</i>"),
+                    "bf" => (b"<b>", b"</b>"),
+                    "sc" => (br#"<font style="font-variant: small-caps">"#, b"</font>"),
+                    _ => (br#"<div class="center">"#, b"</div>"),
+                };
+                self.write_all(open)?;
+                self.write_nodes(content)?;
+                self.write_all(close)
+            }
+            NodeKind::Environment {
+                name,
+                args,
+                content,
+            } => self.write_environment(&node.span, name, args, content),
+            NodeKind::Item { label, content } => {
+                if let Some(label) = label {
+                    self.write_all(b"<dt>")?;
+                    self.write_nodes(label)?;
+                    self.write_all(b"</dt><dd>")?;
+                    self.write_nodes(content)?;
+                    self.write_all(b"</dd>")
                 } else {
-                    // There is no end to this
-                    return latex;
+                    self.write_all(b"<li>")?;
+                    self.write_nodes(content)?;
+                    self.write_all(b"</li>")
                 }
-        } else if earlier(next_end, next_begin) && next_end.is_some() {
-            let i = next_end.unwrap();
-            if nestedness == 0 {
-                return &latex[..so_far + i];
-            } else {
-                nestedness -= 1;
-                so_far += i + r"\\end{".len();
             }
-        } else if let Some(i) = next_begin {
-            nestedness += 1;
-            so_far += i + r"\\begin{".len();
-        } else {
-            return latex;
+            NodeKind::Row(cells) => {
+                self.write_all(b"<tr>")?;
+                for cell in cells {
+                    self.write_all(b"<td>")?;
+                    self.write_nodes(cell)?;
+                    self.write_all(b"</td>")?;
+                }
+                self.write_all(b"</tr>")
+            }
+            NodeKind::Math { style, latex } => {
+                let mut style = std::borrow::Cow::Borrowed(style);
+                let mut latex = std::borrow::Cow::Borrowed(latex.as_str());
+                let mut labels = Vec::new();
+                if let Some(equations) = &mut self.equations {
+                    if let Some(numbered) = equations.take(&node.span) {
+                        style =
+                            std::borrow::Cow::Owned(MathStyle::Environment(numbered.environment));
+                        latex = numbered.latex.into();
+                        labels = numbered.labels;
+                    }
+                    if let Some(resolved) = equations.resolve_in_math(&latex) {
+                        latex = resolved.into();
+                    }
+                }
+                for label in labels {
+                    self.write_all(br#"<span id=""#)?;
+                    fmt_as_html(self, &label)?;
+                    self.write_all(br#""></span>"#)?;
+                }
+                let units = self.options.unit_options();
+                if let Some(expanded) = mhchem::expand_in_math(&latex, units) {
+                    latex = expanded.into();
+                }
+                if let Some(expanded) = siunitx::expand_in_math(&latex, units) {
+                    latex = expanded.into();
+                }
+                let spoken = match self.options.speech_verbosity() {
+                    Speech::Off => None,
+                    verbosity => match speech::speech(
+                        &latex,
+                        &style,
+                        verbosity,
+                        self.options.render_limits().depth(),
+                    ) {
+                        Ok(spoken) => Some(spoken),
+                        Err(e) => {
+                            self.diagnostics.push(Diagnostic {
+                                code: "unspoken-math",
+                                severity: Severity::Warning,
+                                message: format!("no speech for this math: {}", e.message),
+                                span: node.span.clone(),
+                            });
+                            None
+                        }
+                    },
+                };
+                if let Some(spoken) = &spoken {
+                    let mut label = String::new();
+                    mathml::escape(&mut label, spoken);
+                    write!(self, r#"<span role="math" aria-label="{}">"#, label)?;
+                }
+                self.write_math_output(&style, &latex, &node.span)?;
+                if spoken.is_some() {
+                    self.write_all(b"</span>")?;
+                }
+                Ok(())
+            }
+            NodeKind::Error(latex) => fmt_error(self, latex),
         }
     }
-}
 
-fn finish_item(latex: &str) -> &str {
-    if latex.len() == 0 {
-        return "";
+    /// Write the math `latex` as we were asked to, falling back to
+    /// LaTeX.
+    fn write_math_output(
+        &mut self,
+        style: &MathStyle,
+        latex: &str,
+        span: &std::ops::Range<usize>,
+    ) -> Result<(), std::io::Error> {
+        match self.options.math_output() {
+            MathOutput::Tex => (),
+            MathOutput::Lite => {
+                if *style == MathStyle::Inline {
+                    let depth = self.options.render_limits().depth();
+                    if let Some(html) = mathlite::html(latex, depth) {
+                        return self.write_all(html.as_bytes());
+                    }
+                }
+            }
+            MathOutput::MathMl => {
+                let depth = self.options.render_limits().depth();
+                match mathml::mathml(latex, style, depth) {
+                    Ok(mathml) => return self.write_all(mathml.as_bytes()),
+                    Err(e) => self.math_error(e, span),
+                }
+            }
+        }
+        write_math(self, style, latex)
+    }
+
+    /// Report why we left the math at `span` as LaTeX.
+    fn math_error(&mut self, e: math::MathError, span: &std::ops::Range<usize>) {
+        let diagnostic = if e.code == "max-depth" {
+            Diagnostic {
+                code: e.code,
+                severity: Severity::Error,
+                message: format!("left as LaTeX: math {}", e.message),
+                span: span.clone(),
+            }
+        } else {
+            Diagnostic {
+                code: "unsupported-math",
+                severity: Severity::Warning,
+                message: format!("left as LaTeX: {}", e.message),
+                span: span.clone(),
+            }
+        };
+        self.diagnostics.push(diagnostic);
     }
-    let end_list = regex::Regex::new(r"\\end\{(itemize|enumerate|description)\}").unwrap();
-    let begin_list = regex::Regex::new(r"\\begin\{(itemize|enumerate|description)\}").unwrap();
-    let mut so_far = 0;
-    let mut nestedness = 0;
-    loop {
-        let next_item = latex[so_far..].find(r"\item");
-        let next_end = end_list.find(&latex[so_far..]).map(|m| m.start());
-        let next_begin = begin_list.find(&latex[so_far..]).map(|m| m.start());
-        if nestedness == 0 && earlier(next_item, next_begin) && earlier(next_item, next_end) {
-            if let Some(i) = next_item {
-                return &latex[..so_far + i];
-            } else {
-                // There is no end to this
-                return "";
+
+    fn write_macro(&mut self, name: &str, args: &[Argument]) -> Result<(), std::io::Error> {
+        match (name, args) {
+            ("\\" | "newpage" | "vspace" | "vfill", _) => {
+                self.write_all(b"<br/>") // just treat a \vspace as a line break
+            }
+            ("textbackslash", []) => self.write_all(b"\\"),
+            (accent, [Argument::Verbatim(letter)]) if text::is_accent(accent) => {
+                match text::accented(accent, letter) {
+                    Some(html) => self.write_all(html.as_bytes()),
+                    None => fmt_error(self, &format!(r"\{}{{{}}}", accent, letter)),
+                }
+            }
+            (character, []) if text::character(character).is_some() => {
+                self.write_all(text::character(character).unwrap_or_default().as_bytes())
+            }
+            ("ldots", []) => self.write_all(b"..."),
+            ("label", [Argument::Verbatim(label)]) => {
+                // A label is an anchor for what it refers to, if anything.
+                if self.references.target(label).is_some() && self.anchored.insert(label.clone()) {
+                    self.write_all(br#"<span id=""#)?;
+                    fmt_as_html(self, label)?;
+                    self.write_all(br#""></span>"#)?;
+                }
+                Ok(())
             }
-        } else if earlier(next_end, next_begin) {
-            if let Some(i) = next_end {
-                if nestedness == 0 {
-                    return &latex[..so_far + i];
+            (name, [Argument::Verbatim(label)]) if references::is_reference(name) => {
+                self.write_reference(name, label)
+            }
+            ("hyperref", [Argument::Optional(label), Argument::Latex(content)]) => {
+                if self.references.is_defined(label, self.equations.as_ref()) {
+                    self.write_all(br##"<a href="#"##)?;
+                    fmt_as_html(self, label)?;
+                    self.write_all(br#"">"#)?;
+                    self.write_wrapped("", content, "</a>")
                 } else {
-                    nestedness -= 1;
-                    so_far += i + r"\\end{".len();
+                    self.write_wrapped(r#"<span class="error">"#, content, "</span>")
                 }
-            } else {
-                // There is no ending, but we are nested!!!
-                return "";
             }
-        } else {
-            if let Some(i) = next_begin {
-                nestedness += 1;
-                so_far += i + r"\\begin{".len();
-            } else {
-                panic!("next_begin gives unexpected None");
+            ("verb", [Argument::Verbatim(content)]) => {
+                self.write_all(b"<code>")?;
+                fmt_as_html(self, content)?;
+                self.write_all(b"</code>")
+            }
+            ("emph", [Argument::Latex(content)]) => self.write_wrapped("<em>", content, "</em>"),
+            ("underline", [Argument::Latex(content)]) => self.write_wrapped("<u>", content, "</u>"),
+            ("textit", [Argument::Latex(content)]) => self.write_wrapped("<i>", content, "</i>"),
+            ("textbf", [Argument::Latex(content)]) => self.write_wrapped("<b>", content, "</b>"),
+            ("texttt", [Argument::Latex(content)]) => {
+                self.write_wrapped("<code>", content, "</code>")
+            }
+            ("footnote", [Argument::Latex(content)]) => {
+                self.write_wrapped("<sup>*</sup><aside><sup>*</sup>", content, "</aside>")
+            }
+            ("caption", [Argument::Latex(content)]) => {
+                self.write_all(b"<figcaption>")?;
+                if let Some(caption) = self.caption.take() {
+                    fmt_as_html(self, &caption)?;
+                }
+                self.write_wrapped("", content, "</figcaption>")
+            }
+            ("warning", [Argument::Latex(content)]) => {
+                self.write_wrapped(r#"<span class="warning">"#, content, "</span>")
+            }
+            ("error", [Argument::Latex(content)]) => {
+                self.write_wrapped(r#"<span class="error">"#, content, "</span>")
+            }
+            ("paragraph" | "paragraph*", [Argument::Latex(content)]) => {
+                let h = self.options.html_heading(4);
+                write!(self, "<h{}>", h)?;
+                self.write_nodes(content)?;
+                write!(self, "</h{}>", h)
+            }
+            ("textcolor", [Argument::Verbatim(color), Argument::Latex(content)]) => {
+                if parse::TEXT_COLORS.contains(&color.as_str()) {
+                    self.write_all(br#"<span style="color:"#)?;
+                    self.write_all(color.as_bytes())?;
+                    self.write_all(br#";">"#)?;
+                    self.write_nodes(content)?;
+                    self.write_all(b"</span>")
+                } else {
+                    fmt_error(
+                        self,
+                        &format!(
+                            r"\textcolor{{Invalid color {} Allowed colors: red, blue, forestgreen, purple, gray, brown}}",
+                            color
+                        ),
+                    )?;
+                    self.write_nodes(content)
+                }
+            }
+            ("url", [Argument::Verbatim(url)]) => {
+                let url = process_url_argument(url);
+                write_link_start(self, &url)?;
+                self.write_all(url.as_bytes())?;
+                self.write_all(b"</a>")
+            }
+            ("href", [Argument::Verbatim(url), Argument::Latex(content)]) => {
+                write_link_start(self, &process_url_argument(url))?;
+                self.write_nodes(content)?;
+                self.write_all(b"</a>")
             }
+            ("includegraphics", [Argument::Verbatim(file)]) => {
+                let file = format!("{}{}", self.options.image_prefix(), file);
+                write_image(self, "", &file)
+            }
+            ("includegraphics", [Argument::Optional(opt), Argument::Verbatim(file)]) => {
+                let file = format!("{}{}", self.options.image_prefix(), file);
+                write_image(self, &parse_width(&format!("[{}]", opt)), &file)
+            }
+            ("hline", []) => self.write_all(
+                br#"<tr style="border-bottom:1px solid black"><td colspan="100%"></td></tr>
+"#,
+            ),
+            (" ", []) => self.write_all(b" "),
+            ("noindent", []) => {
+                // Nothing to do?
+                Ok(())
+            }
+            // The parser reports one in a heading, which would hold itself.
+            ("tableofcontents", []) if self.in_heading => Ok(()),
+            ("tableofcontents", []) => self.write_table_of_contents(),
+            ("%", []) => self.write_all(b"%"),
+            ("#", []) => self.write_all(b"#"),
+            ("$", []) => self.write_all(br"<span>$</span>"),
+            ("&", []) => self.write_all(b"&amp;"),
+            ("_", []) => self.write_all(b"_"),
+            ("{", []) => self.write_all(br"\{"),
+            ("}", []) => self.write_all(br"\}"),
+            ("ce" | "pu", [Argument::Verbatim(content)]) => {
+                match mhchem::html(name, &[content], self.options.unit_options()) {
+                    Some(html) => self.write_all(html.as_bytes()),
+                    None => fmt_error(self, &format!(r"\{}{{{}}}", name, content)),
+                }
+            }
+            ("SI" | "qty" | "si" | "unit" | "num" | "ang", _) => {
+                let args: Vec<&str> = args
+                    .iter()
+                    .filter_map(|arg| match arg {
+                        Argument::Verbatim(arg) => Some(arg.as_str()),
+                        _ => None,
+                    })
+                    .collect();
+                match siunitx::html(name, &args, self.options.unit_options()) {
+                    Some(html) => self.write_all(html.as_bytes()),
+                    None => fmt_error(self, &format!(r"\{}", name)),
+                }
+            }
+            _ => fmt_error(self, &format!(r"\{}", name)),
         }
     }
-}
 
-fn argument(latex: &str) -> &str {
-    if latex.len() == 0 {
-        ""
-    } else if latex.chars().next().unwrap().is_digit(10) {
-        &latex[..1]
-    } else if latex.chars().next().unwrap() == '{' {
-        let mut n = 0;
-        let mut arg = String::from("{");
-        for c in latex[1..].chars() {
-            arg.push(c);
-            if c == '{' {
-                n += 1
-            } else if c == '}' {
-                if n == 0 {
-                    return &latex[..arg.len()];
+    fn write_wrapped(
+        &mut self,
+        open: &str,
+        content: &[Node],
+        close: &str,
+    ) -> Result<(), std::io::Error> {
+        self.write_all(open.as_bytes())?;
+        self.write_nodes(content)?;
+        self.write_all(close.as_bytes())
+    }
+
+    fn write_environment(
+        &mut self,
+        span: &std::ops::Range<usize>,
+        name: &str,
+        args: &[Argument],
+        content: &[Node],
+    ) -> Result<(), std::io::Error> {
+        match name {
+            "figure" | "wrapfigure" | "table" => {
+                let (centered, content) = match content.split_first() {
+                    Some((
+                        Node {
+                            kind: NodeKind::Macro { name, args },
+                            ..
+                        },
+                        rest,
+                    )) if name == "centering" && args.is_empty() => (true, rest),
+                    _ => (false, content),
+                };
+                if let [_, Argument::Verbatim(width)] = args {
+                    if centered {
+                        self.write_all(br#"<figure class="wrapfigure center""#)?;
+                    } else {
+                        self.write_all(br#"<figure class="wrapfigure""#)?;
+                    }
+                    self.write_all(parse_width(&format!("{{{}}}", width)).as_bytes())?;
+                    self.write_all(b">")?;
+                } else if name == "table" {
+                    if centered {
+                        self.write_all(br#"<figure class="table center">"#)?;
+                    } else {
+                        self.write_all(br#"<figure class="table">"#)?;
+                    }
+                } else if centered {
+                    self.write_all(br#"<figure class="center">"#)?;
+                } else {
+                    self.write_all(b"<figure>")?;
                 }
-                n -= 1
+                // Only a labelled figure shows its number, on its caption.
+                let kind = if name == "table" { "Table" } else { "Figure" };
+                let caption = self
+                    .references
+                    .number(span)
+                    .map(|number| format!("{} {}: ", kind, number));
+                let outer = std::mem::replace(&mut self.caption, caption);
+                self.write_nodes(content)?;
+                self.caption = outer;
+                self.write_all(b"</figure>")
+            }
+            "solution" | "guide" | "handout" => {
+                if content.is_empty() {
+                    // Nothing to do here, this solution is empty
+                    return Ok(());
+                }
+                self.write_all(br#"<blockquote class=""#)?;
+                self.write_all(name.as_bytes())?;
+                self.write_all(br#"">"#)?;
+                self.write_nodes(content)?;
+                self.write_all(b"</blockquote>")
+            }
+            "tabular" => {
+                // We just ignore the alignment marks.
+                self.write_wrapped("<table>", content, "</table>")
+            }
+            "center" => self.write_wrapped(r#"<div class="center">"#, content, "</div>"),
+            "quote" | "quotation" => self.write_wrapped("<blockquote>", content, "</blockquote>"),
+            "verbatim" => {
+                self.write_all(b"<pre>")?;
+                if let [Argument::Verbatim(text)] = args {
+                    fmt_as_html(self, text)?;
+                }
+                self.write_all(b"</pre>")
+            }
+            "itemize" => self.write_wrapped("<ul>", content, "</ul>"),
+            "enumerate" => self.write_wrapped("<ol>", content, "</ol>"),
+            "description" => {
+                self.write_all(b"<dl>")?;
+                for node in content {
+                    if let NodeKind::Item {
+                        label: None,
+                        content,
+                    } = &node.kind
+                    {
+                        self.write_wrapped("<dd>", content, "</dd>")?;
+                    } else {
+                        self.write_node(node)?;
+                    }
+                }
+                self.write_all(b"</dl>")
+            }
+            _ if references::theorem_name(name).is_some() => {
+                write!(self, r#"<div class="{}"><b>"#, name)?;
+                fmt_as_html(self, references::theorem_name(name).unwrap_or_default())?;
+                if let Some(number) = self.references.number(span).map(str::to_string) {
+                    write!(self, " {}", number)?;
+                }
+                if let [Argument::Optional(note)] = args {
+                    self.write_all(b" (")?;
+                    fmt_as_html(self, note)?;
+                    self.write_all(b")")?;
+                }
+                self.write_all(b".</b> ")?;
+                self.write_wrapped("", content, "</div>")
+            }
+            _ => {
+                self.write_all(br#"<span class="error">"#)?;
+                fmt_as_html(self, &format!(r"\begin{{{}}}", name))?;
+                self.write_nodes(content)?;
+                fmt_as_html(self, &format!(r"\end{{{}}}", name))?;
+                self.write_all(br#"</span>"#)
             }
         }
-        // we must have unbalanced parentheses
-        &latex[..1]
+    }
+}
+
+fn write_link_start(fmt: &mut impl std::io::Write, url: &str) -> Result<(), std::io::Error> {
+    if url.starts_with("https://") {
+        fmt.write_all(b"<a target=\"_parent\" href=\"")?;
     } else {
-        &latex[..1]
+        fmt.write_all(b"<a href=\"")?;
     }
+    fmt.write_all(url.as_bytes())?;
+    fmt.write_all(b"\">")
+}
+
+fn write_image(
+    fmt: &mut impl std::io::Write,
+    width: &str,
+    file: &str,
+) -> Result<(), std::io::Error> {
+    fmt.write_all(br#"<img"#)?;
+    fmt.write_all(width.as_bytes())?;
+    fmt.write_all(br#" src=""#)?;
+    fmt_as_html(fmt, file)?;
+    fmt.write_all(br#""/>"#)
 }
 
-fn optional_argument(latex: &str) -> &str {
-    if latex.len() == 0 {
-        ""
-    } else if latex.chars().next().unwrap() == '[' {
-        let mut n: isize = 0;
-        let mut arg = String::from("[");
-        for c in latex[1..].chars() {
-            arg.push(c);
-            if c == '{' {
-                n += 1
-            } else if c == '}' {
-                n -= 1
-            } else if c == ']' && n == 0 {
-                return &latex[..arg.len()];
+fn write_math(
+    fmt: &mut impl std::io::Write,
+    style: &MathStyle,
+    latex: &str,
+) -> Result<(), std::io::Error> {
+    match style {
+        MathStyle::Inline => {
+            if let Some(sub) = SUBSCRIPT
+                .captures(latex)
+                .or_else(|| SUBSCRIPT_OTHER.captures(latex))
+            {
+                fmt.write_all(b"<sub>")?;
+                fmt.write_all(sub[1].as_bytes())?;
+                fmt.write_all(b"</sub>")
+            } else if let Some(sup) = SUPERSCRIPT
+                .captures(latex)
+                .or_else(|| SUPERSCRIPT_OTHER.captures(latex))
+            {
+                fmt.write_all(b"<sup>")?;
+                fmt.write_all(sup[1].as_bytes())?;
+                fmt.write_all(b"</sup>")
+            } else if latex == r"^\circ" {
+                fmt.write_all(b"&deg;")
+            } else {
+                fmt.write_all(br"\(")?;
+                fmt_math_as_html(fmt, latex)?;
+                fmt.write_all(br"\)")
             }
         }
-        // we must have unbalanced parentheses
-        &latex[..1]
-    } else {
-        ""
+        MathStyle::Display => {
+            fmt.write_all(br"\[")?;
+            fmt_math_as_html(fmt, latex)?;
+            fmt.write_all(br"\]")
+        }
+        MathStyle::Environment(name) => {
+            write!(fmt, r"\begin{{{}}}", name)?;
+            fmt_math_as_html(fmt, latex)?;
+            write!(fmt, r"\end{{{}}}", name)
+        }
     }
 }
 
+fn process_url_argument(url: &str) -> String {
+    let mut url = url.to_string();
+    url = url.replace('{', "");
+    url = url.replace('}', "");
+    url = url.replace(r"\%", "%");
+    url = url.replace(' ', "%20");
+    url
+}
+
 /// Returns the class to be used
 fn parse_width(option: &str) -> String {
-    let em = regex::Regex::new(r"[\[\{]width=([0-9\.]+)(.+)[\}\]]").unwrap();
-    let other = regex::Regex::new(r"[\[\{]([0-9\.]+)(.+)[\}\]]").unwrap();
-    if let Some(c) = em.captures(option).or(other.captures(option)) {
+    if let Some(c) = WIDTH_EM
+        .captures(option)
+        .or_else(|| WIDTH_OTHER.captures(option))
+    {
         let value = c.get(1).unwrap().as_str();
         let units = c.get(2).unwrap().as_str();
         match units {
@@ -1568,7 +1519,7 @@ fn parse_width(option: &str) -> String {
             _ => (),
         }
     }
-    return "".to_string();
+    "".to_string()
 }
 
 #[test]
@@ -1579,161 +1530,83 @@ fn parse_width_test() {
     );
 }
 
-fn parse_title(latex: &str) -> &str {
-    if latex.len() == 0 {
-        return "";
-    }
-    if latex.chars().next().unwrap().is_digit(10) {
-        &latex[..1]
-    } else if latex.chars().next().unwrap() == '{' {
-        let mut n = 0;
-        let mut arg = String::from("{");
-        for c in latex[1..].chars() {
-            arg.push(c);
-            if c == '{' {
-                n += 1
-            } else if c == '}' {
-                if n == 0 {
-                    return &latex[..arg.len()];
-                }
-                n -= 1
-            }
-        }
-        // we must have unbalanced parentheses
-        &latex[..1]
-    } else {
-        &latex[..1]
-    }
-}
-
-#[test]
-fn test_argument() {
-    assert_eq!(argument(r"{foo"), r"{");
-    assert_eq!(argument(r"{foo}  "), r"{foo}");
-}
-
 /// Substitute five physics macros
+///
+/// These are the [`MacroTable::physics`] pack.
 pub fn physics_macros(latex: &str) -> String {
-    let mut latex = latex; // this makes the lifetime local to the function
-    let mut refined = String::with_capacity(latex.len());
-    while let Some(i) = latex.find(r"\ket{") {
-        refined.push_str(&latex[..i]);
-        latex = &latex[i + r"\ket".len()..];
-        let arg = argument(latex);
-        latex = &latex[arg.len()..];
-        refined.push_str(r"\left|");
-        refined.push_str(&physics_macros(arg));
-        refined.push_str(r"\right\rangle ");
-    }
-    refined.push_str(latex);
-    latex = &refined;
-
-    let mut refined = String::with_capacity(latex.len());
-    while let Some(i) = latex.find(r"\bra{") {
-        refined.push_str(&latex[..i]);
-        latex = &latex[i + r"\bra".len()..];
-        let arg = argument(latex);
-        latex = &latex[arg.len()..];
-        refined.push_str(r"\left\langle ");
-        refined.push_str(&physics_macros(arg));
-        refined.push_str(r"\right|");
-    }
-    refined.push_str(latex);
-    latex = &refined;
-
-    let mut refined = String::with_capacity(latex.len());
-    while let Some(i) = latex.find(r"\right|\left|") {
-        refined.push_str(&latex[..i]);
-        latex = &latex[i + r"\right|\left|".len()..];
-        refined.push_str(r"\middle|");
-    }
-    refined.push_str(latex);
-    latex = &refined;
-
-    let mut refined = String::with_capacity(latex.len());
-    while let Some(i) = latex.find(r"\dbar ") {
-        refined.push_str(&latex[..i]);
-        latex = &latex[i + r"\dbar".len()..];
-        refined.push_str(LATEX_DBAR);
-    }
-    refined.push_str(latex);
-    latex = &refined;
+    MacroTable::physics().expand(latex)
+}
 
-    let mut refined = String::with_capacity(latex.len());
-    while let Some(i) = latex.find(r"\myderiv{") {
-        refined.push_str(&latex[..i]);
-        latex = &latex[i + r"\myderiv".len()..];
-        let arg1 = argument(latex);
-        latex = &latex[arg1.len()..];
-        let arg2 = argument(latex);
-        latex = &latex[arg2.len()..];
-        let arg3 = argument(latex);
-        latex = &latex[arg3.len()..];
-        refined.push_str(r"\left(\frac{\partial ");
-        refined.push_str(&physics_macros(arg1));
-        refined.push_str(r"}{\partial ");
-        refined.push_str(&physics_macros(arg2));
-        refined.push_str(r"}\right)_");
-        refined.push_str(&physics_macros(arg3));
-    }
-    refined.push_str(latex);
-    latex = &refined;
+/// What a macro becomes when we may not expand it, which the parser
+/// turns into an error.
+pub(crate) const EXPANSION_LIMIT: &str = r"\expansionlimit";
+
+/// How many more macros we may expand, how deeply, and how many more
+/// bytes of LaTeX they may expand to.
+pub(crate) struct Expansions {
+    left: usize,
+    depth: usize,
+    max_depth: usize,
+    bytes: usize,
+}
 
-    let mut refined = String::with_capacity(latex.len());
-    while let Some(i) = latex.find(r"\thermoderivative{") {
-        refined.push_str(&latex[..i]);
-        latex = &latex[i + r"\thermoderivative".len()..];
-        let arg1 = argument(latex);
-        latex = &latex[arg1.len()..];
-        let arg2 = argument(latex);
-        latex = &latex[arg2.len()..];
-        let arg3 = argument(latex);
-        latex = &latex[arg3.len()..];
-        refined.push_str(r"\left(\frac{\partial ");
-        refined.push_str(&physics_macros(arg1));
-        refined.push_str(r"}{\partial ");
-        refined.push_str(&physics_macros(arg2));
-        refined.push_str(r"}\right)_");
-        refined.push_str(&physics_macros(arg3));
+impl Expansions {
+    pub(crate) fn new(limits: &RenderLimits) -> Self {
+        Expansions {
+            left: limits.expansions(),
+            depth: 0,
+            max_depth: limits.depth(),
+            bytes: limits.output_bytes(),
+        }
     }
-    refined.push_str(latex);
-
-    refined
 }
 
 /// Pull (sub)sections out of guide/solution/handout
 pub fn pull_sections_out(latex: &str) -> String {
     let latex = pull_sections_out_of_environ(latex, "handout");
-    let latex = pull_sections_out_of_environ(&latex, "guide");
-    let latex = pull_sections_out_of_environ(&latex, "solution");
-    latex
+    let latex = pull_sections_out_of_environ(&latex.text, "guide");
+    pull_sections_out_of_environ(&latex.text, "solution").text
 }
 
 /// Pull (sub)sections out of guide/solution/handout
-fn pull_sections_out_of_environ(latex: &str, environ: &str) -> String {
-    let mut latex = latex; // this makes the lifetime local to the function
-    let mut refined = String::with_capacity(latex.len());
+pub(crate) fn pull_sections_out_of_environ(whole: &str, environ: &str) -> Rewritten {
+    let mut latex = whole;
+    let offset = |latex: &str| whole.len() - latex.len();
+    let mut refined = Rewritten::with_capacity(latex.len());
     let begin = format!(r"\begin{{{}}}", environ);
     let end = format!(r"\end{{{}}}", environ);
-    let section = regex::Regex::new(r"\\[sub]*section\{[^\}]+\}").unwrap();
+    // Where the next end and section are, which we only look for again
+    // once we pass them, so that many environments do not each look
+    // through the rest of the LaTeX.
+    let mut next_end = whole.find(&end);
+    let mut next_section = SECTION.find(whole);
     while let Some(i) = latex.find(&begin) {
-        refined.push_str(&latex[..i + begin.len()]);
+        refined.copy(&latex[..i + begin.len()], offset(latex));
         latex = &latex[i + begin.len()..];
-        if let Some(mut i) = latex.find(&end) {
-            while let Some(next_section) = section.find(latex) {
-                if next_section.end() >= i {
-                    break;
-                }
-                refined.push_str(&latex[..next_section.start()]);
-                refined.push_str(&end);
-                refined.push_str(&latex[next_section.start()..next_section.end()]);
-                refined.push_str(&begin);
-                latex = &latex[next_section.end()..];
-                i -= next_section.end();
+        if next_end.is_some_and(|e| e < offset(latex)) {
+            next_end = latex.find(&end).map(|e| e + offset(latex));
+        }
+        let end_at = match next_end {
+            Some(e) => e,
+            None => continue,
+        };
+        loop {
+            if next_section.is_some_and(|s| s.start() < offset(latex)) {
+                next_section = SECTION.find_at(whole, offset(latex));
             }
+            let section = match next_section {
+                Some(s) if s.end() < end_at => s,
+                _ => break,
+            };
+            let start = section.start();
+            refined.copy(&whole[offset(latex)..start], offset(latex));
+            refined.insert(&end, start..start);
+            refined.copy(section.as_str(), start);
+            refined.insert(&begin, section.end()..section.end());
+            latex = &whole[section.end()..];
         }
     }
-    refined.push_str(latex);
+    refined.copy(latex, offset(latex));
     refined
 }
 
@@ -1741,8 +1614,7 @@ fn pull_sections_out_of_environ(latex: &str, environ: &str) -> String {
 #[wasm_bindgen]
 pub fn check_latex(latex: &str) -> String {
     let mut refined = String::with_capacity(latex.len());
-    let environments = regex::Regex::new(r"\\begin\{([^\}]+)\}").unwrap();
-    let mut environments: std::collections::HashSet<String> = environments
+    let mut environments: std::collections::HashSet<String> = BEGIN_ENVIRONMENT
         .captures_iter(latex)
         .map(|m| m[1].to_string())
         .collect();
@@ -1751,15 +1623,8 @@ pub fn check_latex(latex: &str) -> String {
         "solution",
         "enumerate",
         "description",
-        "equation",
-        "equation*",
-        "align",
-        "align*",
         "figure",
-        "eqnarray",
-        "eqnarray*",
-        "multline",
-        "multline*",
+        "table",
         "quote",
         "quotation",
         "center",
@@ -1767,6 +1632,15 @@ pub fn check_latex(latex: &str) -> String {
     for &e in good_environments.iter() {
         environments.remove(e);
     }
+    // As are the math environments we know.
+    environments.retain(|e| environs::find(e).is_none());
+    // And the theorem-like ones.
+    environments.retain(|e| references::theorem_name(e).is_none());
+    let defined = macros::defined_names(latex);
+    // Environments defined by \newenvironment are fine too.
+    for e in defined.iter().filter(|e| !e.starts_with('\\')) {
+        environments.remove(e);
+    }
     // Unsupported environments.  I'm not actually aware of anything
     // that we cannot handle or that we will not want to permit.
     let bad_environments: &[&'static str] = &["buggy"];
@@ -1781,8 +1655,7 @@ pub fn check_latex(latex: &str) -> String {
         }
     }
 
-    let macros = regex::Regex::new(r"\\([^0-9_/|><\\$\-+\s\(\)\[\]{}]+)").unwrap();
-    let mut macros: std::collections::HashSet<String> = macros
+    let mut macros: std::collections::HashSet<String> = MACRO
         .captures_iter(latex)
         .map(|m| m[1].to_string())
         .collect();
@@ -1798,6 +1671,11 @@ pub fn check_latex(latex: &str) -> String {
         "section",
         "eqref",
         "ref",
+        "pageref",
+        "autoref",
+        "cref",
+        "Cref",
+        "hyperref",
         "label",
         "centering",
         "subsection",
@@ -1813,6 +1691,7 @@ pub fn check_latex(latex: &str) -> String {
         "emph",
         "paragraph",
         "noindent",
+        "tableofcontents",
         "textwidth",
         "item",
         "textbf",
@@ -1844,15 +1723,50 @@ pub fn check_latex(latex: &str) -> String {
     for &m in good_macros.iter() {
         macros.remove(m);
     }
-    // Unsupported macros.
-    let bad_macros = &[
+    // Macros defined by \newcommand are fine too.
+    for m in [
         "newcommand",
-        "mathchar", // unsupported by mathjax
         "renewcommand",
-        "newenvironment", // have namespacing issues
-        "usepackage",     // big can of worms
-        "def",            // namespacing issues?
-        "cases",          // old cases that doesn't work with amsmath
+        "DeclareMathOperator",
+        "newenvironment",
+        "renewenvironment",
+    ] {
+        macros.remove(m);
+    }
+    for m in defined.iter().filter_map(|m| m.strip_prefix('\\')) {
+        macros.remove(m);
+    }
+    // As are those we expand with physics_macros.
+    for m in MacroTable::physics().names() {
+        macros.remove(&m[1..]);
+    }
+    // As are the siunitx macros, along with the units inside them.
+    let siunitx_macros = ["SI", "qty", "si", "unit", "num", "ang"];
+    let used = siunitx_macros.iter().filter(|&&m| macros.remove(m)).count();
+    if used > 0 {
+        for m in siunitx::unit_macros() {
+            macros.remove(m);
+        }
+    }
+    // As are accents and special letters, along with the letter after
+    // an accent such as \'e.
+    for m in text::names() {
+        macros.remove(m);
+    }
+    macros.retain(|m| {
+        !m.get(..1)
+            .is_some_and(|a| text::is_accent(a) && !a.starts_with(char::is_alphabetic))
+    });
+    // And the mhchem ones.
+    for m in mhchem::MACROS {
+        macros.remove(*m);
+    }
+    // Unsupported macros.
+    let bad_macros = &[
+        "mathchar",   // unsupported by mathjax
+        "usepackage", // big can of worms
+        "def",        // namespacing issues?
+        "cases",      // old cases that doesn't work with amsmath
     ];
     for &m in bad_macros.iter() {
         if macros.contains(m) {
@@ -1864,6 +1778,35 @@ pub fn check_latex(latex: &str) -> String {
             macros.remove(m);
         }
     }
+    // Problems in math, which we find with a list of what MathJax
+    // understands, so we need not warn about the macros in math again.
+    let mut in_math = std::collections::HashSet::new();
+    for_each_math(latex, |math, _, _, _| {
+        in_math.extend(MACRO.captures_iter(math).map(|m| m[1].to_string()));
+    });
+    macros.retain(|m| !(in_math.contains(m) && mathjax::is_known(m)));
+    for d in check_math(latex) {
+        if d.code == "unknown-math-macro" {
+            if let Some(name) = latex.get(d.span.clone()).and_then(|m| m.strip_prefix('\\')) {
+                macros.remove(name);
+            }
+        }
+        let message: String = d
+            .message
+            .chars()
+            .map(|c| match c {
+                '\\' => r"\textbackslash{}".to_string(),
+                '^' => r"\textasciicircum{}".to_string(),
+                '{' | '}' | '&' | '%' | '$' | '#' | '_' => format!("\\{}", c),
+                c => c.to_string(),
+            })
+            .collect();
+        refined.push_str(&format!(
+            r#"\error{{bad math at {}..{}: {}}}\\
+"#,
+            d.span.start, d.span.end, message
+        ));
+    }
     for e in environments {
         refined.push_str(&format!(
             r#"\warning{{possibly bad environment: {}}}\\
@@ -1878,10 +1821,75 @@ pub fn check_latex(latex: &str) -> String {
             m
         ));
     }
-    refined.push_str(&latex);
+    refined.push_str(latex);
     refined
 }
 
+/// Check the math in some LaTeX for what MathJax would choke on, such
+/// as unbalanced braces, a `\left` without its `\right`, an `&` outside
+/// of an alignment, or a macro it does not know.
+///
+/// ```
+/// let diagnostics = latex_snippet::check_math(r"Here $\left( x^{2$.");
+/// assert_eq!("unmatched-left", diagnostics[0].code);
+/// assert_eq!(6..11, diagnostics[0].span);
+/// assert_eq!("unbalanced-brace", diagnostics[1].code);
+/// assert_eq!(15..16, diagnostics[1].span);
+/// ```
+pub fn check_math(latex: &str) -> Vec<Diagnostic> {
+    let physics = MacroTable::physics();
+    // The macros we expand ourselves are fine too.
+    let known = |name: &str| {
+        physics.names().any(|m| &m[1..] == name)
+            || siunitx::arguments(name).is_some()
+            || siunitx::unit_macros().any(|m| m == name)
+            || mhchem::MACROS.contains(&name)
+    };
+    let mut diagnostics = Vec::new();
+    for_each_math(latex, |math, style, start, span| {
+        let environment = match style {
+            MathStyle::Environment(name) => Some(name.as_str()),
+            _ => None,
+        };
+        for problem in mathjax::check(math, environment, known) {
+            let span = match start {
+                Some(start) => start + problem.span.start..start + problem.span.end,
+                None => span.clone(),
+            };
+            diagnostics.push(Diagnostic {
+                code: problem.code,
+                severity: Severity::Error,
+                message: problem.message,
+                span,
+            });
+        }
+    });
+    diagnostics
+}
+
+/// Call `f` with the LaTeX and style of each piece of math in `latex`,
+/// where it starts in `latex` if our parsing left it unchanged, and the
+/// span of the whole thing.
+fn for_each_math(
+    latex: &str,
+    mut f: impl FnMut(&str, &MathStyle, Option<usize>, &std::ops::Range<usize>),
+) {
+    for node in &parse(latex).content {
+        node.walk(&mut |node| {
+            if let NodeKind::Math { style, latex: math } = &node.kind {
+                f(math, style, math_start(latex, node, math), &node.span);
+            }
+        });
+    }
+}
+
+/// Where `math`, the LaTeX of `node`, starts in `source`, if our parsing
+/// left it unchanged.
+pub(crate) fn math_start(source: &str, node: &Node, math: &str) -> Option<usize> {
+    let i = source.get(node.span.clone())?.find(math)?;
+    Some(node.span.start + i)
+}
+
 /// Include solutions via \begin{solution}
 pub fn include_solutions(mut latex: &str) -> String {
     let mut refined = String::with_capacity(latex.len());
@@ -1909,81 +1917,62 @@ pub fn include_solutions(mut latex: &str) -> String {
 }
 
 /// Strip out solutions
-pub fn omit_solutions(mut latex: &str) -> String {
-    let mut refined = String::with_capacity(latex.len());
-    // need to strip out solutions...
-    loop {
-        if let Some(i) = latex.find(r"\begin{solution}") {
-            refined.push_str(&latex[..i]);
-            latex = &latex[i + r"\begin{solution}".len()..];
-            if let Some(i) = latex.find(r"\end{solution}") {
-                latex = &latex[i + r"\end{solution}".len()..];
-            } else {
-                break;
-            }
-        } else {
-            refined.push_str(latex);
-            break;
-        }
-    }
-    refined
+pub fn omit_solutions(latex: &str) -> String {
+    omit_environ_mapped(latex, "solution").text
 }
 
 /// Strip out guides
-pub fn omit_guide(mut latex: &str) -> String {
-    let mut refined = String::with_capacity(latex.len());
-    // need to strip out guides...
-    loop {
-        if let Some(i) = latex.find(r"\begin{guide}") {
-            refined.push_str(&latex[..i]);
-            latex = &latex[i + r"\begin{guide}".len()..];
-            if let Some(i) = latex.find(r"\end{guide}") {
-                latex = &latex[i + r"\end{guide}".len()..];
-            } else {
-                break;
-            }
-        } else {
-            refined.push_str(latex);
-            break;
-        }
-    }
-    refined
+pub fn omit_guide(latex: &str) -> String {
+    omit_environ_mapped(latex, "guide").text
 }
 
 /// Strip out handouts
-pub fn omit_handout(mut latex: &str) -> String {
-    let mut refined = String::with_capacity(latex.len());
-    // need to strip out handouts...
+pub fn omit_handout(latex: &str) -> String {
+    omit_environ_mapped(latex, "handout").text
+}
+
+/// Keep just the handouts
+pub fn only_handout(latex: &str) -> String {
+    only_environ_mapped(latex, "handout").text
+}
+
+/// Strip out an environment, remembering where the rest came from.
+pub(crate) fn omit_environ_mapped(whole: &str, environ: &str) -> Rewritten {
+    let mut latex = whole;
+    let offset = |latex: &str| whole.len() - latex.len();
+    let mut refined = Rewritten::with_capacity(latex.len());
+    let begin = format!(r"\begin{{{}}}", environ);
+    let end = format!(r"\end{{{}}}", environ);
     loop {
-        if let Some(i) = latex.find(r"\begin{handout}") {
-            refined.push_str(&latex[..i]);
-            latex = &latex[i + r"\begin{handout}".len()..];
-            if let Some(i) = latex.find(r"\end{handout}") {
-                latex = &latex[i + r"\end{handout}".len()..];
+        if let Some(i) = latex.find(&begin) {
+            refined.copy(&latex[..i], offset(latex));
+            latex = &latex[i + begin.len()..];
+            if let Some(i) = latex.find(&end) {
+                latex = &latex[i + end.len()..];
             } else {
                 break;
             }
         } else {
-            refined.push_str(latex);
+            refined.copy(latex, offset(latex));
             break;
         }
     }
     refined
 }
 
-/// Keep just the handouts
-pub fn only_handout(mut latex: &str) -> String {
-    let mut refined = String::with_capacity(latex.len());
-    // need to strip out handouts...
-    loop {
-        if let Some(i) = latex.find(r"\begin{handout}") {
-            latex = &latex[i + r"\begin{handout}".len()..];
-            if let Some(i) = latex.find(r"\end{handout}") {
-                refined.push_str(&latex[..i]);
-                latex = &latex[i + r"\end{handout}".len()..];
-            } else {
-                break;
-            }
+/// Keep just the content of an environment, remembering where it came
+/// from.
+pub(crate) fn only_environ_mapped(whole: &str, environ: &str) -> Rewritten {
+    let mut latex = whole;
+    let offset = |latex: &str| whole.len() - latex.len();
+    let mut refined = Rewritten::with_capacity(latex.len());
+    let begin = format!(r"\begin{{{}}}", environ);
+    let end = format!(r"\end{{{}}}", environ);
+    while let Some(i) = latex.find(&begin) {
+        latex = &latex[i + begin.len()..];
+        if let Some(i) = latex.find(&end) {
+            refined.copy(&latex[..i], offset(latex));
+            latex = &latex[i + end.len()..];
         } else {
             break;
         }
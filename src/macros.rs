@@ -0,0 +1,1083 @@
+//! Macros and environments defined in the LaTeX itself, with
+//! `\newcommand` and friends, or by an application in a [`MacroTable`].
+
+use crate::parse::{inside, macro_name, Braces};
+use crate::source_map::Rewritten;
+use crate::{Diagnostic, Expansions, RenderLimits, Severity, EXPANSION_LIMIT, LATEX_DBAR};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use wasm_bindgen::prelude::*;
+
+/// The macros that define other macros or environments.
+const DEFINERS: &[&str] = &[
+    r"\newcommand",
+    r"\renewcommand",
+    r"\DeclareMathOperator",
+    r"\newenvironment",
+    r"\renewenvironment",
+];
+
+/// Whether `name` (as found by [`macro_name`]) defines a macro or
+/// environment.
+pub(crate) fn is_definer(name: &str) -> bool {
+    DEFINERS.contains(&name.trim_end_matches('*'))
+}
+
+/// A macro or environment defined by the LaTeX.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Macro {
+    /// How many arguments it takes, including any optional one.
+    arguments: usize,
+    /// The default for the first argument, which makes it optional.
+    default: Option<String>,
+    /// What it expands to, or what `\begin` expands to for an
+    /// environment.
+    body: String,
+    /// What `\end` expands to, if this is an environment.
+    end: Option<String>,
+    /// What its only argument opens and closes with, such as `(` and
+    /// `)`, when it does not take its arguments in braces.
+    delimiters: Option<(String, String)>,
+}
+
+impl Macro {
+    /// How many arguments it takes in braces.
+    fn braced(&self) -> usize {
+        self.arguments - self.default.is_some() as usize
+    }
+
+    /// Whether this and `other` take the same kinds of arguments, so
+    /// that one must replace the other.
+    fn same_arguments(&self, other: &Macro) -> bool {
+        self.arguments == other.arguments
+            && self.default.is_some() == other.default.is_some()
+            && self.delimiters == other.delimiters
+    }
+}
+
+/// Where an expansion ending in `left` meets one starting with `right`,
+/// the two are joined by `with` instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Join {
+    left: String,
+    right: String,
+    with: String,
+}
+
+/// Macros for an application to expand before rendering, such as its
+/// own house macros or the [`physics`](MacroTable::physics) pack.
+///
+/// Each macro has a template in which `#1` to `#9` stand for its
+/// arguments, as in `\newcommand`, and the whole table is expanded in a
+/// single pass.  A macro may have several templates, for different
+/// numbers or kinds of arguments, as `\dv{x}` and `\dv{f}{x}` do in the
+/// physics pack, and a use takes as many arguments in braces as it can.
+///
+/// ```
+/// use latex_snippet::{render, MacroTable, RenderOptions};
+/// let table = MacroTable::new()
+///     .define(r"\vect", 1, r"\mathbf{#1}")
+///     .define_with_default(r"\unit", 1, "m", r"\,\mathrm{#1}");
+/// assert_eq!(r"$\mathbf{v} = 3\,\mathrm{m}$", table.expand(r"$\vect{v} = 3\unit$"));
+/// let options = RenderOptions::new().macros(table);
+/// let rendered = render(r"$\vect{v}$", &options).unwrap();
+/// assert_eq!(r"\(\mathbf{v}\)", rendered.html);
+/// ```
+#[wasm_bindgen]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MacroTable {
+    macros: HashMap<String, Vec<Macro>>,
+    joins: Vec<Join>,
+}
+
+#[wasm_bindgen]
+impl MacroTable {
+    /// A table with no macros in it.
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        MacroTable::default()
+    }
+
+    /// The pack of physics macros: `\dbar`, `\myderiv`,
+    /// `\thermoderivative`, and those of the LaTeX `physics` package
+    /// for bras and kets, brackets, derivatives, vectors and operators,
+    /// with a `\bra` just before a `\ket` joined by `\middle|`.
+    pub fn physics() -> Self {
+        let derivative = r"\left(\frac{\partial {#1}}{\partial {#2}}\right)_{#3}";
+        MacroTable::new()
+            .define(r"\dbar", 0, &format!("{} ", LATEX_DBAR))
+            .define(r"\myderiv", 3, derivative)
+            .define(r"\thermoderivative", 3, derivative)
+            // Quantum mechanics
+            .define(r"\ket", 1, r"\left|{#1}\right\rangle ")
+            .define(r"\bra", 1, r"\left\langle {#1}\right|")
+            .define(
+                r"\braket",
+                1,
+                r"\left\langle {#1}\middle|{#1}\right\rangle ",
+            )
+            .define(
+                r"\braket",
+                2,
+                r"\left\langle {#1}\middle|{#2}\right\rangle ",
+            )
+            .define(r"\braket*", 2, r"\langle {#1}|{#2}\rangle ")
+            .define(
+                r"\ketbra",
+                1,
+                r"\left|{#1}\right\rangle\!\left\langle {#1}\right|",
+            )
+            .define(
+                r"\ketbra",
+                2,
+                r"\left|{#1}\right\rangle\!\left\langle {#2}\right|",
+            )
+            .define(r"\expval", 1, r"\left\langle {#1}\right\rangle ")
+            .define(
+                r"\expval",
+                2,
+                r"\left\langle {#2}\middle|{#1}\middle|{#2}\right\rangle ",
+            )
+            .define(
+                r"\mel",
+                3,
+                r"\left\langle {#1}\middle|{#2}\middle|{#3}\right\rangle ",
+            )
+            // Brackets
+            .define(r"\abs", 1, r"\left\lvert {#1}\right\rvert ")
+            .define(r"\abs*", 1, r"\lvert {#1}\rvert ")
+            .define(r"\norm", 1, r"\left\lVert {#1}\right\rVert ")
+            .define(r"\norm*", 1, r"\lVert {#1}\rVert ")
+            .define(r"\qty", 1, r"\left\{{#1}\right\}")
+            .define_delimited(r"\qty", "(", ")", r"\left({#1}\right)")
+            .define_delimited(r"\qty", "[", "]", r"\left[{#1}\right]")
+            .define_delimited(r"\qty", "|", "|", r"\left\lvert {#1}\right\rvert ")
+            .define(r"\comm", 2, r"\left[{#1},{#2}\right]")
+            .define(r"\acomm", 2, r"\left\{{#1},{#2}\right\}")
+            .define(r"\order", 1, r"\mathcal{O}\left({#1}\right)")
+            .define_delimited(r"\order", "(", ")", r"\mathcal{O}\left({#1}\right)")
+            .define(r"\eval", 1, r"\left.{#1}\right|")
+            .define_delimited(r"\eval", "(", ")|", r"\left.\left({#1}\right)\right|")
+            .define_delimited(r"\eval", "[", "|", r"\left[{#1}\right|")
+            // Derivatives
+            .define(r"\dv", 1, r"\frac{\mathrm{d}}{\mathrm{d}{#1}}")
+            .define(r"\dv", 2, r"\frac{\mathrm{d}{#1}}{\mathrm{d}{#2}}")
+            .define_with_default(
+                r"\dv",
+                2,
+                "1",
+                r"\frac{\mathrm{d}^{#1}}{\mathrm{d}{#2}^{#1}}",
+            )
+            .define_with_default(
+                r"\dv",
+                3,
+                "1",
+                r"\frac{\mathrm{d}^{#1}{#2}}{\mathrm{d}{#3}^{#1}}",
+            )
+            .define(r"\pdv", 1, r"\frac{\partial}{\partial {#1}}")
+            .define(r"\pdv", 2, r"\frac{\partial {#1}}{\partial {#2}}")
+            .define(
+                r"\pdv",
+                3,
+                r"\frac{\partial^{2} {#1}}{\partial {#2}\,\partial {#3}}",
+            )
+            .define_with_default(r"\pdv", 2, "1", r"\frac{\partial^{#1}}{\partial {#2}^{#1}}")
+            .define_with_default(
+                r"\pdv",
+                3,
+                "1",
+                r"\frac{\partial^{#1} {#2}}{\partial {#3}^{#1}}",
+            )
+            // Vectors and operators
+            .define(r"\vb", 1, r"\mathbf{#1}")
+            .define(r"\vb*", 1, r"\boldsymbol{#1}")
+            .define(r"\vu", 1, r"\hat{\mathbf{#1}}")
+            .define(r"\vu*", 1, r"\hat{\boldsymbol{#1}}")
+            .define(r"\tr", 0, r"\operatorname{tr}")
+            .define(r"\Tr", 0, r"\operatorname{Tr}")
+            .join(r"\right|", r"\left|", r"\middle|")
+    }
+
+    /// Define `name`, such as `\vect`, to take `arguments` arguments and
+    /// expand to `template`.  This replaces any template already defined
+    /// for `name` with as many arguments.  A `name` that is not a macro
+    /// name, or more than 9 arguments, is ignored.
+    pub fn define(self, name: &str, arguments: usize, template: &str) -> Self {
+        self.insert(name, arguments, None, None, template)
+    }
+
+    /// Define `name` like [`define`](MacroTable::define), but with its
+    /// first argument optional, taking the value `default` when it is
+    /// left out.
+    pub fn define_with_default(
+        self,
+        name: &str,
+        arguments: usize,
+        default: &str,
+        template: &str,
+    ) -> Self {
+        self.insert(name, arguments.max(1), Some(default), None, template)
+    }
+
+    /// Define `name` like [`define`](MacroTable::define), but taking a
+    /// single argument between `open` and `close` rather than in braces,
+    /// as in `\qty(x)`.
+    pub fn define_delimited(self, name: &str, open: &str, close: &str, template: &str) -> Self {
+        if open.is_empty() || close.is_empty() {
+            return self;
+        }
+        let delimiters = Some((open.to_string(), close.to_string()));
+        self.insert(name, 1, None, delimiters, template)
+    }
+
+    /// Define the macros that `latex` defines with `\newcommand` and
+    /// friends, as if they were defined at the start of whatever we
+    /// expand.  Anything else in `latex`, including definitions of
+    /// environments and those we cannot make sense of, is ignored.
+    pub fn define_latex(mut self, latex: &str) -> Self {
+        for_each_definition(latex, |name, m| {
+            if name.starts_with('\\') {
+                self.macros.insert(name.to_string(), vec![m]);
+            }
+        });
+        self
+    }
+
+    /// Where the expansion of one macro ending in `left` is followed
+    /// straight away by one starting with `right`, put `with` in place
+    /// of both.
+    pub fn join(mut self, left: &str, right: &str, with: &str) -> Self {
+        self.joins.push(Join {
+            left: left.to_string(),
+            right: right.to_string(),
+            with: with.to_string(),
+        });
+        self
+    }
+
+    /// Add the macros and joins of `other`, whose templates win over
+    /// ours.
+    pub fn extend(mut self, other: &MacroTable) -> Self {
+        for (name, variants) in &other.macros {
+            for m in variants {
+                self.add(name, m.clone());
+            }
+        }
+        self.joins.extend(other.joins.iter().cloned());
+        self
+    }
+
+    /// Whether `name` is defined.
+    pub fn contains(&self, name: &str) -> bool {
+        self.macros.contains_key(name)
+    }
+
+    /// Expand the macros in `latex`.
+    pub fn expand(&self, latex: &str) -> String {
+        self.expand_mapped(latex, &mut Expansions::new(&RenderLimits::new()))
+            .text
+    }
+}
+
+impl MacroTable {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.macros.is_empty()
+    }
+
+    /// The names of the macros defined.
+    pub(crate) fn names(&self) -> impl Iterator<Item = &str> {
+        self.macros.keys().map(String::as_str)
+    }
+
+    fn insert(
+        mut self,
+        name: &str,
+        arguments: usize,
+        default: Option<&str>,
+        delimiters: Option<(String, String)>,
+        template: &str,
+    ) -> Self {
+        if name.len() > 1 && name.starts_with('\\') && macro_name(name) == name && arguments <= 9 {
+            let m = Macro {
+                arguments,
+                default: default.map(str::to_string),
+                body: template.to_string(),
+                end: None,
+                delimiters,
+            };
+            self.add(name, m);
+        }
+        self
+    }
+
+    /// Add `m` to the templates for `name`, in place of any that takes
+    /// the same arguments.
+    fn add(&mut self, name: &str, m: Macro) {
+        let variants = self.macros.entry(name.to_string()).or_default();
+        match variants.iter_mut().find(|v| v.same_arguments(&m)) {
+            Some(v) => *v = m,
+            None => variants.push(m),
+        }
+    }
+
+    /// Expand the macros in `latex`, remembering where the result came
+    /// from.
+    pub(crate) fn expand_mapped(&self, latex: &str, budget: &mut Expansions) -> Rewritten {
+        let mut expander = Expander {
+            macros: self.macros.clone(),
+            definitions: false,
+            joins: &self.joins,
+            open: Vec::new(),
+            budget,
+            diagnostics: Vec::new(),
+        };
+        // The parser reports any macros we could not expand.
+        expander.expand(latex)
+    }
+}
+
+/// A definition that we could not make sense of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Invalid {
+    code: &'static str,
+    message: String,
+}
+
+/// Read the definition at the start of `latex`, which starts with one of
+/// the [`DEFINERS`], returning how long it is along with the name and
+/// macro it defines.  The name of a macro starts with a backslash, and
+/// the name of an environment does not.
+fn definition<'l>(latex: &'l str, braces: &Braces) -> (usize, Result<(&'l str, Macro), Invalid>) {
+    let definer = macro_name(latex).trim_end_matches('*');
+    let mut rest = &latex[definer.len()..];
+    let starred = rest.starts_with('*');
+    if starred {
+        rest = &rest[1..];
+    }
+    let len = |rest: &str| latex.len() - rest.len();
+    let invalid = |rest: &str, code, message| (len(rest), Err(Invalid { code, message }));
+
+    let environment = definer.ends_with("environment");
+    let name = if rest.starts_with('\\') && !environment {
+        macro_name(rest)
+    } else {
+        braces.argument(rest)
+    };
+    rest = &rest[name.len()..];
+    if name == "{" {
+        return invalid(
+            rest,
+            "unbalanced-brace",
+            format!("{} is missing a closing brace", definer),
+        );
+    }
+    let name = inside(name).trim();
+    if environment {
+        if name.is_empty() || !name.chars().all(|c| c.is_alphabetic() || c == '*') {
+            let message = format!("{} needs an environment name, not {:?}", definer, name);
+            return invalid(rest, "missing-argument", message);
+        }
+    } else if name.len() < 2 || !name.starts_with('\\') || macro_name(name) != name {
+        let message = format!("{} needs a macro name, not {:?}", definer, name);
+        return invalid(rest, "missing-argument", message);
+    }
+
+    let mut arguments = 0;
+    let mut default = None;
+    let mut problem = None;
+    if definer != r"\DeclareMathOperator" {
+        let count = braces.optional_argument(rest);
+        rest = &rest[count.len()..];
+        if count.len() > 2 {
+            match count[1..count.len() - 1].trim().parse() {
+                Ok(n) if n <= 9 => arguments = n,
+                _ => {
+                    let message = format!("{} can take from 0 to 9 arguments, not {}", name, count);
+                    problem = Some(message);
+                }
+            }
+            let o = braces.optional_argument(rest);
+            rest = &rest[o.len()..];
+            if o.len() > 1 {
+                if arguments == 0 {
+                    problem = Some(format!("{} has a default but no arguments", name));
+                }
+                default = Some(o[1..o.len() - 1].to_string());
+            }
+        }
+    }
+
+    let body = braces.argument(rest);
+    rest = &rest[body.len()..];
+    if body == "{" {
+        return invalid(
+            rest,
+            "unbalanced-brace",
+            format!("{} is missing a closing brace", name),
+        );
+    } else if body.is_empty() {
+        return invalid(
+            rest,
+            "missing-argument",
+            format!("{} needs a definition", name),
+        );
+    } else if let Some(message) = problem {
+        return invalid(rest, "invalid-definition", message);
+    }
+    let body = inside(body);
+    let end = if environment {
+        let end = braces.argument(rest);
+        rest = &rest[end.len()..];
+        if end == "{" {
+            let message = format!(r"\end{{{}}} is missing a closing brace", name);
+            return invalid(rest, "unbalanced-brace", message);
+        } else if end.is_empty() {
+            let message = format!(r"\end{{{}}} needs a definition", name);
+            return invalid(rest, "missing-argument", message);
+        }
+        Some(inside(end).to_string())
+    } else {
+        None
+    };
+    let body = if definer == r"\DeclareMathOperator" {
+        let star = if starred { "*" } else { "" };
+        format!(r"\operatorname{}{{{}}}", star, body)
+    } else {
+        body.to_string()
+    };
+    let m = Macro {
+        arguments,
+        default,
+        body,
+        end,
+        delimiters: None,
+    };
+    (len(rest), Ok((name, m)))
+}
+
+/// How long the definition at the start of `latex` is, so that the
+/// parser can skip over one that we refused to make.
+pub(crate) fn definition_len(latex: &str, braces: &Braces) -> usize {
+    definition(latex, braces).0
+}
+
+/// Put `args` in place of `#1` to `#9` in `body`, or `None` if that
+/// comes to more than `max_bytes`.
+fn substitute(body: &str, args: &[&str], max_bytes: usize) -> Option<String> {
+    let mut out = String::with_capacity(body.len().min(max_bytes));
+    let mut rest = body;
+    while let Some(i) = rest.find('#') {
+        if out.len() > max_bytes {
+            return None;
+        }
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        match rest.chars().next() {
+            Some('#') => {
+                out.push('#');
+                rest = &rest[1..];
+            }
+            Some(c @ '1'..='9') => {
+                if let Some(arg) = args.get(c as usize - '1' as usize) {
+                    out.push_str(arg);
+                }
+                rest = &rest[1..];
+            }
+            _ => out.push('#'),
+        }
+    }
+    out.push_str(rest);
+    Some(out).filter(|out| out.len() <= max_bytes)
+}
+
+/// The text between `open` at the start of `latex` and the `close` that
+/// matches it, along with what follows.
+fn delimited<'l>(latex: &'l str, open: &str, close: &str) -> Option<(&'l str, &'l str)> {
+    let inner = latex.strip_prefix(open)?;
+    let mut depth = 0;
+    let mut braces = 0_usize;
+    let mut escaped = false;
+    for (i, c) in inner.char_indices() {
+        let rest = &inner[i..];
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '{' {
+            braces += 1;
+        } else if c == '}' {
+            braces = braces.checked_sub(1)?;
+        } else if braces > 0 {
+            continue;
+        } else if let Some(after) = rest.strip_prefix(close).filter(|_| depth == 0) {
+            return Some((&inner[..i], after));
+        } else if rest.starts_with(open) {
+            depth += 1;
+        } else if depth > 0 && close.starts_with(c) {
+            // A close like ")|" ends a nested "(" with its ")".
+            depth -= 1;
+        }
+    }
+    None
+}
+
+/// The offset of each `open` of a pair of delimiters, with that of the
+/// `close` that ends its argument, if it has one.
+type DelimitedEnds = Vec<(usize, Option<usize>)>;
+
+/// Where the arguments of macros end in a source, so that each unclosed
+/// one need not look through the rest of it.
+struct ArgumentEnds<'a> {
+    source: &'a str,
+    braces: Braces<'a>,
+    /// The ends for each pair of delimiters we have looked for.
+    delimited: HashMap<(String, String), DelimitedEnds>,
+}
+
+impl<'a> ArgumentEnds<'a> {
+    fn new(source: &'a str) -> Self {
+        ArgumentEnds {
+            source,
+            braces: Braces::new(source),
+            delimited: HashMap::new(),
+        }
+    }
+
+    /// The text between `open` at the start of `latex` and the `close`
+    /// that matches it, along with what follows, as [`delimited`] finds
+    /// them.
+    fn delimited<'l>(
+        &mut self,
+        latex: &'l str,
+        open: &str,
+        close: &str,
+    ) -> Option<(&'l str, &'l str)> {
+        let Some(offset) = self
+            .braces
+            .within(latex)
+            .filter(|_| latex.starts_with(open))
+        else {
+            return delimited(latex, open, close);
+        };
+        let (source, braces) = (self.source, &self.braces);
+        let ends = self
+            .delimited
+            .entry((open.to_string(), close.to_string()))
+            .or_insert_with(|| delimited_ends(source, open, close, braces));
+        let Ok(i) = ends.binary_search_by_key(&offset, |e| e.0) else {
+            return delimited(latex, open, close);
+        };
+        let end = ends[i]
+            .1
+            .filter(|&end| end + close.len() <= offset + latex.len())?
+            - offset;
+        Some((&latex[open.len()..end], &latex[end + close.len()..]))
+    }
+}
+
+/// The offset of each `open` in `source`, with that of the `close` that
+/// ends the argument it starts, as [`delimited`] finds them.
+///
+/// We first pair each `open` with what ends it when it is inside an
+/// argument, which is the first character of `close`, as `(` is ended by
+/// the `)` of `)|`.  Then we find the ends of the arguments from the last
+/// to the first, so that each can skip over those inside it.
+fn delimited_ends(source: &str, open: &str, close: &str, braces: &Braces) -> DelimitedEnds {
+    let mut opens: Vec<(usize, Option<usize>)> = Vec::new();
+    // The `open`s not yet ended in each group that is open.
+    let mut unclosed: Vec<Vec<usize>> = vec![Vec::new()];
+    let mut escaped = false;
+    for (i, c) in source.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '{' {
+            unclosed.push(Vec::new());
+        } else if c == '}' {
+            if unclosed.len() > 1 {
+                unclosed.pop();
+            } else {
+                unclosed[0].clear();
+            }
+        } else if source[i..].starts_with(open) {
+            unclosed.last_mut().unwrap().push(opens.len());
+            opens.push((i, None));
+        } else if close.starts_with(c) {
+            if let Some(j) = unclosed.last_mut().unwrap().pop() {
+                opens[j].1 = Some(i);
+            }
+        }
+    }
+    let mut ends = vec![None; opens.len()];
+    for k in (0..opens.len()).rev() {
+        let mut i = opens[k].0 + open.len();
+        ends[k] = loop {
+            let rest = &source[i..];
+            let Some(c) = rest.chars().next() else {
+                break None;
+            };
+            if c == '\\' {
+                i += 1 + rest[1..].chars().next().map_or(0, char::len_utf8);
+            } else if c == '{' {
+                let group = braces.argument(rest);
+                if group == "{" {
+                    break None;
+                }
+                i += group.len();
+            } else if c == '}' {
+                break None;
+            } else if rest.starts_with(close) {
+                break Some(i);
+            } else if let Ok(j) = opens.binary_search_by_key(&i, |o| o.0) {
+                // Past what ends the `open` inside, we would go on just
+                // as its own argument does, unless that ended there.
+                match opens[j].1 {
+                    None => break None,
+                    Some(end) if ends[j] == Some(end) => {
+                        i = end + source[end..].chars().next().map_or(0, char::len_utf8);
+                    }
+                    Some(_) => break ends[j],
+                }
+            } else {
+                i += c.len_utf8();
+            }
+        };
+    }
+    opens
+        .into_iter()
+        .zip(ends)
+        .map(|((i, _), end)| (i, end))
+        .collect()
+}
+
+/// Read the arguments that `m` takes from the start of `latex`, returning
+/// them along with what follows.  There are fewer than `m.arguments` if
+/// some are missing, or if `braced` and some are not in braces.
+fn take_arguments<'a, 'l: 'a>(
+    m: &'a Macro,
+    mut latex: &'l str,
+    braced: bool,
+    ends: &mut ArgumentEnds,
+) -> (Vec<&'a str>, &'l str) {
+    if let Some((open, close)) = &m.delimiters {
+        return match ends.delimited(latex, open, close) {
+            Some((arg, rest)) => (vec![arg], rest),
+            None => (Vec::new(), latex),
+        };
+    }
+    let mut args = Vec::with_capacity(m.arguments);
+    if let Some(default) = &m.default {
+        let o = ends.braces.optional_argument(latex);
+        if o.len() > 1 {
+            latex = &latex[o.len()..];
+            args.push(&o[1..o.len() - 1]);
+        } else {
+            args.push(default);
+        }
+    }
+    while args.len() < m.arguments {
+        let arg = ends.braces.argument(latex);
+        if arg.is_empty() || arg == "{" || (braced && !arg.starts_with('{')) {
+            break;
+        }
+        latex = &latex[arg.len()..];
+        args.push(inside(arg));
+    }
+    (args, latex)
+}
+
+/// Which of the `variants` of a macro to use at the start of `latex`.
+/// This is one whose delimiters come next, or else the one taking the
+/// most arguments that are there, with one taking an optional argument
+/// first if there is one.  Only those taking the fewest arguments may
+/// take them without braces.
+fn choose<'a>(variants: &'a [Macro], latex: &str, ends: &mut ArgumentEnds) -> Option<&'a Macro> {
+    let (delimited, mut braced): (Vec<&Macro>, Vec<&Macro>) =
+        variants.iter().partition(|m| m.delimiters.is_some());
+    if let Some(m) = delimited
+        .into_iter()
+        .find(|m| !take_arguments(m, latex, false, ends).0.is_empty())
+    {
+        return Some(m);
+    }
+    let fewest = braced.iter().map(|m| m.braced()).min()?;
+    let optional = ends.braces.optional_argument(latex).len() > 1;
+    braced.sort_by_key(|m| {
+        (
+            m.default.is_some() != optional,
+            std::cmp::Reverse(m.arguments),
+        )
+    });
+    braced.into_iter().find(|m| {
+        let (args, _) = take_arguments(m, latex, m.braced() > fewest, ends);
+        args.len() == m.arguments
+    })
+}
+
+/// Expands the macros defined in some LaTeX, as it goes.
+struct Expander<'b> {
+    /// The macros defined so far, with their variants, along with
+    /// environments, whose names have no backslash.
+    macros: HashMap<String, Vec<Macro>>,
+    /// Whether to define macros with `\newcommand` and friends.
+    definitions: bool,
+    joins: &'b [Join],
+    /// The environments we have begun but not yet ended, with the spans
+    /// of their `\begin`.
+    open: Vec<(String, Range<usize>)>,
+    budget: &'b mut Expansions,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Expander<'_> {
+    fn diagnose(&mut self, code: &'static str, message: String, span: Range<usize>) {
+        self.diagnostics.push(Diagnostic {
+            code,
+            severity: Severity::Error,
+            message,
+            span,
+        });
+    }
+
+    /// Define the macro or environment from a definition with span
+    /// `span`, returning whether we could.
+    fn define(&mut self, definer: &str, new: &str, m: Macro, span: Range<usize>) -> bool {
+        let renew = definer.starts_with(r"\renew");
+        let (undefined, redefined) = if definer.ends_with("environment") {
+            ("undefined-environment", "redefined-environment")
+        } else {
+            ("undefined-macro", "redefined-macro")
+        };
+        let exists = self.macros.contains_key(new);
+        if renew && !exists {
+            let new_definer = definer.replacen("re", "", 1);
+            let message = format!("{} is not defined, so use {}", new, new_definer);
+            self.diagnose(undefined, message, span);
+            false
+        } else if !renew && exists {
+            let message = format!("{} is already defined", new);
+            self.diagnose(redefined, message, span);
+            false
+        } else {
+            self.macros.insert(new.to_string(), vec![m]);
+            true
+        }
+    }
+
+    /// Expand `body`, which replaces the LaTeX at `span`, unless that
+    /// would take more bytes than we have left.
+    fn expand_body(&mut self, body: &str, args: &[&str], span: Range<usize>) -> Rewritten {
+        self.budget.left -= 1;
+        let body = match substitute(body, args, self.budget.bytes) {
+            Some(body) => body,
+            None => {
+                self.budget.bytes = 0;
+                return Rewritten::new(EXPANSION_LIMIT);
+            }
+        };
+        self.budget.bytes -= body.len();
+        self.budget.depth += 1;
+        let before = self.diagnostics.len();
+        let opened = self.open.len();
+        let expanded = self.expand(&body);
+        self.budget.depth -= 1;
+        for d in &mut self.diagnostics[before..] {
+            d.span = span.clone();
+        }
+        for (_, begun) in self.open.iter_mut().skip(opened) {
+            *begun = span.clone();
+        }
+        expanded
+    }
+
+    /// Insert the expansion `text` of the LaTeX at `span`, joining it to
+    /// an expansion just before it if we can.
+    fn insert_joined(&self, refined: &mut Rewritten, text: &str, span: Range<usize>) {
+        let join = refined.inserted_before(span.start).and_then(|before| {
+            self.joins
+                .iter()
+                .find(|j| before.ends_with(&j.left) && text.starts_with(&j.right))
+        });
+        if let Some(j) = join {
+            if let Some((before, start)) = refined.pop_insertion() {
+                let joined = format!(
+                    "{}{}{}",
+                    &before[..before.len() - j.left.len()],
+                    j.with,
+                    &text[j.right.len()..]
+                );
+                refined.insert(&joined, start..span.end);
+                return;
+            }
+        }
+        refined.insert(text, span);
+    }
+
+    fn expand(&mut self, whole: &str) -> Rewritten {
+        let mut latex = whole;
+        let offset = |latex: &str| whole.len() - latex.len();
+        let mut refined = Rewritten::with_capacity(whole.len());
+        let mut ends = ArgumentEnds::new(whole);
+        while let Some(i) = latex.find('\\') {
+            refined.copy(&latex[..i], offset(latex));
+            latex = &latex[i..];
+            let start = offset(latex);
+            let name = macro_name(latex);
+            let environment = match name {
+                r"\begin" | r"\end" => {
+                    let env = ends.braces.argument(&latex[name.len()..]);
+                    Some((env, inside(env)))
+                }
+                _ => None,
+            };
+            if self.definitions && is_definer(name) {
+                let definer = name.trim_end_matches('*');
+                let (len, defined) = definition(latex, &ends.braces);
+                let span = start..start + len;
+                let defined = match defined {
+                    Ok((new, m)) => self.define(definer, new, m, span),
+                    Err(Invalid { code, message }) => {
+                        self.diagnose(code, message, span);
+                        false
+                    }
+                };
+                if !defined {
+                    refined.copy(&latex[..len], start);
+                }
+                latex = &latex[len..];
+            } else if is_definer(name) {
+                // Definitions in the LaTeX win over our own, so we leave
+                // them for the parser, though expanding our macros in
+                // their bodies.
+                let mut len = name.len();
+                if let (_, Ok((new, _))) = definition(latex, &ends.braces) {
+                    self.macros.remove(new);
+                    len = new.as_ptr() as usize - latex.as_ptr() as usize + new.len();
+                }
+                refined.copy(&latex[..len], start);
+                latex = &latex[len..];
+            } else if let Some(m) = environment
+                .filter(|(env, _)| env.len() > 2)
+                .and_then(|(_, env)| self.macros.get(env))
+                .and_then(|variants| variants.first().cloned())
+            {
+                let (env, env_name) = environment.unwrap();
+                latex = &latex[name.len() + env.len()..];
+                if self.budget.left == 0 || self.budget.depth >= self.budget.max_depth {
+                    refined.insert(EXPANSION_LIMIT, start..offset(latex));
+                    continue;
+                }
+                if name == r"\end" {
+                    let span = start..offset(latex);
+                    if self.open.last().map(|(open, _)| open.as_str()) != Some(env_name) {
+                        // The parser will report this.
+                        refined.copy(&whole[span], start);
+                        continue;
+                    }
+                    self.open.pop();
+                    // As in LaTeX, the end code cannot see the arguments.
+                    let end = m.end.as_deref().unwrap_or_default();
+                    let expanded = self.expand_body(end, &[], span.clone());
+                    refined.insert(&expanded.text, span);
+                    continue;
+                }
+                let (args, rest) = take_arguments(&m, latex, false, &mut ends);
+                latex = rest;
+                let span = start..offset(latex);
+                if args.len() < m.arguments {
+                    let message = format!("{} needs {} arguments", env_name, m.arguments);
+                    self.diagnose("missing-argument", message, span.clone());
+                    refined.copy(&whole[span], start);
+                    continue;
+                }
+                self.open.push((env_name.to_string(), span.clone()));
+                let expanded = self.expand_body(&m.body, &args, span.clone());
+                refined.insert(&expanded.text, span);
+            } else if let Some(variants) = self.macros.get(name) {
+                latex = &latex[name.len()..];
+                if self.budget.left == 0 || self.budget.depth >= self.budget.max_depth {
+                    refined.insert(EXPANSION_LIMIT, start..offset(latex));
+                    continue;
+                }
+                let m = match choose(variants, latex, &mut ends) {
+                    Some(m) => m.clone(),
+                    None => {
+                        let fewest = variants.iter().min_by_key(|m| m.arguments);
+                        let arguments = fewest.map_or(0, |m| m.arguments);
+                        if let Some(m) = fewest {
+                            latex = take_arguments(m, latex, false, &mut ends).1;
+                        }
+                        let message = format!("{} needs {} arguments", name, arguments);
+                        let span = start..offset(latex);
+                        self.diagnose("missing-argument", message, span.clone());
+                        refined.copy(&whole[span], start);
+                        continue;
+                    }
+                };
+                let (args, rest) = take_arguments(&m, latex, false, &mut ends);
+                latex = rest;
+                let span = start..offset(latex);
+                if m.arguments == 0 && name[1..].starts_with(char::is_alphabetic) {
+                    // Like LaTeX, we swallow the space after a macro name.
+                    latex = latex.strip_prefix(' ').unwrap_or(latex);
+                }
+                let expanded = self.expand_body(&m.body, &args, span.clone());
+                self.insert_joined(&mut refined, &expanded.text, span.start..offset(latex));
+            } else {
+                refined.copy(name, start);
+                latex = &latex[name.len()..];
+            }
+        }
+        refined.copy(latex, offset(latex));
+        refined
+    }
+}
+
+/// Expand the macros and environments that `latex` defines, leaving out
+/// their definitions.
+///
+/// Definitions and uses that we cannot expand are left in place, for the
+/// parser to mark as errors, and reported here.
+pub(crate) fn expand_definitions(latex: &str, budget: &mut Expansions) -> Expanded {
+    let mut expander = Expander {
+        macros: HashMap::new(),
+        definitions: true,
+        joins: &[],
+        open: Vec::new(),
+        budget,
+        diagnostics: Vec::new(),
+    };
+    let latex = expander.expand(latex);
+    for (env, span) in std::mem::take(&mut expander.open) {
+        let message = format!(r"\begin{{{}}} is never ended", env);
+        expander.diagnose("unclosed-environment", message, span);
+    }
+    Expanded {
+        latex,
+        diagnostics: expander.diagnostics,
+        names: expander.macros.into_keys().collect(),
+    }
+}
+
+/// LaTeX with its macros expanded by [`expand_definitions`].
+pub(crate) struct Expanded {
+    pub(crate) latex: Rewritten,
+    /// What went wrong, with spans in the LaTeX we expanded.
+    pub(crate) diagnostics: Vec<Diagnostic>,
+    /// The names of the macros defined, along with those of the
+    /// environments, which have no backslash.
+    pub(crate) names: HashSet<String>,
+}
+
+/// The names of the macros and environments that `latex` defines, where
+/// only the macros start with a backslash.
+pub(crate) fn defined_names(latex: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    for_each_definition(latex, |name, _| names.push(name.to_string()));
+    names
+}
+
+/// Call `f` with the name and macro of each definition in `latex` that
+/// we can make sense of, in order.
+fn for_each_definition(latex: &str, mut f: impl FnMut(&str, Macro)) {
+    let braces = Braces::new(latex);
+    let mut rest = latex;
+    while let Some(i) = rest.find('\\') {
+        rest = &rest[i..];
+        let name = macro_name(rest);
+        if is_definer(name) {
+            let (len, defined) = definition(rest, &braces);
+            if let Ok((new, m)) = defined {
+                f(new, m);
+            }
+            rest = &rest[len..];
+        } else {
+            rest = &rest[name.len()..];
+        }
+    }
+}
+
+#[test]
+fn test_definition() {
+    let definition = |latex| definition(latex, &Braces::new(latex));
+    let (len, defined) = definition(r"\newcommand{\R}{\mathbb{R}} rest");
+    assert_eq!(27, len);
+    let (name, m) = defined.unwrap();
+    assert_eq!(r"\R", name);
+    assert_eq!(r"\mathbb{R}", m.body);
+    let (_, defined) = definition(r"\newcommand\vect[2][x]{#1_#2}");
+    let (name, m) = defined.unwrap();
+    assert_eq!(r"\vect", name);
+    assert_eq!(2, m.arguments);
+    assert_eq!(Some("x".to_string()), m.default);
+    let (_, defined) = definition(r"\DeclareMathOperator*{\argmax}{arg\,max}");
+    assert_eq!(r"\operatorname*{arg\,max}", defined.unwrap().1.body);
+    let (len, defined) = definition(r"\newcommand{\x}[12]{y} z");
+    assert_eq!("invalid-definition", defined.unwrap_err().code);
+    assert_eq!(22, len);
+    let (_, defined) = definition(r"\newcommand{\x}{y");
+    assert_eq!("unbalanced-brace", defined.unwrap_err().code);
+    let (_, defined) = definition(r"\newcommand{x}{y}");
+    assert_eq!("missing-argument", defined.unwrap_err().code);
+    let (len, defined) = definition(r"\newenvironment{answer}[1]{(#1}{)} x");
+    assert_eq!(34, len);
+    let (name, m) = defined.unwrap();
+    assert_eq!("answer", name);
+    assert_eq!(1, m.arguments);
+    assert_eq!("(#1", m.body);
+    assert_eq!(Some(")".to_string()), m.end);
+    let (_, defined) = definition(r"\newenvironment{answer}{(}");
+    assert_eq!("missing-argument", defined.unwrap_err().code);
+}
+
+#[test]
+fn test_delimited() {
+    assert_eq!(Some(("a(b)", " c")), delimited("(a(b)) c", "(", ")"));
+    assert_eq!(Some(("a)b", "")), delimited("(a)b)|", "(", ")|"));
+    assert_eq!(None, delimited("({)}", "(", ")"));
+    // Every short string of the characters that matter, where each
+    // argument should be the same however we find it.
+    let mut strings = vec![String::new()];
+    for length in 0..6 {
+        for i in strings.len() - 8_usize.pow(length)..strings.len() {
+            for c in ['(', ')', '|', '[', '{', '}', '\\', 'x'] {
+                strings.push(format!("{}{}", strings[i], c));
+            }
+        }
+    }
+    for latex in &strings {
+        let mut ends = ArgumentEnds::new(latex);
+        for (open, close) in [("(", ")"), ("(", ")|"), ("[", "|"), ("|", "|")] {
+            for (i, _) in latex.match_indices(open) {
+                let rest = &latex[i..];
+                assert_eq!(
+                    delimited(rest, open, close),
+                    ends.delimited(rest, open, close),
+                    "{} in {:?}",
+                    open,
+                    latex
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_substitute() {
+    assert_eq!(None, substitute("#1#1#1", &["ab"], 5));
+    let substitute = |body, args| substitute(body, args, 100).unwrap();
+    assert_eq!("a+b", substitute("#1+#2", &["a", "b"]));
+    assert_eq!(r"\#1 and #", substitute(r"\##1 and ##", &["#1"]));
+}
+
+#[test]
+fn test_define_latex() {
+    let table = MacroTable::new()
+        .define(r"\R", 0, "R")
+        .define_latex(r"\newcommand{\R}{\mathbb{R}} \renewcommand\vect[1]{\mathbf{#1}}")
+        .define_latex(r"\newenvironment{answer}{(}{)} \newcommand{\x}{y");
+    assert_eq!(r"$\mathbb{R}\mathbf{v}$", table.expand(r"$\R \vect{v}$"));
+    assert!(!table.contains("answer"));
+    assert!(!table.contains(r"\x"));
+}
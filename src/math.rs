@@ -0,0 +1,1028 @@
+//! Parsing the LaTeX in math into a tree, so that we can write it out
+//! ourselves rather than leave all of it to MathJax.
+
+use crate::environs::{self, Placement};
+use crate::parse::{inside, macro_name, Braces};
+use std::ops::Range;
+
+/// A piece of parsed math.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Math {
+    /// A variable, such as `x` or `\alpha`.
+    Identifier(String),
+    /// The name of a function, such as `\sin`, which is upright.
+    Function(String),
+    Number(String),
+    /// An operator, relation or delimiter, such as `+`, `\leq` or `(`.
+    Operator(String),
+    /// An operator such as `\sum`, which is larger in display math.
+    LargeOperator(String),
+    /// Text, such as that of `\text`.
+    Text(String),
+    /// Horizontal space, such as `0.167em`.
+    Space(&'static str),
+    Row(Vec<Math>),
+    /// A fraction, or a binomial coefficient if it has no line.
+    Fraction {
+        numerator: Box<Math>,
+        denominator: Box<Math>,
+        line: bool,
+    },
+    Root {
+        radicand: Box<Math>,
+        index: Option<Box<Math>>,
+    },
+    /// Subscripts and superscripts, which go below and above the base
+    /// if `limits`.
+    Scripts {
+        base: Box<Math>,
+        sub: Option<Box<Math>>,
+        sup: Option<Box<Math>>,
+        limits: bool,
+    },
+    /// An accent such as `\hat`, over the base or else under it.
+    Accent {
+        base: Box<Math>,
+        mark: &'static str,
+        over: bool,
+    },
+    /// Delimiters that grow with their content, from `\left` and
+    /// `\right`, where an empty delimiter is written `.`.
+    Fenced {
+        open: &'static str,
+        close: &'static str,
+        content: Box<Math>,
+    },
+    /// A font such as `\mathbf`, by its MathML `mathvariant`.
+    Variant {
+        variant: &'static str,
+        content: Box<Math>,
+    },
+    /// A matrix or alignment, with the MathML `columnalign` of its
+    /// columns.
+    Table {
+        rows: Vec<Vec<Math>>,
+        align: &'static str,
+    },
+}
+
+/// Why we could not parse some math.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MathError {
+    pub(crate) code: &'static str,
+    pub(crate) message: String,
+    /// The byte range of the problem within the math.
+    pub(crate) span: Range<usize>,
+}
+
+const GREEK: &[(&str, &str)] = &[
+    ("alpha", "\u{3b1}"),
+    ("beta", "\u{3b2}"),
+    ("gamma", "\u{3b3}"),
+    ("delta", "\u{3b4}"),
+    ("epsilon", "\u{3f5}"),
+    ("varepsilon", "\u{3b5}"),
+    ("zeta", "\u{3b6}"),
+    ("eta", "\u{3b7}"),
+    ("theta", "\u{3b8}"),
+    ("vartheta", "\u{3d1}"),
+    ("iota", "\u{3b9}"),
+    ("kappa", "\u{3ba}"),
+    ("lambda", "\u{3bb}"),
+    ("mu", "\u{3bc}"),
+    ("nu", "\u{3bd}"),
+    ("xi", "\u{3be}"),
+    ("omicron", "\u{3bf}"),
+    ("pi", "\u{3c0}"),
+    ("varpi", "\u{3d6}"),
+    ("rho", "\u{3c1}"),
+    ("varrho", "\u{3f1}"),
+    ("sigma", "\u{3c3}"),
+    ("varsigma", "\u{3c2}"),
+    ("tau", "\u{3c4}"),
+    ("upsilon", "\u{3c5}"),
+    ("phi", "\u{3d5}"),
+    ("varphi", "\u{3c6}"),
+    ("chi", "\u{3c7}"),
+    ("psi", "\u{3c8}"),
+    ("omega", "\u{3c9}"),
+    ("Gamma", "\u{393}"),
+    ("Delta", "\u{394}"),
+    ("Theta", "\u{398}"),
+    ("Lambda", "\u{39b}"),
+    ("Xi", "\u{39e}"),
+    ("Pi", "\u{3a0}"),
+    ("Sigma", "\u{3a3}"),
+    ("Upsilon", "\u{3a5}"),
+    ("Phi", "\u{3a6}"),
+    ("Psi", "\u{3a8}"),
+    ("Omega", "\u{3a9}"),
+];
+
+/// Symbols that are written like variables.
+const IDENTIFIERS: &[(&str, &str)] = &[
+    ("infty", "\u{221e}"),
+    ("partial", "\u{2202}"),
+    ("nabla", "\u{2207}"),
+    ("hbar", "\u{210f}"),
+    ("ell", "\u{2113}"),
+    ("emptyset", "\u{2205}"),
+    ("varnothing", "\u{2205}"),
+    ("Re", "\u{211c}"),
+    ("Im", "\u{2111}"),
+    ("aleph", "\u{2135}"),
+    ("imath", "\u{131}"),
+    ("jmath", "\u{237}"),
+    ("wp", "\u{2118}"),
+    ("top", "\u{22a4}"),
+    ("bot", "\u{22a5}"),
+    ("angle", "\u{2220}"),
+    ("triangle", "\u{25b3}"),
+    ("prime", "\u{2032}"),
+];
+
+const OPERATORS: &[(&str, &str)] = &[
+    ("pm", "\u{b1}"),
+    ("mp", "\u{2213}"),
+    ("times", "\u{d7}"),
+    ("div", "\u{f7}"),
+    ("cdot", "\u{22c5}"),
+    ("ast", "\u{2217}"),
+    ("star", "\u{22c6}"),
+    ("circ", "\u{2218}"),
+    ("bullet", "\u{2219}"),
+    ("oplus", "\u{2295}"),
+    ("ominus", "\u{2296}"),
+    ("otimes", "\u{2297}"),
+    ("wedge", "\u{2227}"),
+    ("land", "\u{2227}"),
+    ("vee", "\u{2228}"),
+    ("lor", "\u{2228}"),
+    ("neg", "\u{ac}"),
+    ("lnot", "\u{ac}"),
+    ("cup", "\u{222a}"),
+    ("cap", "\u{2229}"),
+    ("setminus", "\u{2216}"),
+    ("dagger", "\u{2020}"),
+    ("leq", "\u{2264}"),
+    ("le", "\u{2264}"),
+    ("geq", "\u{2265}"),
+    ("ge", "\u{2265}"),
+    ("neq", "\u{2260}"),
+    ("ne", "\u{2260}"),
+    ("ll", "\u{226a}"),
+    ("gg", "\u{226b}"),
+    ("approx", "\u{2248}"),
+    ("equiv", "\u{2261}"),
+    ("sim", "\u{223c}"),
+    ("simeq", "\u{2243}"),
+    ("cong", "\u{2245}"),
+    ("propto", "\u{221d}"),
+    ("perp", "\u{22a5}"),
+    ("parallel", "\u{2225}"),
+    ("mid", "\u{2223}"),
+    ("in", "\u{2208}"),
+    ("notin", "\u{2209}"),
+    ("ni", "\u{220b}"),
+    ("subset", "\u{2282}"),
+    ("subseteq", "\u{2286}"),
+    ("supset", "\u{2283}"),
+    ("supseteq", "\u{2287}"),
+    ("forall", "\u{2200}"),
+    ("exists", "\u{2203}"),
+    ("to", "\u{2192}"),
+    ("rightarrow", "\u{2192}"),
+    ("leftarrow", "\u{2190}"),
+    ("gets", "\u{2190}"),
+    ("leftrightarrow", "\u{2194}"),
+    ("Rightarrow", "\u{21d2}"),
+    ("Leftarrow", "\u{21d0}"),
+    ("Leftrightarrow", "\u{21d4}"),
+    ("implies", "\u{27f9}"),
+    ("iff", "\u{27fa}"),
+    ("longrightarrow", "\u{27f6}"),
+    ("longleftarrow", "\u{27f5}"),
+    ("longleftrightarrow", "\u{27f7}"),
+    ("mapsto", "\u{21a6}"),
+    ("uparrow", "\u{2191}"),
+    ("downarrow", "\u{2193}"),
+    ("rightleftharpoons", "\u{21cc}"),
+    ("rightleftarrows", "\u{21c4}"),
+    ("ldots", "\u{2026}"),
+    ("dots", "\u{2026}"),
+    ("cdots", "\u{22ef}"),
+    ("vdots", "\u{22ee}"),
+    ("ddots", "\u{22f1}"),
+    ("langle", "\u{27e8}"),
+    ("rangle", "\u{27e9}"),
+    ("lvert", "|"),
+    ("rvert", "|"),
+    ("vert", "|"),
+    ("lVert", "\u{2016}"),
+    ("rVert", "\u{2016}"),
+    ("Vert", "\u{2016}"),
+    ("|", "\u{2016}"),
+    ("lfloor", "\u{230a}"),
+    ("rfloor", "\u{230b}"),
+    ("lceil", "\u{2308}"),
+    ("rceil", "\u{2309}"),
+    ("{", "{"),
+    ("}", "}"),
+    ("lbrace", "{"),
+    ("rbrace", "}"),
+    ("lbrack", "["),
+    ("rbrack", "]"),
+    ("backslash", "\\"),
+    ("%", "%"),
+    ("#", "#"),
+    ("&", "&"),
+    ("$", "$"),
+    ("_", "_"),
+    ("colon", ":"),
+];
+
+const LARGE_OPERATORS: &[(&str, &str)] = &[
+    ("sum", "\u{2211}"),
+    ("prod", "\u{220f}"),
+    ("coprod", "\u{2210}"),
+    ("int", "\u{222b}"),
+    ("iint", "\u{222c}"),
+    ("iiint", "\u{222d}"),
+    ("oint", "\u{222e}"),
+    ("bigcup", "\u{22c3}"),
+    ("bigcap", "\u{22c2}"),
+    ("bigoplus", "\u{2a01}"),
+    ("bigotimes", "\u{2a02}"),
+    ("bigvee", "\u{22c1}"),
+    ("bigwedge", "\u{22c0}"),
+];
+
+const FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "cot", "sec", "csc", "arcsin", "arccos", "arctan", "sinh", "cosh", "tanh",
+    "coth", "log", "ln", "lg", "exp", "lim", "liminf", "limsup", "max", "min", "sup", "inf", "det",
+    "dim", "ker", "deg", "arg", "gcd", "hom", "Pr",
+];
+
+/// The functions whose subscripts go below them in display math.
+const LIMIT_FUNCTIONS: &[&str] = &[
+    "lim", "liminf", "limsup", "max", "min", "sup", "inf", "det", "gcd", "Pr",
+];
+
+const ACCENTS: &[(&str, &str, bool)] = &[
+    ("hat", "^", true),
+    ("widehat", "^", true),
+    ("bar", "\u{af}", true),
+    ("overline", "\u{af}", true),
+    ("underline", "_", false),
+    ("vec", "\u{2192}", true),
+    ("overrightarrow", "\u{2192}", true),
+    ("overleftarrow", "\u{2190}", true),
+    ("dot", "\u{2d9}", true),
+    ("ddot", "\u{a8}", true),
+    ("tilde", "\u{2dc}", true),
+    ("widetilde", "\u{2dc}", true),
+    ("check", "\u{2c7}", true),
+    ("breve", "\u{2d8}", true),
+    ("acute", "\u{b4}", true),
+    ("grave", "`", true),
+    ("mathring", "\u{2da}", true),
+    ("overbrace", "\u{23de}", true),
+    ("underbrace", "\u{23df}", false),
+];
+
+const VARIANTS: &[(&str, &str)] = &[
+    ("mathbf", "bold"),
+    ("mathrm", "normal"),
+    ("mathit", "italic"),
+    ("mathbb", "double-struck"),
+    ("mathcal", "script"),
+    ("mathscr", "script"),
+    ("mathfrak", "fraktur"),
+    ("mathsf", "sans-serif"),
+    ("mathtt", "monospace"),
+    ("boldsymbol", "bold-italic"),
+    ("bm", "bold-italic"),
+];
+
+const SPACES: &[(&str, &str)] = &[
+    (",", "0.167em"),
+    ("thinspace", "0.167em"),
+    (":", "0.222em"),
+    (">", "0.222em"),
+    ("medspace", "0.222em"),
+    (";", "0.278em"),
+    ("thickspace", "0.278em"),
+    (" ", "0.25em"),
+    ("enspace", "0.5em"),
+    ("quad", "1em"),
+    ("qquad", "2em"),
+    ("!", "-0.167em"),
+];
+
+const TEXT: &[&str] = &["text", "textrm", "textnormal", "mbox", "textit", "textbf"];
+
+/// Macros that change nothing we write.
+const IGNORED: &[&str] = &[
+    "displaystyle",
+    "textstyle",
+    "scriptstyle",
+    "nonumber",
+    "notag",
+    "limits",
+    "nolimits",
+];
+
+/// The delimiters that `\left`, `\right` and the like may take.
+const DELIMITERS: &[(&str, &str)] = &[
+    ("(", "("),
+    (")", ")"),
+    ("[", "["),
+    ("]", "]"),
+    ("|", "|"),
+    ("/", "/"),
+    (".", ""),
+    (r"\{", "{"),
+    (r"\}", "}"),
+    (r"\lbrace", "{"),
+    (r"\rbrace", "}"),
+    (r"\lbrack", "["),
+    (r"\rbrack", "]"),
+    (r"\langle", "\u{27e8}"),
+    (r"\rangle", "\u{27e9}"),
+    (r"\lvert", "|"),
+    (r"\rvert", "|"),
+    (r"\vert", "|"),
+    (r"\|", "\u{2016}"),
+    (r"\lVert", "\u{2016}"),
+    (r"\rVert", "\u{2016}"),
+    (r"\Vert", "\u{2016}"),
+    (r"\lfloor", "\u{230a}"),
+    (r"\rfloor", "\u{230b}"),
+    (r"\lceil", "\u{2308}"),
+    (r"\rceil", "\u{2309}"),
+    (r"\backslash", "\\"),
+    (r"\uparrow", "\u{2191}"),
+    (r"\downarrow", "\u{2193}"),
+];
+
+/// The sized delimiters, such as `\bigl(`.
+const SIZED: &[&str] = &[
+    "big", "Big", "bigg", "Bigg", "bigl", "Bigl", "biggl", "Biggl", "bigr", "Bigr", "biggr",
+    "Biggr", "bigm", "Bigm", "biggm", "Biggm",
+];
+
+/// The other macros we understand, which take arguments.
+const COMMANDS: &[&str] = &[
+    "frac",
+    "dfrac",
+    "tfrac",
+    "cfrac",
+    "binom",
+    "dbinom",
+    "tbinom",
+    "sqrt",
+    "overset",
+    "underset",
+    "stackrel",
+    "operatorname",
+    "unicode",
+    "label",
+    "tag",
+    "left",
+    "middle",
+    "right",
+    "begin",
+    "end",
+];
+
+/// The names of the macros we understand in math, without their
+/// backslashes.
+pub(crate) fn names() -> impl Iterator<Item = &'static str> {
+    let symbols = GREEK.iter().chain(IDENTIFIERS).chain(OPERATORS);
+    let symbols = symbols.chain(LARGE_OPERATORS).chain(VARIANTS).chain(SPACES);
+    let accents = ACCENTS.iter().map(|(name, ..)| *name);
+    let others = FUNCTIONS.iter().chain(TEXT).chain(IGNORED).chain(SIZED);
+    let others = others.chain(COMMANDS).copied();
+    symbols.map(|(name, _)| *name).chain(accents).chain(others)
+}
+
+/// The name of the Greek letter `symbol`, such as `Gamma` or
+/// `varepsilon`.
+pub(crate) fn greek_name(symbol: &str) -> Option<&'static str> {
+    GREEK
+        .iter()
+        .find(|(_, s)| *s == symbol)
+        .map(|&(name, _)| name)
+}
+
+fn lookup<T: Copy>(table: &[(&str, T)], name: &str) -> Option<T> {
+    table.iter().find(|(n, _)| *n == name).map(|&(_, t)| t)
+}
+
+/// What ended a row of math.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    /// The end of the math.
+    Finish,
+    /// A `}`.
+    Brace,
+    /// A `&` between columns.
+    Column,
+    /// A `\\` between rows.
+    Line,
+    /// A `\right`, which is followed by its delimiter.
+    Right,
+    /// A `\end`, which is followed by the name of its environment.
+    End,
+}
+
+struct Parser<'a, 'b> {
+    source: &'a str,
+    rest: &'a str,
+    /// Where the groups and optional arguments end, found once for the
+    /// whole of the math so that an unclosed one is not looked through
+    /// again and again.
+    braces: &'b Braces<'a>,
+    /// The `\tag` of the row we are in, as it should be shown.
+    tag: Option<String>,
+    depth: usize,
+    max_depth: usize,
+}
+
+/// Parse the math `latex`, which is the content of the environment
+/// `environment` if it is not `$...$` or the like, nesting no more than
+/// `max_depth` deep.
+pub(crate) fn parse(
+    latex: &str,
+    environment: Option<&str>,
+    max_depth: usize,
+) -> Result<Math, MathError> {
+    parse_within(latex, environment, max_depth, &Braces::new(latex))
+}
+
+/// Parse the math `latex` as [`parse`] does, where `braces` holds where
+/// the groups end in it or in the math around it.
+fn parse_within<'a>(
+    latex: &'a str,
+    environment: Option<&str>,
+    max_depth: usize,
+    braces: &Braces<'a>,
+) -> Result<Math, MathError> {
+    let mut parser = Parser {
+        source: latex,
+        rest: latex,
+        braces,
+        tag: None,
+        depth: 0,
+        max_depth,
+    };
+    let environ = environment.and_then(environs::find);
+    if let Some(environ) = environ.filter(|e| e.argument.is_some()) {
+        // We ignore the number of columns.
+        parser.braced(environ.name)?;
+    }
+    match environ.and_then(|e| e.columns) {
+        Some(align) => {
+            let rows = parser.table(0, None)?;
+            Ok(Math::Table { rows, align })
+        }
+        None => {
+            let (row, end) = parser.row()?;
+            parser.expect_finish(end)?;
+            match parser.tag {
+                // The tag goes to the right of the equation.
+                Some(tag) => Ok(Math::Table {
+                    rows: vec![vec![Math::Row(row), Math::Text(tag)]],
+                    align: "center right",
+                }),
+                None => Ok(Math::Row(row)),
+            }
+        }
+    }
+}
+
+impl<'a, 'b> Parser<'a, 'b> {
+    fn offset(&self) -> usize {
+        self.source.len() - self.rest.len()
+    }
+
+    fn error(&self, start: usize, code: &'static str, message: String) -> MathError {
+        MathError {
+            code,
+            message,
+            span: start..self.offset().max(start + 1).min(self.source.len()),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    /// Complain unless `end` is the end of the math.
+    fn expect_finish(&self, end: Stop) -> Result<(), MathError> {
+        let (code, message) = match end {
+            Stop::Finish => return Ok(()),
+            Stop::Brace => ("unbalanced-brace", "} has no matching {".to_string()),
+            Stop::Column => (
+                "misplaced-ampersand",
+                "& outside of an alignment".to_string(),
+            ),
+            Stop::Line => (
+                "misplaced-newline",
+                r"\\ outside of an alignment".to_string(),
+            ),
+            Stop::Right => (
+                "unmatched-right",
+                r"\right has no matching \left".to_string(),
+            ),
+            Stop::End => ("unexpected-end", r"\end has no matching \begin".to_string()),
+        };
+        Err(self.error(self.offset() - 1, code, message))
+    }
+
+    /// Parse math up to whatever ends it.
+    fn row(&mut self) -> Result<(Vec<Math>, Stop), MathError> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest;
+            let end = if rest.is_empty() {
+                Some((Stop::Finish, 0))
+            } else if rest.starts_with('}') {
+                Some((Stop::Brace, 1))
+            } else if rest.starts_with('&') {
+                Some((Stop::Column, 1))
+            } else if rest.starts_with(r"\\") {
+                Some((Stop::Line, 2))
+            } else if rest.starts_with('\\') {
+                match macro_name(rest) {
+                    r"\right" => Some((Stop::Right, r"\right".len())),
+                    r"\end" => Some((Stop::End, r"\end".len())),
+                    _ => None,
+                }
+            } else {
+                None
+            };
+            if let Some((end, len)) = end {
+                self.rest = &rest[len..];
+                if end == Stop::Line {
+                    // Skip any extra space such as \\[2pt].
+                    self.rest = &self.rest[self.braces.optional_argument(self.rest).len()..];
+                }
+                return Ok((items, end));
+            }
+            if let Some(item) = self.scripted()? {
+                items.push(item);
+            }
+        }
+    }
+
+    /// Parse something with any subscripts and superscripts it has.
+    fn scripted(&mut self) -> Result<Option<Math>, MathError> {
+        let base = if self.rest.starts_with(['^', '_']) {
+            Math::Row(Vec::new())
+        } else {
+            match self.atom()? {
+                Some(base) => base,
+                None => return Ok(None),
+            }
+        };
+        let mut limits = match &base {
+            Math::LargeOperator(op) => {
+                !op.starts_with(['\u{222b}', '\u{222c}', '\u{222d}', '\u{222e}'])
+            }
+            Math::Function(name) => LIMIT_FUNCTIONS.contains(&name.as_str()),
+            _ => false,
+        };
+        let (mut sub, mut sup) = (None, None);
+        let mut primes = String::new();
+        loop {
+            self.skip_whitespace();
+            let start = self.offset();
+            if self.rest.starts_with(r"\limits") || self.rest.starts_with(r"\nolimits") {
+                let name = macro_name(self.rest);
+                limits = name == r"\limits";
+                self.rest = &self.rest[name.len()..];
+            } else if self.rest.starts_with('\'') {
+                self.rest = &self.rest[1..];
+                primes.push('\u{2032}');
+            } else if let Some(c) = self.rest.chars().next().filter(|c| *c == '^' || *c == '_') {
+                self.rest = &self.rest[1..];
+                let script = if c == '^' { &mut sup } else { &mut sub };
+                if script.is_some() {
+                    let message = format!(
+                        "double {}",
+                        if c == '^' { "superscript" } else { "subscript" }
+                    );
+                    return Err(self.error(start, "double-script", message));
+                }
+                *script = Some(Box::new(self.argument(&c.to_string())?));
+            } else {
+                break;
+            }
+        }
+        if !primes.is_empty() {
+            let primes = Math::Operator(primes);
+            sup = Some(Box::new(match sup {
+                Some(sup) => Math::Row(vec![primes, *sup]),
+                None => primes,
+            }));
+        }
+        if sub.is_none() && sup.is_none() {
+            return Ok(Some(base));
+        }
+        Ok(Some(Math::Scripts {
+            base: Box::new(base),
+            sub,
+            sup,
+            limits,
+        }))
+    }
+
+    /// Parse the argument of `name`, which is a group or else a single
+    /// token.
+    fn argument(&mut self, name: &str) -> Result<Math, MathError> {
+        self.skip_whitespace();
+        let start = self.offset();
+        if let Some(digit) = self.rest.chars().next().filter(char::is_ascii_digit) {
+            self.rest = &self.rest[1..];
+            return Ok(Math::Number(digit.to_string()));
+        }
+        let ended = self.rest.is_empty() || self.rest.starts_with(['}', '&', '^', '_']);
+        match self.atom()? {
+            Some(math) if !ended => Ok(math),
+            _ => {
+                let message = format!("{} needs an argument", name);
+                Err(self.error(start, "missing-argument", message))
+            }
+        }
+    }
+
+    /// The content of a braced argument, such as that of `\text`.
+    fn braced(&mut self, name: &str) -> Result<&'a str, MathError> {
+        self.skip_whitespace();
+        let start = self.offset();
+        let arg = self.braces.argument(self.rest);
+        if !arg.starts_with('{') || arg.len() < 2 {
+            let message = format!("{} needs a braced argument", name);
+            return Err(self.error(start, "missing-argument", message));
+        }
+        self.rest = &self.rest[arg.len()..];
+        Ok(inside(arg))
+    }
+
+    /// Parse the delimiter after `name`.
+    fn delimiter(&mut self, name: &str) -> Result<&'static str, MathError> {
+        self.skip_whitespace();
+        let start = self.offset();
+        let token = if self.rest.starts_with('\\') {
+            macro_name(self.rest)
+        } else {
+            &self.rest[..self.rest.chars().next().map_or(0, char::len_utf8)]
+        };
+        self.rest = &self.rest[token.len()..];
+        lookup(DELIMITERS, token).ok_or_else(|| {
+            let message = format!("{} needs a delimiter, not {:?}", name, token);
+            self.error(start, "missing-delimiter", message)
+        })
+    }
+
+    /// Parse rows of cells up to the `\end` of `environment`, or the end
+    /// of the math.
+    fn table(
+        &mut self,
+        start: usize,
+        environment: Option<&str>,
+    ) -> Result<Vec<Vec<Math>>, MathError> {
+        let mut rows = Vec::new();
+        let mut cells = Vec::new();
+        loop {
+            let (cell, end) = self.row()?;
+            cells.push(Math::Row(cell));
+            if end != Stop::Column {
+                // The tag goes in a column of its own.
+                cells.extend(self.tag.take().map(Math::Text));
+            }
+            match (end, environment) {
+                (Stop::Column, _) => continue,
+                (Stop::Line, _) => rows.push(std::mem::take(&mut cells)),
+                (Stop::Finish, None) => break,
+                (Stop::End, Some(environment)) => {
+                    let name = self.braces.argument(self.rest);
+                    self.rest = &self.rest[name.len()..];
+                    if inside(name) != environment {
+                        let message =
+                            format!(r"\end{} does not match \begin{{{}}}", name, environment);
+                        return Err(self.error(start, "mismatched-end", message));
+                    }
+                    break;
+                }
+                (Stop::Finish, Some(environment)) => {
+                    let message = format!(
+                        r"\begin{{{}}} has no matching \end{{{}}}",
+                        environment, environment
+                    );
+                    return Err(self.error(start, "unclosed-environment", message));
+                }
+                (end, _) => self.expect_finish(end)?,
+            }
+        }
+        // A trailing \\ does not start another row.
+        if cells != [Math::Row(Vec::new())] || rows.is_empty() {
+            rows.push(cells);
+        }
+        Ok(rows)
+    }
+
+    /// Parse a single thing, or `None` for something that we skip.
+    ///
+    /// Everything nested comes back through here, so this is where we
+    /// limit how deeply things may nest.
+    fn atom(&mut self) -> Result<Option<Math>, MathError> {
+        if self.depth >= self.max_depth {
+            let message = format!("nested more than {} deep", self.max_depth);
+            return Err(self.error(self.offset(), "max-depth", message));
+        }
+        self.depth += 1;
+        let atom = self.atom_nested();
+        self.depth -= 1;
+        atom
+    }
+
+    fn atom_nested(&mut self) -> Result<Option<Math>, MathError> {
+        self.skip_whitespace();
+        let start = self.offset();
+        let c = match self.rest.chars().next() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        if c == '\\' {
+            return self.command(start);
+        }
+        let rest = self.rest;
+        self.rest = &rest[c.len_utf8()..];
+        let math = match c {
+            '{' => {
+                let (row, end) = self.row()?;
+                if end != Stop::Brace {
+                    let message = "{ has no matching }".to_string();
+                    let mut error = self.error(start, "unbalanced-brace", message);
+                    error.span = start..start + 1;
+                    return Err(error);
+                }
+                Math::Row(row)
+            }
+            '0'..='9' | '.' => {
+                let len = rest
+                    .find(|c: char| !c.is_ascii_digit() && c != '.')
+                    .unwrap_or(rest.len());
+                if c == '.' && len == 1 {
+                    Math::Operator(".".to_string())
+                } else {
+                    self.rest = &rest[len..];
+                    Math::Number(rest[..len].to_string())
+                }
+            }
+            'a'..='z' | 'A'..='Z' => Math::Identifier(c.to_string()),
+            '-' => Math::Operator("\u{2212}".to_string()),
+            '*' => Math::Operator("\u{2217}".to_string()),
+            '\'' => Math::Operator("\u{2032}".to_string()),
+            '~' => Math::Space("0.25em"),
+            '+' | '=' | '<' | '>' | '(' | ')' | '[' | ']' | ',' | ';' | ':' | '!' | '|' | '/'
+            | '?' | '@' | '"' => Math::Operator(c.to_string()),
+            '#' | '$' | '%' | '^' | '_' => {
+                let message = format!("{} is not allowed here", c);
+                return Err(self.error(start, "unexpected-character", message));
+            }
+            c => Math::Identifier(c.to_string()),
+        };
+        Ok(Some(math))
+    }
+
+    /// Parse a macro such as `\frac` with its arguments.
+    fn command(&mut self, start: usize) -> Result<Option<Math>, MathError> {
+        let full = macro_name(self.rest);
+        self.rest = &self.rest[full.len()..];
+        let name = &full[1..];
+        let text = |s: &str| s.to_string();
+        let math = if let Some(s) = lookup(GREEK, name).or_else(|| lookup(IDENTIFIERS, name)) {
+            Math::Identifier(text(s))
+        } else if let Some(s) = lookup(OPERATORS, name) {
+            Math::Operator(text(s))
+        } else if let Some(s) = lookup(LARGE_OPERATORS, name) {
+            Math::LargeOperator(text(s))
+        } else if FUNCTIONS.contains(&name) {
+            Math::Function(text(name))
+        } else if let Some(width) = lookup(SPACES, name) {
+            Math::Space(width)
+        } else if let Some(&(_, mark, over)) = ACCENTS.iter().find(|(a, ..)| *a == name) {
+            Math::Accent {
+                base: Box::new(self.argument(full)?),
+                mark,
+                over,
+            }
+        } else if let Some(variant) = lookup(VARIANTS, name) {
+            Math::Variant {
+                variant,
+                content: Box::new(self.argument(full)?),
+            }
+        } else if TEXT.contains(&name) {
+            Math::Text(text(self.braced(full)?))
+        } else if IGNORED.contains(&name) {
+            return Ok(None);
+        } else if SIZED.contains(&name) || name == "middle" {
+            Math::Operator(text(self.delimiter(full)?))
+        } else {
+            match name {
+                "frac" | "dfrac" | "tfrac" | "cfrac" | "binom" | "dbinom" | "tbinom" => {
+                    let numerator = Box::new(self.argument(full)?);
+                    let denominator = Box::new(self.argument(full)?);
+                    let line = name.ends_with("frac");
+                    let fraction = Math::Fraction {
+                        numerator,
+                        denominator,
+                        line,
+                    };
+                    if line {
+                        fraction
+                    } else {
+                        Math::Fenced {
+                            open: "(",
+                            close: ")",
+                            content: Box::new(fraction),
+                        }
+                    }
+                }
+                "sqrt" => {
+                    self.skip_whitespace();
+                    let o = self.braces.optional_argument(self.rest);
+                    let index = if o.len() > 1 {
+                        let offset = self.offset() + 1;
+                        self.rest = &self.rest[o.len()..];
+                        let depth = self.max_depth - self.depth;
+                        let index = parse_within(&o[1..o.len() - 1], None, depth, self.braces)
+                            .map_err(|mut e| {
+                                e.span = e.span.start + offset..e.span.end + offset;
+                                e
+                            })?;
+                        Some(Box::new(index))
+                    } else {
+                        None
+                    };
+                    Math::Root {
+                        radicand: Box::new(self.argument(full)?),
+                        index,
+                    }
+                }
+                "overset" | "underset" | "stackrel" => {
+                    let script = Some(Box::new(self.argument(full)?));
+                    let base = Box::new(self.argument(full)?);
+                    let (sub, sup) = if name == "underset" {
+                        (script, None)
+                    } else {
+                        (None, script)
+                    };
+                    Math::Scripts {
+                        base,
+                        sub,
+                        sup,
+                        limits: true,
+                    }
+                }
+                "operatorname" => Math::Function(text(self.braced(full)?)),
+                "unicode" => {
+                    let arg = self.braced(full)?;
+                    let code = match arg.strip_prefix(['x', 'X']) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                        None => arg.parse().ok(),
+                    };
+                    match code.and_then(char::from_u32) {
+                        Some(c) => Math::Identifier(c.to_string()),
+                        None => {
+                            let message = format!(r"\unicode{{{}}} is not a character", arg);
+                            return Err(self.error(start, "invalid-unicode", message));
+                        }
+                    }
+                }
+                "label" => {
+                    self.braced(full)?;
+                    return Ok(None);
+                }
+                "tag" | "tag*" => {
+                    let tag = self.braced(full)?;
+                    self.tag = Some(match name {
+                        "tag" => format!("({})", tag),
+                        _ => tag.to_string(),
+                    });
+                    return Ok(None);
+                }
+                "left" => {
+                    let open = self.delimiter(full)?;
+                    let (row, end) = self.row()?;
+                    if end != Stop::Right {
+                        let message = r"\left has no matching \right".to_string();
+                        let mut error = self.error(start, "unmatched-left", message);
+                        error.span = start..start + full.len();
+                        return Err(error);
+                    }
+                    let close = self.delimiter(r"\right")?;
+                    Math::Fenced {
+                        open,
+                        close,
+                        content: Box::new(Math::Row(row)),
+                    }
+                }
+                "begin" => {
+                    let env = self.braces.argument(self.rest);
+                    self.rest = &self.rest[env.len()..];
+                    let env = inside(env);
+                    let environ = environs::find(env)
+                        .filter(|e| e.placement == Placement::Inner && e.name == env);
+                    let Some(environ) = environ else {
+                        let message = format!(r"unknown environment {} in math", env);
+                        return Err(self.error(start, "unknown-environment", message));
+                    };
+                    let Some(align) = environ.columns else {
+                        let message = format!(r"\begin{{{}}} is not supported in math", env);
+                        return Err(self.error(start, "unsupported-math", message));
+                    };
+                    if environ.argument.is_some() {
+                        // We ignore the number or specification of the
+                        // columns.
+                        self.braced(full)?;
+                    }
+                    let rows = self.table(start, Some(env))?;
+                    let (open, close) = environ.delimiters;
+                    let table = Math::Table { rows, align };
+                    if open.is_empty() && close.is_empty() {
+                        table
+                    } else {
+                        Math::Fenced {
+                            open,
+                            close,
+                            content: Box::new(table),
+                        }
+                    }
+                }
+                _ => {
+                    let message = format!("{} is not supported in math", full);
+                    return Err(self.error(start, "unsupported-math", message));
+                }
+            }
+        };
+        Ok(Some(math))
+    }
+}
+
+#[test]
+fn test_parse() {
+    let parsed = |latex| parse(latex, None, 64);
+    assert_eq!(
+        Ok(Math::Row(vec![
+            Math::Scripts {
+                base: Box::new(Math::Identifier("x".to_string())),
+                sub: None,
+                sup: Box::new(Math::Number("2".to_string())).into(),
+                limits: false,
+            },
+            Math::Number("3".to_string()),
+        ])),
+        parsed("x^23")
+    );
+    assert_eq!(
+        Ok(Math::Row(vec![Math::Fraction {
+            numerator: Box::new(Math::Number("1".to_string())),
+            denominator: Box::new(Math::Number("2".to_string())),
+            line: true,
+        }])),
+        parsed(r"\frac12")
+    );
+    let code = |latex| parsed(latex).map_err(|e| (e.code, e.span));
+    assert_eq!(Err(("unbalanced-brace", 2..3)), code("x+{y"));
+    assert_eq!(Err(("unbalanced-brace", 1..2)), code("x}"));
+    assert_eq!(Err(("unmatched-left", 2..7)), code(r"a+\left( b"));
+    assert_eq!(Err(("misplaced-ampersand", 1..2)), code("a&b"));
+    assert_eq!(Err(("unsupported-math", 0..4)), code(r"\foo"));
+    assert_eq!(
+        r"\begin{CD} is not supported in math",
+        parsed(r"\begin{CD}\end{CD}").unwrap_err().message
+    );
+    assert_eq!(Err(("double-script", 3..4)), code("x^2^3"));
+    assert_eq!(Err(("missing-argument", 5..5)), code(r"\frac"));
+    let deep = format!("{}x{}", r"\sqrt{".repeat(100), "}".repeat(100));
+    assert_eq!("max-depth", parsed(&deep).unwrap_err().code);
+    let deep = format!("{}x{}", r"\sqrt[{".repeat(100), "}]y".repeat(100));
+    assert_eq!("max-depth", parsed(&deep).unwrap_err().code);
+    let rows = match parse(r"a &= b \\ c &= d \\", Some("align"), 64) {
+        Ok(Math::Table { rows, .. }) => rows,
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(2, rows.len());
+    assert_eq!(2, rows[1].len());
+    let rows = match parse(r"a &= b \tag{1} \\ c &= d", Some("align*"), 64) {
+        Ok(Math::Table { rows, .. }) => rows,
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(Math::Text("(1)".to_string()), rows[0][2]);
+    assert_eq!(2, rows[1].len());
+}
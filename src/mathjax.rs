@@ -0,0 +1,692 @@
+//! Checking math before MathJax sees it, so that mistakes show up when
+//! the LaTeX is written rather than in the student's browser.
+
+use crate::environs;
+use crate::math::{self, MathError};
+use crate::parse::{inside, macro_name, Braces};
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+/// The macros MathJax understands in math, beyond those we can write as
+/// MathML ourselves.
+const COMMANDS: &[&str] = &[
+    // Letters and symbols
+    "Epsilon",
+    "Alpha",
+    "Beta",
+    "Zeta",
+    "Eta",
+    "Iota",
+    "Kappa",
+    "Mu",
+    "Nu",
+    "Omicron",
+    "Rho",
+    "Tau",
+    "Chi",
+    "varkappa",
+    "digamma",
+    "varGamma",
+    "varDelta",
+    "varTheta",
+    "varLambda",
+    "varXi",
+    "varPi",
+    "varSigma",
+    "varUpsilon",
+    "varPhi",
+    "varPsi",
+    "varOmega",
+    "beth",
+    "gimel",
+    "daleth",
+    "eth",
+    "mho",
+    "Finv",
+    "Game",
+    "complement",
+    "hslash",
+    "surd",
+    "diamondsuit",
+    "heartsuit",
+    "clubsuit",
+    "spadesuit",
+    "flat",
+    "natural",
+    "sharp",
+    "backprime",
+    "nexists",
+    "Box",
+    "Diamond",
+    "square",
+    "blacksquare",
+    "lozenge",
+    "blacklozenge",
+    "triangledown",
+    "blacktriangle",
+    "blacktriangledown",
+    "bigstar",
+    "sphericalangle",
+    "measuredangle",
+    "diagup",
+    "diagdown",
+    "checkmark",
+    "circledR",
+    "circledS",
+    "yen",
+    "maltese",
+    "S",
+    "P",
+    "dag",
+    "ddag",
+    "dagger",
+    "ddagger",
+    "AA",
+    "And",
+    "cdotp",
+    "ldotp",
+    "dotsb",
+    "dotsc",
+    "dotsi",
+    "dotsm",
+    "dotso",
+    "iddots",
+    // Binary operators
+    "amalg",
+    "bigcirc",
+    "bigtriangledown",
+    "bigtriangleup",
+    "diamond",
+    "odot",
+    "oslash",
+    "uplus",
+    "sqcap",
+    "sqcup",
+    "triangleleft",
+    "triangleright",
+    "wr",
+    "barwedge",
+    "veebar",
+    "doublebarwedge",
+    "boxminus",
+    "boxtimes",
+    "boxdot",
+    "boxplus",
+    "divideontimes",
+    "ltimes",
+    "rtimes",
+    "leftthreetimes",
+    "rightthreetimes",
+    "curlywedge",
+    "curlyvee",
+    "circleddash",
+    "circledast",
+    "circledcirc",
+    "centerdot",
+    "intercal",
+    "dotplus",
+    "smallsetminus",
+    "Cap",
+    "Cup",
+    "doublecap",
+    "doublecup",
+    "lhd",
+    "rhd",
+    "unlhd",
+    "unrhd",
+    // Relations
+    "prec",
+    "preceq",
+    "succ",
+    "succeq",
+    "sqsubset",
+    "sqsubseteq",
+    "sqsupset",
+    "sqsupseteq",
+    "vdash",
+    "dashv",
+    "models",
+    "smile",
+    "frown",
+    "asymp",
+    "bowtie",
+    "Join",
+    "doteq",
+    "approxeq",
+    "backsim",
+    "backsimeq",
+    "nsim",
+    "ncong",
+    "nmid",
+    "nparallel",
+    "nleq",
+    "ngeq",
+    "nless",
+    "ngtr",
+    "nsubseteq",
+    "nsupseteq",
+    "subsetneq",
+    "supsetneq",
+    "leqslant",
+    "geqslant",
+    "lesssim",
+    "gtrsim",
+    "lessgtr",
+    "gtrless",
+    "lll",
+    "ggg",
+    "lesseqgtr",
+    "gtreqless",
+    "coloneqq",
+    "eqqcolon",
+    "Coloneqq",
+    "triangleq",
+    "eqcirc",
+    "circeq",
+    "bumpeq",
+    "Bumpeq",
+    "doteqdot",
+    "risingdotseq",
+    "fallingdotseq",
+    "thicksim",
+    "thickapprox",
+    "lessdot",
+    "gtrdot",
+    "leqq",
+    "geqq",
+    "lneq",
+    "gneq",
+    "lneqq",
+    "gneqq",
+    "precsim",
+    "succsim",
+    "vDash",
+    "Vdash",
+    "Vvdash",
+    "nvdash",
+    "nvDash",
+    "nVdash",
+    "therefore",
+    "because",
+    "varpropto",
+    "between",
+    "pitchfork",
+    "backepsilon",
+    "shortmid",
+    "shortparallel",
+    "Subset",
+    "Supset",
+    "subseteqq",
+    "supseteqq",
+    "owns",
+    "notni",
+    "lt",
+    "gt",
+    "ngeqslant",
+    "nleqslant",
+    // Arrows
+    "Uparrow",
+    "Downarrow",
+    "updownarrow",
+    "Updownarrow",
+    "nearrow",
+    "searrow",
+    "swarrow",
+    "nwarrow",
+    "Longrightarrow",
+    "Longleftarrow",
+    "Longleftrightarrow",
+    "longmapsto",
+    "hookrightarrow",
+    "hookleftarrow",
+    "leftharpoonup",
+    "leftharpoondown",
+    "rightharpoonup",
+    "rightharpoondown",
+    "leftrightharpoons",
+    "upharpoonleft",
+    "upharpoonright",
+    "downharpoonleft",
+    "downharpoonright",
+    "leadsto",
+    "rightsquigarrow",
+    "leftrightsquigarrow",
+    "twoheadrightarrow",
+    "twoheadleftarrow",
+    "rightarrowtail",
+    "leftarrowtail",
+    "looparrowright",
+    "looparrowleft",
+    "curvearrowright",
+    "curvearrowleft",
+    "circlearrowright",
+    "circlearrowleft",
+    "Lsh",
+    "Rsh",
+    "upuparrows",
+    "downdownarrows",
+    "leftleftarrows",
+    "rightrightarrows",
+    "leftrightarrows",
+    "Lleftarrow",
+    "Rrightarrow",
+    "nleftarrow",
+    "nrightarrow",
+    "nLeftarrow",
+    "nRightarrow",
+    "nleftrightarrow",
+    "nLeftrightarrow",
+    "multimap",
+    "impliedby",
+    "xrightarrow",
+    "xleftarrow",
+    "xleftrightarrow",
+    "xRightarrow",
+    "xLeftarrow",
+    "xLeftrightarrow",
+    "xmapsto",
+    "xhookrightarrow",
+    "xhookleftarrow",
+    "xrightleftharpoons",
+    "xtwoheadrightarrow",
+    // Functions and operators
+    "arccot",
+    "arcsec",
+    "arccsc",
+    "sech",
+    "csch",
+    "injlim",
+    "projlim",
+    "varinjlim",
+    "varprojlim",
+    "varliminf",
+    "varlimsup",
+    "bmod",
+    "pmod",
+    "mod",
+    "pod",
+    "biguplus",
+    "bigsqcup",
+    "bigodot",
+    "intop",
+    "smallint",
+    "iiiint",
+    "idotsint",
+    "oiint",
+    "oiiint",
+    "sideset",
+    "substack",
+    "mathop",
+    "mathrel",
+    "mathbin",
+    "mathord",
+    "mathopen",
+    "mathclose",
+    "mathpunct",
+    "mathinner",
+    "DeclareMathOperator",
+    "not",
+    // Fractions, roots and the like
+    "over",
+    "atop",
+    "choose",
+    "above",
+    "genfrac",
+    "brace",
+    "brack",
+    "root",
+    "of",
+    "overleftrightarrow",
+    "underleftarrow",
+    "underrightarrow",
+    "underleftrightarrow",
+    "overparen",
+    "underparen",
+    "overbracket",
+    "underbracket",
+    "widecheck",
+    "utilde",
+    "dddot",
+    "ddddot",
+    "boxed",
+    "cancel",
+    "bcancel",
+    "xcancel",
+    "cancelto",
+    "fbox",
+    "phantom",
+    "hphantom",
+    "vphantom",
+    "smash",
+    "mathstrut",
+    "strut",
+    "rlap",
+    "llap",
+    "mathrlap",
+    "mathllap",
+    "mathclap",
+    "clap",
+    "raise",
+    "lower",
+    "raisebox",
+    "overunderset",
+    // Fonts, colours and styles
+    "rm",
+    "it",
+    "bf",
+    "sf",
+    "tt",
+    "cal",
+    "mit",
+    "oldstyle",
+    "scr",
+    "frak",
+    "mathnormal",
+    "pmb",
+    "mathbfit",
+    "mathsfit",
+    "emph",
+    "textsf",
+    "texttt",
+    "textup",
+    "textsl",
+    "textmd",
+    "color",
+    "textcolor",
+    "colorbox",
+    "fcolorbox",
+    "definecolor",
+    "Tiny",
+    "tiny",
+    "scriptsize",
+    "footnotesize",
+    "small",
+    "normalsize",
+    "large",
+    "Large",
+    "LARGE",
+    "huge",
+    "Huge",
+    "scriptscriptstyle",
+    "class",
+    "cssId",
+    "style",
+    "href",
+    "tooltip",
+    "mmlToken",
+    // Spacing and layout
+    "enskip",
+    "negthinspace",
+    "negmedspace",
+    "negthickspace",
+    "hspace",
+    "kern",
+    "mkern",
+    "mskip",
+    "hskip",
+    "mspace",
+    "hfill",
+    "hfil",
+    "space",
+    "nobreakspace",
+    "allowbreak",
+    "newline",
+    "linebreak",
+    "hline",
+    "hdashline",
+    "cline",
+    "arraystretch",
+    "cr",
+    "nobreak",
+    "vcenter",
+    "hbox",
+    "vbox",
+    "buildrel",
+    "shoveleft",
+    "shoveright",
+    "eqref",
+    "ref",
+    "nonscript",
+    "mathchoice",
+    "underline",
+    "displaylines",
+    "smallskip",
+    "medskip",
+    "bigskip",
+    // Definitions
+    "newcommand",
+    "renewcommand",
+    "newenvironment",
+    "renewenvironment",
+    "def",
+    "let",
+    "require",
+];
+
+static KNOWN: LazyLock<HashSet<&'static str>> =
+    LazyLock::new(|| math::names().chain(COMMANDS.iter().copied()).collect());
+
+/// Whether MathJax understands `name`, without its backslash, in math.
+pub(crate) fn is_known(name: &str) -> bool {
+    KNOWN.contains(name) || KNOWN.contains(name.trim_end_matches('*'))
+}
+
+/// Whether `environment` is one that MathJax understands, and if so,
+/// whether it is an alignment.
+fn environment(environment: &str) -> Option<bool> {
+    environs::find(environment).map(|e| e.aligned)
+}
+
+/// Something opened in math that should be closed again.
+enum Open<'a> {
+    Brace,
+    Left,
+    Begin(&'a str),
+}
+
+/// The problems MathJax would have with the math `latex`, which is the
+/// content of `environment` if it is not `$...$` or the like, where
+/// `known` says which other macros (without their backslashes) are
+/// fine, such as those we expand ourselves.
+pub(crate) fn check(
+    latex: &str,
+    environment: Option<&str>,
+    known: impl Fn(&str) -> bool,
+) -> Vec<MathError> {
+    let mut problems = Vec::new();
+    let error = |span: std::ops::Range<usize>, code, message| MathError {
+        code,
+        message,
+        span,
+    };
+    let outer_aligned = environment.is_some_and(|e| self::environment(e) == Some(true));
+    let mut open: Vec<(Open, usize)> = Vec::new();
+    let braces = Braces::new(latex);
+    let mut rest = latex;
+    while let Some(c) = rest.chars().next() {
+        let start = latex.len() - rest.len();
+        let mut len = c.len_utf8();
+        match c {
+            '{' => open.push((Open::Brace, start)),
+            '}' => match open.pop() {
+                Some((Open::Brace, _)) => (),
+                other => {
+                    if let Some(other) = other {
+                        open.push(other);
+                    }
+                    let message = "} has no matching {".to_string();
+                    problems.push(error(start..start + 1, "unbalanced-brace", message));
+                }
+            },
+            '&' => {
+                let aligned = match open.iter().rev().find_map(|(o, _)| match o {
+                    Open::Begin(e) => Some(*e),
+                    _ => None,
+                }) {
+                    Some(e) => self::environment(e) == Some(true),
+                    None => outer_aligned,
+                };
+                if !aligned {
+                    let message = "& outside of an alignment".to_string();
+                    problems.push(error(start..start + 1, "misplaced-ampersand", message));
+                }
+            }
+            '\\' => {
+                let name = macro_name(rest);
+                len = name.len();
+                let after = rest[len..].trim_start();
+                let argument_start = latex.len() - after.len();
+                match &name[1..] {
+                    "" => (),
+                    "left" => open.push((Open::Left, start)),
+                    "right" | "middle" => {
+                        if !open.iter().any(|(o, _)| matches!(o, Open::Left)) {
+                            let message = format!(r"{} has no matching \left", name);
+                            problems.push(error(start..start + len, "unmatched-right", message));
+                        } else if name == r"\right" {
+                            // Anything opened since the \left is unclosed.
+                            while let Some((o, at)) = open.pop() {
+                                if let Open::Left = o {
+                                    break;
+                                }
+                                problems.push(unclosed(o, at));
+                            }
+                        }
+                    }
+                    "begin" | "end" => {
+                        let arg = braces.argument(after);
+                        len = argument_start - start + arg.len();
+                        let env = inside(arg);
+                        if !arg.starts_with('{') || arg.len() < 2 {
+                            let message = format!("{} needs the name of an environment", name);
+                            problems.push(error(start..start + len, "missing-argument", message));
+                        } else if name == r"\end" {
+                            match open.iter().rposition(|(o, _)| matches!(o, Open::Begin(_))) {
+                                Some(i) => {
+                                    for (o, at) in open.drain(i + 1..) {
+                                        problems.push(unclosed(o, at));
+                                    }
+                                    if let Some((Open::Begin(begun), _)) = open.pop() {
+                                        if begun != env {
+                                            let message = format!(
+                                                r"\end{{{}}} does not match \begin{{{}}}",
+                                                env, begun
+                                            );
+                                            let span = start..start + len;
+                                            problems.push(error(span, "mismatched-end", message));
+                                        }
+                                    }
+                                }
+                                None => {
+                                    let message =
+                                        format!(r"\end{{{}}} has no matching \begin", env);
+                                    let span = start..start + len;
+                                    problems.push(error(span, "unexpected-end", message));
+                                }
+                            }
+                        } else if self::environment(env).is_none() {
+                            let message = format!("MathJax does not know the environment {}", env);
+                            problems.push(error(
+                                start..start + len,
+                                "unknown-environment",
+                                message,
+                            ));
+                            open.push((Open::Begin(env), start));
+                        } else {
+                            open.push((Open::Begin(env), start));
+                        }
+                    }
+                    "text" | "textrm" | "textnormal" | "mbox" | "textit" | "textbf" | "textsf"
+                    | "texttt" | "hbox" | "label" | "tag" | "operatorname" | "operatorname*" => {
+                        // The argument is text, not math, so we skip it
+                        // if it is complete.
+                        let arg = braces.argument(after);
+                        if arg.len() > 1 {
+                            len = argument_start - start + arg.len();
+                        }
+                    }
+                    n if n.chars().count() == 1 && !n.starts_with(char::is_alphabetic) => (),
+                    n if is_known(n) || known(n) || known(n.trim_end_matches('*')) => (),
+                    _ => {
+                        let message = format!("MathJax does not know {}", name);
+                        problems.push(error(start..start + len, "unknown-math-macro", message));
+                    }
+                }
+            }
+            _ => (),
+        }
+        rest = &rest[len..];
+    }
+    for (o, at) in open {
+        problems.push(unclosed(o, at));
+    }
+    problems.sort_by_key(|p| p.span.start);
+    problems
+}
+
+/// The problem with leaving `open`, which was at `at`, unclosed.
+fn unclosed(open: Open, at: usize) -> MathError {
+    let (code, message, len) = match open {
+        Open::Brace => ("unbalanced-brace", "{ has no matching }".to_string(), 1),
+        Open::Left => (
+            "unmatched-left",
+            r"\left has no matching \right".to_string(),
+            r"\left".len(),
+        ),
+        Open::Begin(e) => (
+            "unclosed-environment",
+            format!(r"\begin{{{}}} has no matching \end{{{}}}", e, e),
+            format!(r"\begin{{{}}}", e).len(),
+        ),
+    };
+    MathError {
+        code,
+        message,
+        span: at..at + len,
+    }
+}
+
+#[test]
+fn test_check() {
+    let problems = |latex, environment| {
+        check(latex, environment, |_| false)
+            .into_iter()
+            .map(|p| (p.code, p.span))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(problems(r"\frac{a}{b} + \left( x \right)", None), vec![]);
+    assert_eq!(problems(r"a &= b \\ c &= d", Some("align*")), vec![]);
+    assert_eq!(
+        problems(r"\begin{pmatrix} 1 & 2 \end{pmatrix}", None),
+        vec![]
+    );
+    assert_eq!(problems(r"\text{a & b}", None), vec![]);
+    assert_eq!(problems(r"\{ x \}", None), vec![]);
+    assert_eq!(problems(r"x^{2", None), vec![("unbalanced-brace", 2..3)]);
+    assert_eq!(problems(r"x}", None), vec![("unbalanced-brace", 1..2)]);
+    assert_eq!(problems(r"\left( x", None), vec![("unmatched-left", 0..5)]);
+    assert_eq!(
+        problems(r"x \right)", None),
+        vec![("unmatched-right", 2..8)]
+    );
+    assert_eq!(
+        problems(r"a & b", Some("equation")),
+        vec![("misplaced-ampersand", 2..3)]
+    );
+    assert_eq!(
+        problems(r"\foo + \alpha", None),
+        vec![("unknown-math-macro", 0..4)]
+    );
+    assert_eq!(
+        problems(r"\begin{matrix} a \end{bmatrix}", None),
+        vec![("mismatched-end", 17..30)]
+    );
+    assert_eq!(
+        problems(r"\left( \begin{cases} a \right)", None),
+        vec![("unclosed-environment", 7..20)]
+    );
+    assert_eq!(
+        check(r"\ket{0}", None, |m| m == "ket"),
+        Vec::<MathError>::new()
+    );
+}
@@ -0,0 +1,184 @@
+//! Writing simple inline math, such as `$x^2$` or `$\alpha + \beta$`, as
+//! plain HTML, so that pages with nothing harder need no MathJax.
+
+use crate::math::{self, Math};
+use crate::mathml::escape;
+
+/// Operators that are not spaced like `+` or `=`.
+const UNSPACED: &[&str] = &[
+    "(", ")", "[", "]", "{", "}", "|", "/", "!", ".", "'", "\u{2032}", "\u{2016}", "\u{27e8}",
+    "\u{27e9}", "\u{230a}", "\u{230b}", "\u{2308}", "\u{2309}", "\u{2026}", "\u{22ef}",
+];
+
+/// Operators that may be signs, as in `-x`.
+const SIGNS: &[&str] = &["+", "\u{2212}", "\u{b1}", "\u{2213}"];
+
+/// The HTML for `latex`, or `None` if it is too complicated for
+/// anything but MathJax, as it is if it nests more than `max_depth` deep.
+pub(crate) fn html(latex: &str, max_depth: usize) -> Option<String> {
+    let math = math::parse(latex, None, max_depth).ok()?;
+    let mut out = String::new();
+    write(&mut out, &math, true)?;
+    Some(out)
+}
+
+/// Write `math`, with its variables in italics if `italic`.
+fn write(out: &mut String, math: &Math, italic: bool) -> Option<()> {
+    match math {
+        Math::Identifier(name) if italic && name.chars().all(|c| c.is_ascii_alphabetic()) => {
+            out.push_str("<i>");
+            escape(out, name);
+            out.push_str("</i>");
+        }
+        Math::Identifier(name) | Math::Number(name) | Math::Text(name) => escape(out, name),
+        Math::Function(name) => {
+            escape(out, name);
+            out.push(' ');
+        }
+        Math::Operator(op) => match op.as_str() {
+            "," | ";" | ":" => {
+                out.push_str(op);
+                out.push(' ');
+            }
+            op if UNSPACED.contains(&op) => operator(out, op),
+            op => {
+                out.push(' ');
+                operator(out, op);
+                out.push(' ');
+            }
+        },
+        Math::Space(width) => {
+            if !width.starts_with('-') {
+                out.push_str("&nbsp;");
+            }
+        }
+        Math::Row(items) => {
+            for (i, item) in items.iter().enumerate() {
+                // A sign is not spaced like a binary operator when there
+                // is nothing before it for it to act on.
+                let unary = match i.checked_sub(1).map(|i| &items[i]) {
+                    None => true,
+                    Some(Math::Operator(before)) => !matches!(before.as_str(), ")" | "]" | "|"),
+                    Some(_) => false,
+                };
+                match item {
+                    Math::Operator(op) if unary && SIGNS.contains(&op.as_str()) => {
+                        operator(out, op)
+                    }
+                    item => write(out, item, italic)?,
+                }
+            }
+        }
+        Math::Scripts {
+            base,
+            sub,
+            sup,
+            limits: false,
+        } => {
+            match &**base {
+                // The space after a function goes after its scripts.
+                Math::Function(name) => escape(out, name),
+                base => write(out, base, italic)?,
+            }
+            if let Some(sub) = sub {
+                out.push_str("<sub>");
+                write_script(out, sub, italic)?;
+                out.push_str("</sub>");
+            }
+            match sup.as_deref() {
+                Some(Math::Operator(circ)) if circ == "\u{2218}" => out.push_str("&deg;"),
+                Some(sup) => {
+                    out.push_str("<sup>");
+                    write_script(out, sup, italic)?;
+                    out.push_str("</sup>");
+                }
+                None => (),
+            }
+            if let Math::Function(_) = &**base {
+                out.push(' ');
+            }
+        }
+        Math::Fenced {
+            open,
+            close,
+            content,
+        } => {
+            escape(out, open);
+            write(out, content, italic)?;
+            escape(out, close);
+        }
+        Math::Variant {
+            variant: "normal",
+            content,
+        } => write(out, content, false)?,
+        Math::Variant {
+            variant: "bold",
+            content,
+        } => {
+            out.push_str("<b>");
+            write(out, content, false)?;
+            out.push_str("</b>");
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+fn operator(out: &mut String, op: &str) {
+    match op {
+        "\u{2212}" => out.push_str("&minus;"),
+        op => escape(out, op),
+    }
+}
+
+/// Write a subscript or superscript, which must not have scripts of its
+/// own, with its operators unspaced.
+fn write_script(out: &mut String, script: &Math, italic: bool) -> Option<()> {
+    let items = match script {
+        Math::Row(items) => items.as_slice(),
+        script => std::slice::from_ref(script),
+    };
+    for item in items {
+        match item {
+            Math::Scripts { .. } => return None,
+            Math::Operator(op) => operator(out, op),
+            item => write(out, item, italic)?,
+        }
+    }
+    Some(())
+}
+
+#[test]
+fn test_html() {
+    let html = |latex| html(latex, 64);
+    assert_eq!(Some("<i>x</i><sup>2</sup>".to_string()), html("x^2"));
+    assert_eq!(Some("<i>v</i><sub>0</sub>".to_string()), html("v_0"));
+    assert_eq!(Some("&#945; + &#946;".to_string()), html(r"\alpha + \beta"));
+    assert_eq!(Some("&#916;<i>t</i>".to_string()), html(r"\Delta t"));
+    assert_eq!(Some("2&#960;<i>r</i>".to_string()), html(r"2\pi r"));
+    assert_eq!(Some("<sub>2</sub>".to_string()), html("_2"));
+    assert_eq!(Some("90&deg;".to_string()), html(r"90^\circ"));
+    assert_eq!(
+        Some("&minus;<i>e</i><sup><i>i</i>&minus;1</sup>".to_string()),
+        html("-e^{i-1}")
+    );
+    assert_eq!(
+        Some("<i>f</i>(<i>x</i>) = sin <i>x</i>".to_string()),
+        html(r"f(x) = \sin x")
+    );
+    assert_eq!(
+        Some("<i>a</i> &minus; (&minus;<i>b</i>)".to_string()),
+        html("a - (-b)")
+    );
+    assert_eq!(
+        Some("log<sub>2</sub> <i>n</i>".to_string()),
+        html(r"\log_2 n")
+    );
+    assert_eq!(None, html(r"\frac{1}{2}"));
+    assert_eq!(None, html(r"x^{y^2}"));
+    assert_eq!(None, html(r"\sum_i x_i"));
+    assert_eq!(
+        None,
+        html(&format!("{}x{}", "{".repeat(5_000), "}".repeat(5_000)))
+    );
+}
@@ -0,0 +1,227 @@
+//! Writing math as MathML, so that it needs no JavaScript to display.
+
+use crate::math::{self, Math, MathError};
+use crate::MathStyle;
+use std::fmt::Write;
+
+/// The MathML for `latex`, nesting no more than `max_depth` deep, or why
+/// we could not make it.
+pub(crate) fn mathml(
+    latex: &str,
+    style: &MathStyle,
+    max_depth: usize,
+) -> Result<String, MathError> {
+    let latex = latex.replace(crate::LATEX_DBAR, r"{\mathit{\unicode{273}}}");
+    let environment = match style {
+        MathStyle::Environment(name) => Some(name.as_str()),
+        _ => None,
+    };
+    let math = math::parse(&latex, environment, max_depth).map_err(|mut e| {
+        // The span should not point past what we were given.
+        e.span = e.span.start.min(latex.len())..e.span.end.min(latex.len());
+        e
+    })?;
+    let mut out = String::new();
+    match style {
+        MathStyle::Inline => out.push_str("<math>"),
+        _ => out.push_str(r#"<math display="block">"#),
+    }
+    match &math {
+        Math::Row(items) => write_all(&mut out, items, None),
+        math => write(&mut out, math, None),
+    }
+    out.push_str("</math>");
+    Ok(out)
+}
+
+fn write_all(out: &mut String, items: &[Math], variant: Option<&str>) {
+    for item in items {
+        write(out, item, variant);
+    }
+}
+
+fn write_token(out: &mut String, tag: &str, attributes: &str, text: &str, variant: Option<&str>) {
+    out.push('<');
+    out.push_str(tag);
+    out.push_str(attributes);
+    if let Some(variant) = variant {
+        let _ = write!(out, r#" mathvariant="{}""#, variant);
+    }
+    out.push('>');
+    escape(out, text);
+    let _ = write!(out, "</{}>", tag);
+}
+
+fn write(out: &mut String, math: &Math, variant: Option<&str>) {
+    match math {
+        Math::Identifier(name) => {
+            // Capital Greek letters are upright, unlike other variables.
+            let upright = matches!(name.chars().next(), Some('\u{391}'..='\u{3a9}'));
+            match variant {
+                None if upright => write_token(out, "mi", r#" mathvariant="normal""#, name, None),
+                _ => write_token(out, "mi", "", name, variant),
+            }
+        }
+        Math::Function(name) => {
+            write_token(out, "mi", "", name, variant);
+            out.push_str("<mo>&#8289;</mo>");
+        }
+        Math::Number(n) => write_token(out, "mn", "", n, variant),
+        Math::Operator(op) => write_token(out, "mo", "", op, variant),
+        Math::LargeOperator(op) => write_token(out, "mo", r#" largeop="true""#, op, variant),
+        Math::Text(text) => write_token(out, "mtext", "", text, variant),
+        Math::Space(width) => {
+            let _ = write!(out, r#"<mspace width="{}"/>"#, width);
+        }
+        Math::Row(items) if items.len() == 1 => write(out, &items[0], variant),
+        Math::Row(items) => {
+            out.push_str("<mrow>");
+            write_all(out, items, variant);
+            out.push_str("</mrow>");
+        }
+        Math::Fraction {
+            numerator,
+            denominator,
+            line,
+        } => {
+            out.push_str(if *line {
+                "<mfrac>"
+            } else {
+                r#"<mfrac linethickness="0">"#
+            });
+            write(out, numerator, variant);
+            write(out, denominator, variant);
+            out.push_str("</mfrac>");
+        }
+        Math::Root {
+            radicand,
+            index: None,
+        } => {
+            out.push_str("<msqrt>");
+            write(out, radicand, variant);
+            out.push_str("</msqrt>");
+        }
+        Math::Root {
+            radicand,
+            index: Some(index),
+        } => {
+            out.push_str("<mroot>");
+            write(out, radicand, variant);
+            write(out, index, variant);
+            out.push_str("</mroot>");
+        }
+        Math::Scripts {
+            base,
+            sub,
+            sup,
+            limits,
+        } => {
+            let tag = match (sub, sup, limits) {
+                (Some(_), Some(_), true) => "munderover",
+                (Some(_), None, true) => "munder",
+                (None, _, true) => "mover",
+                (Some(_), Some(_), false) => "msubsup",
+                (Some(_), None, false) => "msub",
+                (None, _, false) => "msup",
+            };
+            let _ = write!(out, "<{}>", tag);
+            match &**base {
+                // The function application goes after the scripts.
+                Math::Function(name) => write_token(out, "mi", "", name, variant),
+                base => write(out, base, variant),
+            }
+            for script in sub.iter().chain(sup) {
+                write(out, script, variant);
+            }
+            let _ = write!(out, "</{}>", tag);
+            if let Math::Function(_) = &**base {
+                out.push_str("<mo>&#8289;</mo>");
+            }
+        }
+        Math::Accent { base, mark, over } => {
+            out.push_str(if *over {
+                r#"<mover accent="true">"#
+            } else {
+                r#"<munder accentunder="true">"#
+            });
+            write(out, base, variant);
+            write_token(out, "mo", r#" stretchy="true""#, mark, None);
+            out.push_str(if *over { "</mover>" } else { "</munder>" });
+        }
+        Math::Fenced {
+            open,
+            close,
+            content,
+        } => {
+            out.push_str("<mrow>");
+            if !open.is_empty() {
+                write_token(out, "mo", r#" fence="true" stretchy="true""#, open, None);
+            }
+            write(out, content, variant);
+            if !close.is_empty() {
+                write_token(out, "mo", r#" fence="true" stretchy="true""#, close, None);
+            }
+            out.push_str("</mrow>");
+        }
+        Math::Variant { variant, content } => write(out, content, Some(variant)),
+        Math::Table { rows, align } => {
+            let _ = write!(out, r#"<mtable columnalign="{}">"#, align);
+            for row in rows {
+                out.push_str("<mtr>");
+                for cell in row {
+                    out.push_str("<mtd>");
+                    match cell {
+                        Math::Row(items) => write_all(out, items, variant),
+                        cell => write(out, cell, variant),
+                    }
+                    out.push_str("</mtd>");
+                }
+                out.push_str("</mtr>");
+            }
+            out.push_str("</mtable>");
+        }
+    }
+}
+
+/// Escape `text` for MathML or an attribute, writing anything that is not ASCII as a
+/// numeric character reference, as we do in HTML.
+pub(crate) fn escape(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            c if c.is_ascii() => out.push(c),
+            c => {
+                let _ = write!(out, "&#{};", c as u32);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_mathml() {
+    let inline = |latex| mathml(latex, &MathStyle::Inline, 64).map_err(|e| e.code);
+    assert_eq!(
+        Ok("<math><msup><mi>x</mi><mn>2</mn></msup></math>".to_string()),
+        inline("x^2")
+    );
+    assert_eq!(
+        Ok("<math><mi>a</mi><mo>&lt;</mo><mi>b</mi></math>".to_string()),
+        inline("a<b")
+    );
+    assert_eq!(
+        Ok(r#"<math><mi mathvariant="normal">&#937;</mi></math>"#.to_string()),
+        inline(r"\Omega")
+    );
+    assert_eq!(
+        Ok(r#"<math><mi mathvariant="bold">v</mi></math>"#.to_string()),
+        inline(r"\mathbf{v}")
+    );
+    assert_eq!(
+        Ok(r#"<math><mi mathvariant="italic">&#273;</mi></math>"#.to_string()),
+        inline(r"{\mkern3mu\mathchar'26\mkern-12mu d}")
+    );
+    assert_eq!(Err("unsupported-math"), inline(r"\foo"));
+}
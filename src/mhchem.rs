@@ -0,0 +1,366 @@
+//! Chemical formulae and equations, written as with the `mhchem`
+//! package's `\ce` and `\pu`.
+
+use crate::parse::{defined_in_math, inside, optional_argument, rewrite_macros};
+use crate::{siunitx, UnitOptions};
+
+/// The `mhchem` macros we understand, without their backslashes.
+pub(crate) const MACROS: &[&str] = &["ce", "pu"];
+
+/// The arrows of a reaction, longest first, with how they are written
+/// in HTML and in math.
+const ARROWS: &[(&str, &str, &str)] = &[
+    ("<=>>", "&rlhar;", r"\rightleftharpoons"),
+    ("<<=>", "&rlhar;", r"\rightleftharpoons"),
+    ("<-->", "&rlarr;", r"\rightleftarrows"),
+    ("<=>", "&rlhar;", r"\rightleftharpoons"),
+    ("<->", "&harr;", r"\leftrightarrow"),
+    ("->", "&rarr;", r"\longrightarrow"),
+    ("<-", "&larr;", r"\longleftarrow"),
+];
+
+/// The states that may follow a formula, as in `NaCl(aq)`.
+const STATES: &[&str] = &["(s)", "(l)", "(g)", "(aq)"];
+
+/// A piece of a formula such as `SO4^2-`.
+#[derive(Debug, PartialEq, Eq)]
+enum Part {
+    /// Upright text, such as an element or a coefficient.
+    Text(String),
+    Sub(String),
+    Sup(String),
+    /// The dot of an adduct such as `CuSO4*5H2O`.
+    Dot,
+}
+
+/// A piece of a reaction.
+#[derive(Debug, PartialEq, Eq)]
+enum Item {
+    Formula(Vec<Part>),
+    Plus,
+    /// An arrow from [`ARROWS`], with what is written above and below
+    /// it.
+    Arrow(usize, Vec<Item>, Vec<Item>),
+    /// A gas given off, written `^`.
+    Gas,
+    /// A precipitate, written `v`.
+    Precipitate,
+}
+
+/// Read a superscript or subscript after its `^` or `_`, which is
+/// either braced or a number with an optional charge.
+fn script(latex: &str) -> Option<(&str, &str)> {
+    if latex.starts_with('{') {
+        let arg = crate::parse::argument(latex);
+        if arg.len() < 2 {
+            return None;
+        }
+        let script = inside(arg);
+        if !script
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+        {
+            return None;
+        }
+        return Some((script, &latex[arg.len()..]));
+    }
+    let digits = latex
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(latex.len());
+    let len = digits + usize::from(latex[digits..].starts_with(['+', '-']));
+    if len == 0 {
+        return None;
+    }
+    Some(latex.split_at(len))
+}
+
+/// Read a single formula such as `2H2O`, `SO4^2-` or `^{14}_{6}C`.
+fn formula(mut latex: &str) -> Option<Vec<Part>> {
+    let mut parts = Vec::new();
+    // Digits are a coefficient at the start and after the dot of an
+    // adduct, and a subscript anywhere else.
+    let mut coefficient = true;
+    while let Some(c) = latex.chars().next() {
+        let len = if c.is_ascii_uppercase() {
+            1 + latex[1..]
+                .find(|c: char| !c.is_ascii_lowercase())
+                .unwrap_or(latex.len() - 1)
+        } else if c.is_ascii_lowercase() {
+            latex
+                .find(|c: char| !c.is_ascii_lowercase())
+                .unwrap_or(latex.len())
+        } else if c.is_ascii_digit() && coefficient {
+            latex
+                .find(|c: char| !c.is_ascii_digit() && c != '.' && c != '/')
+                .unwrap_or(latex.len())
+        } else if c.is_ascii_digit() {
+            let len = latex
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(latex.len());
+            parts.push(Part::Sub(latex[..len].to_string()));
+            latex = &latex[len..];
+            continue;
+        } else if c == '^' || c == '_' {
+            let (script, rest) = script(&latex[1..])?;
+            parts.push(if c == '^' {
+                Part::Sup(script.to_string())
+            } else {
+                Part::Sub(script.to_string())
+            });
+            latex = rest;
+            coefficient = false;
+            continue;
+        } else if (c == '+' || c == '-')
+            && !parts.is_empty()
+            && (latex.len() == 1 || STATES.contains(&&latex[1..]))
+        {
+            // A charge, which ends the formula but for its state.
+            parts.push(Part::Sup(c.to_string()));
+            latex = &latex[1..];
+            continue;
+        } else if c == '*' || c == '.' {
+            parts.push(Part::Dot);
+            latex = &latex[1..];
+            coefficient = true;
+            continue;
+        } else if matches!(c, '(' | ')' | '[' | ']' | '-' | '=') {
+            1
+        } else {
+            return None;
+        };
+        coefficient = false;
+        match parts.last_mut() {
+            Some(Part::Text(text)) => text.push_str(&latex[..len]),
+            _ => parts.push(Part::Text(latex[..len].to_string())),
+        }
+        latex = &latex[len..];
+    }
+    if parts.is_empty() {
+        return None;
+    }
+    Some(parts)
+}
+
+/// Read the content of a `\ce`, or `None` if we cannot make sense of
+/// it.
+fn reaction(mut latex: &str) -> Option<Vec<Item>> {
+    let mut items = Vec::new();
+    latex = latex.trim_start();
+    while !latex.is_empty() {
+        if let Some(i) = ARROWS.iter().position(|(a, _, _)| latex.starts_with(a)) {
+            latex = &latex[ARROWS[i].0.len()..];
+            let mut labels = [Vec::new(), Vec::new()];
+            for label in labels.iter_mut() {
+                let o = optional_argument(latex);
+                if o.is_empty() {
+                    break;
+                } else if o.len() < 2 {
+                    return None;
+                }
+                latex = &latex[o.len()..];
+                *label = reaction(&o[1..o.len() - 1])?;
+            }
+            let [above, below] = labels;
+            items.push(Item::Arrow(i, above, below));
+        } else {
+            let len = latex.find(char::is_whitespace).unwrap_or(latex.len());
+            items.push(match &latex[..len] {
+                "+" => Item::Plus,
+                "^" => Item::Gas,
+                "v" => Item::Precipitate,
+                token => Item::Formula(formula(token)?),
+            });
+            latex = &latex[len..];
+        }
+        latex = latex.trim_start();
+    }
+    Some(items)
+}
+
+/// How to write the pieces of a reaction.
+struct Notation {
+    html: bool,
+}
+
+impl Notation {
+    fn parts(&self, parts: &[Part]) -> String {
+        let mut out = String::new();
+        for part in parts {
+            match (part, self.html) {
+                (Part::Text(text), true) => out.push_str(text),
+                (Part::Text(text), false) => {
+                    out.push_str(&format!(r"\mathrm{{{}}}", text));
+                }
+                (Part::Sub(sub), true) => out.push_str(&format!("<sub>{}</sub>", sub)),
+                (Part::Sup(sup), true) => {
+                    let sup = sup.replace('-', "&minus;");
+                    out.push_str(&format!("<sup>{}</sup>", sup));
+                }
+                (Part::Sub(script), false) | (Part::Sup(script), false) => {
+                    if out.is_empty() {
+                        // An isotope needs something to attach to.
+                        out.push_str("{}");
+                    }
+                    let mark = if matches!(part, Part::Sub(_)) {
+                        '_'
+                    } else {
+                        '^'
+                    };
+                    out.push_str(&format!("{}{{{}}}", mark, script));
+                }
+                (Part::Dot, true) => out.push_str("&middot;"),
+                (Part::Dot, false) => out.push_str(r"\cdot "),
+            }
+        }
+        out
+    }
+
+    fn items(&self, items: &[Item]) -> String {
+        let written: Vec<String> = items
+            .iter()
+            .map(|item| match item {
+                Item::Formula(parts) => self.parts(parts),
+                Item::Plus => "+".to_string(),
+                Item::Gas if self.html => "&uarr;".to_string(),
+                Item::Gas => r"\uparrow".to_string(),
+                Item::Precipitate if self.html => "&darr;".to_string(),
+                Item::Precipitate => r"\downarrow".to_string(),
+                Item::Arrow(i, above, below) => {
+                    let (_, html, tex) = ARROWS[*i];
+                    let (above, below) = (self.items(above), self.items(below));
+                    match (self.html, above.is_empty() && below.is_empty()) {
+                        (true, true) => html.to_string(),
+                        (false, true) => tex.to_string(),
+                        (true, false) if below.is_empty() => {
+                            format!(r#"<span class="arrow">{}<sup>{}</sup></span>"#, html, above)
+                        }
+                        (true, false) => format!(
+                            r#"<span class="arrow">{}<sup>{}</sup><sub>{}</sub></span>"#,
+                            html, above, below
+                        ),
+                        (false, false) if below.is_empty() => {
+                            format!(r"\overset{{{}}}{{{}}}", above, tex)
+                        }
+                        (false, false) => {
+                            format!(
+                                r"\underset{{{}}}{{\overset{{{}}}{{{}}}}}",
+                                below, above, tex
+                            )
+                        }
+                    }
+                }
+            })
+            .collect();
+        written.join(" ")
+    }
+}
+
+/// Turn the content of a `\pu` into a value and units as `siunitx`
+/// writes them, so that `123 kJ mol-1` becomes `123` and
+/// `kJ mol^{-1}`.
+fn physical_unit(latex: &str) -> Option<(Option<&str>, String)> {
+    let latex = latex.trim();
+    let (value, mut rest) = match latex.split_once(' ') {
+        Some((value, units)) if siunitx::html("num", &[value], &UnitOptions::new()).is_some() => {
+            (Some(value), units)
+        }
+        _ if siunitx::html("num", &[latex], &UnitOptions::new()).is_some() => (Some(latex), ""),
+        _ => (None, latex),
+    };
+    let mut units = String::new();
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_alphabetic() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(rest.len());
+            units.push_str(&rest[..len]);
+            rest = &rest[len..];
+            let caret = usize::from(rest.starts_with('^'));
+            let sign = usize::from(rest[caret..].starts_with('-'));
+            let digits = rest[caret + sign..]
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len() - caret - sign);
+            if digits > 0 {
+                units.push_str(&format!("^{{{}}}", &rest[caret..caret + sign + digits]));
+                rest = &rest[caret + sign + digits..];
+            }
+        } else if matches!(c, ' ' | '.' | '*' | '/') {
+            units.push(if c == '*' { '.' } else { c });
+            rest = &rest[1..];
+        } else {
+            return None;
+        }
+    }
+    Some((value, units))
+}
+
+fn format(name: &str, args: &[&str], options: &UnitOptions, html: bool) -> Option<String> {
+    let siunitx = if html { siunitx::html } else { siunitx::tex };
+    match (name, args) {
+        ("ce", [latex]) => Some(Notation { html }.items(&reaction(latex)?)),
+        ("pu", [latex]) => match physical_unit(latex)? {
+            (Some(value), units) if units.is_empty() => siunitx("num", &[value], options),
+            (Some(value), units) => siunitx("qty", &[value, &units], options),
+            (None, units) => siunitx("unit", &[&units], options),
+        },
+        _ => None,
+    }
+}
+
+/// The HTML for the `mhchem` macro `name` with arguments `args`, or
+/// `None` if we cannot make sense of them.
+pub(crate) fn html(name: &str, args: &[&str], options: &UnitOptions) -> Option<String> {
+    format(name, args, options, true)
+}
+
+/// Rewrite the `mhchem` macros in `math` as plain LaTeX that MathJax
+/// understands without its `mhchem` extension, or `None` if there are
+/// none.  Any the math defines for itself are left for MathJax.
+pub(crate) fn expand_in_math(math: &str, options: &UnitOptions) -> Option<String> {
+    let defined = defined_in_math(math);
+    let arguments = |name: &str| (MACROS.contains(&name) && !defined.contains(name)).then_some(1);
+    rewrite_macros(math, arguments, |name, args| {
+        format(name, args, options, false)
+    })
+}
+
+#[test]
+fn test_formula() {
+    let html = |latex| reaction(latex).map(|items| Notation { html: true }.items(&items));
+    assert_eq!(Some("H<sub>2</sub>O".to_string()), html("H2O"));
+    assert_eq!(Some("2H<sub>2</sub>O".to_string()), html("2H2O"));
+    assert_eq!(
+        Some("SO<sub>4</sub><sup>2&minus;</sup>".to_string()),
+        html("SO4^2-")
+    );
+    assert_eq!(Some("Fe<sup>3+</sup>".to_string()), html("Fe^{3+}"));
+    assert_eq!(Some("OH<sup>&minus;</sup>".to_string()), html("OH-"));
+    assert_eq!(
+        Some("<sup>14</sup><sub>6</sub>C".to_string()),
+        html("^{14}_{6}C")
+    );
+    assert_eq!(
+        Some("CuSO<sub>4</sub>&middot;5H<sub>2</sub>O".to_string()),
+        html("CuSO4*5H2O")
+    );
+    assert_eq!(Some("NaCl(aq)".to_string()), html("NaCl(aq)"));
+    assert_eq!(
+        Some("OH<sup>&minus;</sup>(aq)".to_string()),
+        html("OH-(aq)")
+    );
+    assert_eq!(None, html("H+(x)"));
+    assert_eq!(Some("CO<sub>2</sub> &uarr;".to_string()), html("CO2 ^"));
+    assert_eq!(None, html("H2O!"));
+    assert_eq!(None, html("->[unclosed"));
+}
+
+#[test]
+fn test_physical_unit() {
+    assert_eq!(
+        Some((Some("123"), "kJ mol^{-1}".to_string())),
+        physical_unit("123 kJ mol-1")
+    );
+    assert_eq!(Some((None, "m/s^{2}".to_string())), physical_unit("m/s^2"));
+    assert_eq!(Some((Some("1.2e3"), String::new())), physical_unit("1.2e3"));
+    assert_eq!(None, physical_unit("12 (kJ)"));
+}
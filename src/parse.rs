@@ -0,0 +1,1622 @@
+//! Parsing LaTeX into a [`Document`].
+
+use crate::environs::{self, Arg, MathEnviron, Placement};
+use crate::macros::{definition_len, expand_definitions, is_definer, Expanded};
+use crate::source_map::Rewritten;
+use crate::{mhchem, references, siunitx, text, UnitOptions};
+use crate::{
+    pull_sections_out_of_environ, strip_comments_mapped, Argument, Diagnostic, Document,
+    Expansions, MathStyle, Node, NodeKind, RenderLimits, Severity, EXPANSION_LIMIT,
+};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::LazyLock;
+
+/// Parse some LaTeX into a [`Document`].
+///
+/// Like [`html`](crate::html), this strips comments, expands any macros
+/// defined with `\newcommand` and pulls (sub)sections out of
+/// solutions, guides and handouts first.
+pub fn parse(latex: &str) -> Document {
+    parse_with_diagnostics(latex).0
+}
+
+/// Parse some LaTeX into a [`Document`], also returning any problems
+/// found, with spans in `latex`.
+pub fn parse_with_diagnostics(latex: &str) -> (Document, Vec<Diagnostic>) {
+    let mut budget = Expansions::new(&RenderLimits::new());
+    parse_rewritten(Rewritten::new(latex), &mut budget)
+}
+
+/// Parse LaTeX that may already have been rewritten, with spans in the
+/// text it was rewritten from.
+pub(crate) fn parse_rewritten(
+    latex: Rewritten,
+    budget: &mut Expansions,
+) -> (Document, Vec<Diagnostic>) {
+    let latex = latex.then(strip_comments_mapped);
+    let Expanded {
+        latex: expanded,
+        diagnostics: mut definition_diagnostics,
+        names,
+    } = expand_definitions(&latex.text, budget);
+    for d in definition_diagnostics.iter_mut() {
+        d.span = latex.map.original_range(d.span.clone());
+    }
+    let latex = latex
+        .then(|_| expanded)
+        .then(|latex| pull_sections_out_of_environ(latex, "handout"))
+        .then(|latex| pull_sections_out_of_environ(latex, "guide"))
+        .then(|latex| pull_sections_out_of_environ(latex, "solution"));
+    let mut parser = Parser::new(&latex.text)
+        .max_depth(budget.max_depth)
+        .defined(names);
+    let mut content = parser.sections(&latex.text, 1);
+    let mut diagnostics = parser.finish();
+    for d in diagnostics.iter_mut() {
+        d.span = latex.map.original_range(d.span.clone());
+    }
+    diagnostics.extend(definition_diagnostics);
+    diagnostics.sort_by_key(|d| d.span.start);
+    for node in content.iter_mut() {
+        node.walk_mut(&mut |node| node.span = latex.map.original_range(node.span.clone()));
+    }
+    (Document { content }, diagnostics)
+}
+
+/// What [`finish_paragraph`] looks for, in a single pass.
+static PARAGRAPH_TOKEN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\n\s*\n|\\begin\{|\\end\{").unwrap());
+/// What [`Ends`] looks for, besides braces.
+static END_TOKEN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\\end\{[^{}\\]*\}|\\\)|\\\]").unwrap());
+/// What [`finish_item`] looks for, in a single pass.
+static ITEM_TOKEN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\\item|\\(begin|end)\{(itemize|enumerate|description)\}").unwrap()
+});
+
+const HEADINGS: [&str; 3] = [r"\section", r"\subsection", r"\subsubsection"];
+
+/// The `{`s in a source with the `}`s that match them, and the `[`s
+/// with the `]`s that close them, found in a single pass so that each
+/// unclosed `{` or `[` need not look through the rest of it.
+pub(crate) struct Braces<'a> {
+    source: &'a str,
+    /// The offset of each `{`, with that of its `}` if it has one.
+    braces: Vec<(usize, Option<usize>)>,
+    /// The offset of each `[`, with that of the first `]` after it in
+    /// the same group, if there is one.
+    brackets: Vec<(usize, Option<usize>)>,
+}
+
+impl<'a> Braces<'a> {
+    pub(crate) fn new(source: &'a str) -> Self {
+        let mut braces = Vec::new();
+        let mut open = Vec::new();
+        let mut brackets = Vec::new();
+        // The `[`s not yet closed in each group that is open.
+        let mut unclosed: Vec<Vec<usize>> = vec![Vec::new()];
+        let mut escaped = false;
+        for (i, c) in source.char_indices() {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '{' {
+                open.push(braces.len());
+                braces.push((i, None));
+                unclosed.push(Vec::new());
+            } else if c == '}' {
+                if let Some(j) = open.pop() {
+                    braces[j].1 = Some(i);
+                    unclosed.pop();
+                } else {
+                    unclosed[0].clear();
+                }
+            } else if c == '[' {
+                if let Some(group) = unclosed.last_mut() {
+                    group.push(brackets.len());
+                }
+                brackets.push((i, None));
+            } else if c == ']' {
+                for j in unclosed
+                    .last_mut()
+                    .into_iter()
+                    .flat_map(|group| group.drain(..))
+                {
+                    brackets[j].1 = Some(i);
+                }
+            }
+        }
+        Braces {
+            source,
+            braces,
+            brackets,
+        }
+    }
+
+    /// The offset of `latex`, if it is a slice of our source.
+    pub(crate) fn within(&self, latex: &str) -> Option<usize> {
+        let offset = (latex.as_ptr() as usize).checked_sub(self.source.as_ptr() as usize)?;
+        Some(offset).filter(|&offset| offset + latex.len() <= self.source.len())
+    }
+
+    /// The argument at the start of `latex`, as [`argument`] finds it.
+    pub(crate) fn argument<'l>(&self, latex: &'l str) -> &'l str {
+        if latex.starts_with('{') {
+            if let Some(offset) = self.within(latex) {
+                if let Ok(i) = self.braces.binary_search_by_key(&offset, |b| b.0) {
+                    let close = self.braces[i]
+                        .1
+                        .filter(|&close| close < offset + latex.len());
+                    return &latex[..close.map_or(1, |close| close - offset + 1)];
+                }
+            }
+        }
+        argument(latex)
+    }
+
+    /// The optional argument at the start of `latex`, as
+    /// [`optional_argument`] finds it.
+    pub(crate) fn optional_argument<'l>(&self, latex: &'l str) -> &'l str {
+        if latex.starts_with('[') {
+            if let Some(offset) = self.within(latex) {
+                if let Ok(i) = self.brackets.binary_search_by_key(&offset, |b| b.0) {
+                    let close = self.brackets[i]
+                        .1
+                        .filter(|&close| close < offset + latex.len());
+                    return &latex[..close.map_or(1, |close| close - offset + 1)];
+                }
+            }
+        }
+        optional_argument(latex)
+    }
+}
+
+/// Where things in a source end, found in a single pass so that each
+/// unclosed `{` or `\begin` need not look through the rest of it.
+struct Ends<'a> {
+    braces: Braces<'a>,
+    /// The offsets of each `\end{...}`, `\)` and `\]`.
+    tokens: HashMap<&'a str, Vec<usize>>,
+    /// The offset of each [`ITEM_TOKEN`], with how many lists are open
+    /// before it.
+    items: Vec<(usize, isize)>,
+    /// The spans of the `\item`s and list `\end`s with each number of
+    /// lists open before them, which is where [`finish_item`] stops.
+    item_ends: HashMap<isize, Vec<Range<usize>>>,
+}
+
+impl<'a> Ends<'a> {
+    fn new(source: &'a str) -> Self {
+        let mut tokens: HashMap<&'a str, Vec<usize>> = HashMap::new();
+        for m in END_TOKEN.find_iter(source) {
+            tokens.entry(m.as_str()).or_default().push(m.start());
+        }
+        let mut items = Vec::new();
+        let mut item_ends: HashMap<isize, Vec<Range<usize>>> = HashMap::new();
+        let mut open = 0;
+        for c in ITEM_TOKEN.captures_iter(source) {
+            let m = c.get(0).unwrap();
+            items.push((m.start(), open));
+            match c.get(1).map(|m| m.as_str()) {
+                Some("begin") => open += 1,
+                ending => {
+                    item_ends.entry(open).or_default().push(m.range());
+                    if ending.is_some() {
+                        open -= 1;
+                    }
+                }
+            }
+        }
+        Ends {
+            braces: Braces::new(source),
+            tokens,
+            items,
+            item_ends,
+        }
+    }
+
+    /// The offset of the first `token` from `offset` that ends by `end`,
+    /// or `None` if `token` is not one we know of.
+    fn find(&self, offset: usize, end: usize, token: &str) -> Option<Option<usize>> {
+        if END_TOKEN.find(token).map(|m| m.len()) != Some(token.len()) {
+            return None;
+        }
+        let found = self.tokens.get(token).and_then(|offsets| {
+            let i = offsets.partition_point(|&o| o < offset);
+            offsets.get(i).filter(|&&o| o + token.len() <= end).copied()
+        });
+        Some(found)
+    }
+
+    /// The offset at which [`finish_item`] stops from `offset`, if that
+    /// comes before `end`.
+    fn item(&self, offset: usize, end: usize) -> Option<usize> {
+        let i = self.items.partition_point(|&(o, _)| o < offset);
+        let open = self.items.get(i).map_or(0, |&(_, open)| open);
+        let ends = self.item_ends.get(&open)?;
+        let i = ends.partition_point(|e| e.start < offset);
+        ends.get(i).filter(|e| e.end <= end).map(|e| e.start)
+    }
+}
+
+/// Parses slices of a single source, collecting diagnostics as it goes.
+pub(crate) struct Parser<'a> {
+    source: &'a str,
+    ends: Ends<'a>,
+    diagnostics: Vec<Diagnostic>,
+    depth: usize,
+    max_depth: usize,
+    /// Macros defined with `\newcommand` and environments defined with
+    /// `\newenvironment`, which we report any problems with as we expand
+    /// them.
+    defined: HashSet<String>,
+    /// Whether we are parsing the title of a heading, which cannot hold
+    /// a table of contents, since that holds the title.
+    in_title: bool,
+}
+
+impl<'a> Parser<'a> {
+    pub(crate) fn new(source: &'a str) -> Self {
+        Parser {
+            source,
+            ends: Ends::new(source),
+            diagnostics: Vec::new(),
+            depth: 0,
+            max_depth: RenderLimits::new().depth(),
+            defined: HashSet::new(),
+            in_title: false,
+        }
+    }
+
+    pub(crate) fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    pub(crate) fn defined(mut self, names: HashSet<String>) -> Self {
+        self.defined = names;
+        self
+    }
+
+    /// The diagnostics found, in source order.
+    pub(crate) fn finish(mut self) -> Vec<Diagnostic> {
+        self.diagnostics.sort_by_key(|d| d.span.start);
+        self.diagnostics
+    }
+
+    /// A node for the LaTeX from `start` up to `rest`.
+    fn node(&self, start: &str, rest: &str, kind: NodeKind) -> Node {
+        Node::new(kind, self.offset(start)..self.offset(rest))
+    }
+
+    /// A node for all of `latex`.
+    fn whole(&self, latex: &str, kind: NodeKind) -> Node {
+        self.node(latex, &latex[latex.len()..], kind)
+    }
+
+    /// The offset of `latex`, if it is a slice of our source.
+    fn within(&self, latex: &str) -> Option<usize> {
+        self.ends.braces.within(latex)
+    }
+
+    /// The argument at the start of `latex`, as [`argument`] finds it.
+    fn argument(&self, latex: &'a str) -> &'a str {
+        self.ends.braces.argument(latex)
+    }
+
+    /// The optional argument at the start of `latex`, as
+    /// [`optional_argument`] finds it.
+    fn optional_argument(&self, latex: &'a str) -> &'a str {
+        self.ends.braces.optional_argument(latex)
+    }
+
+    /// Where `token` first appears in `latex`, as `latex.find(token)`
+    /// finds it.
+    fn find(&self, latex: &'a str, token: &str) -> Option<usize> {
+        let offset = self.within(latex);
+        match offset.and_then(|offset| self.ends.find(offset, offset + latex.len(), token)) {
+            Some(found) => found.map(|found| found - offset.unwrap_or_default()),
+            None => latex.find(token),
+        }
+    }
+
+    /// The `latex` up to the next `\item` or end of its list, as
+    /// [`finish_item`] finds it.
+    fn finish_item(&self, latex: &'a str) -> &'a str {
+        match self.within(latex) {
+            Some(offset) => self
+                .ends
+                .item(offset, offset + latex.len())
+                .map_or("", |i| &latex[..i - offset]),
+            None => finish_item(latex),
+        }
+    }
+
+    /// The offset of `latex`, which must be a slice of our source.
+    fn offset(&self, latex: &str) -> usize {
+        (latex.as_ptr() as usize)
+            .saturating_sub(self.source.as_ptr() as usize)
+            .min(self.source.len())
+    }
+
+    fn diagnose(
+        &mut self,
+        start: &str,
+        rest: &str,
+        severity: Severity,
+        code: &'static str,
+        message: String,
+    ) {
+        let span = self.offset(start)..self.offset(rest);
+        self.diagnostics.push(Diagnostic {
+            code,
+            severity,
+            message,
+            span,
+        });
+    }
+
+    /// An error for the LaTeX from `start` up to `rest`.
+    fn error(
+        &mut self,
+        start: &'a str,
+        rest: &'a str,
+        code: &'static str,
+        message: String,
+    ) -> Node {
+        let len = rest.as_ptr() as usize - start.as_ptr() as usize;
+        let latex = &start[..len];
+        self.diagnose(start, rest, Severity::Error, code, message);
+        self.node(start, rest, NodeKind::Error(latex.to_string()))
+    }
+
+    fn unbalanced(&mut self, start: &'a str, rest: &'a str, name: &str) -> Node {
+        let message = format!("{} is missing a closing brace", name);
+        self.error(start, rest, "unbalanced-brace", message)
+    }
+
+    fn unclosed(&mut self, start: &'a str, rest: &'a str, name: &str) -> Node {
+        let message = format!(r"\begin{{{}}} has no matching \end{{{}}}", name, name);
+        self.error(start, rest, "unclosed-environment", message)
+    }
+
+    fn expansion_limit(&mut self, start: &'a str, rest: &'a str) -> Node {
+        let message = "too many macros to expand, or they expand to too much".to_string();
+        self.error(start, rest, "expansion-limit", message)
+    }
+
+    /// Flag characters that we render as errors.
+    fn check_ascii(&mut self, latex: &'a str) {
+        let mut rest = latex;
+        while let Some(i) = rest.find(|c: char| !c.is_ascii()) {
+            let run = &rest[i..];
+            let len = run.find(|c: char| c.is_ascii()).unwrap_or(run.len());
+            let message = format!(
+                "non-ASCII text {:?} should be written using LaTeX",
+                &run[..len]
+            );
+            self.diagnose(run, &run[len..], Severity::Error, "non-ascii", message);
+            rest = &run[len..];
+        }
+    }
+
+    fn text(&mut self, latex: &'a str) -> Node {
+        self.check_ascii(latex);
+        self.whole(latex, NodeKind::Text(latex.to_string()))
+    }
+
+    fn verbatim(&mut self, latex: &'a str) -> Argument {
+        self.check_ascii(latex);
+        Argument::Verbatim(latex.to_string())
+    }
+
+    /// Math holding `latex`, delimited by everything from `start` up
+    /// to `rest`.
+    fn math(&mut self, style: MathStyle, latex: &'a str, start: &'a str, rest: &'a str) -> Node {
+        if latex.contains(EXPANSION_LIMIT) {
+            return self.expansion_limit(start, rest);
+        }
+        self.check_ascii(latex);
+        let latex = latex.to_string();
+        self.node(start, rest, NodeKind::Math { style, latex })
+    }
+
+    /// The math environment `name` holding `latex`, delimited by
+    /// everything from `start` up to `rest`.
+    fn math_environment(
+        &mut self,
+        environ: &MathEnviron,
+        name: &str,
+        latex: &'a str,
+        start: &'a str,
+        rest: &'a str,
+    ) -> Node {
+        let arg = self.argument(latex.trim_start());
+        let braced = arg.len() > 1 && arg.starts_with('{');
+        match environ.argument {
+            Some(Arg::Pairs) => {
+                let pairs = inside(arg).trim().parse::<usize>().ok();
+                let pairs = match pairs.filter(|&n| braced && n > 0) {
+                    Some(pairs) => pairs,
+                    None => {
+                        let message = format!(
+                            r"\begin{{{}}} needs a positive number of columns, not {:?}",
+                            name, arg
+                        );
+                        return self.error(start, rest, "invalid-argument", message);
+                    }
+                };
+                let body = &latex[latex.len() - latex.trim_start().len() + arg.len()..];
+                for row in environs::rows(body) {
+                    let columns = environs::separators(&body[row.clone()]) + 1;
+                    if columns > 2 * pairs {
+                        let message = format!(
+                            r"a row with {} columns is too wide for \begin{{{}}}{{{}}}",
+                            columns, name, pairs
+                        );
+                        let (row, rest) = (&body[row.start..], &body[row.end..]);
+                        self.diagnose(row, rest, Severity::Error, "too-many-columns", message);
+                    }
+                }
+            }
+            Some(Arg::Columns) if !braced => {
+                let message = format!(r"\begin{{{}}} needs its columns specified", name);
+                return self.error(start, rest, "missing-argument", message);
+            }
+            _ => (),
+        }
+        let style = match environ.placement {
+            Placement::Inline => MathStyle::Inline,
+            Placement::Display => MathStyle::Display,
+            Placement::Environment => MathStyle::Environment(name.to_string()),
+            Placement::Inner => {
+                // This belongs in math, so we put the whole environment
+                // in display math.
+                let len = start.len() - rest.len();
+                return self.math(MathStyle::Display, &start[..len], start, rest);
+            }
+        };
+        self.math(style, latex, start, rest)
+    }
+
+    /// Parse content that may hold headings of `level` or deeper.
+    pub(crate) fn sections(&mut self, mut latex: &'a str, level: u8) -> Vec<Node> {
+        let heading = if let Some(heading) = HEADINGS.get(level as usize - 1) {
+            heading
+        } else {
+            return self.paragraphs(latex);
+        };
+        let i = latex.find(heading).unwrap_or(latex.len());
+        let mut nodes = self.sections(&latex[..i], level + 1);
+        latex = &latex[i..];
+        while !latex.is_empty() {
+            let start = latex;
+            latex = &latex[heading.len()..];
+            let starred = latex.starts_with('*');
+            if starred {
+                latex = &latex[1..];
+            }
+            let short_title = match self.optional_argument(latex) {
+                opt if opt.len() > 1 => {
+                    latex = &latex[opt.len()..];
+                    Some(&opt[1..opt.len() - 1])
+                }
+                _ => None,
+            };
+            let title = self.argument(latex);
+            latex = &latex[title.len()..];
+            let error = if title == "{" {
+                Some(self.unbalanced(start, latex, heading))
+            } else {
+                None
+            };
+            let end = latex.find(heading).unwrap_or(latex.len());
+            let content = self.sections(&latex[..end], level + 1);
+            latex = &latex[end..];
+            if let Some(error) = error {
+                nodes.push(error);
+                nodes.extend(content);
+            } else {
+                let in_title = std::mem::replace(&mut self.in_title, true);
+                let short_title = short_title.map(|short| self.inline(short));
+                let title = self.nested(title, Self::group);
+                self.in_title = in_title;
+                let section = NodeKind::Section {
+                    level,
+                    starred,
+                    short_title,
+                    title,
+                    content,
+                };
+                nodes.push(self.node(start, latex, section));
+            }
+        }
+        nodes
+    }
+
+    /// Parse content that may hold several paragraphs.
+    ///
+    /// If there is just one paragraph, its content is returned directly.
+    pub(crate) fn paragraphs(&mut self, mut latex: &'a str) -> Vec<Node> {
+        let am_alone = finish_paragraph(latex).len() == latex.len();
+        let mut nodes = Vec::new();
+        loop {
+            let p = finish_paragraph(latex);
+            latex = &latex[p.len()..];
+            if p.is_empty() {
+                return nodes;
+            }
+            if p.trim().is_empty() {
+                continue;
+            }
+            if am_alone {
+                nodes.extend(self.inline(p));
+            } else {
+                let content = self.inline(p);
+                nodes.push(self.whole(p, NodeKind::Paragraph(content)));
+            }
+        }
+    }
+
+    /// Parse an argument, which may be a `{...}` group.
+    fn group(&mut self, arg: &'a str) -> Vec<Node> {
+        if arg.len() > 1 && arg.starts_with('{') {
+            self.sections(inside(arg), 1)
+        } else {
+            self.inline(arg)
+        }
+    }
+
+    /// Parse the content of a single paragraph.
+    pub(crate) fn inline(&mut self, latex: &'a str) -> Vec<Node> {
+        self.nested(latex, Self::inline_nested)
+    }
+
+    /// Parse `latex` with `parse` one level deeper, unless that is too
+    /// deep.
+    ///
+    /// Everything nested comes back through here, by way of
+    /// [`Parser::inline`] or else as a section title, so this is where we
+    /// limit how deeply things may nest.
+    fn nested(&mut self, latex: &'a str, parse: fn(&mut Self, &'a str) -> Vec<Node>) -> Vec<Node> {
+        if self.depth >= self.max_depth {
+            let message = format!("nested more than {} deep", self.max_depth);
+            return vec![self.error(latex, &latex[latex.len()..], "too-deep", message)];
+        }
+        self.depth += 1;
+        let nodes = parse(self, latex);
+        self.depth -= 1;
+        nodes
+    }
+
+    fn inline_nested(&mut self, mut latex: &'a str) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        while let Some(i) = latex.find(['~', '\\', '{', '$']) {
+            if i > 0 {
+                nodes.push(self.text(&latex[..i]));
+            }
+            latex = &latex[i..];
+            let start = latex;
+            if latex.starts_with('~') {
+                latex = &latex[1..];
+                nodes.push(self.node(start, latex, NodeKind::NonBreakingSpace));
+            } else if latex.starts_with('\\') {
+                let name = macro_name(latex);
+                latex = &latex[name.len()..];
+                match name {
+                    r"\tableofcontents" if self.in_title => {
+                        let message = r"\tableofcontents cannot go in a heading".to_string();
+                        nodes.push(self.error(start, latex, "misplaced-contents", message));
+                    }
+                    r"\\" | r"\newpage" | r"\textbackslash" | r"\ldots" | r"\ " | r"\noindent"
+                    | r"\tableofcontents" | r"\%" | r"\#" | r"\$" | r"\&" | r"\_" | r"\{"
+                    | r"\}" => {
+                        nodes.push(self.node(start, latex, macro_node(name, Vec::new())));
+                    }
+                    r"\vspace" | r"\vfill" | r"\label" | r"\eqref" | r"\ref" | r"\pageref"
+                    | r"\autoref" | r"\cref" | r"\Cref" | r"\url" => {
+                        let arg = self.argument(latex);
+                        latex = &latex[arg.len()..];
+                        if arg == "{" {
+                            nodes.push(self.unbalanced(start, latex, name));
+                        } else {
+                            let arg = if name == r"\url" {
+                                Argument::Verbatim(inside(arg).to_string())
+                            } else {
+                                self.verbatim(inside(arg))
+                            };
+                            nodes.push(self.node(start, latex, macro_node(name, vec![arg])));
+                        }
+                    }
+                    _ if text::is_accent(&name[1..]) => {
+                        latex = latex.trim_start_matches(' ');
+                        let arg = if latex.starts_with('{') {
+                            self.argument(latex)
+                        } else if latex.starts_with('\\') {
+                            macro_name(latex)
+                        } else {
+                            first_char(latex)
+                        };
+                        latex = &latex[arg.len()..];
+                        if latex.is_empty() && arg.is_empty() {
+                            let message = format!("{} needs a letter to accent", name);
+                            nodes.push(self.error(start, latex, "missing-argument", message));
+                        } else if arg == "{" {
+                            nodes.push(self.unbalanced(start, latex, name));
+                        } else if text::accented(&name[1..], inside(arg)).is_none() {
+                            let message = format!("cannot put the accent {} on {}", name, arg);
+                            nodes.push(self.error(start, latex, "invalid-accent", message));
+                        } else {
+                            let arg = Argument::Verbatim(inside(arg).to_string());
+                            nodes.push(self.node(start, latex, macro_node(name, vec![arg])));
+                        }
+                    }
+                    _ if text::character(&name[1..]).is_some() => {
+                        latex = finish_standalone_macro(latex);
+                        nodes.push(self.node(start, latex, macro_node(name, Vec::new())));
+                    }
+                    r"\verb" => {
+                        if latex.is_empty() {
+                            let message = r"\verb needs a delimiter".to_string();
+                            nodes.push(self.error(start, latex, "missing-argument", message));
+                            continue;
+                        }
+                        let sep = first_char(latex);
+                        latex = &latex[sep.len()..];
+                        if let Some(end) = latex.find(sep) {
+                            let content = &latex[..end];
+                            latex = &latex[end + sep.len()..];
+                            let arg = self.verbatim(content);
+                            nodes.push(self.node(start, latex, macro_node(name, vec![arg])));
+                        } else {
+                            let message = format!(r"\verb{} has no closing {}", sep, sep);
+                            nodes.push(self.error(start, latex, "unterminated-verb", message));
+                        }
+                    }
+                    r"\emph" | r"\underline" | r"\textit" | r"\textbf" | r"\texttt"
+                    | r"\footnote" | r"\caption" => {
+                        let arg = self.argument(latex);
+                        latex = &latex[arg.len()..];
+                        if arg == "{" {
+                            nodes.push(self.unbalanced(start, latex, name));
+                        } else {
+                            let arg = Argument::Latex(self.paragraphs(inside(arg)));
+                            nodes.push(self.node(start, latex, macro_node(name, vec![arg])));
+                        }
+                    }
+                    r"\warning" | r"\error" => {
+                        let arg = self.argument(latex);
+                        latex = &latex[arg.len()..];
+                        if arg == "{" {
+                            nodes.push(self.unbalanced(start, latex, name));
+                        } else {
+                            let arg = Argument::Latex(self.sections(inside(arg), 1));
+                            nodes.push(self.node(start, latex, macro_node(name, vec![arg])));
+                        }
+                    }
+                    r"\textcolor" => {
+                        let color = self.argument(latex);
+                        latex = &latex[color.len()..];
+                        if color == "{" {
+                            nodes.push(self.unbalanced(start, latex, name));
+                        } else {
+                            let color = color.replace(['{', '}'], "");
+                            let arg = self.argument(latex);
+                            latex = &latex[arg.len()..];
+                            if arg == "{" {
+                                nodes.push(self.unbalanced(start, latex, name));
+                            } else {
+                                if !TEXT_COLORS.contains(&color.as_str()) {
+                                    let message = format!("invalid color {}", color);
+                                    let code = "invalid-color";
+                                    self.diagnose(start, latex, Severity::Error, code, message);
+                                }
+                                let args = vec![
+                                    Argument::Verbatim(color),
+                                    Argument::Latex(self.paragraphs(inside(arg))),
+                                ];
+                                nodes.push(self.node(start, latex, macro_node(name, args)));
+                            }
+                        }
+                    }
+                    r"\href" => {
+                        let url = self.argument(latex);
+                        latex = &latex[url.len()..];
+                        if url == "{" {
+                            nodes.push(self.unbalanced(start, latex, name));
+                        } else {
+                            let arg = self.argument(latex);
+                            latex = &latex[arg.len()..];
+                            if arg == "{" {
+                                nodes.push(self.unbalanced(start, latex, name));
+                            } else {
+                                let args = vec![
+                                    Argument::Verbatim(inside(url).to_string()),
+                                    Argument::Latex(self.paragraphs(inside(arg))),
+                                ];
+                                nodes.push(self.node(start, latex, macro_node(name, args)));
+                            }
+                        }
+                    }
+                    r"\hyperref" => latex = self.hyperref(start, latex, &mut nodes),
+                    r"\includegraphics" => {
+                        let opt = self.optional_argument(latex);
+                        latex = &latex[opt.len()..];
+                        let arg = self.argument(latex);
+                        latex = &latex[arg.len()..];
+                        if arg == "{" {
+                            nodes.push(self.unbalanced(start, latex, name));
+                        } else {
+                            let mut args = Vec::new();
+                            if opt.len() > 1 {
+                                args.push(Argument::Optional(opt[1..opt.len() - 1].to_string()));
+                            }
+                            args.push(self.verbatim(inside(arg)));
+                            nodes.push(self.node(start, latex, macro_node(name, args)));
+                        }
+                    }
+                    r"\paragraph" | r"\paragraph*" => {
+                        let arg = self.argument(latex);
+                        latex = &latex[arg.len()..];
+                        if arg == "{" {
+                            nodes.push(self.unbalanced(start, latex, name));
+                        } else {
+                            let arg = Argument::Latex(self.sections(inside(arg), 1));
+                            nodes.push(self.node(start, latex, macro_node(name, vec![arg])));
+                        }
+                        latex = latex.trim_start();
+                    }
+                    r"\it" | r"\centering" => {
+                        latex = finish_standalone_macro(latex);
+                        let end = &latex[latex.len()..];
+                        let content = self.paragraphs(latex);
+                        nodes.push(self.node(start, end, declaration(name, content)));
+                        return nodes;
+                    }
+                    r"\bf" | r"\sc" => {
+                        latex = finish_standalone_macro(latex);
+                        let end = &latex[latex.len()..];
+                        let content = self.sections(latex, 1);
+                        nodes.push(self.node(start, end, declaration(name, content)));
+                        return nodes;
+                    }
+                    r"\(" | r"\[" => {
+                        let (end, style) = if name == r"\(" {
+                            (r"\)", MathStyle::Inline)
+                        } else {
+                            (r"\]", MathStyle::Display)
+                        };
+                        if let Some(i) = self.find(latex, end) {
+                            let math = &latex[..i];
+                            latex = &latex[i + 2..];
+                            nodes.push(self.math(style, math, start, latex));
+                        } else {
+                            let message = format!("{} has no matching {}", name, end);
+                            nodes.push(self.error(start, latex, "unterminated-math", message));
+                        }
+                    }
+                    r"\begin" => {
+                        let name = env_name(latex);
+                        latex = &latex[name.len()..];
+                        if !name.ends_with('}') {
+                            nodes.push(self.unbalanced(start, latex, r"\begin"));
+                        } else {
+                            let name = &name[1..name.len() - 1];
+                            latex = self.environment(start, name, latex, &mut nodes);
+                        }
+                    }
+                    r"\end" => {
+                        let name = env_name(latex);
+                        latex = &latex[name.len()..];
+                        let message = format!(r"\end{} has no matching \begin{}", name, name);
+                        nodes.push(self.error(start, latex, "unexpected-end", message));
+                    }
+                    r"\ce" | r"\pu" => {
+                        let arg = self.argument(latex);
+                        latex = &latex[arg.len()..];
+                        if arg == "{" {
+                            nodes.push(self.unbalanced(start, latex, name));
+                        } else if mhchem::html(&name[1..], &[inside(arg)], &UnitOptions::new())
+                            .is_none()
+                        {
+                            let len = start.len() - latex.len();
+                            let message = format!("cannot make sense of {}", &start[..len]);
+                            nodes.push(self.error(start, latex, "invalid-chemistry", message));
+                        } else {
+                            let arg = self.verbatim(inside(arg));
+                            nodes.push(self.node(start, latex, macro_node(name, vec![arg])));
+                        }
+                    }
+                    r"\SI" | r"\qty" | r"\si" | r"\unit" | r"\num" | r"\ang" => {
+                        // Any options are for siunitx, not for us.
+                        let opt = self.optional_argument(latex);
+                        latex = &latex[opt.len()..];
+                        let mut args = Vec::new();
+                        let mut unbalanced = false;
+                        for _ in 0..siunitx::arguments(&name[1..]).unwrap_or(1) {
+                            let arg = self.argument(latex);
+                            latex = &latex[arg.len()..];
+                            unbalanced |= arg == "{";
+                            args.push(inside(arg));
+                        }
+                        if unbalanced {
+                            nodes.push(self.unbalanced(start, latex, name));
+                        } else if siunitx::html(&name[1..], &args, &UnitOptions::new()).is_none() {
+                            let len = start.len() - latex.len();
+                            let message = format!("cannot make sense of {}", &start[..len]);
+                            nodes.push(self.error(start, latex, "invalid-quantity", message));
+                        } else {
+                            let args = args.into_iter().map(|arg| self.verbatim(arg)).collect();
+                            nodes.push(self.node(start, latex, macro_node(name, args)));
+                        }
+                    }
+                    EXPANSION_LIMIT => nodes.push(self.expansion_limit(start, latex)),
+                    _ if is_definer(name) => {
+                        // We already reported why we did not use this.
+                        latex = &start[definition_len(start, &self.ends.braces)..];
+                        let kind = NodeKind::Error(start[..start.len() - latex.len()].to_string());
+                        nodes.push(self.node(start, latex, kind));
+                    }
+                    _ if self.defined.contains(name) => {
+                        // We already reported why we could not expand this.
+                        let kind = NodeKind::Error(name.to_string());
+                        nodes.push(self.node(start, latex, kind));
+                    }
+                    _ => {
+                        let message = format!("unknown macro {}", name);
+                        nodes.push(self.error(start, latex, "unknown-macro", message));
+                    }
+                }
+            } else if latex.starts_with('$') {
+                if let Some(i) = latex[1..].find('$') {
+                    if i == 0 {
+                        // It is a $$ actually
+                        if let Some(i) = latex[2..].find("$$") {
+                            let math = &latex[2..i + 2];
+                            latex = &latex[i + 4..];
+                            nodes.push(self.math(MathStyle::Display, math, start, latex));
+                        } else {
+                            latex = &latex[2..];
+                            let message = "$$ has no matching $$".to_string();
+                            nodes.push(self.error(start, latex, "unterminated-math", message));
+                        }
+                    } else {
+                        let math = &latex[1..i + 1];
+                        latex = &latex[i + 2..];
+                        nodes.push(self.math(MathStyle::Inline, math, start, latex));
+                    }
+                } else {
+                    latex = &latex[1..];
+                    let message = "$ has no matching $".to_string();
+                    nodes.push(self.error(start, latex, "unterminated-math", message));
+                }
+            } else {
+                let arg = self.argument(latex);
+                latex = &latex[arg.len()..];
+                if arg == "{" {
+                    let message = "{ has no matching }".to_string();
+                    nodes.push(self.error(start, latex, "unbalanced-brace", message));
+                } else {
+                    let content = self.sections(inside(arg), 1);
+                    nodes.push(self.node(start, latex, NodeKind::Group(content)));
+                }
+            }
+        }
+        if !latex.is_empty() {
+            nodes.push(self.text(latex));
+        }
+        nodes
+    }
+
+    /// Parse the `[label]{text}` of a `\hyperref` we have just read,
+    /// returning the LaTeX that follows it.
+    ///
+    /// This is kept out of `inline_nested`, whose stack frame every level
+    /// of nesting pays for.
+    fn hyperref(&mut self, start: &'a str, mut latex: &'a str, nodes: &mut Vec<Node>) -> &'a str {
+        let opt = self.optional_argument(latex);
+        latex = &latex[opt.len()..];
+        let arg = self.argument(latex);
+        latex = &latex[arg.len()..];
+        if opt.len() < 2 {
+            let message = r"\hyperref needs a [label] to link to".to_string();
+            nodes.push(self.error(start, latex, "missing-argument", message));
+        } else if arg == "{" {
+            nodes.push(self.unbalanced(start, latex, r"\hyperref"));
+        } else {
+            let args = vec![
+                Argument::Optional(opt[1..opt.len() - 1].to_string()),
+                Argument::Latex(self.paragraphs(inside(arg))),
+            ];
+            nodes.push(self.node(start, latex, macro_node(r"\hyperref", args)));
+        }
+        latex
+    }
+
+    /// Parse the environment `name` whose `\begin{name}` we have just
+    /// read, returning the LaTeX that follows it.
+    fn environment(
+        &mut self,
+        start: &'a str,
+        name: &str,
+        mut latex: &'a str,
+        nodes: &mut Vec<Node>,
+    ) -> &'a str {
+        let end = format!(r"\end{{{}}}", name);
+        match name {
+            "figure" | "wrapfigure" | "table" => {
+                let mut args = Vec::new();
+                if name == "wrapfigure" {
+                    let align = self.argument(latex);
+                    latex = &latex[align.len()..];
+                    let width = self.argument(latex);
+                    latex = &latex[width.len()..];
+                    args.push(Argument::Verbatim(inside(align).to_string()));
+                    args.push(Argument::Verbatim(inside(width).to_string()));
+                } else if latex.starts_with('[') {
+                    // Just skip any figure placement parameters
+                    if let Some(i) = latex.find(']') {
+                        latex = &latex[i + 1..];
+                    }
+                }
+                if let Some(i) = self.find(latex, &end) {
+                    let mut content = Vec::new();
+                    let mut body = &latex[..i];
+                    if let Some(rest) = body.strip_prefix(r"\centering") {
+                        if rest.starts_with(' ') || rest.starts_with('\n') {
+                            let centering = macro_node(r"\centering", Vec::new());
+                            content.push(self.node(body, rest, centering));
+                            body = &rest[1..];
+                        }
+                    }
+                    content.extend(self.inline(body));
+                    latex = &latex[i + end.len()..];
+                    nodes.push(self.node(start, latex, environment(name, args, content)));
+                } else {
+                    nodes.push(self.unclosed(start, latex, name));
+                }
+            }
+            "solution" | "guide" | "handout" => {
+                if let Some(i) = self.find(latex, &end) {
+                    let content = self.paragraphs(&latex[..i]);
+                    latex = &latex[i + end.len()..];
+                    nodes.push(self.node(start, latex, environment(name, Vec::new(), content)));
+                } else {
+                    nodes.push(self.unclosed(start, latex, name));
+                }
+            }
+            _ if references::theorem_name(name).is_some() => {
+                if let Some(i) = self.find(latex, &end) {
+                    // Any [...] is the name of the theorem.
+                    let opt = self.optional_argument(&latex[..i]);
+                    let mut args = Vec::new();
+                    if opt.len() > 1 {
+                        args.push(Argument::Optional(opt[1..opt.len() - 1].to_string()));
+                    }
+                    let content = self.paragraphs(&latex[opt.len()..i]);
+                    latex = &latex[i + end.len()..];
+                    nodes.push(self.node(start, latex, environment(name, args, content)));
+                } else {
+                    nodes.push(self.unclosed(start, latex, name));
+                }
+            }
+            "tabular" => {
+                if let Some(i) = self.find(latex, &end) {
+                    let arg = self.argument(latex);
+                    let body = latex.get(arg.len()..i).unwrap_or("");
+                    let args = vec![Argument::Verbatim(inside(arg).to_string())];
+                    let rows = self.table_rows(body);
+                    latex = &latex[i + end.len()..];
+                    nodes.push(self.node(start, latex, environment(name, args, rows)));
+                } else {
+                    nodes.push(self.unclosed(start, latex, name));
+                }
+            }
+            "center" | "quote" | "quotation" => {
+                if let Some(i) = self.find(latex, &end) {
+                    let content = self.inline(&latex[..i]);
+                    latex = &latex[i + end.len()..];
+                    nodes.push(self.node(start, latex, environment(name, Vec::new(), content)));
+                } else {
+                    nodes.push(self.unclosed(start, latex, name));
+                }
+            }
+            "verbatim" => {
+                if let Some(i) = self.find(latex, &end) {
+                    let args = vec![self.verbatim(&latex[..i])];
+                    latex = &latex[i + end.len()..];
+                    nodes.push(self.node(start, latex, environment(name, args, Vec::new())));
+                } else {
+                    nodes.push(self.unclosed(start, latex, name));
+                }
+            }
+            "itemize" | "enumerate" | "description" => {
+                let begin = &start[..start.len() - latex.len()];
+                let mut items = Vec::new();
+                let li = self.finish_item(latex);
+                latex = &latex[li.len()..];
+                if !li.trim().is_empty() {
+                    // Nothing should precede the first
+                    // \item except whitespace.
+                    let message = format!(r"text before the first \item in {}", name);
+                    items.push(self.error(li, latex, "text-before-item", message));
+                }
+                let mut trailing = None;
+                loop {
+                    let li = self.finish_item(latex);
+                    latex = &latex[li.len()..];
+                    if li.is_empty() {
+                        if let Some(other) = ["itemize", "enumerate", "description"]
+                            .iter()
+                            .map(|e| format!(r"\end{{{}}}", e))
+                            .find(|e| latex.starts_with(e.as_str()))
+                        {
+                            let found = latex;
+                            latex = &latex[other.len()..];
+                            if other != end {
+                                let message = format!("{} does not match {}", other, begin);
+                                trailing =
+                                    Some(self.error(found, latex, "mismatched-end", message));
+                            }
+                            break;
+                        } else if latex.starts_with(r"\item") {
+                            // It must start with \item
+                            latex = &latex[r"\item".len()..];
+                            latex = finish_standalone_macro(latex);
+                        } else {
+                            let message = format!("{} has no matching {}", begin, end);
+                            let code = "unclosed-environment";
+                            self.diagnose(
+                                begin,
+                                &start[begin.len()..],
+                                Severity::Error,
+                                code,
+                                message,
+                            );
+                            let missing = NodeKind::Error(format!("MISSING {}", end));
+                            trailing = Some(self.whole(begin, missing));
+                            break;
+                        }
+                    } else if name == "description" {
+                        let o = self.optional_argument(li);
+                        let label = if o.len() > 2 {
+                            Some(self.sections(&o[1..o.len() - 1], 1))
+                        } else {
+                            None
+                        };
+                        let content = self.sections(&li[o.len()..], 1);
+                        items.push(self.whole(li, NodeKind::Item { label, content }));
+                    } else {
+                        let content = self.sections(li, 1);
+                        let item = NodeKind::Item {
+                            label: None,
+                            content,
+                        };
+                        items.push(self.whole(li, item));
+                    }
+                }
+                let list = environment(name, Vec::new(), items);
+                nodes.push(self.node(start, latex, list));
+                nodes.extend(trailing);
+            }
+            _ if environs::find(name).is_some() => {
+                if let (Some(environ), Some(i)) = (environs::find(name), self.find(latex, &end)) {
+                    let math = &latex[..i];
+                    latex = &latex[i + end.len()..];
+                    nodes.push(self.math_environment(environ, name, math, start, latex));
+                } else {
+                    nodes.push(self.unclosed(start, latex, name));
+                }
+            }
+            _ if self.defined.contains(name) => {
+                // We already reported why we did not expand this.
+                if let Some(i) = self.find(latex, &end) {
+                    latex = &latex[i + end.len()..];
+                }
+                let kind = NodeKind::Error(start[..start.len() - latex.len()].to_string());
+                nodes.push(self.node(start, latex, kind));
+            }
+            _ => {
+                if let Some(i) = self.find(latex, &end) {
+                    latex = &latex[i + end.len()..];
+                    let message = format!("unknown environment {}", name);
+                    nodes.push(self.error(start, latex, "unknown-environment", message));
+                } else {
+                    nodes.push(self.unclosed(start, latex, name));
+                }
+            }
+        }
+        latex
+    }
+
+    /// Split the body of a `tabular` into rows and `\hline` rules.
+    fn table_rows(&mut self, mut latex: &'a str) -> Vec<Node> {
+        let mut rows = Vec::new();
+        loop {
+            let end = latex
+                .match_indices('\\')
+                .map(|(i, _)| i)
+                .find(|&i| latex[i..].starts_with(r"\\") || latex[i..].starts_with(r"\hline"))
+                .unwrap_or(latex.len());
+            let row = NodeKind::Row(self.table_cells(&latex[..end]));
+            rows.push(self.whole(&latex[..end], row));
+            if end == latex.len() {
+                return rows;
+            }
+            if latex[end..].starts_with(r"\hline") {
+                let hline = &latex[end..];
+                latex = &latex[end + r"\hline".len()..];
+                rows.push(self.node(hline, latex, macro_node(r"\hline", Vec::new())));
+            } else {
+                latex = &latex[end + r"\\".len()..];
+            }
+        }
+    }
+
+    /// Split a row of a `tabular` into cells at each unescaped `&`.
+    fn table_cells(&mut self, latex: &'a str) -> Vec<Vec<Node>> {
+        let mut cells = Vec::new();
+        let mut start = 0;
+        for (i, _) in latex.match_indices('&') {
+            if latex[..i].ends_with('\\') {
+                continue;
+            }
+            cells.push(self.inline(&latex[start..i]));
+            start = i + 1;
+        }
+        cells.push(self.inline(&latex[start..]));
+        cells
+    }
+}
+
+/// The colors allowed in `\textcolor`.
+pub(crate) const TEXT_COLORS: &[&str] = &[
+    "red",
+    "blue",
+    "forestgreen",
+    "purple",
+    "brown",
+    "gray",
+    "orange",
+];
+
+/// The content of an argument, without any surrounding braces.
+pub(crate) fn inside(arg: &str) -> &str {
+    if arg.len() > 1 && arg.starts_with('{') {
+        &arg[1..arg.len() - 1]
+    } else {
+        arg
+    }
+}
+
+fn macro_node(name: &str, args: Vec<Argument>) -> NodeKind {
+    NodeKind::Macro {
+        name: name[1..].to_string(),
+        args,
+    }
+}
+
+fn declaration(name: &str, content: Vec<Node>) -> NodeKind {
+    NodeKind::Declaration {
+        name: name[1..].to_string(),
+        content,
+    }
+}
+
+fn environment(name: &str, args: Vec<Argument>, content: Vec<Node>) -> NodeKind {
+    NodeKind::Environment {
+        name: name.to_string(),
+        args,
+        content,
+    }
+}
+
+fn finish_standalone_macro(latex: &str) -> &str {
+    latex.strip_prefix(' ').unwrap_or(latex)
+}
+
+pub(crate) fn macro_name(latex: &str) -> &str {
+    if let Some(i) = latex[1..].find(|c: char| !c.is_alphabetic() && c != '*') {
+        if i == 0 {
+            &latex[..1 + first_char(&latex[1..]).len()]
+        } else {
+            &latex[..i + 1]
+        }
+    } else {
+        latex
+    }
+}
+
+#[test]
+fn test_macro_name() {
+    assert_eq!(macro_name(r"\emph{foo"), r"\emph");
+    assert_eq!(macro_name(r"\\ extra"), r"\\");
+    assert_eq!(macro_name(r"\% extra"), r"\%");
+    assert_eq!(macro_name("\\é extra"), "\\é");
+}
+
+fn env_name(latex: &str) -> &str {
+    if let Some(i) = latex.find('}') {
+        &latex[..i + 1]
+    } else if let Some(i) = latex.find(|c: char| c != '{' && !c.is_alphabetic()) {
+        &latex[..i + 1]
+    } else {
+        latex
+    }
+}
+
+#[test]
+fn test_find_paragraph() {
+    let find_paragraph = |latex| PARAGRAPH_TOKEN.find(latex).map(|m| m.end());
+    assert_eq!(Some(3), find_paragraph("\n\n\nHello world"));
+    assert_eq!(Some(5), find_paragraph("\n\n\n\r\nHello world"));
+}
+#[test]
+fn test_finish_paragraph() {
+    assert_eq!(
+        r"\begin{center}
+contents
+\end{center}
+
+",
+        finish_paragraph(
+            r"\begin{center}
+contents
+\end{center}
+
+"
+        )
+    );
+    assert_eq!(
+        r"\begin{center}
+contents
+
+with paragraph
+\end{center}
+
+",
+        finish_paragraph(
+            r"\begin{center}
+contents
+
+with paragraph
+\end{center}
+
+"
+        )
+    );
+    assert_eq!(
+        r"center
+contents
+
+",
+        finish_paragraph(
+            r"center
+contents
+
+with paragraph
+end center
+
+"
+        )
+    );
+    assert_eq!("\n\n\n", finish_paragraph("\n\n\nHello world"));
+    assert_eq!("\n\n\n\r\n", finish_paragraph("\n\n\n\r\nHello world"));
+    assert_eq!(
+        "\nFirst me\n\n\n\r\n",
+        finish_paragraph("\nFirst me\n\n\n\r\nHello world")
+    );
+
+    use expect_test::expect;
+    expect![[r#"
+
+        First paragraph.
+
+        \begin{itemize}
+        \item First
+        \item Second
+        \end{itemize}
+
+    "#]]
+    .assert_eq(finish_paragraph(
+        r#"
+First paragraph.
+
+\begin{itemize}
+\item First
+\item Second
+\end{itemize}
+
+Second paragraph.
+"#,
+    ));
+
+    expect![[r#"
+
+    First paragraph.
+
+    \begin{itemize}
+    \item First
+    
+    \item Second
+    \end{itemize}
+
+"#]]
+    .assert_eq(finish_paragraph(
+        r#"
+First paragraph.
+
+\begin{itemize}
+\item First
+
+\item Second
+\end{itemize}
+
+Second paragraph.
+"#,
+    ));
+}
+
+fn finish_paragraph(latex: &str) -> &str {
+    let mut nestedness = 0;
+    for m in PARAGRAPH_TOKEN.find_iter(latex) {
+        match m.as_str() {
+            r"\begin{" => nestedness += 1,
+            r"\end{" => {
+                // A stray \end at the top level will be reported as an
+                // error rather than ending the paragraph early.
+                if nestedness > 0 {
+                    nestedness -= 1;
+                }
+            }
+            _ if nestedness > 0 => (),
+            // A blank line just before a \begin or \end does not end
+            // the paragraph.
+            _ if latex[m.end()..].starts_with(r"\begin{") => (),
+            _ if latex[m.end()..].starts_with(r"\end{") => (),
+            _ => return &latex[..m.end()],
+        }
+    }
+    // There is no end to this
+    latex
+}
+
+fn finish_item(latex: &str) -> &str {
+    let mut nestedness = 0;
+    for c in ITEM_TOKEN.captures_iter(latex) {
+        let m = c.get(0).unwrap();
+        match c.get(1).map(|m| m.as_str()) {
+            Some("begin") => nestedness += 1,
+            Some(_) if nestedness > 0 => nestedness -= 1,
+            _ if nestedness == 0 => return &latex[..m.start()],
+            _ => (),
+        }
+    }
+    // There is no end to this, or there is no ending but we are nested
+    ""
+}
+
+/// Find the argument at the start of `latex`, which is either a
+/// `{...}` group or a single character.  An unbalanced group gives
+/// just `"{"`.
+pub(crate) fn argument(latex: &str) -> &str {
+    if latex.is_empty() {
+        ""
+    } else if latex.starts_with('{') {
+        let mut n = 0;
+        let mut escaped = false;
+        for (i, c) in latex.char_indices().skip(1) {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '{' {
+                n += 1
+            } else if c == '}' {
+                if n == 0 {
+                    return &latex[..i + 1];
+                }
+                n -= 1
+            }
+        }
+        // we must have unbalanced parentheses
+        &latex[..1]
+    } else {
+        first_char(latex)
+    }
+}
+
+/// The first character of `latex`, which may be more than one byte.
+fn first_char(latex: &str) -> &str {
+    let len = latex.chars().next().map_or(0, char::len_utf8);
+    &latex[..len]
+}
+
+pub(crate) fn optional_argument(latex: &str) -> &str {
+    if latex.starts_with('[') {
+        let mut n: isize = 0;
+        let mut escaped = false;
+        for (i, c) in latex.char_indices().skip(1) {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '{' {
+                n += 1
+            } else if c == '}' {
+                if n == 0 {
+                    // The group we are in ends before the argument does.
+                    break;
+                }
+                n -= 1
+            } else if c == ']' && n == 0 {
+                return &latex[..i + 1];
+            }
+        }
+        // we must have unbalanced parentheses
+        &latex[..1]
+    } else {
+        ""
+    }
+}
+
+/// What defines a macro in math, for MathJax, with the name it defines.
+static MATH_DEFINITION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\\(?:[gex]?def|let|(?:re|provide|new)command\*?)\s*\{?\s*\\([a-zA-Z]+)").unwrap()
+});
+
+/// The macros that `math` defines for itself, without their
+/// backslashes.  MathJax uses these definitions, so we should leave
+/// their uses alone rather than rewriting them as our own.
+pub(crate) fn defined_in_math(math: &str) -> HashSet<&str> {
+    MATH_DEFINITION
+        .captures_iter(math)
+        .filter_map(|c| c.get(1))
+        .map(|name| name.as_str())
+        .collect()
+}
+
+/// Rewrite each macro in `math` for which `arguments` gives how many
+/// braced arguments it takes, after any optional one, with whatever
+/// `rewrite` makes of it, or `None` if we rewrote nothing.  Both are
+/// given names without their backslashes, and any macro that `rewrite`
+/// cannot make sense of is left alone.
+pub(crate) fn rewrite_macros(
+    math: &str,
+    arguments: impl Fn(&str) -> Option<usize>,
+    mut rewrite: impl FnMut(&str, &[&str]) -> Option<String>,
+) -> Option<String> {
+    let braces = Braces::new(math);
+    let mut rest = math;
+    let mut out = String::new();
+    let mut changed = false;
+    while let Some(i) = rest.find('\\') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let name = macro_name(rest);
+        let mut after = &rest[name.len()..];
+        if let Some(count) = arguments(&name[1..]) {
+            let o = braces.optional_argument(after);
+            after = &after[o.len()..];
+            let mut args = Vec::with_capacity(count);
+            while args.len() < count {
+                let arg = braces.argument(after);
+                if !arg.starts_with('{') || arg.len() < 2 {
+                    break;
+                }
+                after = &after[arg.len()..];
+                args.push(inside(arg));
+            }
+            if let Some(tex) = rewrite(&name[1..], &args) {
+                out.push('{');
+                out.push_str(&tex);
+                out.push('}');
+                rest = after;
+                changed = true;
+                continue;
+            }
+        }
+        out.push_str(name);
+        rest = &rest[name.len()..];
+    }
+    out.push_str(rest);
+    changed.then_some(out)
+}
+
+#[test]
+fn test_defined_in_math() {
+    let defined = defined_in_math(r"\def\ce#1{#1} \let\SI=\relax \providecommand*{\num}{1} \si");
+    assert_eq!(HashSet::from(["ce", "SI", "num"]), defined);
+}
+
+#[test]
+fn test_argument() {
+    assert_eq!(argument(r"{foo"), r"{");
+    assert_eq!(argument(r"{foo}  "), r"{foo}");
+    assert_eq!(argument(r"{a\}b}c"), r"{a\}b}");
+    assert_eq!(argument("éa"), "é");
+
+    // The parser finds the same arguments without rescanning.
+    let source = r"{a{b}\{c} {d \end{x} {e\)";
+    let parser = Parser::new(source);
+    for (i, c) in source.char_indices() {
+        let latex = &source[i..];
+        for latex in [latex, &latex[..latex.len() / 2]] {
+            if c == '{' {
+                assert_eq!(argument(latex), parser.argument(latex), "{}", latex);
+            }
+            for token in [r"\end{x}", r"\)", r"\]", "}"] {
+                assert_eq!(latex.find(token), parser.find(latex, token), "{}", latex);
+            }
+        }
+    }
+
+    // And the same items.
+    let source =
+        r"a\item b\begin{enumerate}\item c\end{enumerate}\item d\end{itemize} e\end{itemize}";
+    let parser = Parser::new(source);
+    for (i, _) in source.char_indices() {
+        let latex = &source[i..];
+        for latex in [latex, &latex[..latex.len() / 2]] {
+            assert_eq!(finish_item(latex), parser.finish_item(latex), "{}", latex);
+        }
+    }
+}
+
+#[test]
+fn test_parse() {
+    use expect_test::expect;
+    expect![[r#"
+        Document {
+            content: [
+                Node {
+                    kind: Text(
+                        "Some ",
+                    ),
+                    span: 0..5,
+                },
+                Node {
+                    kind: Macro {
+                        name: "emph",
+                        args: [
+                            Latex(
+                                [
+                                    Node {
+                                        kind: Text(
+                                            "good",
+                                        ),
+                                        span: 11..15,
+                                    },
+                                ],
+                            ),
+                        ],
+                    },
+                    span: 5..16,
+                },
+                Node {
+                    kind: Text(
+                        " math: ",
+                    ),
+                    span: 16..23,
+                },
+                Node {
+                    kind: Math {
+                        style: Inline,
+                        latex: "x^2",
+                    },
+                    span: 23..28,
+                },
+                Node {
+                    kind: Text(
+                        ".",
+                    ),
+                    span: 28..29,
+                },
+            ],
+        }
+    "#]]
+    .assert_debug_eq(&parse(r"Some \emph{good} math: $x^2$."));
+}
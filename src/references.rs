@@ -0,0 +1,455 @@
+//! Numbering what labels in the text refer to, such as sections, figures
+//! and theorems, so that `\ref` and its friends can link to them.
+
+use crate::equations::Equations;
+use crate::headings::Headings;
+use crate::parse::{inside, Braces};
+use crate::{Argument, Diagnostic, Node, NodeKind, Severity};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+/// The theorem-like environments, with what each is called.
+const THEOREMS: &[(&str, &str)] = &[
+    ("theorem", "Theorem"),
+    ("lemma", "Lemma"),
+    ("corollary", "Corollary"),
+    ("proposition", "Proposition"),
+    ("definition", "Definition"),
+    ("example", "Example"),
+    ("remark", "Remark"),
+    ("exercise", "Exercise"),
+];
+
+/// What the theorem-like environment `name` is called, such as `Lemma`.
+pub(crate) fn theorem_name(name: &str) -> Option<&'static str> {
+    THEOREMS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|&(_, title)| title)
+}
+
+/// The environments that hold a numbered `\caption`.
+const FLOATS: &[&str] = &["figure", "wrapfigure", "table"];
+
+/// What a label refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    /// A heading, where 1 is `\section`.
+    Section(u8),
+    Figure,
+    Table,
+    /// An item of an `enumerate`.
+    Item,
+    /// A theorem-like environment, by what it is called.
+    Theorem(&'static str),
+    Equation,
+}
+
+/// What a label refers to, along with its number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Target {
+    pub(crate) kind: Kind,
+    pub(crate) number: String,
+}
+
+/// Whether `name` is a macro that refers to a label, such as `\ref`.
+pub(crate) fn is_reference(name: &str) -> bool {
+    matches!(
+        name,
+        "ref" | "eqref" | "pageref" | "autoref" | "cref" | "Cref"
+    )
+}
+
+/// What the reference `name` to `target` says, such as `Figure 2` for
+/// an `\autoref`, as hyperref and cleveref would say it.
+///
+/// We have no pages, so a `\pageref` says what a `\ref` would.
+pub(crate) fn reference_text(name: &str, target: &Target) -> String {
+    let (autoref, cref, capital) = match target.kind {
+        Kind::Section(1) => ("section", "section", "Section"),
+        Kind::Section(2) => ("subsection", "section", "Section"),
+        Kind::Section(_) => ("subsubsection", "section", "Section"),
+        Kind::Figure => ("Figure", "fig.", "Figure"),
+        Kind::Table => ("Table", "table", "Table"),
+        Kind::Item => ("item", "item", "Item"),
+        Kind::Theorem(title) => (title, title, title),
+        Kind::Equation => ("Equation", "eq.", "Equation"),
+    };
+    let number = match (name, target.kind) {
+        ("eqref", _) | ("cref" | "Cref", Kind::Equation) => format!("({})", target.number),
+        _ => target.number.clone(),
+    };
+    match name {
+        "autoref" => format!("{} {}", autoref, number),
+        "cref" => format!("{} {}", cref.to_lowercase(), number),
+        "Cref" => format!("{} {}", capital, number),
+        _ => number,
+    }
+}
+
+/// The numbers of everything labelled in a document.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct References {
+    /// What each label refers to.
+    targets: HashMap<String, Target>,
+    /// The labels in math, which MathJax may know.
+    math_labels: HashSet<String>,
+    /// The number to show on each theorem, and on each figure and table
+    /// with a label, by where it came from.
+    numbers: HashMap<Range<usize>, String>,
+    /// Undefined, duplicate and stray labels.
+    pub(crate) diagnostics: Vec<Diagnostic>,
+}
+
+/// How many of each thing we have numbered so far.
+#[derive(Default)]
+struct Counts {
+    figures: usize,
+    tables: usize,
+    theorems: HashMap<&'static str, usize>,
+}
+
+impl References {
+    /// Number everything labelled in `content`, given its `headings` and
+    /// its `equations` if we number them ourselves, which lose any label
+    /// the text defines first.
+    pub(crate) fn new(
+        content: &[Node],
+        headings: &Headings,
+        mut equations: Option<&mut Equations>,
+    ) -> Self {
+        let mut references = References::default();
+        let mut counts = Counts::default();
+        references.visit(content, None, &[], headings, &mut counts);
+        if let Some(equations) = equations.as_deref_mut() {
+            for node in content {
+                node.walk(&mut |node| references.check_equations(node, equations));
+            }
+        }
+        for node in content {
+            node.walk(&mut |node| references.check(node, equations.as_deref()));
+        }
+        references
+    }
+
+    /// Number what is labelled in `nodes`, which are within `current`
+    /// and within items of `enumerate`s numbered `items`.
+    fn visit(
+        &mut self,
+        nodes: &[Node],
+        current: Option<&Target>,
+        items: &[String],
+        headings: &Headings,
+        counts: &mut Counts,
+    ) {
+        for node in nodes {
+            let target = match &node.kind {
+                NodeKind::Section { .. } => headings.get(&node.span).and_then(|heading| {
+                    Some(Target {
+                        kind: Kind::Section(heading.level),
+                        number: heading.number.clone()?,
+                    })
+                }),
+                NodeKind::Environment { name, content, .. } if FLOATS.contains(&name.as_str()) => {
+                    // Only a caption gives a figure a number.
+                    if !contains(content, |name| name == "caption") {
+                        None
+                    } else {
+                        let (kind, count) = if name == "table" {
+                            (Kind::Table, &mut counts.tables)
+                        } else {
+                            (Kind::Figure, &mut counts.figures)
+                        };
+                        *count += 1;
+                        let number = count.to_string();
+                        if contains(content, |name| name == "label") {
+                            self.numbers.insert(node.span.clone(), number.clone());
+                        }
+                        Some(Target { kind, number })
+                    }
+                }
+                NodeKind::Environment { name, .. } if theorem_name(name).is_some() => {
+                    let title = theorem_name(name).unwrap_or_default();
+                    let count = counts.theorems.entry(title).or_insert(0);
+                    *count += 1;
+                    let number = count.to_string();
+                    self.numbers.insert(node.span.clone(), number.clone());
+                    Some(Target {
+                        kind: Kind::Theorem(title),
+                        number,
+                    })
+                }
+                NodeKind::Environment { name, content, .. } if name == "enumerate" => {
+                    let mut count = 0;
+                    for item in content {
+                        if let NodeKind::Item { content, .. } = &item.kind {
+                            count += 1;
+                            let mut numbers = items.to_vec();
+                            numbers.push(item_counter(items.len(), count));
+                            let target = Target {
+                                kind: Kind::Item,
+                                number: item_number(&numbers),
+                            };
+                            self.visit(content, Some(&target), &numbers, headings, counts);
+                        } else {
+                            let item = std::slice::from_ref(item);
+                            self.visit(item, current, items, headings, counts);
+                        }
+                    }
+                    continue;
+                }
+                NodeKind::Macro { name, args } if name == "label" => {
+                    if let [Argument::Verbatim(label)] = args.as_slice() {
+                        self.define(label, current, &node.span);
+                    }
+                    continue;
+                }
+                NodeKind::Math { latex, .. } => {
+                    let braces = Braces::new(latex);
+                    let mut rest = latex.as_str();
+                    while let Some(i) = rest.find(r"\label") {
+                        rest = &rest[i + r"\label".len()..];
+                        let arg = braces.argument(rest);
+                        if arg.len() > 1 && arg.starts_with('{') {
+                            self.math_labels.insert(inside(arg).to_string());
+                        }
+                    }
+                    continue;
+                }
+                _ => None,
+            };
+            let current = target.as_ref().or(current);
+            for children in node.children() {
+                self.visit(children, current, items, headings, counts);
+            }
+        }
+    }
+
+    /// Define `label` as referring to `current`.
+    fn define(&mut self, label: &str, current: Option<&Target>, span: &Range<usize>) {
+        let (code, message) = if self.targets.contains_key(label) {
+            ("duplicate-label", "is already defined")
+        } else if let Some(target) = current {
+            self.targets.insert(label.to_string(), target.clone());
+            return;
+        } else {
+            ("stray-label", "is not on anything numbered")
+        };
+        self.diagnostics.push(Diagnostic {
+            code,
+            severity: Severity::Warning,
+            message: format!("the label {} {}", label, message),
+            span: span.clone(),
+        });
+    }
+
+    /// Complain if `node` labels something with a label that one of the
+    /// `equations` has too, keeping only whichever comes first.
+    fn check_equations(&mut self, node: &Node, equations: &mut Equations) {
+        let label = match &node.kind {
+            NodeKind::Macro { name, args } if name == "label" => match args.as_slice() {
+                [Argument::Verbatim(label)] => label,
+                _ => return,
+            },
+            _ => return,
+        };
+        if !self.targets.contains_key(label) {
+            return;
+        }
+        let span = match equations.label_span(label) {
+            Some(span) if span.start < node.span.start => {
+                self.targets.remove(label);
+                node.span.clone()
+            }
+            Some(span) => {
+                let span = span.clone();
+                equations.remove_label(label);
+                span
+            }
+            None => return,
+        };
+        self.diagnostics.push(Diagnostic {
+            code: "duplicate-label",
+            severity: Severity::Warning,
+            message: format!("the label {} is already defined", label),
+            span,
+        });
+    }
+
+    /// Complain if `node` refers to a label we do not know.
+    fn check(&mut self, node: &Node, equations: Option<&Equations>) {
+        let label = match &node.kind {
+            NodeKind::Macro { name, args } if is_reference(name) => match args.as_slice() {
+                [Argument::Verbatim(label)] => label,
+                _ => return,
+            },
+            NodeKind::Macro { name, args } if name == "hyperref" => match args.as_slice() {
+                [Argument::Optional(label), ..] => label,
+                _ => return,
+            },
+            _ => return,
+        };
+        if !self.is_defined(label, equations) {
+            self.diagnostics.push(Diagnostic {
+                code: "undefined-label",
+                severity: Severity::Warning,
+                message: format!("the label {} is not defined", label),
+                span: node.span.clone(),
+            });
+        }
+    }
+
+    /// What `label` refers to, unless it is an equation.
+    pub(crate) fn target(&self, label: &str) -> Option<&Target> {
+        self.targets.get(label)
+    }
+
+    /// Whether `label` is in some math, where MathJax will find it.
+    pub(crate) fn in_math(&self, label: &str) -> bool {
+        self.math_labels.contains(label)
+    }
+
+    /// Whether there is anything for `label` to refer to, given the
+    /// `equations` if we number them, and otherwise leaving equations to
+    /// MathJax.
+    pub(crate) fn is_defined(&self, label: &str, equations: Option<&Equations>) -> bool {
+        self.targets.contains_key(label)
+            || match equations {
+                Some(equations) => equations.number(label).is_some(),
+                None => self.in_math(label),
+            }
+    }
+
+    /// The number to show on the theorem, figure or table that came from
+    /// `span`.
+    pub(crate) fn number(&self, span: &Range<usize>) -> Option<&str> {
+        self.numbers.get(span).map(String::as_str)
+    }
+}
+
+/// Whether any of `nodes` holds a macro whose name satisfies `f`.
+fn contains(nodes: &[Node], f: impl Fn(&str) -> bool) -> bool {
+    let mut found = false;
+    for node in nodes {
+        node.walk(&mut |node| {
+            if let NodeKind::Macro { name, .. } = &node.kind {
+                found |= f(name);
+            }
+        });
+    }
+    found
+}
+
+/// The counter of item `count` of an `enumerate` nested in `depth`
+/// others, which is 1, a, i and then A, as in LaTeX.
+fn item_counter(depth: usize, count: usize) -> String {
+    const ROMAN: &[(usize, &str)] = &[
+        (1000, "m"),
+        (900, "cm"),
+        (500, "d"),
+        (400, "cd"),
+        (100, "c"),
+        (90, "xc"),
+        (50, "l"),
+        (40, "xl"),
+        (10, "x"),
+        (9, "ix"),
+        (5, "v"),
+        (4, "iv"),
+        (1, "i"),
+    ];
+    match depth {
+        1 | 3 if count <= 26 => {
+            let letter = (b'a' + count as u8 - 1) as char;
+            if depth == 1 {
+                letter.to_string()
+            } else {
+                letter.to_ascii_uppercase().to_string()
+            }
+        }
+        2 => {
+            let mut roman = String::new();
+            let mut rest = count;
+            for &(value, numeral) in ROMAN {
+                while rest >= value {
+                    roman.push_str(numeral);
+                    rest -= value;
+                }
+            }
+            roman
+        }
+        _ => count.to_string(),
+    }
+}
+
+/// How LaTeX refers to an item whose counter and those of the items it
+/// is in are `counters`, such as `2b` or `2(b)iii`.
+fn item_number(counters: &[String]) -> String {
+    match counters {
+        [first, second, rest @ ..] if !rest.is_empty() => {
+            format!("{}({}){}", first, second, rest.concat())
+        }
+        _ => counters.concat(),
+    }
+}
+
+#[test]
+fn test_references() {
+    let latex = r"\section{Intro}\label{sec:intro}
+\begin{figure}\caption{A}\end{figure}
+\begin{figure}x\caption{B}\label{fig:b}\end{figure}
+\begin{table}\caption{C}\label{tab:c}\end{table}
+\begin{enumerate}
+\item one \label{item:1}
+\item two \begin{enumerate}\item a \item inner \label{item:2b}
+  \begin{enumerate}\item i \item ii \item deep \label{item:2biii}\end{enumerate}\end{enumerate}
+\end{enumerate}
+\begin{lemma}\label{lem}\end{lemma}\begin{theorem}\end{theorem}\begin{lemma}\label{lem2}\end{lemma}
+\section*{Aside}\label{sec:aside}\label{fig:b}
+$$x \label{math}$$ \ref{math} \ref{elsewhere} \cref{missing}";
+    let document = crate::parse(latex);
+    let headings = Headings::new(&document.content);
+    let references = References::new(&document.content, &headings, None);
+    let number = |label| {
+        references
+            .target(label)
+            .map(|t| (t.kind, t.number.as_str()))
+    };
+    assert_eq!(Some((Kind::Section(1), "1")), number("sec:intro"));
+    assert_eq!(None, number("sec:aside"));
+    assert_eq!(Some((Kind::Figure, "2")), number("fig:b"));
+    assert_eq!(Some((Kind::Table, "1")), number("tab:c"));
+    assert_eq!(Some((Kind::Item, "1")), number("item:1"));
+    assert_eq!(Some((Kind::Item, "2b")), number("item:2b"));
+    assert_eq!(Some((Kind::Item, "2(b)iii")), number("item:2biii"));
+    assert_eq!(Some((Kind::Theorem("Lemma"), "2")), number("lem2"));
+    assert!(references.in_math("math"));
+    let codes: Vec<_> = references.diagnostics.iter().map(|d| d.code).collect();
+    assert_eq!(
+        vec![
+            "stray-label",
+            "duplicate-label",
+            "undefined-label",
+            "undefined-label"
+        ],
+        codes
+    );
+
+    let figure = Target {
+        kind: Kind::Figure,
+        number: "2".to_string(),
+    };
+    assert_eq!("Figure 2", reference_text("autoref", &figure));
+    assert_eq!("fig. 2", reference_text("cref", &figure));
+    let lemma = Target {
+        kind: Kind::Theorem("Lemma"),
+        number: "1".to_string(),
+    };
+    assert_eq!("lemma 1", reference_text("cref", &lemma));
+    let equation = Target {
+        kind: Kind::Equation,
+        number: "3".to_string(),
+    };
+    assert_eq!("Equation (3)", reference_text("Cref", &equation));
+    assert_eq!("3", reference_text("pageref", &equation));
+}
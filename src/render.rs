@@ -0,0 +1,476 @@
+//! Rendering LaTeX to HTML with a choice of options.
+
+use crate::source_map::Rewritten;
+use crate::{
+    omit_environ_mapped, only_environ_mapped, parse, Diagnostic, Equations, Expansions, HtmlWriter,
+    MacroTable, Severity, UnitOptions,
+};
+use wasm_bindgen::prelude::*;
+
+/// Whether to show a kind of environment, such as `solution`.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Visibility {
+    /// Show the environment along with everything else.
+    Show,
+    /// Leave the environment out.
+    Hide,
+    /// Show nothing but the content of the environment.
+    Only,
+}
+
+/// What to do when the LaTeX has errors in it.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorPolicy {
+    /// Render errors as `<span class="error">` elements.
+    Render,
+    /// Return an [`Error`] rather than any HTML.
+    Fail,
+}
+
+/// How to write math.
+///
+/// ```
+/// use latex_snippet::{render, MathOutput, RenderOptions};
+/// let options = RenderOptions::new().math(MathOutput::MathMl);
+/// let rendered = render(r"$\sqrt{x}$", &options).unwrap();
+/// assert_eq!("<math><msqrt><mi>x</mi></msqrt></math>", rendered.html);
+/// ```
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MathOutput {
+    /// Leave the LaTeX for MathJax or the like to typeset in the browser.
+    Tex,
+    /// Write MathML, leaving as LaTeX (with a diagnostic) any math that
+    /// we cannot convert.
+    MathMl,
+    /// Write simple inline math such as `$x^2$` as HTML, with italic
+    /// variables and `<sub>` and `<sup>`, leaving anything harder as
+    /// LaTeX.
+    Lite,
+}
+
+/// Whether to say in words what each piece of math is, for screen
+/// readers, and how fully.
+///
+/// The math is then wrapped in a `<span role="math">` whose
+/// `aria-label` holds the words, which need neither MathJax nor
+/// JavaScript.
+///
+/// ```
+/// use latex_snippet::{render, RenderOptions, Speech};
+/// let options = RenderOptions::new().speech(Speech::Brief);
+/// let rendered = render(r"$x^2 + 1$", &options).unwrap();
+/// assert_eq!(
+///     r#"<span role="math" aria-label="x squared plus 1">\(x^2 + 1\)</span>"#,
+///     rendered.html
+/// );
+/// ```
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Speech {
+    /// Say nothing, leaving it to MathJax or the like.
+    Off,
+    /// Speak briefly and naturally, as in "x squared plus one over two".
+    Brief,
+    /// Mark where everything starts and ends, as in "x superscript 2
+    /// baseline plus start fraction 1 over 2 end fraction".
+    Verbose,
+}
+
+/// How much work [`render`] may do, so that hostile LaTeX cannot make
+/// it run out of stack or memory.
+///
+/// When a limit is reached the HTML holds an error there instead, and
+/// there is a diagnostic saying which limit it was.
+///
+/// ```
+/// use latex_snippet::{render, RenderLimits, RenderOptions};
+/// let options = RenderOptions::new().limits(RenderLimits::new().max_depth(2));
+/// let rendered = render(r"\emph{\emph{\emph{deep}}}", &options).unwrap();
+/// assert_eq!(r#"<em><em><span class="error">\emph{deep}</span></em></em>"#, rendered.html);
+/// assert_eq!("too-deep", rendered.diagnostics[0].code);
+/// ```
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RenderLimits {
+    max_depth: usize,
+    max_output_bytes: usize,
+    max_expansions: usize,
+}
+
+impl Default for RenderLimits {
+    fn default() -> Self {
+        RenderLimits::new()
+    }
+}
+
+#[wasm_bindgen]
+impl RenderLimits {
+    /// Limits generous enough for any reasonable LaTeX.
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        RenderLimits {
+            max_depth: 64,
+            max_output_bytes: 64 << 20,
+            max_expansions: 100_000,
+        }
+    }
+
+    /// How deeply groups, arguments and environments may nest.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// How much HTML to write, and how much LaTeX macros may expand to.
+    /// This is checked before each element, so the HTML may run a little
+    /// past it.
+    pub fn max_output_bytes(mut self, bytes: usize) -> Self {
+        self.max_output_bytes = bytes;
+        self
+    }
+
+    /// How many macros to expand, such as `\ket` or those defined with
+    /// `\newcommand`.
+    pub fn max_expansions(mut self, expansions: usize) -> Self {
+        self.max_expansions = expansions;
+        self
+    }
+}
+
+impl RenderLimits {
+    pub(crate) fn depth(&self) -> usize {
+        self.max_depth
+    }
+
+    pub(crate) fn output_bytes(&self) -> usize {
+        self.max_output_bytes
+    }
+
+    pub(crate) fn expansions(&self) -> usize {
+        self.max_expansions
+    }
+}
+
+/// How to render LaTeX with [`render`].
+///
+/// ```
+/// use latex_snippet::{render, RenderOptions, Visibility};
+/// let options = RenderOptions::new()
+///     .physics_macros(true)
+///     .solutions(Visibility::Hide);
+/// let rendered = render(r"$\ket{0}$\begin{solution}secret\end{solution}", &options).unwrap();
+/// assert_eq!(r"\(\left|{0}\right\rangle \)", rendered.html);
+/// ```
+#[wasm_bindgen]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderOptions {
+    physics_macros: bool,
+    macros: MacroTable,
+    solutions: Visibility,
+    guides: Visibility,
+    handouts: Visibility,
+    image_directory: String,
+    heading_level: u8,
+    errors: ErrorPolicy,
+    source_positions: bool,
+    limits: RenderLimits,
+    units: UnitOptions,
+    math: MathOutput,
+    number_equations: bool,
+    speech: Speech,
+    number_sections: bool,
+    heading_ids: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions::new()
+    }
+}
+
+#[wasm_bindgen]
+impl RenderOptions {
+    /// The options used by [`html`](crate::html).
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        RenderOptions {
+            physics_macros: false,
+            macros: MacroTable::new(),
+            solutions: Visibility::Show,
+            guides: Visibility::Show,
+            handouts: Visibility::Show,
+            image_directory: String::new(),
+            heading_level: 2,
+            errors: ErrorPolicy::Render,
+            source_positions: false,
+            limits: RenderLimits::new(),
+            units: UnitOptions::new(),
+            math: MathOutput::Tex,
+            number_equations: false,
+            speech: Speech::Off,
+            number_sections: false,
+            heading_ids: false,
+        }
+    }
+
+    /// Whether to expand `\ket`, `\bra` and the other
+    /// [`physics_macros`](crate::physics_macros) first.
+    pub fn physics_macros(mut self, expand: bool) -> Self {
+        self.physics_macros = expand;
+        self
+    }
+
+    /// Expand the macros in `table` first, along with the physics macros
+    /// if we expand those, which `table` may override.
+    pub fn macros(mut self, table: MacroTable) -> Self {
+        self.macros = table;
+        self
+    }
+
+    /// Whether to show `solution` environments.
+    pub fn solutions(mut self, visibility: Visibility) -> Self {
+        self.solutions = visibility;
+        self
+    }
+
+    /// Whether to show `guide` environments.
+    pub fn guides(mut self, visibility: Visibility) -> Self {
+        self.guides = visibility;
+        self
+    }
+
+    /// Whether to show `handout` environments.
+    pub fn handouts(mut self, visibility: Visibility) -> Self {
+        self.handouts = visibility;
+        self
+    }
+
+    /// A directory to prepend to the file name of each
+    /// `\includegraphics`.
+    pub fn image_directory(mut self, directory: &str) -> Self {
+        self.image_directory = directory.to_string();
+        self
+    }
+
+    /// The HTML heading level of a `\section`, which is 2 by default so
+    /// that `\section` becomes `<h2>`.
+    pub fn heading_level(mut self, level: u8) -> Self {
+        self.heading_level = level.clamp(1, 6);
+        self
+    }
+
+    /// What to do about errors in the LaTeX.
+    pub fn errors(mut self, policy: ErrorPolicy) -> Self {
+        self.errors = policy;
+        self
+    }
+
+    /// Whether to mark each element with `data-src-start` and
+    /// `data-src-end` attributes holding the byte range of the LaTeX it
+    /// came from.
+    pub fn source_positions(mut self, mark: bool) -> Self {
+        self.source_positions = mark;
+        self
+    }
+
+    /// How much work to do before giving up.
+    pub fn limits(mut self, limits: RenderLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// How to write the numbers and units of `\SI`, `\num` and friends.
+    /// With [`physics_macros`](Self::physics_macros), `\qty` is the
+    /// physics one, as it is in LaTeX.
+    pub fn units(mut self, units: UnitOptions) -> Self {
+        self.units = units;
+        self
+    }
+
+    /// Whether to write math as LaTeX, which is the default, as MathML,
+    /// or as HTML where it is simple enough.
+    pub fn math(mut self, output: MathOutput) -> Self {
+        self.math = output;
+        self
+    }
+
+    /// Whether to number equations ourselves, so that `\ref` and
+    /// `\eqref` become links to them, rather than leaving that to
+    /// MathJax.  Undefined and duplicate labels are then diagnostics.
+    pub fn number_equations(mut self, number: bool) -> Self {
+        self.number_equations = number;
+        self
+    }
+
+    /// Whether to give each piece of math an `aria-label` saying it in
+    /// words, and how fully.
+    pub fn speech(mut self, verbosity: Speech) -> Self {
+        self.speech = verbosity;
+        self
+    }
+
+    /// Whether to number headings as LaTeX does, as in 1, 1.1 and
+    /// 1.1.1, leaving starred headings unnumbered.
+    pub fn number_sections(mut self, number: bool) -> Self {
+        self.number_sections = number;
+        self
+    }
+
+    /// Whether to give each heading an id made from its title, such as
+    /// `id="energy-and-work"`, so that it can be linked to.  A document
+    /// with `\tableofcontents` gets them regardless.
+    pub fn heading_ids(mut self, ids: bool) -> Self {
+        self.heading_ids = ids;
+        self
+    }
+}
+
+impl RenderOptions {
+    /// The HTML heading level for a LaTeX heading of `level`, where 1 is
+    /// `\section`.
+    pub(crate) fn html_heading(&self, level: u8) -> u8 {
+        (self.heading_level + level - 1).min(6)
+    }
+
+    pub(crate) fn image_prefix(&self) -> &str {
+        &self.image_directory
+    }
+
+    pub(crate) fn render_limits(&self) -> &RenderLimits {
+        &self.limits
+    }
+
+    pub(crate) fn unit_options(&self) -> &UnitOptions {
+        &self.units
+    }
+
+    pub(crate) fn math_output(&self) -> MathOutput {
+        self.math
+    }
+
+    pub(crate) fn speech_verbosity(&self) -> Speech {
+        self.speech
+    }
+
+    pub(crate) fn section_numbering(&self) -> bool {
+        self.number_sections
+    }
+
+    pub(crate) fn heading_anchors(&self) -> bool {
+        self.heading_ids
+    }
+}
+
+/// LaTeX rendered as HTML by [`render`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rendered {
+    /// The HTML.
+    pub html: String,
+    /// Any problems found in the LaTeX.
+    pub diagnostics: Vec<Diagnostic>,
+    /// The table of contents, as nested lists of links to the headings,
+    /// which is empty unless the headings have ids.
+    pub table_of_contents: String,
+}
+
+/// Why [`render`] failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The LaTeX had errors, and we were asked to fail on errors.
+    Invalid(Vec<Diagnostic>),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Invalid(diagnostics) => {
+                write!(f, "invalid LaTeX")?;
+                for d in diagnostics.iter().filter(|d| d.severity == Severity::Error) {
+                    write!(f, "\n{}..{}: {}", d.span.start, d.span.end, d.message)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Render some LaTeX as HTML.
+pub fn render(latex: &str, options: &RenderOptions) -> Result<Rendered, Error> {
+    let source = latex;
+    let mut latex = Rewritten::new(latex);
+    let mut budget = Expansions::new(&options.limits);
+    if options.physics_macros {
+        let table = MacroTable::physics().extend(&options.macros);
+        latex = latex.then(|latex| table.expand_mapped(latex, &mut budget));
+    } else if !options.macros.is_empty() {
+        latex = latex.then(|latex| options.macros.expand_mapped(latex, &mut budget));
+    }
+    for (environ, visibility) in [
+        ("solution", options.solutions),
+        ("guide", options.guides),
+        ("handout", options.handouts),
+    ] {
+        latex = match visibility {
+            Visibility::Show => latex,
+            Visibility::Hide => latex.then(|latex| omit_environ_mapped(latex, environ)),
+            Visibility::Only => latex.then(|latex| only_environ_mapped(latex, environ)),
+        };
+    }
+    let (document, mut diagnostics) = parse::parse_rewritten(latex, &mut budget);
+    let mut w = HtmlWriter::with_options(Vec::new(), options.clone());
+    if options.source_positions {
+        w.sources = Some(Vec::new());
+    }
+    if options.number_equations {
+        let mut equations = Equations::new(&document.content, source);
+        w.diagnostics.append(&mut equations.diagnostics);
+        w.equations = Some(equations);
+    }
+    w.write_document(&document.content)
+        .expect("writing to a Vec cannot fail");
+    if !w.diagnostics.is_empty() {
+        diagnostics.append(&mut w.diagnostics);
+        diagnostics.sort_by_key(|d| d.span.start);
+    }
+    if let Some(span) = w.truncated.clone() {
+        diagnostics.push(Diagnostic {
+            code: "output-too-large",
+            severity: Severity::Error,
+            message: format!(
+                "the HTML is longer than the limit of {} bytes",
+                options.limits.max_output_bytes
+            ),
+            span,
+        });
+    }
+    // Only now do we have all the diagnostics, including those from
+    // writing the HTML.
+    if options.errors == ErrorPolicy::Fail
+        && diagnostics.iter().any(|d| d.severity == Severity::Error)
+    {
+        return Err(Error::Invalid(diagnostics));
+    }
+    let mut contents = HtmlWriter::with_options(Vec::new(), options.clone());
+    if w.heading_ids() {
+        contents.headings = w.headings.clone();
+        contents
+            .write_table_of_contents()
+            .expect("writing to a Vec cannot fail");
+    }
+    let html = if options.source_positions {
+        w.with_source_positions()
+    } else {
+        w.fmt
+    };
+    Ok(Rendered {
+        html: String::from_utf8(html).expect("should be no problem with utf8 conversion"),
+        diagnostics,
+        table_of_contents: String::from_utf8(contents.fmt)
+            .expect("should be no problem with utf8 conversion"),
+    })
+}
@@ -0,0 +1,775 @@
+//! Numbers and quantities with units, written as with the `siunitx`
+//! package.
+
+use crate::parse::{argument, defined_in_math, inside, macro_name, rewrite_macros};
+use wasm_bindgen::prelude::*;
+
+/// How to write the power of ten in a number such as `\num{6.02e23}`.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExponentStyle {
+    /// 6.02 × 10²³
+    Times,
+    /// 6.02 · 10²³
+    Cdot,
+    /// 6.02e23, much as it was written.
+    Letter,
+}
+
+/// How to write the units we divide by, as in `\meter\per\second`.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PerMode {
+    /// With negative powers, as in m s⁻¹.
+    Power,
+    /// After a slash, as in m/s.
+    Symbol,
+}
+
+/// How to write numbers and units in `\SI`, `\num` and friends.
+///
+/// ```
+/// use latex_snippet::{render, ExponentStyle, RenderOptions, UnitOptions};
+/// let units = UnitOptions::new()
+///     .decimal_marker(',')
+///     .exponent(ExponentStyle::Cdot);
+/// let options = RenderOptions::new().units(units);
+/// let rendered = render(r"\num{6.02e23}", &options).unwrap();
+/// assert_eq!("6,02&#8239;&middot;&#8239;10<sup>23</sup>", rendered.html);
+/// ```
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UnitOptions {
+    decimal_marker: char,
+    group_digits: bool,
+    exponent: ExponentStyle,
+    per_mode: PerMode,
+}
+
+impl Default for UnitOptions {
+    fn default() -> Self {
+        UnitOptions::new()
+    }
+}
+
+#[wasm_bindgen]
+impl UnitOptions {
+    /// The defaults of `siunitx`.
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        UnitOptions {
+            decimal_marker: '.',
+            group_digits: true,
+            exponent: ExponentStyle::Times,
+            per_mode: PerMode::Power,
+        }
+    }
+
+    /// What to write between the integer and decimal parts of a number,
+    /// whichever of `.` or `,` it was written with.
+    pub fn decimal_marker(mut self, marker: char) -> Self {
+        self.decimal_marker = marker;
+        self
+    }
+
+    /// Whether to group the digits of numbers with five or more digits
+    /// before or after the decimal marker in threes.
+    pub fn group_digits(mut self, group: bool) -> Self {
+        self.group_digits = group;
+        self
+    }
+
+    /// How to write the power of ten.
+    pub fn exponent(mut self, style: ExponentStyle) -> Self {
+        self.exponent = style;
+        self
+    }
+
+    /// How to write the units we divide by.
+    pub fn per_mode(mut self, mode: PerMode) -> Self {
+        self.per_mode = mode;
+        self
+    }
+}
+
+/// The `siunitx` macros we understand, without their backslashes, with
+/// how many arguments they take after any optional one.
+const MACROS: &[(&str, usize)] = &[
+    ("SI", 2),
+    ("qty", 2),
+    ("si", 1),
+    ("unit", 1),
+    ("num", 1),
+    ("ang", 1),
+];
+
+/// How many arguments the `siunitx` macro `name` takes, if it is one.
+pub(crate) fn arguments(name: &str) -> Option<usize> {
+    MACROS.iter().find(|(m, _)| *m == name).map(|&(_, n)| n)
+}
+
+/// The macros we understand in units, without their backslashes.
+pub(crate) fn unit_macros() -> impl Iterator<Item = &'static str> {
+    let units = UNITS.iter().map(|(name, _)| *name);
+    let prefixes = PREFIXES.iter().map(|(name, _)| *name);
+    let abbreviations = ABBREVIATIONS.iter().map(|(name, _, _)| *name);
+    let powers = [
+        "per", "square", "cubic", "squared", "cubed", "tothe", "raiseto",
+    ];
+    units.chain(prefixes).chain(abbreviations).chain(powers)
+}
+
+/// How a unit or prefix is written in HTML and in math.
+type Symbol = (&'static str, &'static str);
+
+const UNITS: &[(&str, Symbol)] = &[
+    ("ampere", ("A", r"\mathrm{A}")),
+    ("candela", ("cd", r"\mathrm{cd}")),
+    ("kelvin", ("K", r"\mathrm{K}")),
+    ("kilogram", ("kg", r"\mathrm{kg}")),
+    ("gram", ("g", r"\mathrm{g}")),
+    ("metre", ("m", r"\mathrm{m}")),
+    ("meter", ("m", r"\mathrm{m}")),
+    ("mole", ("mol", r"\mathrm{mol}")),
+    ("second", ("s", r"\mathrm{s}")),
+    ("becquerel", ("Bq", r"\mathrm{Bq}")),
+    ("degreeCelsius", ("&deg;C", r"{}^{\circ}\mathrm{C}")),
+    ("coulomb", ("C", r"\mathrm{C}")),
+    ("farad", ("F", r"\mathrm{F}")),
+    ("gray", ("Gy", r"\mathrm{Gy}")),
+    ("hertz", ("Hz", r"\mathrm{Hz}")),
+    ("henry", ("H", r"\mathrm{H}")),
+    ("joule", ("J", r"\mathrm{J}")),
+    ("katal", ("kat", r"\mathrm{kat}")),
+    ("lumen", ("lm", r"\mathrm{lm}")),
+    ("lux", ("lx", r"\mathrm{lx}")),
+    ("newton", ("N", r"\mathrm{N}")),
+    ("ohm", ("&Omega;", r"\Omega")),
+    ("pascal", ("Pa", r"\mathrm{Pa}")),
+    ("radian", ("rad", r"\mathrm{rad}")),
+    ("siemens", ("S", r"\mathrm{S}")),
+    ("sievert", ("Sv", r"\mathrm{Sv}")),
+    ("steradian", ("sr", r"\mathrm{sr}")),
+    ("tesla", ("T", r"\mathrm{T}")),
+    ("volt", ("V", r"\mathrm{V}")),
+    ("watt", ("W", r"\mathrm{W}")),
+    ("weber", ("Wb", r"\mathrm{Wb}")),
+    ("astronomicalunit", ("au", r"\mathrm{au}")),
+    ("bel", ("B", r"\mathrm{B}")),
+    ("dalton", ("Da", r"\mathrm{Da}")),
+    ("day", ("d", r"\mathrm{d}")),
+    ("decibel", ("dB", r"\mathrm{dB}")),
+    ("degree", ("&deg;", r"{}^{\circ}")),
+    ("electronvolt", ("eV", r"\mathrm{eV}")),
+    ("hectare", ("ha", r"\mathrm{ha}")),
+    ("hour", ("h", r"\mathrm{h}")),
+    ("litre", ("L", r"\mathrm{L}")),
+    ("liter", ("L", r"\mathrm{L}")),
+    ("arcminute", ("&prime;", r"{}'")),
+    ("arcsecond", ("&Prime;", r"{}''")),
+    ("minute", ("min", r"\mathrm{min}")),
+    ("neper", ("Np", r"\mathrm{Np}")),
+    ("tonne", ("t", r"\mathrm{t}")),
+    ("percent", ("%", r"\%")),
+    ("angstrom", ("&Aring;", r"\mathring{\mathrm{A}}")),
+    ("bar", ("bar", r"\mathrm{bar}")),
+    ("atomicmassunit", ("u", r"\mathrm{u}")),
+];
+
+const PREFIXES: &[(&str, Symbol)] = &[
+    ("quecto", ("q", r"\mathrm{q}")),
+    ("ronto", ("r", r"\mathrm{r}")),
+    ("yocto", ("y", r"\mathrm{y}")),
+    ("zepto", ("z", r"\mathrm{z}")),
+    ("atto", ("a", r"\mathrm{a}")),
+    ("femto", ("f", r"\mathrm{f}")),
+    ("pico", ("p", r"\mathrm{p}")),
+    ("nano", ("n", r"\mathrm{n}")),
+    ("micro", ("&micro;", r"\mu")),
+    ("milli", ("m", r"\mathrm{m}")),
+    ("centi", ("c", r"\mathrm{c}")),
+    ("deci", ("d", r"\mathrm{d}")),
+    ("deca", ("da", r"\mathrm{da}")),
+    ("deka", ("da", r"\mathrm{da}")),
+    ("hecto", ("h", r"\mathrm{h}")),
+    ("kilo", ("k", r"\mathrm{k}")),
+    ("mega", ("M", r"\mathrm{M}")),
+    ("giga", ("G", r"\mathrm{G}")),
+    ("tera", ("T", r"\mathrm{T}")),
+    ("peta", ("P", r"\mathrm{P}")),
+    ("exa", ("E", r"\mathrm{E}")),
+    ("zetta", ("Z", r"\mathrm{Z}")),
+    ("yotta", ("Y", r"\mathrm{Y}")),
+    ("ronna", ("R", r"\mathrm{R}")),
+    ("quetta", ("Q", r"\mathrm{Q}")),
+];
+
+/// Abbreviations for a prefix and a unit, such as `\km`.
+const ABBREVIATIONS: &[(&str, &str, &str)] = &[
+    ("fm", "femto", "metre"),
+    ("pm", "pico", "metre"),
+    ("nm", "nano", "metre"),
+    ("um", "micro", "metre"),
+    ("mm", "milli", "metre"),
+    ("cm", "centi", "metre"),
+    ("dm", "deci", "metre"),
+    ("m", "", "metre"),
+    ("km", "kilo", "metre"),
+    ("as", "atto", "second"),
+    ("fs", "femto", "second"),
+    ("ps", "pico", "second"),
+    ("ns", "nano", "second"),
+    ("us", "micro", "second"),
+    ("ms", "milli", "second"),
+    ("s", "", "second"),
+    ("ug", "micro", "gram"),
+    ("mg", "milli", "gram"),
+    ("g", "", "gram"),
+    ("kg", "", "kilogram"),
+    ("pA", "pico", "ampere"),
+    ("nA", "nano", "ampere"),
+    ("uA", "micro", "ampere"),
+    ("mA", "milli", "ampere"),
+    ("A", "", "ampere"),
+    ("kA", "kilo", "ampere"),
+    ("K", "", "kelvin"),
+    ("mmol", "milli", "mole"),
+    ("mol", "", "mole"),
+    ("kmol", "kilo", "mole"),
+    ("Hz", "", "hertz"),
+    ("kHz", "kilo", "hertz"),
+    ("MHz", "mega", "hertz"),
+    ("GHz", "giga", "hertz"),
+    ("THz", "tera", "hertz"),
+    ("mN", "milli", "newton"),
+    ("N", "", "newton"),
+    ("kN", "kilo", "newton"),
+    ("MN", "mega", "newton"),
+    ("Pa", "", "pascal"),
+    ("kPa", "kilo", "pascal"),
+    ("MPa", "mega", "pascal"),
+    ("GPa", "giga", "pascal"),
+    ("mJ", "milli", "joule"),
+    ("J", "", "joule"),
+    ("kJ", "kilo", "joule"),
+    ("meV", "milli", "electronvolt"),
+    ("eV", "", "electronvolt"),
+    ("keV", "kilo", "electronvolt"),
+    ("MeV", "mega", "electronvolt"),
+    ("GeV", "giga", "electronvolt"),
+    ("TeV", "tera", "electronvolt"),
+    ("mW", "milli", "watt"),
+    ("W", "", "watt"),
+    ("kW", "kilo", "watt"),
+    ("MW", "mega", "watt"),
+    ("GW", "giga", "watt"),
+    ("mV", "milli", "volt"),
+    ("V", "", "volt"),
+    ("kV", "kilo", "volt"),
+    ("nC", "nano", "coulomb"),
+    ("uC", "micro", "coulomb"),
+    ("mC", "milli", "coulomb"),
+    ("C", "", "coulomb"),
+    ("pF", "pico", "farad"),
+    ("nF", "nano", "farad"),
+    ("uF", "micro", "farad"),
+    ("mF", "milli", "farad"),
+    ("F", "", "farad"),
+    ("mT", "milli", "tesla"),
+    ("T", "", "tesla"),
+    ("uL", "micro", "litre"),
+    ("mL", "milli", "litre"),
+    ("L", "", "litre"),
+    ("ul", "micro", "litre"),
+    ("ml", "milli", "litre"),
+    ("l", "", "litre"),
+    ("h", "", "hour"),
+];
+
+fn symbol(table: &[(&str, Symbol)], name: &str) -> Option<Symbol> {
+    table.iter().find(|(n, _)| *n == name).map(|&(_, s)| s)
+}
+
+/// How to write the pieces of numbers and units.
+struct Notation {
+    /// Between groups of digits and between units.
+    thin: &'static str,
+    /// Between a number and its unit.
+    space: &'static str,
+    minus: &'static str,
+    plus_minus: &'static str,
+    times: &'static str,
+    cdot: &'static str,
+    degree: &'static str,
+    arcminute: &'static str,
+    arcsecond: &'static str,
+    html: bool,
+}
+
+const HTML: Notation = Notation {
+    thin: "&#8239;",
+    space: "&nbsp;",
+    minus: "&minus;",
+    plus_minus: "&#8239;&plusmn;&#8239;",
+    times: "&#8239;&times;&#8239;",
+    cdot: "&#8239;&middot;&#8239;",
+    degree: "&deg;",
+    arcminute: "&prime;",
+    arcsecond: "&Prime;",
+    html: true,
+};
+
+const MATH: Notation = Notation {
+    thin: r"\,",
+    space: r"\,",
+    minus: "-",
+    plus_minus: r"\pm ",
+    times: r"\times ",
+    cdot: r"\cdot ",
+    degree: r"^{\circ}",
+    arcminute: "'",
+    arcsecond: "''",
+    html: false,
+};
+
+impl Notation {
+    fn power(&self, power: &str) -> String {
+        if self.html {
+            format!("<sup>{}</sup>", power.replace('-', self.minus))
+        } else {
+            format!("^{{{}}}", power)
+        }
+    }
+
+    fn marker(&self, marker: char) -> String {
+        match marker {
+            ',' if !self.html => "{,}".to_string(),
+            _ => marker.to_string(),
+        }
+    }
+}
+
+/// A number as written in `\num`, in pieces.
+#[derive(Debug, PartialEq, Eq)]
+struct Number<'a> {
+    negative: bool,
+    integer: &'a str,
+    decimal: Option<&'a str>,
+    exponent: Option<(bool, &'a str)>,
+}
+
+/// Whether `latex` starts with a minus sign, and what follows any sign.
+fn sign(latex: &str) -> (bool, &str) {
+    match latex.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, latex.strip_prefix('+').unwrap_or(latex)),
+    }
+}
+
+/// Split a single number such as `-6.02e23` into its pieces.
+fn number(latex: &str) -> Option<Number<'_>> {
+    let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (negative, rest) = sign(latex);
+    let (integer, mut rest) = rest.split_at(digits(rest));
+    let mut decimal = None;
+    if let Some(after) = rest.strip_prefix(['.', ',']) {
+        let (d, after) = after.split_at(digits(after));
+        decimal = Some(d);
+        rest = after;
+    }
+    let mut exponent = None;
+    if let Some(after) = rest.strip_prefix(['e', 'E', 'd', 'D']) {
+        let (negative, after) = sign(after);
+        let (e, after) = after.split_at(digits(after));
+        if e.is_empty() {
+            return None;
+        }
+        exponent = Some((negative, e));
+        rest = after;
+    }
+    let mantissa = integer.len() + decimal.map_or(0, str::len);
+    if !rest.is_empty() || (mantissa == 0 && exponent.is_none()) {
+        return None;
+    }
+    Some(Number {
+        negative,
+        integer,
+        decimal,
+        exponent,
+    })
+}
+
+/// Put `thin` between groups of three digits, counting from the right if
+/// `from_right`, when there are five or more of them.
+fn group(digits: &str, from_right: bool, thin: &str) -> String {
+    if digits.len() < 5 {
+        return digits.to_string();
+    }
+    let mut out = String::with_capacity(digits.len() * 2);
+    let first = if from_right { digits.len() % 3 } else { 3 };
+    for (i, c) in digits.char_indices() {
+        if i > 0 && (i == first || (i > first && (i - first) % 3 == 0)) {
+            out.push_str(thin);
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Write the number in `latex`, which may have an uncertainty after
+/// `\pm` or `+-`.
+fn format_number(latex: &str, options: &UnitOptions, n: &Notation) -> Option<String> {
+    let latex: String = latex.split_whitespace().collect();
+    let latex = latex.replace(r"\pm", "+-");
+    let mut parts = latex.splitn(2, "+-");
+    let value = format_single(parts.next()?, options, n)?;
+    match parts.next() {
+        Some(uncertainty) => {
+            let uncertainty = format_single(uncertainty, options, n)?;
+            Some(format!("{}{}{}", value, n.plus_minus, uncertainty))
+        }
+        None => Some(value),
+    }
+}
+
+fn format_single(latex: &str, options: &UnitOptions, n: &Notation) -> Option<String> {
+    let number = number(latex)?;
+    let mut out = String::new();
+    if number.negative {
+        out.push_str(n.minus);
+    }
+    let grouped = |digits, from_right| {
+        if options.group_digits {
+            group(digits, from_right, n.thin)
+        } else {
+            String::from(digits)
+        }
+    };
+    if number.integer.is_empty() && number.decimal.is_some() {
+        out.push('0');
+    }
+    out.push_str(&grouped(number.integer, true));
+    if let Some(decimal) = number.decimal {
+        out.push_str(&n.marker(options.decimal_marker));
+        out.push_str(&grouped(decimal, false));
+    }
+    if let Some((negative, exponent)) = number.exponent {
+        let sign = if negative { "-" } else { "" };
+        if options.exponent == ExponentStyle::Letter {
+            out.push('e');
+            out.push_str(if negative { n.minus } else { "" });
+            out.push_str(exponent);
+        } else {
+            if !out.is_empty() && out != n.minus {
+                let times = match options.exponent {
+                    ExponentStyle::Cdot => n.cdot,
+                    _ => n.times,
+                };
+                out.push_str(times);
+            }
+            out.push_str("10");
+            out.push_str(&n.power(&format!("{}{}", sign, exponent)));
+        }
+    }
+    Some(out)
+}
+
+/// A unit with its prefix and power.
+#[derive(Debug)]
+struct Factor {
+    symbol: String,
+    /// The power it is raised to as written, which is empty for one.
+    power: String,
+    /// Whether we divide by it, after `\per` or `/`.
+    per: bool,
+}
+
+impl Factor {
+    /// The power it is raised to once we account for dividing by it.
+    fn exponent(&self) -> String {
+        if self.per {
+            negate(&self.power)
+        } else {
+            self.power.clone()
+        }
+    }
+}
+
+/// The negative of `power`, where an empty power is one.
+fn negate(power: &str) -> String {
+    match power.strip_prefix('-') {
+        Some("1") => String::new(),
+        Some(power) => power.to_string(),
+        None if power.is_empty() => "-1".to_string(),
+        None => format!("-{}", power),
+    }
+}
+
+/// A power such as `2`, `{-2}` or `-2` at the start of `latex`, with
+/// what follows it.
+fn power(latex: &str) -> Option<(&str, &str)> {
+    let arg = argument(latex);
+    let (power, rest) = if arg.starts_with('{') && arg.len() > 1 {
+        (inside(arg).trim(), &latex[arg.len()..])
+    } else {
+        let sign = usize::from(latex.starts_with('-'));
+        let len = latex[sign..]
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(latex.len() - sign);
+        latex.split_at(sign + len)
+    };
+    let digits = power.strip_prefix('-').unwrap_or(power);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    Some((power, rest))
+}
+
+/// Read the units in `latex`, written either with macros such as
+/// `\kilo\meter\per\second` or literally such as `km/s`.
+fn units(latex: &str, n: &Notation) -> Option<Vec<Factor>> {
+    let mut factors: Vec<Factor> = Vec::new();
+    let mut prefix = String::new();
+    let mut prefix_power = None;
+    let mut per = false;
+    let mut slash = false;
+    let mut rest = latex.trim();
+    let symbol_of = |s: Symbol| if n.html { s.0 } else { s.1 };
+    while !rest.is_empty() {
+        let mut unit = None;
+        if rest.starts_with('\\') {
+            let name = macro_name(rest);
+            rest = &rest[name.len()..];
+            let name = &name[1..];
+            if let Some(s) = symbol(UNITS, name) {
+                unit = Some(symbol_of(s).to_string());
+            } else if let Some(s) = symbol(PREFIXES, name) {
+                prefix.push_str(symbol_of(s));
+            } else if let Some(&(_, p, u)) = ABBREVIATIONS.iter().find(|(a, _, _)| *a == name) {
+                let p = symbol(PREFIXES, p).map_or("", symbol_of);
+                let u = symbol(UNITS, u).map_or("", symbol_of);
+                unit = Some(format!("{}{}", p, u));
+            } else {
+                let last = factors.last_mut();
+                match (name, last) {
+                    ("per", _) => per = true,
+                    ("square", _) => prefix_power = Some("2".to_string()),
+                    ("cubic", _) => prefix_power = Some("3".to_string()),
+                    ("raiseto", _) => {
+                        let (p, after) = power(rest)?;
+                        prefix_power = Some(p.to_string());
+                        rest = after;
+                    }
+                    ("squared", Some(last)) => last.power = "2".to_string(),
+                    ("cubed", Some(last)) => last.power = "3".to_string(),
+                    ("tothe", Some(last)) => {
+                        let (p, after) = power(rest)?;
+                        last.power = p.to_string();
+                        rest = after;
+                    }
+                    _ => return None,
+                }
+                continue;
+            }
+        } else if rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(rest.len());
+            let (letters, after) = rest.split_at(len);
+            rest = after;
+            unit = Some(if n.html {
+                letters.to_string()
+            } else {
+                format!(r"\mathrm{{{}}}", letters)
+            });
+        } else if let Some(after) = rest.strip_prefix('^') {
+            let (p, after) = power(after)?;
+            factors.last_mut()?.power = p.to_string();
+            rest = after;
+            continue;
+        } else if let Some(after) = rest.strip_prefix('/') {
+            slash = true;
+            rest = after;
+            continue;
+        } else if let Some(after) = rest.strip_prefix(['.', '~', ' ']) {
+            rest = after;
+            continue;
+        } else {
+            return None;
+        }
+        if let Some(unit) = unit {
+            factors.push(Factor {
+                symbol: format!("{}{}", std::mem::take(&mut prefix), unit),
+                power: prefix_power.take().unwrap_or_default(),
+                per: per || slash,
+            });
+            per = false;
+        }
+    }
+    if !prefix.is_empty() || prefix_power.is_some() || per || factors.is_empty() {
+        return None;
+    }
+    Some(factors)
+}
+
+/// Write units, dividing by those with negative powers as `per_mode`
+/// says.
+fn format_units(factors: &[Factor], options: &UnitOptions, n: &Notation) -> String {
+    let write = |factors: &[(&str, String)]| {
+        let written: Vec<String> = factors
+            .iter()
+            .map(|(symbol, power)| {
+                if power.is_empty() {
+                    symbol.to_string()
+                } else {
+                    format!("{}{}", symbol, n.power(power))
+                }
+            })
+            .collect();
+        written.join(n.thin)
+    };
+    let exponents: Vec<(&str, String)> = factors
+        .iter()
+        .map(|f| (f.symbol.as_str(), f.exponent()))
+        .collect();
+    if options.per_mode == PerMode::Power {
+        return write(&exponents);
+    }
+    let (under, over): (Vec<_>, Vec<_>) =
+        exponents.into_iter().partition(|(_, p)| p.starts_with('-'));
+    if under.is_empty() {
+        return write(&over);
+    }
+    let under: Vec<_> = under.into_iter().map(|(s, p)| (s, negate(&p))).collect();
+    let over = if over.is_empty() {
+        "1".to_string()
+    } else {
+        write(&over)
+    };
+    if under.len() > 1 {
+        format!("{}/({})", over, write(&under))
+    } else {
+        format!("{}/{}", over, write(&under))
+    }
+}
+
+/// Whether there is no space between a number and these units, as for
+/// degrees of arc.
+fn unspaced(latex: &str) -> bool {
+    matches!(latex.trim(), r"\degree" | r"\arcminute" | r"\arcsecond")
+}
+
+/// Write an angle such as `30;15;10` in degrees, minutes and seconds.
+fn format_angle(latex: &str, options: &UnitOptions, n: &Notation) -> Option<String> {
+    let mut out = String::new();
+    let marks = [n.degree, n.arcminute, n.arcsecond];
+    let parts: Vec<&str> = latex.split(';').collect();
+    if parts.len() > marks.len() {
+        return None;
+    }
+    for (part, mark) in parts.iter().zip(marks) {
+        if !part.trim().is_empty() {
+            out.push_str(&format_number(part, options, n)?);
+            out.push_str(mark);
+        }
+    }
+    if out.is_empty() {
+        return None;
+    }
+    Some(out)
+}
+
+/// Write the `siunitx` macro `name` with its arguments `args`, leaving
+/// out any optional one, or `None` if we cannot make sense of them.
+fn format(name: &str, args: &[&str], options: &UnitOptions, n: &Notation) -> Option<String> {
+    match (name, args) {
+        ("SI" | "qty", [value, unit]) => {
+            let value = format_number(value, options, n)?;
+            let units = format_units(&units(unit, n)?, options, n);
+            let space = if unspaced(unit) { "" } else { n.space };
+            Some(format!("{}{}{}", value, space, units))
+        }
+        ("si" | "unit", [unit]) => Some(format_units(&units(unit, n)?, options, n)),
+        ("num", [value]) => format_number(value, options, n),
+        ("ang", [angle]) => format_angle(angle, options, n),
+        _ => None,
+    }
+}
+
+/// The HTML for the `siunitx` macro `name` with arguments `args`.
+pub(crate) fn html(name: &str, args: &[&str], options: &UnitOptions) -> Option<String> {
+    format(name, args, options, &HTML)
+}
+
+/// The LaTeX math for the `siunitx` macro `name` with arguments `args`.
+pub(crate) fn tex(name: &str, args: &[&str], options: &UnitOptions) -> Option<String> {
+    format(name, args, options, &MATH)
+}
+
+/// Rewrite the `siunitx` macros in `math` as plain LaTeX that MathJax
+/// understands, or `None` if there are none.  Any we cannot make sense
+/// of are left for MathJax to report, and any the math defines for
+/// itself are left for MathJax to expand.
+pub(crate) fn expand_in_math(math: &str, options: &UnitOptions) -> Option<String> {
+    let defined = defined_in_math(math);
+    let arguments = |name: &str| arguments(name).filter(|_| !defined.contains(name));
+    rewrite_macros(math, arguments, |name, args| tex(name, args, options))
+}
+
+#[test]
+fn test_number() {
+    let html = |latex| format_number(latex, &UnitOptions::new(), &HTML);
+    assert_eq!(
+        Some("6.02&#8239;&times;&#8239;10<sup>23</sup>".to_string()),
+        html("6.02e23")
+    );
+    assert_eq!(Some("&minus;1.5".to_string()), html("-1.5"));
+    assert_eq!(Some("0.5".to_string()), html(".5"));
+    assert_eq!(Some("10<sup>&minus;3</sup>".to_string()), html("e-3"));
+    assert_eq!(Some("1234".to_string()), html("1234"));
+    assert_eq!(
+        Some("12&#8239;345.678&#8239;90".to_string()),
+        html("12345.67890")
+    );
+    assert_eq!(
+        Some("1.2&#8239;&plusmn;&#8239;0.1".to_string()),
+        html(r"1.2 \pm 0.1")
+    );
+    assert_eq!(None, html("1.2.3"));
+    assert_eq!(None, html("x"));
+    assert_eq!(None, html(""));
+    assert_eq!("1&#8239;234&#8239;567", group("1234567", true, "&#8239;"));
+    assert_eq!("123&#8239;456&#8239;7", group("1234567", false, "&#8239;"));
+}
+
+#[test]
+fn test_units() {
+    let options = UnitOptions::new();
+    let html = |latex| units(latex, &HTML).map(|u| format_units(&u, &options, &HTML));
+    assert_eq!(
+        Some("m&#8239;s<sup>&minus;2</sup>".to_string()),
+        html(r"\meter\per\second\squared")
+    );
+    assert_eq!(
+        Some("km<sup>2</sup>".to_string()),
+        html(r"\square\kilo\metre")
+    );
+    assert_eq!(Some("&micro;m".to_string()), html(r"\um"));
+    assert_eq!(
+        Some("kg&#8239;m<sup>&minus;3</sup>".to_string()),
+        html("kg/m^3")
+    );
+    assert_eq!(
+        Some("m&#8239;s<sup>&minus;1</sup>".to_string()),
+        html("m.s^{-1}")
+    );
+    assert_eq!(None, html(r"\kilo"));
+    assert_eq!(None, html(r"\bogus"));
+    let options = options.per_mode(PerMode::Symbol);
+    let html = |latex| units(latex, &HTML).map(|u| format_units(&u, &options, &HTML));
+    assert_eq!(
+        Some("J/(mol&#8239;K)".to_string()),
+        html(r"\joule\per\mole\per\kelvin")
+    );
+    assert_eq!(Some("1/s".to_string()), html(r"\per\second"));
+}
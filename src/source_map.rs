@@ -0,0 +1,201 @@
+//! Tracking where rewritten LaTeX came from.
+
+use std::ops::Range;
+
+/// Maps byte offsets in rewritten LaTeX back to the LaTeX it was
+/// rewritten from.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct OffsetMap {
+    pieces: Vec<Piece>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Piece {
+    /// Where this piece starts in the rewritten text.
+    start: usize,
+    /// Where this piece came from in the original text.
+    original: usize,
+    /// How much of the original text this piece replaced, if it was
+    /// inserted rather than copied.
+    replaced: Option<usize>,
+}
+
+impl OffsetMap {
+    /// The range in the original text that the byte at `offset` came
+    /// from, and whether it was copied from there.
+    fn lookup(&self, offset: usize) -> (Range<usize>, bool) {
+        let i = self.pieces.partition_point(|p| p.start <= offset);
+        if i == 0 {
+            return (offset..offset + 1, true);
+        }
+        let p = self.pieces[i - 1];
+        if let Some(replaced) = p.replaced {
+            (p.original..p.original + replaced, false)
+        } else {
+            let original = p.original + (offset - p.start);
+            (original..original + 1, true)
+        }
+    }
+
+    /// Where the piece containing `offset` ends, if it does.
+    fn piece_end(&self, offset: usize) -> Option<usize> {
+        let i = self.pieces.partition_point(|p| p.start <= offset);
+        self.pieces.get(i).map(|p| p.start)
+    }
+
+    /// The offset in the original text of the byte at `offset`.
+    pub(crate) fn original(&self, offset: usize) -> usize {
+        self.lookup(offset).0.start
+    }
+
+    /// Map a range in the rewritten text back to the original text.
+    pub(crate) fn original_range(&self, range: Range<usize>) -> Range<usize> {
+        let start = self.original(range.start);
+        if range.end > range.start {
+            start..self.lookup(range.end - 1).0.end.max(start)
+        } else {
+            start..start
+        }
+    }
+}
+
+/// LaTeX that has been rewritten, along with where it came from.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Rewritten {
+    pub(crate) text: String,
+    pub(crate) map: OffsetMap,
+}
+
+impl Rewritten {
+    /// Text that has not been rewritten at all.
+    pub(crate) fn new(text: &str) -> Self {
+        let mut r = Rewritten::with_capacity(text.len());
+        r.copy(text, 0);
+        r
+    }
+
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        Rewritten {
+            text: String::with_capacity(capacity),
+            map: OffsetMap::default(),
+        }
+    }
+
+    /// Append text copied from `original` in the original text.
+    pub(crate) fn copy(&mut self, text: &str, original: usize) {
+        if text.is_empty() {
+            return;
+        }
+        self.map.pieces.push(Piece {
+            start: self.text.len(),
+            original,
+            replaced: None,
+        });
+        self.text.push_str(text);
+    }
+
+    /// Append new text, which replaces `original` in the original text.
+    pub(crate) fn insert(&mut self, text: &str, original: Range<usize>) {
+        if text.is_empty() {
+            return;
+        }
+        self.map.pieces.push(Piece {
+            start: self.text.len(),
+            original: original.start,
+            replaced: Some(original.end - original.start),
+        });
+        self.text.push_str(text);
+    }
+
+    /// The text of the last piece, if it was inserted in place of
+    /// original text that ends at `original`.
+    pub(crate) fn inserted_before(&self, original: usize) -> Option<&str> {
+        let p = self.map.pieces.last()?;
+        match p.replaced {
+            Some(replaced) if p.original + replaced == original => Some(&self.text[p.start..]),
+            _ => None,
+        }
+    }
+
+    /// Remove the last piece if it was inserted, returning its text and
+    /// where the original text it replaced starts.
+    pub(crate) fn pop_insertion(&mut self) -> Option<(String, usize)> {
+        let p = *self.map.pieces.last()?;
+        p.replaced?;
+        self.map.pieces.pop();
+        Some((self.text.split_off(p.start), p.original))
+    }
+
+    /// Rewrite this text further, keeping track of where it all came
+    /// from.
+    pub(crate) fn then(self, rewrite: impl FnOnce(&str) -> Rewritten) -> Rewritten {
+        let next = rewrite(&self.text);
+        let mut map = OffsetMap::default();
+        let ends = next.map.pieces.iter().skip(1).map(|p| p.start);
+        let ends = ends.chain(Some(next.text.len()));
+        for (p, end) in next.map.pieces.iter().zip(ends) {
+            if let Some(replaced) = p.replaced {
+                let original = self.map.original_range(p.original..p.original + replaced);
+                map.pieces.push(Piece {
+                    start: p.start,
+                    original: original.start,
+                    replaced: Some(original.end - original.start),
+                });
+                continue;
+            }
+            let mut middle = p.original;
+            let middle_end = p.original + (end - p.start);
+            while middle < middle_end {
+                let (original, copied) = self.map.lookup(middle);
+                map.pieces.push(Piece {
+                    start: p.start + (middle - p.original),
+                    original: original.start,
+                    replaced: if copied { None } else { Some(original.len()) },
+                });
+                middle = self
+                    .map
+                    .piece_end(middle)
+                    .map_or(middle_end, |e| e.min(middle_end));
+            }
+        }
+        Rewritten {
+            text: next.text,
+            map,
+        }
+    }
+}
+
+#[test]
+fn test_offset_map() {
+    let mut r = Rewritten::default();
+    r.copy("hello", 10);
+    r.insert("XX", 20..25);
+    r.copy("world", 30);
+    assert_eq!("helloXXworld", r.text);
+    assert_eq!(10, r.map.original(0));
+    assert_eq!(14, r.map.original(4));
+    assert_eq!(20, r.map.original(6));
+    assert_eq!(31, r.map.original(8));
+    assert_eq!(11..15, r.map.original_range(1..5));
+    assert_eq!(30..35, r.map.original_range(7..12));
+    assert_eq!(13..25, r.map.original_range(3..7));
+
+    assert_eq!(None, r.inserted_before(25));
+    let mut joined = r.clone();
+    joined.insert("YY", 35..36);
+    assert_eq!(Some("YY"), joined.inserted_before(36));
+    assert_eq!(Some(("YY".to_string(), 35)), joined.pop_insertion());
+    assert_eq!("helloXXworld", joined.text);
+    assert_eq!(None, joined.pop_insertion());
+
+    let r = r.then(|text| {
+        let mut s = Rewritten::default();
+        s.copy(&text[3..9], 3);
+        s
+    });
+    assert_eq!("loXXwo", r.text);
+    assert_eq!(13, r.map.original(0));
+    assert_eq!(20, r.map.original(3));
+    assert_eq!(31, r.map.original(5));
+    assert_eq!(13..25, r.map.original_range(0..4));
+}
@@ -0,0 +1,621 @@
+//! Speaking math in English, so that screen readers can read it without
+//! MathJax, in the style of ClearSpeak when brief and of MathSpeak when
+//! verbose.
+
+use crate::math::{self, Math, MathError};
+use crate::{MathStyle, Speech};
+
+/// The words for symbols, which are otherwise spoken as they are.
+const SYMBOLS: &[(&str, &str)] = &[
+    ("+", "plus"),
+    ("\u{2212}", "minus"),
+    ("=", "equals"),
+    ("<", "is less than"),
+    (">", "is greater than"),
+    ("\u{2264}", "is less than or equal to"),
+    ("\u{2265}", "is greater than or equal to"),
+    ("\u{2260}", "is not equal to"),
+    ("\u{226a}", "is much less than"),
+    ("\u{226b}", "is much greater than"),
+    ("\u{2248}", "is approximately equal to"),
+    ("\u{2261}", "is equivalent to"),
+    ("\u{223c}", "is similar to"),
+    ("\u{2245}", "is congruent to"),
+    ("\u{221d}", "is proportional to"),
+    ("\u{b1}", "plus or minus"),
+    ("\u{2213}", "minus or plus"),
+    ("\u{d7}", "times"),
+    ("\u{22c5}", "times"),
+    ("\u{f7}", "divided by"),
+    ("/", "divided by"),
+    ("\u{2217}", "star"),
+    ("\u{2218}", "composed with"),
+    ("!", "factorial"),
+    ("\u{2208}", "is an element of"),
+    ("\u{2209}", "is not an element of"),
+    ("\u{2282}", "is a subset of"),
+    ("\u{2286}", "is a subset of or equal to"),
+    ("\u{2283}", "is a superset of"),
+    ("\u{2287}", "is a superset of or equal to"),
+    ("\u{222a}", "union"),
+    ("\u{2229}", "intersection"),
+    ("\u{2216}", "minus"),
+    ("\u{2200}", "for all"),
+    ("\u{2203}", "there exists"),
+    ("\u{2227}", "and"),
+    ("\u{2228}", "or"),
+    ("\u{ac}", "not"),
+    ("\u{2223}", "divides"),
+    ("\u{2225}", "is parallel to"),
+    ("\u{22a5}", "is perpendicular to"),
+    ("\u{2192}", "right arrow"),
+    ("\u{2190}", "left arrow"),
+    ("\u{2194}", "left right arrow"),
+    ("\u{21d2}", "implies"),
+    ("\u{27f9}", "implies"),
+    ("\u{21d4}", "if and only if"),
+    ("\u{27fa}", "if and only if"),
+    ("\u{21a6}", "maps to"),
+    ("\u{2026}", "dot dot dot"),
+    ("\u{22ef}", "dot dot dot"),
+    ("\u{22ee}", "vertical dots"),
+    ("\u{22f1}", "diagonal dots"),
+    ("\u{221e}", "infinity"),
+    ("\u{2202}", "partial"),
+    ("\u{2207}", "nabla"),
+    ("\u{210f}", "h bar"),
+    ("\u{2113}", "ell"),
+    ("\u{2205}", "the empty set"),
+    ("\u{2032}", "prime"),
+    ("\u{2032}\u{2032}", "double prime"),
+    ("\u{2032}\u{2032}\u{2032}", "triple prime"),
+    ("|", "vertical bar"),
+    ("\u{2016}", "double vertical bar"),
+    ("\u{2211}", "the sum"),
+    ("\u{220f}", "the product"),
+    ("\u{222b}", "the integral"),
+    ("\u{222c}", "the double integral"),
+    ("\u{222d}", "the triple integral"),
+    ("\u{222e}", "the contour integral"),
+    ("\u{22c3}", "the union"),
+    ("\u{22c2}", "the intersection"),
+];
+
+/// The words for delimiters, brief and verbose, when they open and when
+/// they close.
+const DELIMITERS: &[(&str, [&str; 2], [&str; 2])] = &[
+    (
+        "(",
+        ["open paren", "close paren"],
+        ["left parenthesis", "right parenthesis"],
+    ),
+    (
+        ")",
+        ["open paren", "close paren"],
+        ["left parenthesis", "right parenthesis"],
+    ),
+    (
+        "[",
+        ["open bracket", "close bracket"],
+        ["left bracket", "right bracket"],
+    ),
+    (
+        "]",
+        ["open bracket", "close bracket"],
+        ["left bracket", "right bracket"],
+    ),
+    (
+        "{",
+        ["open brace", "close brace"],
+        ["left brace", "right brace"],
+    ),
+    (
+        "}",
+        ["open brace", "close brace"],
+        ["left brace", "right brace"],
+    ),
+    (
+        "\u{27e8}",
+        ["open angle bracket", "close angle bracket"],
+        ["left angle bracket", "right angle bracket"],
+    ),
+    (
+        "\u{27e9}",
+        ["open angle bracket", "close angle bracket"],
+        ["left angle bracket", "right angle bracket"],
+    ),
+];
+
+/// Delimiters that mean something, with what they mean.
+const FENCES: &[(&str, &str, &str)] = &[
+    ("|", "|", "absolute value"),
+    ("\u{2016}", "\u{2016}", "norm"),
+    ("\u{230a}", "\u{230b}", "floor"),
+    ("\u{2308}", "\u{2309}", "ceiling"),
+];
+
+/// The words for functions, which are otherwise spoken as they are
+/// written.
+const FUNCTIONS: &[(&str, &str)] = &[
+    ("sin", "sine"),
+    ("cos", "cosine"),
+    ("tan", "tangent"),
+    ("cot", "cotangent"),
+    ("sec", "secant"),
+    ("csc", "cosecant"),
+    ("arcsin", "arc sine"),
+    ("arccos", "arc cosine"),
+    ("arctan", "arc tangent"),
+    ("sinh", "hyperbolic sine"),
+    ("cosh", "hyperbolic cosine"),
+    ("tanh", "hyperbolic tangent"),
+    ("coth", "hyperbolic cotangent"),
+    ("ln", "natural log"),
+    ("lg", "log"),
+    ("exp", "exponential"),
+    ("lim", "the limit"),
+    ("liminf", "the limit inferior"),
+    ("limsup", "the limit superior"),
+    ("max", "the maximum"),
+    ("min", "the minimum"),
+    ("sup", "the supremum"),
+    ("inf", "the infimum"),
+    ("det", "the determinant"),
+    ("dim", "the dimension"),
+    ("ker", "the kernel"),
+    ("deg", "the degree"),
+    ("gcd", "the greatest common divisor"),
+    ("Pr", "the probability"),
+];
+
+/// The words for accents, and whether they go before what they are on.
+const ACCENTS: &[(&str, &str, bool)] = &[
+    ("^", "hat", false),
+    ("\u{af}", "bar", false),
+    ("_", "underline", false),
+    ("\u{2192}", "vector", true),
+    ("\u{2190}", "left arrow", false),
+    ("\u{2d9}", "dot", false),
+    ("\u{a8}", "double dot", false),
+    ("\u{2dc}", "tilde", false),
+    ("\u{2c7}", "check", false),
+    ("\u{2d8}", "breve", false),
+    ("\u{b4}", "acute", false),
+    ("`", "grave", false),
+    ("\u{2da}", "ring", false),
+    ("\u{23de}", "overbrace", false),
+    ("\u{23df}", "underbrace", false),
+];
+
+fn lookup<T: Copy>(table: &[(&str, T)], name: &str) -> Option<T> {
+    table.iter().find(|(n, _)| *n == name).map(|&(_, t)| t)
+}
+
+/// The English for the math `latex`, nesting no more than `max_depth`
+/// deep, or why we could not parse it.
+pub(crate) fn speech(
+    latex: &str,
+    style: &MathStyle,
+    verbosity: Speech,
+    max_depth: usize,
+) -> Result<String, MathError> {
+    let latex = latex.replace(crate::LATEX_DBAR, r"{\mathit{\unicode{273}}}");
+    let environment = match style {
+        MathStyle::Environment(name) => Some(name.as_str()),
+        _ => None,
+    };
+    let math = math::parse(&latex, environment, max_depth)?;
+    let speaker = Speaker {
+        verbose: verbosity == Speech::Verbose,
+    };
+    let mut out = String::new();
+    match &math {
+        // The rows of an alignment are equations, one after another.
+        Math::Table { rows, .. } => {
+            for row in rows {
+                for cell in row {
+                    speaker.speak(&mut out, cell);
+                }
+                punctuate(&mut out, ";");
+            }
+            out.truncate(out.trim_end_matches(';').len());
+        }
+        math => speaker.speak(&mut out, math),
+    }
+    Ok(out)
+}
+
+/// Add `words` to what we have said.
+fn say(out: &mut String, words: &str) {
+    if words.is_empty() {
+        return;
+    }
+    if !out.is_empty() && !out.ends_with(' ') {
+        out.push(' ');
+    }
+    out.push_str(words);
+}
+
+/// Add punctuation, which goes straight after what we have said.
+fn punctuate(out: &mut String, mark: &str) {
+    if !out.is_empty() {
+        out.push_str(mark);
+    }
+}
+
+/// Whether `math` is a single thing, which need not be set off.
+fn is_simple(math: &Math) -> bool {
+    match math {
+        Math::Identifier(_) | Math::Number(_) | Math::Text(_) => true,
+        Math::Row(items) => items.len() == 1 && is_simple(&items[0]),
+        Math::Variant { content, .. } => is_simple(content),
+        _ => false,
+    }
+}
+
+struct Speaker {
+    verbose: bool,
+}
+
+impl Speaker {
+    /// The English for `math` on its own.
+    fn spoken(&self, math: &Math) -> String {
+        let mut out = String::new();
+        self.speak(&mut out, math);
+        out
+    }
+
+    fn delimiter(&self, delimiter: &str, open: bool) -> String {
+        match DELIMITERS.iter().find(|(d, ..)| *d == delimiter) {
+            Some((_, brief, verbose)) => {
+                let words = if self.verbose { verbose } else { brief };
+                words[if open { 0 } else { 1 }].to_string()
+            }
+            None => symbol(delimiter),
+        }
+    }
+
+    fn speak(&self, out: &mut String, math: &Math) {
+        match math {
+            Math::Identifier(name) | Math::Operator(name) | Math::LargeOperator(name) => {
+                match name.as_str() {
+                    "," | ";" | ":" | "." => punctuate(out, name),
+                    "(" | "[" | "\u{27e8}" => say(out, &self.delimiter(name, true)),
+                    ")" | "]" | "\u{27e9}" => say(out, &self.delimiter(name, false)),
+                    name => say(out, &symbol(name)),
+                }
+            }
+            Math::Function(name) => say(out, lookup(FUNCTIONS, name).unwrap_or(name)),
+            Math::Number(text) | Math::Text(text) => say(out, text),
+            Math::Space(_) => (),
+            Math::Row(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    // A minus is a negative sign when there is nothing
+                    // before it for it to act on.
+                    let unary = match i.checked_sub(1).map(|i| &items[i]) {
+                        None => true,
+                        Some(Math::Operator(before)) => !matches!(before.as_str(), ")" | "]" | "|"),
+                        Some(_) => false,
+                    };
+                    match item {
+                        Math::Operator(op) if unary && op == "\u{2212}" => say(out, "negative"),
+                        item => self.speak(out, item),
+                    }
+                }
+            }
+            Math::Fraction {
+                numerator,
+                denominator,
+                line: false,
+            } => {
+                say(out, &self.spoken(numerator));
+                say(out, "choose");
+                say(out, &self.spoken(denominator));
+            }
+            Math::Fraction {
+                numerator,
+                denominator,
+                ..
+            } => {
+                let simple = is_simple(numerator) && is_simple(denominator);
+                let (numerator, denominator) = (self.spoken(numerator), self.spoken(denominator));
+                if self.verbose {
+                    say(out, "start fraction");
+                    say(out, &numerator);
+                    say(out, "over");
+                    say(out, &denominator);
+                    say(out, "end fraction");
+                } else if simple {
+                    say(out, &numerator);
+                    say(out, "over");
+                    say(out, &denominator);
+                } else {
+                    say(out, "the fraction with numerator");
+                    say(out, &numerator);
+                    punctuate(out, ",");
+                    say(out, "and denominator");
+                    say(out, &denominator);
+                    punctuate(out, ",");
+                }
+            }
+            Math::Root { radicand, index } => {
+                let radicand = self.spoken(radicand);
+                if self.verbose {
+                    if let Some(index) = index {
+                        say(out, "root index");
+                        say(out, &self.spoken(index));
+                    }
+                    say(out, "start root");
+                    say(out, &radicand);
+                    say(out, "end root");
+                } else {
+                    match index.as_deref() {
+                        None => say(out, "the square root of"),
+                        Some(Math::Number(three)) if three == "3" => say(out, "the cube root of"),
+                        Some(index) if is_simple(index) => {
+                            say(out, &format!("the {}th root of", self.spoken(index)))
+                        }
+                        Some(index) => {
+                            say(out, "the root with index");
+                            say(out, &self.spoken(index));
+                            punctuate(out, ",");
+                            say(out, "of");
+                        }
+                    }
+                    say(out, &radicand);
+                }
+            }
+            Math::Scripts {
+                base,
+                sub,
+                sup,
+                limits,
+            } => self.scripts(out, base, sub.as_deref(), sup.as_deref(), *limits),
+            Math::Accent { base, mark, .. } => {
+                let (name, before) = ACCENTS
+                    .iter()
+                    .find(|(m, ..)| m == mark)
+                    .map_or(("accent", false), |&(_, name, before)| (name, before));
+                let base = self.spoken(base);
+                if self.verbose {
+                    say(out, &base);
+                    say(out, "modified with");
+                    say(out, name);
+                } else if before {
+                    say(out, name);
+                    say(out, &base);
+                } else {
+                    say(out, &base);
+                    say(out, name);
+                }
+            }
+            Math::Fenced {
+                open,
+                close,
+                content,
+            } => match &**content {
+                Math::Table { rows, .. } => self.matrix(out, open, close, rows),
+                content => {
+                    let fence = FENCES.iter().find(|(o, c, _)| o == open && c == close);
+                    match fence {
+                        Some((.., name)) if !self.verbose => {
+                            say(out, &format!("the {} of", name));
+                            self.speak(out, content);
+                        }
+                        Some((.., name)) => {
+                            say(out, &format!("start {}", name));
+                            self.speak(out, content);
+                            say(out, &format!("end {}", name));
+                        }
+                        None => {
+                            if !open.is_empty() {
+                                say(out, &self.delimiter(open, true));
+                            }
+                            self.speak(out, content);
+                            if !close.is_empty() {
+                                say(out, &self.delimiter(close, false));
+                            }
+                        }
+                    }
+                }
+            },
+            Math::Variant { variant, content } => {
+                match *variant {
+                    "normal" | "italic" => (),
+                    "bold-italic" => say(out, "bold italic"),
+                    variant => say(out, variant),
+                }
+                self.speak(out, content);
+            }
+            Math::Table { rows, .. } => self.matrix(out, "", "", rows),
+        }
+    }
+
+    /// Speak `base` with its scripts.
+    fn scripts(
+        &self,
+        out: &mut String,
+        base: &Math,
+        sub: Option<&Math>,
+        sup: Option<&Math>,
+        limits: bool,
+    ) {
+        self.speak(out, base);
+        let large = matches!(base, Math::LargeOperator(_));
+        if self.verbose {
+            let (under, over, end) = if limits {
+                ("underscript", "overscript", "endscripts")
+            } else {
+                ("subscript", "superscript", "baseline")
+            };
+            if let Some(sub) = sub {
+                say(out, under);
+                self.speak(out, sub);
+            }
+            if let Some(sup) = sup {
+                say(out, over);
+                self.speak(out, sup);
+            }
+            say(out, end);
+        } else if large || (limits && matches!(base, Math::Function(_))) {
+            if let Some(sub) = sub {
+                let sub = self.spoken(sub);
+                match (base, sup) {
+                    (Math::Function(_), None) => {
+                        say(out, "as");
+                        say(out, &sub.replace("right arrow", "approaches"));
+                    }
+                    (_, None) => {
+                        say(out, "over");
+                        say(out, &sub);
+                    }
+                    _ => {
+                        say(out, "from");
+                        say(out, &sub);
+                    }
+                }
+            }
+            if let Some(sup) = sup {
+                say(out, "to");
+                self.speak(out, sup);
+            }
+            say(out, "of");
+        } else {
+            if let Some(sub) = sub {
+                say(out, "sub");
+                self.speak(out, sub);
+            }
+            match sup {
+                Some(Math::Number(two)) if two == "2" => say(out, "squared"),
+                Some(Math::Number(three)) if three == "3" => say(out, "cubed"),
+                Some(Math::Operator(circ)) if circ == "\u{2218}" => say(out, "degrees"),
+                Some(Math::Operator(primes)) if primes.starts_with('\u{2032}') => {
+                    self.speak(out, &Math::Operator(primes.clone()))
+                }
+                Some(sup) if is_simple(sup) => {
+                    say(out, "to the");
+                    self.speak(out, sup);
+                }
+                Some(sup) => {
+                    say(out, "to the");
+                    self.speak(out, sup);
+                    say(out, "power");
+                }
+                None => (),
+            }
+        }
+    }
+
+    /// Speak a matrix, or cases, row by row.
+    fn matrix(&self, out: &mut String, open: &str, close: &str, rows: &[Vec<Math>]) {
+        let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+        let name = match (open, close) {
+            ("{", "") | ("", "}") => "cases".to_string(),
+            ("|", "|") => format!(
+                "the determinant of the {} by {} matrix",
+                rows.len(),
+                columns
+            ),
+            ("", "") => format!("the {} by {} array", rows.len(), columns),
+            _ => format!("the {} by {} matrix", rows.len(), columns),
+        };
+        if self.verbose {
+            say(out, &name.replacen("the ", "start ", 1));
+        } else {
+            say(out, &name);
+        }
+        punctuate(out, ":");
+        for (i, row) in rows.iter().enumerate() {
+            if i > 0 {
+                punctuate(out, ";");
+            }
+            say(out, &format!("row {}", i + 1));
+            punctuate(out, ":");
+            for (j, cell) in row.iter().enumerate() {
+                if j > 0 {
+                    punctuate(out, ",");
+                }
+                self.speak(out, cell);
+            }
+        }
+        if self.verbose {
+            say(out, "end");
+            say(out, name.rsplit(' ').next().unwrap_or_default());
+        }
+    }
+}
+
+/// The words for `symbol`, such as a Greek letter or an operator.
+fn symbol(symbol: &str) -> String {
+    if let Some(words) = lookup(SYMBOLS, symbol) {
+        return words.to_string();
+    }
+    match math::greek_name(symbol) {
+        Some(name) => {
+            let name = name.trim_start_matches("var");
+            match name.chars().next() {
+                Some(c) if c.is_ascii_uppercase() => {
+                    format!("capital {}", name.to_ascii_lowercase())
+                }
+                _ => name.to_string(),
+            }
+        }
+        None => symbol.to_string(),
+    }
+}
+
+#[test]
+fn test_speech() {
+    let brief = |latex| speech(latex, &MathStyle::Inline, Speech::Brief, 64).unwrap();
+    let verbose = |latex| speech(latex, &MathStyle::Inline, Speech::Verbose, 64).unwrap();
+    assert_eq!("x squared plus 1 over 2", brief(r"x^2 + \frac{1}{2}"));
+    assert_eq!(
+        "x superscript 2 baseline plus start fraction 1 over 2 end fraction",
+        verbose(r"x^2 + \frac{1}{2}")
+    );
+    assert_eq!(
+        "the fraction with numerator a plus b, and denominator c,",
+        brief(r"\frac{a+b}{c}")
+    );
+    assert_eq!("negative capital delta x", brief(r"-\Delta x"));
+    assert_eq!("e to the i pi power", brief(r"e^{i\pi}"));
+    assert_eq!("x sub 0 to the n", brief(r"x_0^n"));
+    assert_eq!("f prime open paren x close paren", brief(r"f'(x)"));
+    assert_eq!(
+        "the sum from i equals 1 to n of i",
+        brief(r"\sum_{i=1}^n i")
+    );
+    assert_eq!(
+        "the limit as x approaches 0 of sine x",
+        brief(r"\lim_{x\to 0} \sin x")
+    );
+    assert_eq!("the square root of 2", brief(r"\sqrt2"));
+    assert_eq!(
+        "root index 3 start root x end root",
+        verbose(r"\sqrt[3]{x}")
+    );
+    assert_eq!("the absolute value of x", brief(r"\left|x\right|"));
+    assert_eq!(
+        "start absolute value x end absolute value",
+        verbose(r"\left|x\right|")
+    );
+    assert_eq!("vector v", brief(r"\vec{v}"));
+    assert_eq!("bold v", brief(r"\mathbf{v}"));
+    assert_eq!(
+        "the 2 by 2 matrix: row 1: 1, 0; row 2: 0, 1",
+        brief(r"\begin{pmatrix}1&0\\0&1\end{pmatrix}")
+    );
+    assert_eq!(
+        "a equals b; c equals d",
+        speech(
+            r"a &= b \\ c &= d",
+            &MathStyle::Environment("align*".to_string()),
+            Speech::Brief,
+            64
+        )
+        .unwrap()
+    );
+    let deep = format!("{}x{}", r"\frac{".repeat(5_000), "}".repeat(5_000));
+    let error = speech(&deep, &MathStyle::Inline, Speech::Verbose, 64).unwrap_err();
+    assert_eq!("max-depth", error.code);
+}
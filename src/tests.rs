@@ -40,6 +40,275 @@ bar
     );
 }
 
+#[test]
+fn test_physics_macros_are_known() {
+    let checked = check_latex(r"$\braket{a}{b} + \dv[2]{f}{x} + \vb*{a}$");
+    assert!(!checked.contains("bad macro"), "{}", checked);
+}
+
+#[test]
+fn test_physics_braket() {
+    expect![[r#"
+        $\left\langle {a}\middle|{b}\right\rangle $
+
+        $\left\langle {\psi}\middle|{\psi}\right\rangle $
+
+        $\langle {a}|{b}\rangle $"#]]
+    .assert_eq(&physics_macros(
+        r"$\braket{a}{b}$
+
+$\braket{\psi}$
+
+$\braket*{a}{b}$",
+    ));
+}
+
+#[test]
+fn test_physics_ketbra() {
+    expect![[r#"
+        $\left|{a}\right\rangle\!\left\langle {b}\right|$
+
+        $\left|{\psi}\right\rangle\!\left\langle {\psi}\right|$"#]]
+    .assert_eq(&physics_macros(
+        r"$\ketbra{a}{b}$
+
+$\ketbra{\psi}$",
+    ));
+}
+
+#[test]
+fn test_physics_expval() {
+    expect![[r#"
+        $\left\langle {A}\right\rangle $
+
+        $\left\langle {\psi}\middle|{A}\middle|{\psi}\right\rangle $"#]]
+    .assert_eq(&physics_macros(
+        r"$\expval{A}$
+
+$\expval{A}{\psi}$",
+    ));
+}
+
+#[test]
+fn test_physics_mel() {
+    expect![[r#"$\left\langle {n}\middle|{H}\middle|{m}\right\rangle $"#]]
+        .assert_eq(&physics_macros(r"$\mel{n}{H}{m}$"));
+}
+
+#[test]
+fn test_physics_abs() {
+    expect![[r#"
+        $\left\lvert {x}\right\rvert $
+
+        $\lvert {x}\rvert $"#]]
+    .assert_eq(&physics_macros(
+        r"$\abs{x}$
+
+$\abs*{x}$",
+    ));
+}
+
+#[test]
+fn test_physics_norm() {
+    expect![[r#"
+        $\left\lVert {\vec v}\right\rVert $
+
+        $\lVert {v}\rVert $"#]]
+    .assert_eq(&physics_macros(
+        r"$\norm{\vec v}$
+
+$\norm*{v}$",
+    ));
+}
+
+#[test]
+fn test_physics_dv() {
+    expect![[r#"
+        $\frac{\mathrm{d}}{\mathrm{d}{x}}$
+
+        $\frac{\mathrm{d}{f}}{\mathrm{d}{x}}$
+
+        $\frac{\mathrm{d}^{2}{f}}{\mathrm{d}{x}^{2}}$
+
+        $\frac{\mathrm{d}^{n}}{\mathrm{d}{t}^{n}}$
+
+        $\frac{\mathrm{d}{f}}{\mathrm{d}{x}} = 0$"#]]
+    .assert_eq(&physics_macros(
+        r"$\dv{x}$
+
+$\dv{f}{x}$
+
+$\dv[2]{f}{x}$
+
+$\dv[n]{t}$
+
+$\dv{f}{x} = 0$",
+    ));
+}
+
+#[test]
+fn test_physics_pdv() {
+    expect![[r#"
+        $\frac{\partial}{\partial {x}}$
+
+        $\frac{\partial {f}}{\partial {x}}$
+
+        $\frac{\partial^{2} {f}}{\partial {x}^{2}}$
+
+        $\frac{\partial^{2} {f}}{\partial {x}\,\partial {y}}$
+
+        $\frac{\partial {f}}{\partial {x}} y$"#]]
+    .assert_eq(&physics_macros(
+        r"$\pdv{x}$
+
+$\pdv{f}{x}$
+
+$\pdv[2]{f}{x}$
+
+$\pdv{f}{x}{y}$
+
+$\pdv{f}{x} y$",
+    ));
+}
+
+#[test]
+fn test_physics_vb() {
+    expect![[r#"
+        $\mathbf{a}$
+
+        $\boldsymbol{\omega}$"#]]
+    .assert_eq(&physics_macros(
+        r"$\vb{a}$
+
+$\vb*{\omega}$",
+    ));
+}
+
+#[test]
+fn test_physics_vu() {
+    expect![[r#"
+        $\hat{\mathbf{r}}$
+
+        $\hat{\boldsymbol{\theta}}$"#]]
+    .assert_eq(&physics_macros(
+        r"$\vu{r}$
+
+$\vu*{\theta}$",
+    ));
+}
+
+#[test]
+fn test_physics_comm() {
+    expect![[r#"$\left[{A},{B}\right]$"#]].assert_eq(&physics_macros(r"$\comm{A}{B}$"));
+}
+
+#[test]
+fn test_physics_acomm() {
+    expect![[r#"$\left\{{A},{B}\right\}$"#]].assert_eq(&physics_macros(r"$\acomm{A}{B}$"));
+}
+
+#[test]
+fn test_physics_qty() {
+    expect![[r#"
+        $\left({\frac{a}{b}}\right)$
+
+        $\left[{f(x)}\right]$
+
+        $\left\{{1, 2}\right\}$
+
+        $\left\lvert {x}\right\rvert $
+
+        $\left({a (b) c}\right)$"#]]
+    .assert_eq(&physics_macros(
+        r"$\qty(\frac{a}{b})$
+
+$\qty[f(x)]$
+
+$\qty{1, 2}$
+
+$\qty|x|$
+
+$\qty(a (b) c)$",
+    ));
+}
+
+#[test]
+fn test_physics_order() {
+    expect![[r#"
+        $\mathcal{O}\left({x^2}\right)$
+
+        $\mathcal{O}\left({h}\right)$"#]]
+    .assert_eq(&physics_macros(
+        r"$\order{x^2}$
+
+$\order(h)$",
+    ));
+}
+
+#[test]
+fn test_physics_tr() {
+    expect![[r#"
+        $\operatorname{tr}\rho$
+
+        $\operatorname{Tr}{\rho}$"#]]
+    .assert_eq(&physics_macros(
+        r"$\tr \rho$
+
+$\Tr{\rho}$",
+    ));
+}
+
+#[test]
+fn test_physics_eval() {
+    expect![[r#"
+        $\left.{x^2}\right|_0^1$
+
+        $\left.\left({f(x)}\right)\right|_0^1$"#]]
+    .assert_eq(&physics_macros(
+        r"$\eval{x^2}_0^1$
+
+$\eval(f(x))|_0^1$",
+    ));
+}
+
+#[test]
+fn macro_table() {
+    let table = MacroTable::new()
+        .define(r"\vect", 1, r"\mathbf{#1}")
+        .define_with_default(r"\unit", 1, "m", r"\,\mathrm{#1}")
+        .define(r"\R", 0, r"\mathbb{R}")
+        .define("nobackslash", 0, "x")
+        .define(r"\many", 10, "x");
+    assert!(table.contains(r"\vect"));
+    assert!(!table.contains("nobackslash"));
+    assert!(!table.contains(r"\many"));
+    assert_eq!(
+        r"$\mathbf{\mathbb{R}} 3\,\mathrm{m}+2\,\mathrm{s}$",
+        table.expand(r"$\vect{\R} 3\unit+2\unit[s]$")
+    );
+    // Arguments that are missing are left for the parser to report.
+    assert_eq!(r"$x$ \vect", table.expand(r"$x$ \vect"));
+
+    let house = MacroTable::new().define(r"\ket", 1, r"|#1\rangle");
+    let options = RenderOptions::new().physics_macros(true).macros(house);
+    let rendered = render(r"$\bra{0}\ket{1}$", &options).unwrap();
+    assert_eq!(r"\(\left\langle {0}\right||1\rangle\)", rendered.html);
+    // A snippet's own definitions win over the table.
+    let latex = r"$\abs{a}$\newcommand{\abs}[1]{|#1| \vb{v}}$\abs{b}$";
+    let rendered = render(latex, &RenderOptions::new().physics_macros(true)).unwrap();
+    assert_eq!(
+        r"\(\left\lvert {a}\right\rvert \)\(|b| \mathbf{v}\)",
+        rendered.html
+    );
+    assert!(rendered.diagnostics.is_empty());
+
+    let joined = MacroTable::new()
+        .define(r"\l", 0, "(a")
+        .define(r"\r", 0, "b)")
+        .join("a", "b", "-");
+    assert_eq!("(-)(a{}b)", joined.expand(r"\l\r \l{}\r"));
+}
+
 #[test]
 fn test_with_image_directory() {
     assert_eq!(
@@ -1026,7 +1295,10 @@ extra & good
 
 #[test]
 fn test_ref() {
-    assert_eq!(r" foo \ref{foo} bar", &html_string(r" foo \ref{foo} bar"));
+    let rendered = render(r" foo \ref{foo} bar", &RenderOptions::new()).unwrap();
+    assert_eq!(r#" foo <span class="error">??</span> bar"#, rendered.html);
+    let codes: Vec<_> = rendered.diagnostics.iter().map(|d| d.code).collect();
+    assert_eq!(vec!["undefined-label"], codes);
 }
 
 #[test]
@@ -1054,3 +1326,1328 @@ contents
         )
     );
 }
+
+#[test]
+fn parse_tree() {
+    expect![[r#"
+        Document {
+            content: [
+                Node {
+                    kind: Section {
+                        level: 1,
+                        starred: false,
+                        short_title: None,
+                        title: [
+                            Node {
+                                kind: Text(
+                                    "Fruit",
+                                ),
+                                span: 9..14,
+                            },
+                        ],
+                        content: [
+                            Node {
+                                kind: Text(
+                                    "\n",
+                                ),
+                                span: 15..16,
+                            },
+                            Node {
+                                kind: Environment {
+                                    name: "itemize",
+                                    args: [],
+                                    content: [
+                                        Node {
+                                            kind: Item {
+                                                label: None,
+                                                content: [
+                                                    Node {
+                                                        kind: Text(
+                                                            "Apples ",
+                                                        ),
+                                                        span: 38..45,
+                                                    },
+                                                    Node {
+                                                        kind: Macro {
+                                                            name: "textbf",
+                                                            args: [
+                                                                Latex(
+                                                                    [
+                                                                        Node {
+                                                                            kind: Text(
+                                                                                "red",
+                                                                            ),
+                                                                            span: 53..56,
+                                                                        },
+                                                                    ],
+                                                                ),
+                                                            ],
+                                                        },
+                                                        span: 45..57,
+                                                    },
+                                                    Node {
+                                                        kind: Text(
+                                                            "\n",
+                                                        ),
+                                                        span: 57..58,
+                                                    },
+                                                ],
+                                            },
+                                            span: 38..58,
+                                        },
+                                    ],
+                                },
+                                span: 16..71,
+                            },
+                            Node {
+                                kind: Text(
+                                    "\n",
+                                ),
+                                span: 71..72,
+                            },
+                            Node {
+                                kind: Error(
+                                    "\\bogus",
+                                ),
+                                span: 72..78,
+                            },
+                        ],
+                    },
+                    span: 0..78,
+                },
+            ],
+        }
+    "#]]
+    .assert_debug_eq(&parse(
+        r"\section{Fruit}
+\begin{itemize}
+\item Apples \textbf{red}
+\end{itemize}
+\bogus",
+    ));
+}
+
+#[test]
+fn diagnostics() {
+    let codes = |latex: &str| -> Vec<(&'static str, String)> {
+        parse_with_diagnostics(latex)
+            .1
+            .into_iter()
+            .map(|d| (d.code, latex[d.span].to_string()))
+            .collect()
+    };
+    assert_eq!(
+        vec![("unclosed-environment", r"\begin{center}".to_string())],
+        codes(r"Hello \begin{center} world")
+    );
+    assert_eq!(
+        vec![("unknown-macro", r"\bogus".to_string())],
+        codes(r"Hello \bogus world")
+    );
+    assert_eq!(
+        vec![("unclosed-environment", r"\begin{itemize}".to_string())],
+        codes(
+            r"\begin{itemize}
+\item Apples"
+        )
+    );
+    assert_eq!(
+        vec![("unterminated-math", "$".to_string())],
+        codes(r"It costs $5")
+    );
+    assert_eq!(
+        vec![
+            ("non-ascii", "é".to_string()),
+            ("unexpected-end", r"\end{center}".to_string()),
+        ],
+        codes(r"café \end{center}")
+    );
+    assert_eq!(Vec::<(&str, String)>::new(), codes(r"$x$ and \emph{y}"));
+}
+
+#[test]
+fn diagnostics_after_comments() {
+    let latex = "% a comment\nsome text % another comment\n\\bogus{}\n";
+    let (html, diagnostics) = html_with_diagnostics(latex);
+    assert!(html.contains(r#"<span class="error">\bogus</span>"#));
+    assert_eq!(1, diagnostics.len());
+    assert_eq!("unknown-macro", diagnostics[0].code);
+    assert_eq!(Severity::Error, diagnostics[0].severity);
+    assert_eq!(r"\bogus", &latex[diagnostics[0].span.clone()]);
+}
+
+#[test]
+fn diagnostics_in_pulled_out_sections() {
+    let latex = r"\begin{solution}
+\section{Part}
+\bogus
+\end{solution}";
+    let (_, diagnostics) = parse_with_diagnostics(latex);
+    assert_eq!(1, diagnostics.len());
+    assert_eq!(r"\bogus", &latex[diagnostics[0].span.clone()]);
+}
+
+#[test]
+fn source_positions() {
+    let latex = r"\section{Fruit}
+% a comment \emph{here}
+Apples are \emph{red}.
+
+\begin{solution}
+\subsection{Why}
+Because \textbf{reasons}.
+\end{solution}";
+    let html = html_string_with_source_positions(latex);
+    expect![[r#"
+        <section data-src-start="0" data-src-end="138"><h2>Fruit</h2>
+        Apples are <em data-src-start="51" data-src-end="61">red</em>.
+
+        <section data-src-start="81" data-src-end="138"><h3>Why</h3><blockquote data-src-start="97" data-src-end="138" class="solution">
+        Because <b data-src-start="106" data-src-end="122">reasons</b>.
+        </blockquote></section></section>"#]]
+    .assert_eq(&html);
+    assert_eq!(r"\emph{red}", &latex[51..61]);
+    assert_eq!(r"\textbf{reasons}", &latex[106..122]);
+}
+
+#[test]
+fn source_positions_match_html() {
+    let latex = r"Hello \emph{world} $x$ and \bogus and
+\begin{itemize}
+\item one
+\end{itemize}";
+    let stripped = regex::Regex::new(r#" data-src-(start|end)="\d+""#)
+        .unwrap()
+        .replace_all(&html_string_with_source_positions(latex), "")
+        .to_string();
+    assert_eq!(html_string(latex), stripped);
+}
+
+#[test]
+fn source_positions_through_physics_macros() {
+    let latex = r"\emph{$\ket{0}$} then \textbf{\bra{1}}";
+    let mut budget = Expansions::new(&RenderLimits::new());
+    let mapped = source_map::Rewritten::new(latex)
+        .then(|latex| MacroTable::physics().expand_mapped(latex, &mut budget));
+    let (document, _) = parse::parse_rewritten(mapped, &mut budget);
+    let spans: Vec<&str> = document
+        .content
+        .iter()
+        .map(|node| &latex[node.span.clone()])
+        .collect();
+    assert_eq!(
+        vec![r"\emph{$\ket{0}$}", " then ", r"\textbf{\bra{1}}"],
+        spans
+    );
+}
+
+#[test]
+fn render_options() {
+    let latex = r"\section{Q}
+$\ket{0}$ \includegraphics[width=2cm]{a.png}
+\begin{solution}S\end{solution}
+\begin{handout}H\end{handout}";
+    let rendered = |options: &RenderOptions| render(latex, options).unwrap().html;
+    assert_eq!(html_string(latex), rendered(&RenderOptions::new()));
+    expect![[r#"
+        <section><h2>Q</h2>
+        \(\left|{0}\right\rangle \) <img style="width:2cm" src="figs&#x2f;a.png"/>
+
+        <blockquote class="handout">H</blockquote></section>"#]]
+    .assert_eq(&rendered(
+        &RenderOptions::new()
+            .physics_macros(true)
+            .solutions(Visibility::Hide)
+            .image_directory("figs/"),
+    ));
+    expect![[r#"<section><h4>Q</h4>
+\(\ket{0}\) <img style="width:2cm" src="a.png"/>
+<blockquote class="solution">S</blockquote>
+</section>"#]]
+    .assert_eq(&rendered(
+        &RenderOptions::new()
+            .heading_level(4)
+            .handouts(Visibility::Hide),
+    ));
+    assert_eq!("H", rendered(&RenderOptions::new().handouts(Visibility::Only)));
+    assert_eq!(
+        "<h6>a</h6>",
+        render(
+            r"\paragraph{a}",
+            &RenderOptions::new().heading_level(3)
+        )
+        .unwrap()
+        .html
+    );
+}
+
+#[test]
+fn render_error_policy() {
+    let options = RenderOptions::new().errors(ErrorPolicy::Fail);
+    assert!(render(r"\emph{fine}", &options).is_ok());
+    match render(r"\bogus", &options) {
+        Err(Error::Invalid(diagnostics)) => assert_eq!("unknown-macro", diagnostics[0].code),
+        other => panic!("expected an error, not {:?}", other),
+    }
+    let rendered = render(r"\bogus", &RenderOptions::new()).unwrap();
+    assert_eq!(r#"<span class="error">\bogus</span>"#, rendered.html);
+    assert_eq!(1, rendered.diagnostics.len());
+
+    // Errors found while writing the HTML count too.
+    let deep = format!("${}x{}$", "{".repeat(100), "}".repeat(100));
+    let options = options.math(MathOutput::MathMl);
+    match render(&deep, &options) {
+        Err(Error::Invalid(diagnostics)) => assert_eq!("max-depth", diagnostics[0].code),
+        other => panic!("expected an error, not {:?}", other),
+    }
+}
+
+#[test]
+fn c_render() {
+    let latex = std::ffi::CString::new(r"\emph{a}\bogus").unwrap();
+    let html = unsafe { std::ffi::CStr::from_ptr(latex_render(latex.as_ptr(), std::ptr::null())) };
+    assert_eq!(
+        r#"<em>a</em><span class="error">\bogus</span>"#,
+        html.to_str().unwrap()
+    );
+    let options = CRenderOptions {
+        physics_macros: false,
+        solutions: 1,
+        guides: 0,
+        handouts: 0,
+        image_directory: std::ptr::null(),
+        heading_level: 2,
+        fail_on_errors: true,
+        source_positions: false,
+        max_depth: 0,
+        max_output_bytes: 0,
+        max_expansions: 0,
+        macros: std::ptr::null(),
+        decimal_marker: 0,
+        ungrouped_digits: false,
+        exponent: 0,
+        per_mode: 0,
+        math: 0,
+        number_equations: false,
+        speech: 0,
+        number_sections: false,
+        heading_ids: false,
+    };
+    assert!(unsafe { latex_render(latex.as_ptr(), &options) }.is_null());
+}
+
+#[test]
+fn c_render_full() {
+    let string = |s: *const std::os::raw::c_char| unsafe { std::ffi::CStr::from_ptr(s) }.to_str();
+    let latex = std::ffi::CString::new(r"\section{A}$\R$ \num{1.5e3}\bogus").unwrap();
+    let macros = std::ffi::CString::new(r"\newcommand{\R}{\mathbb{R}}").unwrap();
+    let mut options = CRenderOptions {
+        physics_macros: false,
+        solutions: 0,
+        guides: 0,
+        handouts: 0,
+        image_directory: std::ptr::null(),
+        heading_level: 0,
+        fail_on_errors: false,
+        source_positions: false,
+        max_depth: 0,
+        max_output_bytes: 0,
+        max_expansions: 0,
+        macros: macros.as_ptr(),
+        decimal_marker: ',' as u32,
+        ungrouped_digits: false,
+        exponent: 1,
+        per_mode: 0,
+        math: 1,
+        number_equations: false,
+        speech: 0,
+        number_sections: true,
+        heading_ids: true,
+    };
+    let raw = unsafe { latex_render_full(latex.as_ptr(), &options) };
+    let rendered = unsafe { &*raw };
+    expect![[r#"<section><h2 id="a"><span class="section-number">1</span> A</h2><math><mi mathvariant="double-struck">R</mi></math> 1,5&#8239;&middot;&#8239;10<sup>3</sup><span class="error">\bogus</span></section>"#]]
+        .assert_eq(string(rendered.html).unwrap());
+    expect![[r##"<nav class="toc"><ul><li><a href="#a"><span class="section-number">1</span> A</a></li></ul></nav>"##]]
+        .assert_eq(string(rendered.table_of_contents).unwrap());
+    assert_eq!(1, rendered.diagnostic_count);
+    let diagnostic = unsafe { &*rendered.diagnostics };
+    assert_eq!(Ok("unknown-macro"), string(diagnostic.code));
+    assert_eq!(1, diagnostic.severity);
+    assert_eq!(
+        r"\bogus",
+        &latex.to_str().unwrap()[diagnostic.start..diagnostic.end]
+    );
+    unsafe { latex_rendered_free(raw) };
+
+    options.fail_on_errors = true;
+    let rendered = unsafe { latex_render_full(latex.as_ptr(), &options) };
+    assert!(unsafe { &*rendered }.html.is_null());
+    assert_eq!(1, unsafe { &*rendered }.diagnostic_count);
+    unsafe { latex_rendered_free(rendered) };
+    assert!(unsafe { latex_render_full(std::ptr::null(), &options) }.is_null());
+}
+
+/// A snippet with `n` pieces of each kind, more than 2MB when `n` is
+/// 20,000.
+fn large_snippet(n: usize) -> String {
+    let mut latex = String::new();
+    latex.push_str("\\begin{verbatim}\n");
+    for i in 0..2 * n {
+        latex.push_str(&format!(
+            "line {} with <html> & \"quotes\" and ``more''\n",
+            i
+        ));
+    }
+    latex.push_str("\\end{verbatim}\n\n\\begin{tabular}{ccc}\n");
+    for i in 0..n {
+        latex.push_str(&format!("{} & $x^{}$ & \\emph{{cell}} \\\\\n", i, i));
+    }
+    latex.push_str("\\end{tabular}\n\n\\begin{itemize}\n");
+    for i in 0..n {
+        latex.push_str(&format!("\\item item {} with “unicode” text\n", i));
+    }
+    latex.push_str("\\end{itemize}\n\n");
+    for i in 0..n / 4 {
+        latex.push_str(&format!(
+            "\\begin{{center}}\nParagraph {} in a center.\n\\end{{center}}\n\n",
+            i
+        ));
+    }
+    latex
+}
+
+#[test]
+fn large_snippet_renders_in_linear_time() {
+    assert!(large_snippet(20_000).len() > 2_000_000);
+    let rendered = render_in_linear_time(large_snippet, &RenderOptions::new());
+    assert!(rendered.html.contains("line 39999 with &lt;html&gt;"));
+    assert!(rendered.html.contains("Paragraph 4999 in a center."));
+}
+
+/// Render what `latex` makes of 5,000 pieces and of 20,000, checking
+/// that the second takes not much more than four times as long, as it
+/// would if we looked through the rest of the input at each piece.
+/// Comparing the two, rather than timing one, keeps this from failing
+/// on a slow or busy machine.
+fn render_in_linear_time(latex: impl Fn(usize) -> String, options: &RenderOptions) -> Rendered {
+    let time = |latex: &str| {
+        let start = std::time::Instant::now();
+        let rendered = render(latex, options).unwrap();
+        (start.elapsed(), rendered)
+    };
+    let (small, large) = (latex(5_000), latex(20_000));
+    let small_time = time(&small).0.min(time(&small).0);
+    let (large_time, rendered) = time(&large);
+    assert!(
+        large_time < 8 * small_time + std::time::Duration::from_millis(100),
+        "rendering {} bytes took {:?}, but {} bytes took {:?}",
+        large.len(),
+        large_time,
+        small.len(),
+        small_time
+    );
+    rendered
+}
+
+#[test]
+fn unbalanced_input_renders_in_linear_time() {
+    for latex in [
+        (|n| "{".repeat(5 * n)) as fn(usize) -> String,
+        |n| r"\emph{".repeat(n),
+        |n| r"\begin{center}".repeat(n),
+        |n| r"\begin{itemize}\item{".repeat(n),
+        |n| r"\begin{".repeat(n),
+        |n| format!(r"\newcommand\x[1]{{#1}}{}", r"\x{".repeat(n)),
+        |n| r"\newcommand{\x}[".repeat(n),
+        |n| r"\newcommand{\x}[1][".repeat(n),
+        |n| r"\newenvironment{x}[".repeat(n),
+        |n| format!(r"{}x\end{{solution}}", r"\begin{solution}".repeat(n)),
+        |n| r"\hyperref[".repeat(n),
+        |n| r"\item[".repeat(n),
+        |n| format!(r"\begin{{description}}{}", r"\item[".repeat(n)),
+    ] {
+        let rendered = render_in_linear_time(latex, &RenderOptions::new());
+        assert!(rendered.html.contains(r#"<span class="error">"#));
+    }
+}
+
+#[test]
+fn long_input_renders_in_linear_time() {
+    for latex in [
+        (|n| r"\begin{solution}x\end{solution}".repeat(n)) as fn(usize) -> String,
+        |n| format!("${}$", r"\SI{".repeat(n)),
+        |n| r"\section{a}x".repeat(n),
+        |n| format!("${}$", r"\label{".repeat(n)),
+        |n| r"\includegraphics[".repeat(n),
+        |n| r"\section[".repeat(n),
+        |n| format!("${}$", r"\SI[".repeat(n)),
+        |n| format!("${}$", r"\SI(".repeat(n)),
+        |n| r"\SI[".repeat(n),
+        |n| r"\begin{tabular}{c}x\\[".repeat(n),
+    ] {
+        render_in_linear_time(latex, &RenderOptions::new());
+    }
+    let latex = |n| format!(r"\begin{{equation}}{}\end{{equation}}", r"\x{".repeat(n));
+    render_in_linear_time(latex, &RenderOptions::new().number_equations(true));
+    for latex in [
+        (|n| format!("${}$", r"\sqrt[".repeat(n))) as fn(usize) -> String,
+        |n| format!("${}$", r"\text{".repeat(n)),
+        |n| format!("${}$", r"x\\[".repeat(n)),
+    ] {
+        render_in_linear_time(latex, &RenderOptions::new().math(MathOutput::MathMl));
+    }
+}
+
+#[test]
+fn physics_macros_render_in_linear_time() {
+    for latex in [
+        (|n| format!("${}$", r"\qty(".repeat(n))) as fn(usize) -> String,
+        |n| format!("${}$", r"\eval(".repeat(n)),
+        |n| format!("${}$", r"\eval()".repeat(n)),
+        |n| format!("${}$", r"\dv[".repeat(n)),
+        |n| format!("${}$", r"\qty[".repeat(n)),
+        |n| format!("${}$", r"\qty|".repeat(n)),
+    ] {
+        render_in_linear_time(latex, &RenderOptions::new().physics_macros(true));
+    }
+}
+
+/// LaTeX-ish snippets, made of the pieces most likely to trip us up.
+fn latex_strategy() -> impl proptest::strategy::Strategy<Value = String> {
+    use proptest::prelude::*;
+    let piece = prop_oneof![
+        "[a-z ]{0,5}",
+        "\\PC{0,3}",
+        Just("\\".to_string()),
+        Just("{".to_string()),
+        Just("}".to_string()),
+        Just("[".to_string()),
+        Just("]".to_string()),
+        Just("$".to_string()),
+        Just("$$".to_string()),
+        Just("%".to_string()),
+        Just("&".to_string()),
+        Just("~".to_string()),
+        Just("\n\n".to_string()),
+        Just("é".to_string()),
+        Just("“".to_string()),
+        Just("``".to_string()),
+        Just("''".to_string()),
+        Just(r"\\".to_string()),
+        Just(r"\item".to_string()),
+        Just(r"\hline".to_string()),
+        Just(r"\verb".to_string()),
+        Just(r"\'".to_string()),
+        Just(r"\section".to_string()),
+        Just(r"\subsection".to_string()),
+        Just(r"\tableofcontents".to_string()),
+        Just(r"\section{\tableofcontents}".to_string()),
+        Just(r"\section[\tableofcontents]{".to_string()),
+        Just(r"\emph".to_string()),
+        Just(r"\textcolor".to_string()),
+        Just(r"\includegraphics[width=".to_string()),
+        Just(r"\href".to_string()),
+        Just(r"\ket".to_string()),
+        Just(r"\bra".to_string()),
+        Just(r"\myderiv".to_string()),
+        Just(r"\dbar ".to_string()),
+        Just(r"\dv".to_string()),
+        Just(r"\qty(".to_string()),
+        Just(r"\eval(".to_string()),
+        Just(")|".to_string()),
+        Just(r"\ce{".to_string()),
+        Just(r"\pu{".to_string()),
+        Just("->[".to_string()),
+        Just("^2-".to_string()),
+        Just(r"\frac".to_string()),
+        Just(r"\sqrt[".to_string()),
+        Just(r"\left(".to_string()),
+        Just(r"\right".to_string()),
+        Just("_".to_string()),
+        Just(r"\label{a}".to_string()),
+        Just(r"\eqref{a}".to_string()),
+        Just(r"\autoref{a}".to_string()),
+        Just(r"\cref{b}".to_string()),
+        Just(r"\pageref".to_string()),
+        Just(r"\hyperref[a]".to_string()),
+        Just(r"\caption{x}".to_string()),
+        Just(r"\tag".to_string()),
+        Just(r"\nonumber".to_string()),
+        Just(r"\newcommand{\x}".to_string()),
+        Just(r"\renewcommand\x".to_string()),
+        Just(r"\DeclareMathOperator".to_string()),
+        Just(r"\x".to_string()),
+        Just("[2]".to_string()),
+        Just("#1".to_string()),
+        Just(r"\newenvironment{x}".to_string()),
+        Just(r"\renewenvironment{x}".to_string()),
+        "\\\\(begin|end)\\{(itemize|enumerate|description|tabular|figure|wrapfigure|table|theorem|lemma|solution|guide|handout|center|verbatim|equation|align|alignat|gather|math|pmatrix|array|bogus|x)\\}",
+    ];
+    proptest::collection::vec(piece, 0..20).prop_map(|pieces| pieces.concat())
+}
+
+proptest::proptest! {
+    #[test]
+    fn never_panics(latex in latex_strategy()) {
+        html_string(&latex);
+        check_latex(&latex);
+        check_math(&latex);
+        physics_macros(&latex);
+        strip_comments(&latex);
+        pull_sections_out(&latex);
+        include_solutions(&latex);
+        omit_solutions(&latex);
+        omit_guide(&latex);
+        omit_handout(&latex);
+        only_handout(&latex);
+        with_image_directory(&latex, "figs/");
+        let options = RenderOptions::new()
+            .physics_macros(true)
+            .solutions(Visibility::Hide)
+            .image_directory("figs/");
+        render(&latex, &options).unwrap();
+        render(&latex, &options.clone().math(MathOutput::MathMl).number_equations(true)).unwrap();
+        render(&latex, &options.clone().math(MathOutput::Lite).speech(Speech::Brief)).unwrap();
+        render(&latex, &options.clone().speech(Speech::Verbose)).unwrap();
+        render(&latex, &options.number_sections(true).heading_ids(true)).unwrap();
+        html_string_with_source_positions(&latex);
+        braille(&latex, BrailleGrade::Contracted).brf();
+    }
+
+    #[test]
+    fn spans_lie_within_the_input(latex in latex_strategy()) {
+        let (document, diagnostics) = parse_with_diagnostics(&latex);
+        for d in &diagnostics {
+            proptest::prop_assert!(latex.get(d.span.clone()).is_some(), "{:?}", d);
+        }
+        let mut content = document.content;
+        for node in &mut content {
+            node.walk_mut(&mut |node| assert!(latex.get(node.span.clone()).is_some(), "{:?}", node));
+        }
+    }
+
+    #[test]
+    fn optional_arguments_are_found_in_one_pass(latex in latex_strategy()) {
+        let braces = parse::Braces::new(&latex);
+        for (i, _) in latex.match_indices('[') {
+            let rest = &latex[i..];
+            proptest::prop_assert_eq!(parse::optional_argument(rest), braces.optional_argument(rest));
+        }
+    }
+
+    #[test]
+    fn plain_text_is_escaped_and_nothing_more(text in "[a-zA-Z0-9 .,;:!?()<>\"'/]*") {
+        let text = format!("x{}x", text);
+        let html = html_string(&text);
+        let unescaped = html
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#x27;", "'")
+            .replace("&#x2f;", "/")
+            .replace("”", "''")
+            .replace("&amp;", "&");
+        proptest::prop_assert_eq!(text, unescaped);
+    }
+
+    #[test]
+    fn filters_leave_plain_latex_alone(text in "[^\\\\%]*") {
+        proptest::prop_assert_eq!(&text, &physics_macros(&text));
+        proptest::prop_assert_eq!(&text, &strip_comments(&text));
+        proptest::prop_assert_eq!(&text, &omit_solutions(&text));
+        proptest::prop_assert_eq!(&text, &include_solutions(&text));
+        proptest::prop_assert_eq!(&text, &with_image_directory(&text, "figs/"));
+    }
+}
+
+#[test]
+fn multibyte_after_macros() {
+    expect![[r#"<span class="error">\<span class="error">é</span></span>"#]]
+        .assert_eq(&html_string(r"\é"));
+    expect![[r#"<span class="error">\verb<span class="error">é</span>x<span class="error">é</span></span>"#]]
+        .assert_eq(&html_string(r"\verbéxé"));
+}
+
+#[test]
+fn render_limits() {
+    let deep = format!("{}x{}", "{".repeat(10_000), "}".repeat(10_000));
+    assert!(html_string(&deep).contains(r#"<span class="error">"#));
+    let deep = format!("{}x{}", r"\emph{".repeat(10_000), "}".repeat(10_000));
+    assert!(html_string(&deep).contains(r#"<span class="error">"#));
+    let deep = format!("{}x{}", r"\section{".repeat(10_000), "}".repeat(10_000));
+    assert!(html_string(&deep).contains(r#"<span class="error">"#));
+    let deep = format!(
+        "{}x{}",
+        r"\begin{itemize}\item ".repeat(1_000),
+        r"\end{itemize}".repeat(1_000)
+    );
+    assert!(html_string(&deep).contains(r#"<span class="error">"#));
+    let deep = format!("${}x{}$", r"\ket{".repeat(1_000), "}".repeat(1_000));
+    assert!(physics_macros(&deep).contains(r"\expansionlimit"));
+
+    let options = RenderOptions::new()
+        .physics_macros(true)
+        .limits(RenderLimits::new().max_expansions(2));
+    let rendered = render(r"$\ket{0}$ $\bra{1}$ and $\ket{2}$", &options).unwrap();
+    expect![[r#"\(\left|{0}\right\rangle \) \(\left\langle {1}\right|\) and <span class="error">$\expansionlimit{2}$</span>"#]]
+        .assert_eq(&rendered.html);
+    assert_eq!("expansion-limit", rendered.diagnostics[0].code);
+    assert_eq!(24..33, rendered.diagnostics[0].span);
+
+    let options = RenderOptions::new().limits(RenderLimits::new().max_output_bytes(10));
+    let rendered = render(r"\emph{a} \textbf{b} \section{c}", &options).unwrap();
+    expect![[r#"<em>a</em><span class="error">output too large</span>"#]]
+        .assert_eq(&rendered.html);
+    assert_eq!("output-too-large", rendered.diagnostics[0].code);
+    assert!(render(
+        r"\emph{a} \textbf{b}",
+        &options.clone().errors(ErrorPolicy::Fail)
+    )
+    .is_err());
+
+    // Macros that double what they expand to make a lot of LaTeX from
+    // few expansions.
+    let mut latex = r"\newcommand\a{xxxxxxxxxx}".to_string();
+    for (a, b) in ('a'..'m').zip('b'..) {
+        latex.push_str(&format!(r"\newcommand\{}{{\{}\{}}}", b, a, a));
+    }
+    latex.push_str(r"\m");
+    let options = RenderOptions::new().limits(RenderLimits::new().max_output_bytes(1000));
+    let rendered = render(&latex, &options).unwrap();
+    assert!(rendered.html.len() < 2000, "{}", rendered.html.len());
+    let codes: Vec<_> = rendered.diagnostics.iter().map(|d| d.code).collect();
+    assert!(codes.contains(&"expansion-limit"), "{:?}", codes);
+}
+
+#[test]
+fn newcommand() {
+    expect![[r#"
+        <p>We have \(\mathbb{R}^{3}\) and \(x_{i}\) or \(x_{j}\), and \(\operatorname{Tr}\rho\).
+
+        </p><p>So <em>very</em> nice.</p>"#]]
+    .assert_eq(&html_string(
+        r"\newcommand{\R}{\mathbb{R}}
+\newcommand\vect[2][x]{#1_{#2}}
+\DeclareMathOperator{\Tr}{Tr}
+We have $\R^{3}$ and $\vect{i}$ or $\vect[x]{j}$, and $\Tr\rho$.
+
+\newcommand{\stress}[1]{\emph{#1}}So \stress{very} nice.",
+    ));
+    let (html, diagnostics) = html_with_diagnostics(r"\newcommand{\a}{\b}\newcommand{\b}{b}$\a$");
+    assert_eq!(r"\(b\)", html);
+    assert!(diagnostics.is_empty());
+
+    let latex = r"\newcommand{\a}{A}\newcommand{\a}{B}\renewcommand{\c}{C}$\a$";
+    let (html, diagnostics) = html_with_diagnostics(latex);
+    expect![[r#"<span class="error">\newcommand{\a}{B}</span><span class="error">\renewcommand{\c}{C}</span>\(A\)"#]]
+        .assert_eq(&html);
+    let codes: Vec<_> = diagnostics.iter().map(|d| d.code).collect();
+    assert_eq!(vec!["redefined-macro", "undefined-macro"], codes);
+    assert_eq!(r"\newcommand{\a}{B}", &latex[diagnostics[0].span.clone()]);
+    assert_eq!(r"\(B\)", html_string(r"\newcommand{\a}{A}\renewcommand{\a}{B}$\a$"));
+
+    let (html, diagnostics) = html_with_diagnostics(r"\newcommand{\loop}{\loop}$\loop$");
+    assert!(html.contains(r#"<span class="error">"#));
+    assert_eq!("expansion-limit", diagnostics[0].code);
+    let (_, diagnostics) = html_with_diagnostics(r"\newcommand{\two}[2]{#1#2}\two{a}");
+    assert_eq!("missing-argument", diagnostics[0].code);
+    assert_eq!(26..33, diagnostics[0].span);
+
+    let checked = check_latex(r"\newcommand{\R}{\mathbb{R}}$\R$");
+    assert!(!checked.contains("newcommand}"), "{}", checked);
+    assert!(!checked.contains(r"\textbackslash{}R}"), "{}", checked);
+}
+
+#[test]
+fn newenvironment() {
+    expect![[r#"
+        <p><b>Hint:</b> Try <em>energy</em>.
+
+        </p><p><b>Box (a):</b> The answer. done and <b>Box (b):</b> x done.</p>"#]]
+    .assert_eq(&html_string(
+        r"\newenvironment{hint}{\textbf{Hint:} }{}
+\newenvironment{answerbox}[1]{\textbf{Box (#1):} }{ done}
+\begin{hint}Try \emph{energy}.\end{hint}
+
+\begin{answerbox}{a}The answer.\end{answerbox} and \begin{answerbox}{b}x\end{answerbox}.",
+    ));
+    assert_eq!(
+        "[[(a)]]",
+        html_string(
+            r"\newenvironment{outer}{[}{]}\newenvironment{inner}{(}{)}\begin{outer}[\begin{inner}a\end{inner}]\end{outer}"
+        )
+    );
+
+    let latex = r"\newenvironment{hint}{H}{}\newenvironment{hint}{I}{}\renewenvironment{tip}{T}{}\begin{hint}x\end{hint}";
+    let (html, diagnostics) = html_with_diagnostics(latex);
+    expect![[r#"<span class="error">\newenvironment{hint}{I}{}</span><span class="error">\renewenvironment{tip}{T}{}</span>Hx"#]]
+        .assert_eq(&html);
+    let codes: Vec<_> = diagnostics.iter().map(|d| d.code).collect();
+    assert_eq!(vec!["redefined-environment", "undefined-environment"], codes);
+    assert_eq!(r"\newenvironment{hint}{I}{}", &latex[diagnostics[0].span.clone()]);
+
+    let (_, diagnostics) = html_with_diagnostics(r"\newenvironment{\hint}{H}{}");
+    assert_eq!("missing-argument", diagnostics[0].code);
+    let (_, diagnostics) = html_with_diagnostics(r"\newenvironment{hint}{H}");
+    assert_eq!("missing-argument", diagnostics[0].code);
+    let latex = r"\newenvironment{box}[1]{#1}{}\begin{box}";
+    let (html, diagnostics) = html_with_diagnostics(latex);
+    assert_eq!(r#"<span class="error">\begin{box}</span>"#, html);
+    assert_eq!("missing-argument", diagnostics[0].code);
+    assert_eq!(r"\begin{box}", &latex[diagnostics[0].span.clone()]);
+    let latex = r"\newenvironment{hint}{H}{}\begin{hint}x";
+    let (_, diagnostics) = html_with_diagnostics(latex);
+    assert_eq!("unclosed-environment", diagnostics[0].code);
+    assert_eq!(r"\begin{hint}", &latex[diagnostics[0].span.clone()]);
+    let (_, diagnostics) = html_with_diagnostics(r"\newenvironment{hint}{H}{}x\end{hint}");
+    let codes: Vec<_> = diagnostics.iter().map(|d| d.code).collect();
+    assert_eq!(vec!["unexpected-end"], codes);
+
+    let checked = check_latex(r"\newenvironment{hint}{H}{}\begin{hint}x\end{hint}");
+    assert!(!checked.contains("bad"), "{}", checked);
+}
+
+#[test]
+fn siunitx() {
+    expect![[r#"
+        <p>The speed is 3.00&#8239;&times;&#8239;10<sup>8</sup>&nbsp;m&#8239;s<sup>&minus;1</sup>, or 299&#8239;792&#8239;458&nbsp;m&#8239;s<sup>&minus;1</sup>.
+
+        </p><p>It weighs 1.2&#8239;&plusmn;&#8239;0.1&nbsp;kg at 25&nbsp;&deg;C and 30&deg;15&prime;10&Prime; with 90&deg;.
+
+        </p><p>Units of kg&#8239;m<sup>2</sup>&#8239;s<sup>&minus;2</sup> and &micro;m.</p>"#]]
+    .assert_eq(&html_string(
+        r"The speed is \SI{3.00e8}{\metre\per\second}, or \qty[mode=text]{299792458}{m/s}.
+
+It weighs \SI{1.2 \pm 0.1}{\kilo\gram} at \SI{25}{\degreeCelsius} and \ang{30;15;10} with \SI{90}{\degree}.
+
+Units of \si{\kilogram\square\metre\per\second\squared} and \unit{\um}.",
+    ));
+    expect![[r#"\(E = {1.60\times 10^{-19}\,\mathrm{J}}\) \(v={3\,\mathrm{m}\,\mathrm{s}^{-1}}\)"#]]
+        .assert_eq(&html_string(r"$E = \SI{1.60e-19}{\joule}$ $v=\qty{3}{m.s^{-1}}$"));
+
+    let units = UnitOptions::new()
+        .decimal_marker(',')
+        .group_digits(false)
+        .exponent(ExponentStyle::Letter)
+        .per_mode(PerMode::Symbol);
+    let options = RenderOptions::new().units(units);
+    let rendered = render(r"\SI{12345.6e-3}{\joule\per\mole\per\kelvin}", &options).unwrap();
+    assert_eq!("12345,6e&minus;3&nbsp;J/(mol&#8239;K)", rendered.html);
+    let rendered = render(r"$\num{0,5}$", &options).unwrap();
+    assert_eq!(r"\({0{,}5}\)", rendered.html);
+
+    let latex = r"\SI{fast}{\metre} \si{\bogus} \num{1}";
+    let (html, diagnostics) = html_with_diagnostics(latex);
+    assert_eq!(
+        r#"<span class="error">\SI{fast}{\metre}</span> <span class="error">\si{\bogus}</span> 1"#,
+        html
+    );
+    let codes: Vec<_> = diagnostics.iter().map(|d| d.code).collect();
+    assert_eq!(vec!["invalid-quantity", "invalid-quantity"], codes);
+    assert_eq!(r"\SI{fast}{\metre}", &latex[diagnostics[0].span.clone()]);
+    let (_, diagnostics) = html_with_diagnostics(r"\num{1");
+    assert_eq!("unbalanced-brace", diagnostics[0].code);
+
+    let checked = check_latex(r"\SI{3}{\kilo\metre\per\second} and \num{2}");
+    assert!(!checked.contains("warning"), "{}", checked);
+    let checked = check_latex(r"\kilo");
+    assert!(checked.contains("kilo"), "{}", checked);
+}
+
+#[test]
+fn mhchem() {
+    expect![[r#"H<sub>2</sub>SO<sub>4</sub> &rarr; 2H<sup>+</sup> + SO<sub>4</sub><sup>2&minus;</sup> and <sup>235</sup><sub>92</sub>U and CaCO<sub>3</sub>(s) <span class="arrow">&rarr;<sup>heat</sup></span> CaO(s) + CO<sub>2</sub> &uarr; or N<sub>2</sub> + 3H<sub>2</sub> &rlhar; 2NH<sub>3</sub> with &minus;92&nbsp;kJ&#8239;mol<sup>&minus;1</sup>"#]]
+    .assert_eq(&html_string(
+        r"\ce{H2SO4 -> 2H+ + SO4^2-} and \ce{^{235}_{92}U} and \ce{CaCO3(s) ->[heat] CaO(s) + CO2 ^} or \ce{N2 + 3H2 <=> 2NH3} with \pu{-92 kJ mol-1}",
+    ));
+    expect![[r#"\({\mathrm{Ag}^{+} + \mathrm{Cl}^{-} \longrightarrow \mathrm{AgCl} \downarrow}\) \[{\mathrm{CuSO}_{4}\cdot \mathrm{5H}_{2}\mathrm{O}} \quad {9.81\,\mathrm{m}\,\mathrm{s}^{-2}}\]"#]]
+    .assert_eq(&html_string(
+        r"$\ce{Ag+ + Cl- -> AgCl v}$ \[\ce{CuSO4*5H2O} \quad \pu{9.81 m s-2}\]",
+    ));
+
+    let latex = r"\ce{H2O!} \pu{3 (kJ)} \ce{NaCl}";
+    let (html, diagnostics) = html_with_diagnostics(latex);
+    assert_eq!(
+        r#"<span class="error">\ce{H2O!}</span> <span class="error">\pu{3 (kJ)}</span> NaCl"#,
+        html
+    );
+    let codes: Vec<_> = diagnostics.iter().map(|d| d.code).collect();
+    assert_eq!(vec!["invalid-chemistry", "invalid-chemistry"], codes);
+    assert_eq!(r"\ce{H2O!}", &latex[diagnostics[0].span.clone()]);
+
+    let checked = check_latex(r"\ce{H2SO4 -> 2H+ + SO4^2-} at \pu{298 K}");
+    assert!(!checked.contains("warning"), "{}", checked);
+
+    // A charge may come before the state.
+    expect!["H<sup>+</sup>(aq)"].assert_eq(&html_string(r"\ce{H+(aq)}"));
+    expect!["Na<sup>+</sup>(aq) + Cl<sup>&minus;</sup>(aq)"]
+        .assert_eq(&html_string(r"\ce{Na+(aq) + Cl-(aq)}"));
+    expect!["H<sub>2</sub>O(l) &rlhar; H<sup>+</sup>(aq) + OH<sup>&minus;</sup>(aq)"]
+        .assert_eq(&html_string(r"\ce{H2O(l) <=> H+(aq) + OH-(aq)}"));
+    expect!["OH<sup>&minus;</sup>(aq)"].assert_eq(&html_string(r"\ce{OH-(aq)}"));
+    expect![[r#"\({\mathrm{OH}^{-}\mathrm{(aq)}}\)"#]].assert_eq(&html_string(r"$\ce{OH-(aq)}$"));
+}
+
+#[test]
+fn math_defines_its_own_units_and_chemistry() {
+    // MathJax expands these definitions, so we leave their uses alone.
+    let latex = r"$\def\ce#1{C(#1)} \ce{a} \pu{1 m}$ \[\let\SI\relax \SI{1}{\meter} \num{2}\]";
+    expect![[r#"\(\def\ce#1{C(#1)} \ce{a} {1\,\mathrm{m}}\) \[\let\SI\relax \SI{1}{\meter} {2}\]"#]]
+        .assert_eq(&html_string(latex));
+}
+
+#[test]
+fn text_accents() {
+    expect![[r#"Dvo&#345;&aacute;k, G&ouml;del, &#321;ukasiewicz, Erd&#337;s, Ho&#287;a, Gau&szlig;, &#346;wi&#281;ty, Fran&ccedil;ois, Andr&eacute;, &Aring;ngstr&ouml;m, na&iuml;ve, &oslash;re, &aelig;ther, &oelig;uvre, Pe&ntilde;a"#]]
+    .assert_eq(&html_string(
+        r#"Dvo\v{r}\'ak, G\"odel, \L ukasiewicz, Erd\H{o}s, Ho\u{g}a, Gau\ss, \'Swi\k{e}ty, Fran\c cois, Andr\'{e}, \r{A}ngstr\"om, na\"{\i}ve, \o re, \ae ther, \oe uvre, Pe\~na"#,
+    ));
+    expect!["&#257; &#7779; &#7687; &#380; o&#865;o &#496; x q&#769;"]
+        .assert_eq(&html_string(r"\=a \d{s} \b b \.z \t{oo} \v\j x \'q"));
+
+    let latex = r"\'{ab} \v1 \t{o} \'";
+    let (html, diagnostics) = html_with_diagnostics(latex);
+    assert_eq!(
+        r#"<span class="error">\&#x27;{ab}</span> <span class="error">\v1</span> <span class="error">\t{o}</span> <span class="error">\&#x27;</span>"#,
+        html
+    );
+    let codes: Vec<_> = diagnostics.iter().map(|d| d.code).collect();
+    assert_eq!(
+        vec!["invalid-accent", "invalid-accent", "invalid-accent", "missing-argument"],
+        codes
+    );
+
+    let checked = check_latex(r#"Dvo\v{r}\'ak, G\"odel, \L ukasiewicz and \c{c} \ss"#);
+    assert!(!checked.contains("warning"), "{}", checked);
+}
+
+#[test]
+fn text_standalone_accents() {
+    let (html, diagnostics) = html_with_diagnostics(r"x\^{}2, \~{}user and \'{} \d{}");
+    expect!["x^2, ~user and &acute; &nbsp;&#803;"].assert_eq(&html);
+    assert_eq!(Vec::<Diagnostic>::new(), diagnostics);
+    let braille = braille(r"x\^{}2 \~{}", BrailleGrade::Uncontracted);
+    expect!["⠭⠈⠢⠼⠃⠀⠈⠔"].assert_eq(&braille.braille);
+    assert_eq!(Vec::<Diagnostic>::new(), braille.diagnostics);
+}
+
+#[test]
+fn text_symbols() {
+    expect![[r#"See &sect;3 and &para;2&dagger;&Dagger;. &copy;2024 Acme&reg; Widget&trade; at 20&deg; &plusmn;1 &micro;m, 3&times;4, costs &euro;5 or &pound;4&hellip; Typeset with LaTeX, not TeX."#]]
+    .assert_eq(&html_string(
+        r"See \S 3 and \P 2\dag\ddag. \copyright 2024 Acme\textregistered{} Widget\texttrademark{} at 20\textdegree{} \textpm 1 \textmu m, 3\texttimes 4, costs \euro 5 or \pounds 4\textellipsis{} Typeset with \LaTeX, not \TeX.",
+    ));
+    expect![[r#"&ldquo;quoted&rdquo; &ndash; &laquo;cit&eacute;&raquo; &lt;tag&gt;"#]].assert_eq(&html_string(
+        r"\textquotedblleft quoted\textquotedblright{} \textendash{} \guillemotleft cit\'e\guillemotright{} \textless tag\textgreater",
+    ));
+
+    let checked = check_latex(r"\S 3 \copyright{} \LaTeX\ and \euro 5");
+    assert!(!checked.contains("warning"), "{}", checked);
+    let checked = check_latex(r"\textnotasymbol");
+    assert!(checked.contains("textnotasymbol"), "{}", checked);
+}
+
+#[test]
+fn mathml() {
+    let options = RenderOptions::new().math(MathOutput::MathMl);
+    let mathml = |latex| render(latex, &options).unwrap().html;
+    expect!["<math><mfrac><mi>a</mi><mn>2</mn></mfrac><mo>+</mo><mroot><msubsup><mi>x</mi><mn>1</mn><mn>2</mn></msubsup><mn>3</mn></mroot><mo>&#8804;</mo><msup><mi>&#945;</mi><mo>&#8242;</mo></msup></math>"]
+    .assert_eq(&mathml(r"$\frac{a}{2} + \sqrt[3]{x_1^2} \leq \alpha'$"));
+    expect![[r#"<math display="block"><munderover><mo largeop="true">&#8721;</mo><mrow><mi>n</mi><mo>=</mo><mn>0</mn></mrow><mi>&#8734;</mi></munderover><mfrac><mn>1</mn><mrow><mi>n</mi><mo>!</mo></mrow></mfrac><mo>=</mo><mi>e</mi><mo>,</mo><mspace width="1em"/><munder><mi>lim</mi><mrow><mi>x</mi><mo>&#8594;</mo><mn>0</mn></mrow></munder><mo>&#8289;</mo><mi>sin</mi><mo>&#8289;</mo><mi>x</mi></math>"#]]
+    .assert_eq(&mathml(r"\[\sum_{n=0}^\infty \frac{1}{n!} = e, \quad \lim_{x\to 0} \sin x\]"));
+    expect![[r#"<math display="block"><mtable columnalign="right left"><mtr><mtd><mi>a</mi></mtd><mtd><mo>=</mo><mrow><mo fence="true" stretchy="true">(</mo><mtable columnalign="center"><mtr><mtd><mn>1</mn></mtd><mtd><mn>2</mn></mtd></mtr><mtr><mtd><mn>3</mn></mtd><mtd><mn>4</mn></mtd></mtr></mtable><mo fence="true" stretchy="true">)</mo></mrow></mtd></mtr><mtr><mtd><mover accent="true"><mi>b</mi><mo stretchy="true">^</mo></mover></mtd><mtd><mo>=</mo><mrow><mo fence="true" stretchy="true">(</mo><mover accent="true"><mi>v</mi><mo stretchy="true">&#8594;</mo></mover><mo fence="true" stretchy="true">)</mo></mrow></mtd></mtr></mtable></math>"#]]
+    .assert_eq(&mathml(
+        r"\begin{align}a &= \begin{pmatrix}1 & 2\\3 & 4\end{pmatrix} \label{eq:a}\\ \hat{b} &= \left(\vec{v}\right)\end{align}",
+    ));
+    expect![[r#"<math><mtext>if </mtext><mi mathvariant="double-struck">R</mi><mo>&#8715;</mo><mrow><mo fence="true" stretchy="true">(</mo><mfrac linethickness="0"><mi>n</mi><mi>k</mi></mfrac><mo fence="true" stretchy="true">)</mo></mrow></math>"#]]
+    .assert_eq(&mathml(r"$\text{if } \mathbb{R} \ni \binom{n}{k}$"));
+
+    let rendered = render(r"Here $\foo{x}$ and $y$", &options).unwrap();
+    expect![[r#"Here \(\foo{x}\) and <math><mi>y</mi></math>"#]].assert_eq(&rendered.html);
+    assert_eq!(1, rendered.diagnostics.len());
+    assert_eq!("unsupported-math", rendered.diagnostics[0].code);
+    assert_eq!(Severity::Warning, rendered.diagnostics[0].severity);
+    assert_eq!(5..14, rendered.diagnostics[0].span);
+
+    let options = options.physics_macros(true);
+    expect![[r#"<math><mrow><mo fence="true" stretchy="true">|</mo><mi>&#968;</mi><mo fence="true" stretchy="true">&#10217;</mo></mrow></math> and <math><mrow><mn>3</mn><mspace width="0.167em"/><mi mathvariant="normal">m</mi></mrow></math>"#]]
+    .assert_eq(&render(r"$\ket{\psi}$ and $\SI{3}{\metre}$", &options).unwrap().html);
+}
+
+#[test]
+fn deep_math() {
+    let braces = format!("${}x{}$", "{".repeat(5_000), "}".repeat(5_000));
+    let roots = format!("${}x{}$", r"\sqrt{".repeat(5_000), "}".repeat(5_000));
+    let options = RenderOptions::new().math(MathOutput::MathMl);
+    for latex in [&braces, &roots] {
+        let rendered = render(latex, &options).unwrap();
+        assert!(rendered.html.starts_with(r"\("));
+        assert_eq!("max-depth", rendered.diagnostics[0].code);
+        assert_eq!(Severity::Error, rendered.diagnostics[0].severity);
+    }
+    // The lite renderer leaves anything so deep to MathJax.
+    let options = RenderOptions::new().math(MathOutput::Lite);
+    for latex in [&braces, &roots] {
+        assert!(render(latex, &options).unwrap().html.starts_with(r"\("));
+    }
+    // So does speech, with a warning.
+    for speech in [Speech::Brief, Speech::Verbose] {
+        let rendered = render(&braces, &RenderOptions::new().speech(speech)).unwrap();
+        assert!(rendered.html.starts_with(r"\("));
+        assert_eq!("unspoken-math", rendered.diagnostics[0].code);
+    }
+    // Braille leaves it as LaTeX.
+    let transcribed = braille(&braces, BrailleGrade::Contracted);
+    assert_eq!("max-depth", transcribed.diagnostics[0].code);
+    assert_eq!(Severity::Error, transcribed.diagnostics[0].severity);
+}
+
+#[test]
+fn math_checks() {
+    let latex = r"Let $\alpha = \frac{1}{2$ and
+\begin{equation}
+  a & b \left( \foo
+\end{equation}
+and \begin{align}x &= \begin{pmatrix} 1 & 2 \end{pmatrix}\end{align}";
+    let diagnostics = check_math(latex);
+    let problems: Vec<_> = diagnostics
+        .iter()
+        .map(|d| (d.code, &latex[d.span.clone()]))
+        .collect();
+    assert_eq!(
+        vec![
+            ("unbalanced-brace", "{"),
+            ("misplaced-ampersand", "&"),
+            ("unmatched-left", r"\left"),
+            ("unknown-math-macro", r"\foo"),
+        ],
+        problems
+    );
+    assert!(diagnostics.iter().all(|d| d.severity == Severity::Error));
+    let diagnostics = check_math(r"$\ket{0} + \SI{3}{\metre} + \ce{H2O}$");
+    assert_eq!(Vec::<Diagnostic>::new(), diagnostics);
+
+    // Unclosed arguments do not each look through the rest of the math.
+    let start = std::time::Instant::now();
+    let diagnostics = check_math(&format!("${}$", r"\text{".repeat(20_000)));
+    assert_eq!("unbalanced-brace", diagnostics[0].code);
+    assert!(start.elapsed() < std::time::Duration::from_secs(10));
+
+    expect![[r#"
+        \error{bad math at 1..6: \textbackslash{}left has no matching \textbackslash{}right}\\
+        \error{bad math at 15..21: MathJax does not know \textbackslash{}bogus}\\
+        \warning{possibly bad macro: \textbackslash{}beta}\\
+        $\left( \alpha \bogus$ and \textbf{\beta}"#]]
+    .assert_eq(&check_latex(r"$\left( \alpha \bogus$ and \textbf{\beta}"));
+}
+
+#[test]
+fn math_lite() {
+    let options = RenderOptions::new().math(MathOutput::Lite);
+    let lite = |latex| render(latex, &options).unwrap().html;
+    expect!["A speed <i>v</i><sub>0</sub> and area 2&#960;<i>r</i><sup>2</sup>, with &#916;<i>t</i> &#8804; 3&nbsp;s at 30&deg;."]
+    .assert_eq(&lite(
+        r"A speed $v_0$ and area $2\pi r^2$, with $\Delta t \leq 3\,\mathrm{s}$ at $30^\circ$.",
+    ));
+    expect![[r#"Here \(\frac{1}{2}\) and \[x^2\]"#]]
+        .assert_eq(&lite(r"Here $\frac{1}{2}$ and \[x^2\]"));
+    assert!(render(r"$\frac{1}{2}$", &options).unwrap().diagnostics.is_empty());
+}
+
+#[test]
+fn equation_numbers() {
+    let options = RenderOptions::new().number_equations(true);
+    let latex = r"By \eqref{eq:b} and \ref{eq:tagged},
+\begin{equation}E = mc^2\label{eq:a}\end{equation}
+\begin{align}a &= b \label{eq:b}\\ c &= d \nonumber\\ e &= f \tag{T}\label{eq:tagged}\end{align}
+\begin{align*}g &= h\end{align*}
+\begin{equation}x \label{eq:a}\end{equation}
+so $x = \eqref{eq:a}$, though \ref{eq:missing} is not.";
+    let rendered = render(latex, &options).unwrap();
+    expect![[r##"
+        By <a href="#eq:b">(2)</a> and <a href="#eq:tagged">T</a>,
+        <span id="eq:a"></span>\begin{equation*}E = mc^2\tag{1}\end{equation*}
+        <span id="eq:b"></span><span id="eq:tagged"></span>\begin{align*}a &amp;= b \tag{2}\\ c &amp;= d \\ e &amp;= f \tag{T}\end{align*}
+        \begin{align*}g &amp;= h\end{align*}
+        \begin{equation*}x \tag{3}\end{equation*}
+        so \(x = {\text{(1)}}\), though <span class="error">??</span> is not."##]]
+    .assert_eq(&rendered.html);
+    let problems: Vec<_> = rendered
+        .diagnostics
+        .iter()
+        .map(|d| (d.code, &latex[d.span.clone()]))
+        .collect();
+    assert_eq!(
+        vec![
+            ("duplicate-label", r"\label{eq:a}"),
+            ("undefined-label", r"\ref{eq:missing}"),
+        ],
+        problems
+    );
+
+    let options = options.math(MathOutput::MathMl);
+    expect![[r##"<span id="y"></span><math display="block"><mtable columnalign="center right"><mtr><mtd><mi>x</mi></mtd><mtd><mtext>(1)</mtext></mtd></mtr></mtable></math> <a href="#y">(1)</a>"##]]
+    .assert_eq(&render(r"\begin{equation}x\label{y}\end{equation} \eqref{y}", &options).unwrap().html);
+    // Without numbering, MathJax does it.
+    expect![[r#"\begin{equation}x\label{y}\end{equation} \eqref{y}"#]]
+        .assert_eq(&html_string(r"\begin{equation}x\label{y}\end{equation} \eqref{y}"));
+}
+
+#[test]
+fn unnumbered_equation_labels() {
+    let options = RenderOptions::new().number_equations(true);
+    let latex = r"\begin{equation*}x\label{eq:x}\end{equation*}
+\begin{align}y \nonumber\label{eq:y}\\ z\end{align}
+See \ref{eq:x} and \ref{eq:y}.";
+    let problems: Vec<_> = render(latex, &options)
+        .unwrap()
+        .diagnostics
+        .iter()
+        .map(|d| (d.code, &latex[d.span.clone()]))
+        .collect();
+    assert_eq!(
+        vec![
+            ("stray-label", r"\label{eq:x}"),
+            ("stray-label", r"\label{eq:y}"),
+            ("undefined-label", r"\ref{eq:x}"),
+            ("undefined-label", r"\ref{eq:y}"),
+        ],
+        problems
+    );
+}
+
+#[test]
+fn labels_in_text_and_equations() {
+    let options = RenderOptions::new().number_equations(true);
+    let latex = r"\section{A}\label{d} \begin{equation} b \label{d} \end{equation} \ref{d}";
+    let rendered = render(latex, &options).unwrap();
+    expect![[r##"<section><h2>A</h2><span id="d"></span> \begin{equation*} b  \tag{1}\end{equation*} <a href="#d">1</a></section>"##]]
+        .assert_eq(&rendered.html);
+    let problems: Vec<_> = rendered
+        .diagnostics
+        .iter()
+        .map(|d| (d.code, d.span.clone()))
+        .collect();
+    let second = latex.rfind(r"\label{d}").unwrap();
+    assert_eq!(vec![("duplicate-label", second..second + 9)], problems);
+
+    let latex = r"\begin{equation} b \label{d} \end{equation} \section{A}\label{d} \ref{d}";
+    let rendered = render(latex, &options).unwrap();
+    expect![[r##"<span id="d"></span>\begin{equation*} b  \tag{1}\end{equation*} <section><h2>A</h2> <a href="#d">1</a></section>"##]]
+        .assert_eq(&rendered.html);
+    let problems: Vec<_> = rendered
+        .diagnostics
+        .iter()
+        .map(|d| (d.code, d.span.clone()))
+        .collect();
+    let second = latex.rfind(r"\label{d}").unwrap();
+    assert_eq!(vec![("duplicate-label", second..second + 9)], problems);
+}
+
+#[test]
+fn math_environments() {
+    expect![[r#"\begin{gather}a \\ b\end{gather} \begin{flalign*}x &amp;= 1\end{flalign*}"#]]
+    .assert_eq(&html_string(r"\begin{gather}a \\ b\end{gather} \begin{flalign*}x &= 1\end{flalign*}"));
+    expect![[r#"So \(x^2\) and \[y\]"#]]
+    .assert_eq(&html_string(r"So \begin{math}x^2\end{math} and \begin{displaymath}y\end{displaymath}"));
+    expect![[r#"\[\begin{pmatrix}1 &amp; 0\\0 &amp; 1\end{pmatrix}\] and \[\begin{cases}1 &amp; x &gt; 0\\0\end{cases}\]"#]]
+    .assert_eq(&html_string(r"\begin{pmatrix}1 & 0\\0 & 1\end{pmatrix} and \begin{cases}1 & x > 0\\0\end{cases}"));
+
+    let options = RenderOptions::new().math(MathOutput::MathMl).number_equations(true);
+    expect![[r#"<math display="block"><mtable columnalign="right left"><mtr><mtd><mi>a</mi></mtd><mtd><mo>=</mo><mi>b</mi></mtd><mtd><mspace width="1em"/><mi>c</mi></mtd><mtd><mo>=</mo><mi>d</mi></mtd><mtd><mtext>(1)</mtext></mtd></mtr></mtable></math>"#]]
+    .assert_eq(&render(r"\begin{alignat}{2}a &= b &\quad c &= d\end{alignat}", &options).unwrap().html);
+    expect![[r#"<math display="block"><mrow><mo fence="true" stretchy="true">[</mo><mtable columnalign="center"><mtr><mtd><mi>a</mi></mtd><mtd><mi>b</mi></mtd></mtr></mtable><mo fence="true" stretchy="true">]</mo></mrow></math>"#]]
+    .assert_eq(&render(r"\begin{bmatrix}a & b\end{bmatrix}", &options).unwrap().html);
+
+    let problems = |latex| -> Vec<_> {
+        let rendered = render(latex, &RenderOptions::new()).unwrap();
+        rendered.diagnostics.iter().map(|d| (d.code, d.span.clone())).collect()
+    };
+    assert_eq!(
+        vec![("invalid-argument", 0..37)],
+        problems(r"\begin{alignat}{0}a &= b\end{alignat}")
+    );
+    assert_eq!(
+        vec![("too-many-columns", 27..38)],
+        problems(r"\begin{alignat*}{1}a &= b\\ c &= d & e\end{alignat*}")
+    );
+    assert_eq!(
+        vec![("missing-argument", 0..29)],
+        problems(r"\begin{array}a & b\end{array}")
+    );
+    // check_latex agrees that these are fine.
+    let latex = r"\begin{gather*}a\end{gather*} \begin{split}b\end{split} $\begin{Vmatrix}1\end{Vmatrix}$";
+    assert_eq!(latex, check_latex(latex));
+}
+
+#[test]
+fn speech() {
+    let options = RenderOptions::new().speech(Speech::Brief);
+    let rendered = render(r"If $a<b$ then \[\frac{a+b}{2} < b\] but not $\foo$.", &options).unwrap();
+    expect![[r#"If <span role="math" aria-label="a is less than b">\(a&lt;b\)</span> then <span role="math" aria-label="the fraction with numerator a plus b, and denominator 2, is less than b">\[\frac{a+b}{2} &lt; b\]</span> but not \(\foo\)."#]]
+    .assert_eq(&rendered.html);
+    assert_eq!(1, rendered.diagnostics.len());
+    assert_eq!("unspoken-math", rendered.diagnostics[0].code);
+
+    let options = options.speech(Speech::Verbose).math(MathOutput::MathMl);
+    expect![[r#"<span role="math" aria-label="start root x subscript 1 baseline end root"><math><msqrt><msub><mi>x</mi><mn>1</mn></msub></msqrt></math></span>"#]]
+    .assert_eq(&render(r"$\sqrt{x_1}$", &options).unwrap().html);
+    let options = RenderOptions::new().speech(Speech::Brief).math(MathOutput::Lite);
+    expect![[r#"<span role="math" aria-label="alpha squared">&#945;<sup>2</sup></span>"#]]
+    .assert_eq(&render(r"$\alpha^2$", &options).unwrap().html);
+}
+
+#[test]
+fn braille_output() {
+    let latex = r"\section{Energy}
+The energy is $E = mc^2$, which is \textbf{very} important.
+\begin{itemize}
+\item First
+\item Second \includegraphics{a.png}
+\end{itemize}
+\begin{equation}
+x = \frac{-b \pm \sqrt{b^2-4ac}}{2a} \label{q}
+\end{equation}
+See \eqref{q} and $\binom{n}{k}$.";
+    let contracted = braille(latex, BrailleGrade::Contracted);
+    expect![[r#"
+        ⠠⠢⠻⠛⠽
+        ⠠⠮⠀⠢⠻⠛⠽⠀⠊⠎⠀⠸⠩⠀⠠⠑⠀⠨⠅⠀⠍⠉⠘⠆⠀⠸⠱⠂⠀⠱⠀⠊⠎⠀⠘⠂⠧⠀⠊⠍⠏⠕⠗⠞⠁⠝⠞⠲
+        ⠸⠲⠀⠠⠋⠊⠗⠌
+        ⠸⠲⠀⠠⠎⠑⠉⠕⠝⠙
+        ⠸⠩⠀⠭⠀⠨⠅⠀⠹⠤⠃⠬⠤⠜⠃⠘⠆⠐⠤⠲⠁⠉⠻⠌⠆⠁⠼⠀⠷⠂⠾⠀⠸⠱
+        ⠠⠎⠑⠑⠀⠐⠣⠼⠁⠐⠜⠀⠯⠀⠸⠡⠃⠊⠝⠕⠍⠸⠣⠝⠸⠜⠸⠣⠅⠸⠜⠲"#]]
+    .assert_eq(&contracted.braille);
+    expect![[r#"
+        ,5]GY
+        ,! 5]GY IS _% ,E .K MC^2 _:1 : IS ^1V IMPORTANT4
+        _4 ,FIR/
+        _4 ,SECOND
+        _% X .K ?-B+->B^2"-4AC]/2A# (1) _:
+        ,SEE "<#A"> & _*BINOM_<N_>_<K_>4"#]]
+    .assert_eq(&contracted.brf());
+    let codes: Vec<_> = contracted.diagnostics.iter().map(|d| d.code).collect();
+    assert_eq!(vec!["unsupported-braille", "unsupported-braille"], codes);
+    expect![[r#"
+        ⠠⠑⠝⠑⠗⠛⠽
+        ⠠⠞⠓⠑⠀⠑⠝⠑⠗⠛⠽⠀⠊⠎⠀⠸⠩⠀⠠⠑⠀⠨⠅⠀⠍⠉⠘⠆⠀⠸⠱⠂⠀⠺⠓⠊⠉⠓⠀⠊⠎⠀⠘⠂⠧⠑⠗⠽⠀⠊⠍⠏⠕⠗⠞⠁⠝⠞⠲
+        ⠸⠲⠀⠠⠋⠊⠗⠎⠞
+        ⠸⠲⠀⠠⠎⠑⠉⠕⠝⠙
+        ⠸⠩⠀⠭⠀⠨⠅⠀⠹⠤⠃⠬⠤⠜⠃⠘⠆⠐⠤⠲⠁⠉⠻⠌⠆⠁⠼⠀⠷⠂⠾⠀⠸⠱
+        ⠠⠎⠑⠑⠀⠐⠣⠼⠁⠐⠜⠀⠁⠝⠙⠀⠸⠡⠃⠊⠝⠕⠍⠸⠣⠝⠸⠜⠸⠣⠅⠸⠜⠲"#]]
+    .assert_eq(&braille(latex, BrailleGrade::Uncontracted).braille);
+    assert_eq!("⠠⠠⠃⠗⠋", braille_string("BRF", BrailleGrade::Uncontracted, false));
+    assert_eq!(",,BRF", braille_string("BRF", BrailleGrade::Uncontracted, true));
+}
+
+#[test]
+fn table_of_contents() {
+    let latex = r"\tableofcontents
+\section[Energy]{Energy and work}
+Intro.
+\subsection{Kinetic energy}
+\subsection*{Aside}
+\subsection{Potential energy}
+\section{Energy and work}";
+    expect![[r##"
+        <nav class="toc"><ul><li><a href="#energy-and-work">Energy</a><ul><li><a href="#kinetic-energy">Kinetic energy</a></li><li><a href="#potential-energy">Potential energy</a></li></ul></li><li><a href="#energy-and-work-2">Energy and work</a></li></ul></nav>
+        <section><h2 id="energy-and-work">Energy and work</h2>
+        Intro.
+        <section><h3 id="kinetic-energy">Kinetic energy</h3></section><section><h3 id="aside">Aside</h3></section><section><h3 id="potential-energy">Potential energy</h3></section></section><section><h2 id="energy-and-work-2">Energy and work</h2></section>"##]]
+    .assert_eq(&html_string(latex));
+
+    let options = RenderOptions::new().number_sections(true).heading_ids(true);
+    let rendered = render(r"\section{Waves} \subsection{Sound} \section*{Notes}", &options).unwrap();
+    expect![[r#"<section><h2 id="waves"><span class="section-number">1</span> Waves</h2><section><h3 id="sound"><span class="section-number">1.1</span> Sound</h3></section></section><section><h2 id="notes">Notes</h2></section>"#]]
+    .assert_eq(&rendered.html);
+    expect![[r##"<nav class="toc"><ul><li><a href="#waves"><span class="section-number">1</span> Waves</a><ul><li><a href="#sound"><span class="section-number">1.1</span> Sound</a></li></ul></li></ul></nav>"##]]
+    .assert_eq(&rendered.table_of_contents);
+
+    let rendered = render(r"\section{Waves}", &RenderOptions::new().number_sections(true)).unwrap();
+    expect![[r#"<section><h2><span class="section-number">1</span> Waves</h2></section>"#]]
+    .assert_eq(&rendered.html);
+    assert_eq!("", rendered.table_of_contents);
+}
+
+#[test]
+fn contents_in_a_heading() {
+    for latex in [
+        r"\section{\tableofcontents}",
+        r"\section{x\tableofcontents}y",
+        r"\section[\tableofcontents]{x}\tableofcontents",
+    ] {
+        let (_, diagnostics) = html_with_diagnostics(latex);
+        let codes: Vec<_> = diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(vec!["misplaced-contents"], codes, "{}", latex);
+        let options = RenderOptions::new().number_sections(true).heading_ids(true);
+        render(latex, &options).unwrap();
+        braille(latex, BrailleGrade::Contracted);
+    }
+    expect![[r#"<section><h2>x<span class="error">\tableofcontents</span></h2>y</section>"#]]
+        .assert_eq(&html_string(r"\section{x\tableofcontents}y"));
+
+    // Nor does a heading built by hand hold the contents.
+    let contents = Node::new(
+        NodeKind::Macro {
+            name: "tableofcontents".to_string(),
+            args: Vec::new(),
+        },
+        0..0,
+    );
+    let section = NodeKind::Section {
+        level: 1,
+        starred: false,
+        short_title: None,
+        title: vec![contents.clone()],
+        content: vec![contents],
+    };
+    let document = Document {
+        content: vec![Node::new(section, 0..0)],
+    };
+    let mut html = Vec::new();
+    document.write_html(&mut html).unwrap();
+    expect![[r##"<section><h2 id="section"></h2><nav class="toc"><ul><li><a href="#section"></a></li></ul></nav></section>"##]]
+        .assert_eq(&String::from_utf8(html).unwrap());
+}
+
+#[test]
+fn cross_references() {
+    let latex = r"\section{Waves}\label{sec:waves}
+\begin{figure}\includegraphics{a.png}\caption{A wave}\label{fig:wave}\end{figure}
+\begin{table}\caption{Speeds}\label{tab:speeds}\end{table}
+\begin{lemma}[Superposition]\label{lem:sup}Waves add.\end{lemma}
+\begin{enumerate}\item First \item Second\label{item:two}\end{enumerate}
+See \autoref{fig:wave}, \cref{tab:speeds}, \Cref{lem:sup}, item \ref{item:two}
+on page \pageref{sec:waves}, \hyperref[sec:waves]{this section} and \autoref{sec:none}.";
+    let rendered = render(latex, &RenderOptions::new()).unwrap();
+    expect![[r##"
+        <section><h2>Waves</h2><span id="sec:waves"></span>
+        <figure><img src="a.png"/><figcaption>Figure 1: A wave</figcaption><span id="fig:wave"></span></figure>
+        <figure class="table"><figcaption>Table 1: Speeds</figcaption><span id="tab:speeds"></span></figure>
+        <div class="lemma"><b>Lemma 1 (Superposition).</b> <span id="lem:sup"></span>Waves add.</div>
+        <ol><li>First </li><li>Second<span id="item:two"></span></li></ol>
+        See <a href="#fig:wave">Figure 1</a>, <a href="#tab:speeds">table 1</a>, <a href="#lem:sup">Lemma 1</a>, item <a href="#item:two">2</a>
+        on page <a href="#sec:waves">1</a>, <a href="#sec:waves">this section</a> and <span class="error">??</span>.</section>"##]]
+    .assert_eq(&rendered.html);
+    let problems: Vec<_> = rendered
+        .diagnostics
+        .iter()
+        .map(|d| (d.code, &latex[d.span.clone()]))
+        .collect();
+    assert_eq!(vec![("undefined-label", r"\autoref{sec:none}")], problems);
+
+    // A label in math is left to MathJax, unless we number equations.
+    let latex = r"\begin{equation}E = mc^2\label{eq:e}\end{equation} \autoref{eq:e}";
+    expect![[r#"\begin{equation}E = mc^2\label{eq:e}\end{equation} Equation \ref{eq:e}"#]]
+    .assert_eq(&html_string(latex));
+    let options = RenderOptions::new().number_equations(true);
+    expect![[r##"<span id="eq:e"></span>\begin{equation*}E = mc^2\tag{1}\end{equation*} <a href="#eq:e">Equation 1</a>"##]]
+    .assert_eq(&render(latex, &options).unwrap().html);
+
+    let latex = r"\begin{theorem}\label{t}\end{theorem} \cref{t}";
+    expect![[r#"
+        ,THEOREM #A4
+        THEOREM #A"#]]
+    .assert_eq(&braille(latex, BrailleGrade::Uncontracted).brf());
+}
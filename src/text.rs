@@ -0,0 +1,272 @@
+//! Accents, special letters and symbols in text, such as `\v{r}`, `\ss`
+//! and `\S`.
+
+/// The accents, with the name of their HTML entities where there is
+/// one, their combining character, and the letters that have a
+/// precomposed form, each followed by that form.
+const ACCENTS: &[(&str, Option<&str>, char, &str)] = &[
+    (
+        "`",
+        Some("grave"),
+        '\u{300}',
+        "AÀEÈIÌOÒUÙaàeèiìoòuùNǸnǹWẀwẁYỲyỳ",
+    ),
+    (
+        "'",
+        Some("acute"),
+        '\u{301}',
+        "AÁCĆEÉGǴIÍKḰLĹMḾNŃOÓPṔRŔSŚUÚWẂYÝZŹaácćeégǵiíkḱlĺmḿnńoópṕrŕsśuúwẃyýzź",
+    ),
+    (
+        "^",
+        Some("circ"),
+        '\u{302}',
+        "AÂCĈEÊGĜHĤIÎJĴOÔSŜUÛWŴYŶZẐaâcĉeêgĝhĥiîjĵoôsŝuûwŵyŷzẑ",
+    ),
+    (
+        "~",
+        Some("tilde"),
+        '\u{303}',
+        "AÃEẼIĨNÑOÕUŨVṼYỸaãeẽiĩnñoõuũvṽyỹ",
+    ),
+    ("=", None, '\u{304}', "AĀEĒGḠIĪOŌUŪYȲaāeēgḡiīoōuūyȳ"),
+    ("u", None, '\u{306}', "AĂEĔGĞIĬOŎUŬaăeĕgğiĭoŏuŭ"),
+    (
+        ".",
+        None,
+        '\u{307}',
+        "BḂCĊDḊEĖFḞGĠHḢIİMṀNṄPṖRṘSṠTṪWẆXẊYẎZŻbḃcċdḋeėfḟgġhḣmṁnṅpṗrṙsṡtṫwẇxẋyẏzż",
+    ),
+    (
+        "\"",
+        Some("uml"),
+        '\u{308}',
+        "AÄEËHḦIÏOÖUÜWẄXẌYŸaäeëhḧiïoötẗuüwẅxẍyÿ",
+    ),
+    ("r", Some("ring"), '\u{30a}', "AÅUŮaåuůwẘyẙ"),
+    ("H", None, '\u{30b}', "OŐUŰoőuű"),
+    (
+        "v",
+        None,
+        '\u{30c}',
+        "AǍCČDĎEĚGǦHȞIǏKǨLĽNŇOǑRŘSŠTŤUǓZŽaǎcčdďeěgǧhȟiǐjǰkǩlľnňoǒrřsštťuǔzž",
+    ),
+    (
+        "c",
+        Some("cedil"),
+        '\u{327}',
+        "CÇDḐEȨGĢHḨKĶLĻNŅRŖSŞTŢcçdḑeȩgģhḩkķlļnņrŗsştţ",
+    ),
+    ("k", None, '\u{328}', "AĄEĘIĮOǪUŲaąeęiįoǫuų"),
+    (
+        "d",
+        None,
+        '\u{323}',
+        "AẠBḄDḌEẸHḤIỊKḲLḶMṂNṆOỌRṚSṢTṬUỤVṾWẈYỴZẒaạbḅdḍeẹhḥiịkḳlḷmṃnṇoọrṛsṣtṭuụvṿwẉyỵzẓ",
+    ),
+    ("b", None, '\u{331}', "BḆDḎKḴLḺNṈRṞTṮZẔbḇdḏhẖkḵlḻnṉrṟtṯzẕ"),
+    ("t", None, '\u{361}', ""),
+];
+
+/// The accents that have a spacing form, as when written on nothing in
+/// `\^{}`, with that form in HTML.  Any other accent goes on a
+/// no-break space.
+const SPACING: &[(&str, &str)] = &[
+    ("`", "`"),
+    ("'", "&acute;"),
+    ("^", "^"),
+    ("~", "~"),
+    ("=", "&macr;"),
+    ("u", "&#728;"),
+    (".", "&#729;"),
+    ("\"", "&uml;"),
+    ("r", "&#730;"),
+    ("H", "&#733;"),
+    ("v", "&#711;"),
+    ("c", "&cedil;"),
+    ("k", "&#731;"),
+];
+
+/// The special letters, with how they are written in HTML.
+const LETTERS: &[(&str, &str)] = &[
+    ("ss", "&szlig;"),
+    ("o", "&oslash;"),
+    ("O", "&Oslash;"),
+    ("ae", "&aelig;"),
+    ("AE", "&AElig;"),
+    ("oe", "&oelig;"),
+    ("OE", "&OElig;"),
+    ("aa", "&aring;"),
+    ("AA", "&#8491;"),
+    ("l", "&#322;"),
+    ("L", "&#321;"),
+    ("i", "&#305;"),
+    ("j", "&#567;"),
+    ("dh", "&eth;"),
+    ("DH", "&ETH;"),
+    ("th", "&thorn;"),
+    ("TH", "&THORN;"),
+    ("ng", "&#331;"),
+    ("NG", "&#330;"),
+    ("dj", "&#273;"),
+    ("DJ", "&#272;"),
+];
+
+/// The symbols, with how they are written in HTML.
+const SYMBOLS: &[(&str, &str)] = &[
+    ("S", "&sect;"),
+    ("textsection", "&sect;"),
+    ("P", "&para;"),
+    ("textparagraph", "&para;"),
+    ("dag", "&dagger;"),
+    ("textdagger", "&dagger;"),
+    ("ddag", "&Dagger;"),
+    ("textdaggerdbl", "&Dagger;"),
+    ("copyright", "&copy;"),
+    ("textcopyright", "&copy;"),
+    ("textregistered", "&reg;"),
+    ("texttrademark", "&trade;"),
+    ("textdegree", "&deg;"),
+    ("textcelsius", "&#8451;"),
+    ("textmu", "&micro;"),
+    ("textohm", "&#8486;"),
+    ("textpm", "&plusmn;"),
+    ("texttimes", "&times;"),
+    ("textdiv", "&divide;"),
+    ("textonehalf", "&frac12;"),
+    ("textonequarter", "&frac14;"),
+    ("textthreequarters", "&frac34;"),
+    ("textperthousand", "&permil;"),
+    ("textnumero", "&#8470;"),
+    ("euro", "&euro;"),
+    ("texteuro", "&euro;"),
+    ("pounds", "&pound;"),
+    ("textsterling", "&pound;"),
+    ("textyen", "&yen;"),
+    ("textcent", "&cent;"),
+    ("textdollar", "<span>$</span>"),
+    ("textellipsis", "&hellip;"),
+    ("dots", "&hellip;"),
+    ("textbullet", "&bull;"),
+    ("textperiodcentered", "&middot;"),
+    ("textasteriskcentered", "*"),
+    ("textendash", "&ndash;"),
+    ("textemdash", "&mdash;"),
+    ("textquoteleft", "&lsquo;"),
+    ("textquoteright", "&rsquo;"),
+    ("textquotedblleft", "&ldquo;"),
+    ("textquotedblright", "&rdquo;"),
+    ("guillemotleft", "&laquo;"),
+    ("guillemotright", "&raquo;"),
+    ("guilsinglleft", "&lsaquo;"),
+    ("guilsinglright", "&rsaquo;"),
+    ("textexclamdown", "&iexcl;"),
+    ("textquestiondown", "&iquest;"),
+    ("textordfeminine", "&ordf;"),
+    ("textordmasculine", "&ordm;"),
+    ("textless", "&lt;"),
+    ("textgreater", "&gt;"),
+    ("textbar", "|"),
+    ("textasciitilde", "~"),
+    ("textasciicircum", "^"),
+    ("textunderscore", "_"),
+    ("textbraceleft", r"\{"),
+    ("textbraceright", r"\}"),
+    ("checkmark", "&#10003;"),
+    ("TeX", "TeX"),
+    ("LaTeX", "LaTeX"),
+    ("LaTeXe", "LaTeX2&epsilon;"),
+];
+
+/// Whether `name`, without its backslash, is an accent such as `v` in
+/// `\v{s}`.
+pub(crate) fn is_accent(name: &str) -> bool {
+    ACCENTS.iter().any(|(accent, ..)| *accent == name)
+}
+
+/// The names of the accents, special letters and symbols, without
+/// their backslashes.
+pub(crate) fn names() -> impl Iterator<Item = &'static str> {
+    let accents = ACCENTS.iter().map(|(name, ..)| *name);
+    let characters = LETTERS.iter().chain(SYMBOLS).map(|(name, _)| *name);
+    accents.chain(characters)
+}
+
+/// The HTML for the special letter or symbol `name`, such as `ss` or
+/// `S`.
+pub(crate) fn character(name: &str) -> Option<&'static str> {
+    let mut characters = LETTERS.iter().chain(SYMBOLS);
+    characters.find(|(c, _)| *c == name).map(|(_, html)| *html)
+}
+
+/// The HTML for `letter` with `accent` on it, using the precomposed
+/// character where there is one, or `None` if `letter` is not something
+/// we can accent.  With no `letter` this is the accent on its own, and
+/// the tie of `\t` goes across two letters.
+pub(crate) fn accented(accent: &str, letter: &str) -> Option<String> {
+    let &(_, entity, combining, composed) = ACCENTS.iter().find(|(a, ..)| *a == accent)?;
+    if letter.trim().is_empty() && accent != "t" {
+        return Some(match SPACING.iter().find(|(a, _)| *a == accent) {
+            Some((_, html)) => html.to_string(),
+            None => format!("&nbsp;&#{};", combining as u32),
+        });
+    }
+    // The dotless letters are what LaTeX puts accents on, but the
+    // precomposed characters are made from dotted ones.
+    let letter = match letter.trim() {
+        r"\i" => "\u{131}",
+        r"\j" => "\u{237}",
+        letter => letter,
+    };
+    let letters: Vec<char> = letter.chars().collect();
+    let base = match (accent, letters.as_slice()) {
+        ("t", [a, b]) if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => {
+            return Some(format!("{}&#{};{}", a, combining as u32, b));
+        }
+        ("t", _) => return None,
+        (_, ['\u{131}']) => 'i',
+        (_, ['\u{237}']) => 'j',
+        (_, [c]) if c.is_ascii_alphabetic() => *c,
+        _ => return None,
+    };
+    let mut chars = composed.chars();
+    while let (Some(from), Some(to)) = (chars.next(), chars.next()) {
+        if from == base {
+            return Some(match entity {
+                Some(name) if ('\u{c0}'..='\u{ff}').contains(&to) => {
+                    format!("&{}{};", base, name)
+                }
+                _ => format!("&#{};", to as u32),
+            });
+        }
+    }
+    let base = letter.chars().next()?;
+    if base.is_ascii() {
+        Some(format!("{}&#{};", base, combining as u32))
+    } else {
+        Some(format!("&#{};&#{};", base as u32, combining as u32))
+    }
+}
+
+#[test]
+fn test_accented() {
+    assert_eq!(Some("&eacute;".to_string()), accented("'", "e"));
+    assert_eq!(Some("&#345;".to_string()), accented("v", "r"));
+    assert_eq!(Some("&iuml;".to_string()), accented("\"", r"\i"));
+    assert_eq!(Some("&#376;".to_string()), accented("\"", "Y"));
+    assert_eq!(Some("&ccedil;".to_string()), accented("c", "c"));
+    assert_eq!(Some("&#337;".to_string()), accented("H", "o"));
+    assert_eq!(Some("q&#769;".to_string()), accented("'", "q"));
+    assert_eq!(Some("&#496;".to_string()), accented("v", r"\j"));
+    assert_eq!(Some("&#567;&#803;".to_string()), accented("d", r"\j"));
+    assert_eq!(Some("o&#865;o".to_string()), accented("t", "oo"));
+    assert_eq!(None, accented("'", "ab"));
+    assert_eq!(None, accented("'", "1"));
+    assert_eq!(Some("^".to_string()), accented("^", ""));
+    assert_eq!(Some("~".to_string()), accented("~", " "));
+    assert_eq!(Some("&acute;".to_string()), accented("'", ""));
+    assert_eq!(Some("&nbsp;&#803;".to_string()), accented("d", ""));
+    assert_eq!(None, accented("t", ""));
+    assert_eq!(None, accented("t", "o"));
+    assert_eq!(None, accented("x", "e"));
+}